@@ -177,6 +177,36 @@ fn rule_match_parsed_elep_slimlist(c: &mut Criterion) {
     );
 }
 
+/// Checks hostname-only requests, which skip filters that need a URL path to match, against a
+/// `Blocker`, both as full requests and with `Blocker::check_hostname`.
+fn hostname_only_requests(c: &mut Criterion) {
+  let rules = rules_from_lists(&vec![
+    String::from("data/easylist.to/easylist/easylist.txt"),
+  ]);
+  let blocker = get_blocker(&rules);
+  let requests: Vec<Request> = load_requests()
+    .into_iter()
+    .filter_map(|r| parse_url(&r.url.to_ascii_lowercase()).map(|parsed| format!("https://{}/", parsed.hostname())))
+    .filter_map(|url| Request::from_urls(&url, "", "other").ok())
+    .take(200)
+    .collect();
+  let request_count = requests.len() as u64;
+  let hostname_checks = (blocker.clone(), requests.clone());
+
+  c.bench(
+    "hostname-only-requests",
+    Benchmark::new("check", move |b| {
+      b.iter(|| requests.iter().filter(|request| blocker.check(request).matched).count())
+    },)
+    .with_function("check-hostname", move |b| {
+      let (blocker, requests) = &hostname_checks;
+      b.iter(|| requests.iter().filter(|request| blocker.check_hostname(request).matched).count())
+    },)
+    .throughput(Throughput::Elements(request_count))
+    .sample_size(10)
+  );
+}
+
 fn serialization(c: &mut Criterion) {
   c.bench(
         "blocker-serialization",
@@ -343,9 +373,81 @@ fn rule_match_browserlike_comparable(c: &mut Criterion) {
       );
 }
 
+/// Measures the cost of looking for the earliest matching filter rather than stopping at the
+/// first one, using a single bucket of filters in which either one or all of them match.
+fn earliest_match(c: &mut Criterion) {
+  let request = Request::from_urls("https://cdn.com/banner/ad.png", "https://example.com/", "image").unwrap();
+  let one_request = request.clone();
+  let blocker_with = |matching: fn(usize) -> bool| {
+    let rules: Vec<String> = (0..200)
+      .map(|i| if matching(i) {
+        format!("/banner/$image,domain=site{}.com|example.com", i)
+      } else {
+        format!("/banner/$image,domain=site{}.com", i)
+      })
+      .collect();
+    let (network_filters, _) = adblock::lists::parse_filters(&rules, false, FilterFormat::Standard);
+    Blocker::new(network_filters, &BlockerOptions { enable_optimizations: false })
+  };
+  let one_match = blocker_with(|i| i == 199);
+  let all_match = blocker_with(|_| true);
+
+  c.bench(
+    "earliest-match",
+    Benchmark::new("one-match", move |b| {
+      b.iter(|| one_match.check(&one_request).matched)
+    },)
+    .with_function("all-match", move |b| {
+      b.iter(|| all_match.check(&request).matched)
+    },)
+    .throughput(Throughput::Elements(1))
+  );
+}
+
+fn hostname_match(c: &mut Criterion) {
+  let requests = load_requests();
+  let mut hostnames: Vec<String> = requests
+    .iter()
+    .filter_map(|r| parse_url(&r.url.to_ascii_lowercase()).map(|parsed| parsed.hostname().to_owned()))
+    .collect();
+  hostnames.sort();
+  hostnames.dedup();
+  let url_hostnames = hostnames.clone();
+  let hostnames_len = hostnames.len() as u64;
+
+  c.bench(
+    "hostname-match",
+    Benchmark::new("url", move |b| {
+      let rules = rules_from_lists(&vec![
+        "data/easylist.to/easylist/easyprivacy.txt".to_owned()
+      ]);
+      let engine = Engine::from_rules(&rules, FilterFormat::Standard);
+      b.iter(|| url_hostnames
+        .iter()
+        .filter(|hostname| engine.check_network_urls(&format!("https://{}/", hostname), "", "other").matched)
+        .count())
+    },)
+    .with_function("hostname", move |b| {
+      let rules = rules_from_lists(&vec![
+        "data/easylist.to/easylist/easyprivacy.txt".to_owned()
+      ]);
+      let engine = Engine::from_rules(&rules, FilterFormat::Standard);
+      b.iter(|| hostnames
+        .iter()
+        .filter(|hostname| engine.check_hostname(hostname).matched)
+        .count())
+    },)
+    .throughput(Throughput::Elements(hostnames_len))
+    .sample_size(20)
+  );
+}
+
 criterion_group!(
   benches,
   rule_match,
+  hostname_match,
+  hostname_only_requests,
+  earliest_match,
   rule_match_parsed_el,
   rule_match_parsed_elep_slimlist,
   rule_match_browserlike_comparable,
@@ -51,6 +51,54 @@ fn string_tokenize(c: &mut Criterion) {
     );
 }
 
+/// Tokenizes like `adblock::utils::tokenize`, classifying one byte at a time, as a baseline for the
+/// word-at-a-time scan of the engine.
+fn tokenize_bytewise(pattern: &str) -> Vec<adblock::utils::Hash> {
+  let bytes = pattern.as_bytes();
+  let mut tokens = Vec::with_capacity(128);
+  let mut start = None;
+  for i in 0..=bytes.len() {
+    let allowed = i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'%');
+    match (start, allowed) {
+      (None, true) => start = Some(i),
+      (Some(s), false) => {
+        start = None;
+        let followed_by_star = i < bytes.len() && bytes[i] == b'*';
+        let preceded_by_star = s > 0 && bytes[s - 1] == b'*';
+        if i - s > 1 && !followed_by_star && !preceded_by_star && tokens.len() < 127 {
+          tokens.push(adblock::utils::fast_hash(&pattern[s..i]));
+        }
+      }
+      _ => {}
+    }
+  }
+  tokens
+}
+
+fn url_tokenize(c: &mut Criterion) {
+  let urls: Vec<String> = read_file_lines("data/requests.json")
+    .into_iter()
+    .filter_map(|line| serde_json::from_str::<serde_json::Value>(&line).ok())
+    .filter_map(|request| request["url"].as_str().map(|url| url.to_ascii_lowercase()))
+    .filter(|url| url.is_ascii())
+    .take(20000)
+    .collect();
+  let bytewise_urls = urls.clone();
+  let url_count = urls.len() as u64;
+
+  c.bench(
+    "url-tokenize",
+    Benchmark::new("tokenize", move |b| {
+      b.iter(|| urls.iter().map(|url| adblock::utils::tokenize(url).len()).sum::<usize>())
+    })
+    .with_function("bytewise", move |b| {
+      b.iter(|| bytewise_urls.iter().map(|url| tokenize_bytewise(url).len()).sum::<usize>())
+    })
+    .throughput(Throughput::Elements(url_count))
+    .sample_size(20)
+  );
+}
+
 fn bench_parsing_impl(lists: &Vec<Vec<String>>) -> usize {
   let mut dummy = 0;
 
@@ -109,5 +157,5 @@ fn blocker_new(c: &mut Criterion) {
 
 
 
-criterion_group!(benches, blocker_new, list_parse, string_hashing, string_tokenize);
+criterion_group!(benches, blocker_new, list_parse, string_hashing, string_tokenize, url_tokenize);
 criterion_main!(benches);
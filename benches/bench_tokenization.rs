@@ -0,0 +1,222 @@
+//! Benchmarks for comparing tokenization strategies and engine settings on a fixed workload, so
+//! that changes to the tokenizer, hashing or optimizer can be evaluated consistently.
+//!
+//! Requests come from `data/bench-requests.json`, a sample of every 100th request of
+//! `data/requests.json`, anonymized by replacing the values of query parameters with `x` and
+//! dropping fragments. Rules come from EasyList and EasyPrivacy.
+//!
+//! Criterion only measures time. The heap memory retained by each engine configuration is measured
+//! with a counting allocator, and the average number of rules that each tokenization strategy
+//! leaves to be checked per request is computed; both are printed before the timings.
+
+use criterion::*;
+
+use serde::Deserialize;
+
+use adblock::engine::Engine;
+use adblock::lists::{parse_filters, FilterFormat};
+use adblock::utils::{fast_hash, rules_from_lists, Hash};
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+#[allow(non_snake_case)]
+#[derive(Deserialize, Clone)]
+struct TestRequest {
+    frameUrl: String,
+    url: String,
+    cpt: String,
+}
+
+fn load_requests() -> Vec<TestRequest> {
+    adblock::utils::read_file_lines("data/bench-requests.json")
+        .into_iter()
+        .map(|r| serde_json::from_str(&r))
+        .filter_map(Result::ok)
+        .collect()
+}
+
+fn load_rules() -> Vec<String> {
+    rules_from_lists(&[
+        "data/easylist.to/easylist/easylist.txt".to_owned(),
+        "data/easylist.to/easylist/easyprivacy.txt".to_owned(),
+    ])
+}
+
+/// A way of splitting URLs and rule patterns into tokens.
+struct Tokenizer {
+    name: &'static str,
+    tokenize: fn(&str, &mut Vec<Hash>),
+}
+
+const TOKENIZERS: &[Tokenizer] = &[
+    Tokenizer { name: "engine", tokenize: tokenize_engine },
+    Tokenizer { name: "all-runs", tokenize: tokenize_all_runs },
+    Tokenizer { name: "4-grams", tokenize: tokenize_4_grams },
+];
+
+/// What the engine uses, see `adblock::tokenize`
+fn tokenize_engine(text: &str, tokens: &mut Vec<Hash>) {
+    tokens.extend(adblock::tokenize(text));
+}
+
+fn alphanumeric_runs(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !c.is_ascii_alphanumeric()).filter(|run| !run.is_empty())
+}
+
+/// Every run of alphanumeric characters, including single characters
+fn tokenize_all_runs(text: &str, tokens: &mut Vec<Hash>) {
+    tokens.extend(alphanumeric_runs(text).map(fast_hash));
+}
+
+/// Overlapping 4-character windows of runs of alphanumeric characters
+fn tokenize_4_grams(text: &str, tokens: &mut Vec<Hash>) {
+    for run in alphanumeric_runs(text) {
+        // Runs are ASCII, so any byte offset is a character boundary
+        tokens.extend((0..run.len().saturating_sub(3)).map(|i| fast_hash(&run[i..i + 4])));
+    }
+}
+
+/// Hash widths in bits. Narrower hashes use less memory per token, at the cost of collisions.
+const HASH_WIDTHS: &[u32] = &[64, 32, 16];
+
+fn truncate(hash: Hash, width: u32) -> Hash {
+    if width >= 64 { hash } else { hash & ((1 << width) - 1) }
+}
+
+/// Token buckets of rules, built as `NetworkFilterList` does by placing each rule under its least
+/// frequent token, with rules without tokens in the wildcard bucket `0`.
+struct TokenIndex {
+    buckets: HashMap<Hash, usize>,
+}
+
+impl TokenIndex {
+    fn new(patterns: &[String], tokenizer: &Tokenizer, width: u32) -> Self {
+        let rule_tokens: Vec<Vec<Hash>> = patterns.iter().map(|pattern| tokens(pattern, tokenizer, width)).collect();
+        let mut histogram: HashMap<Hash, usize> = HashMap::new();
+        rule_tokens.iter().flatten().for_each(|token| *histogram.entry(*token).or_insert(0) += 1);
+
+        let mut buckets = HashMap::new();
+        for tokens in rule_tokens {
+            let best = tokens.into_iter().min_by_key(|token| histogram[token]).unwrap_or(0);
+            *buckets.entry(best).or_insert(0) += 1;
+        }
+        Self { buckets }
+    }
+
+    /// Number of rules in the buckets that a request with the given tokens is checked against.
+    fn candidates(&self, request_tokens: &[Hash]) -> usize {
+        let distinct: HashSet<&Hash> = request_tokens.iter().chain(std::iter::once(&0)).collect();
+        distinct.into_iter().map(|token| self.buckets.get(token).copied().unwrap_or(0)).sum()
+    }
+}
+
+fn tokens(text: &str, tokenizer: &Tokenizer, width: u32) -> Vec<Hash> {
+    let mut tokens = vec![];
+    (tokenizer.tokenize)(text, &mut tokens);
+    tokens.iter_mut().for_each(|token| *token = truncate(*token, width));
+    tokens
+}
+
+/// Patterns of the network rules, without options, which are not tokenized.
+fn network_patterns(rules: &[String]) -> Vec<String> {
+    let (network_filters, _) = parse_filters(rules, true, FilterFormat::Standard);
+    network_filters.into_iter()
+        .filter_map(|filter| filter.raw_line)
+        .map(|line| line.trim_start_matches("@@").split('$').next().unwrap_or_default().to_ascii_lowercase())
+        .collect()
+}
+
+fn tokenization(c: &mut Criterion) {
+    let requests = load_requests();
+    let urls: Vec<String> = requests.iter().map(|r| r.url.to_ascii_lowercase()).collect();
+    let patterns = network_patterns(&load_rules());
+
+    let mut group = c.benchmark_group("tokenization");
+    group.throughput(Throughput::Elements(urls.len() as u64));
+    group.sample_size(10);
+    for tokenizer in TOKENIZERS {
+        for &width in HASH_WIDTHS {
+            let index = TokenIndex::new(&patterns, tokenizer, width);
+            let candidates: usize = urls.iter().map(|url| index.candidates(&tokens(url, tokenizer, width))).sum();
+            println!(
+                "{} tokenizer, {}-bit hashes: {} buckets, {:.1} candidate rules per request",
+                tokenizer.name,
+                width,
+                index.buckets.len(),
+                candidates as f64 / urls.len() as f64,
+            );
+
+            group.bench_with_input(BenchmarkId::new(tokenizer.name, width), &width, |b, &width| {
+                b.iter(|| urls.iter().map(|url| index.candidates(&tokens(url, tokenizer, width))).sum::<usize>())
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Builds an engine, returning it along with the heap memory it retains.
+fn build_engine(rules: &[String], optimize: bool) -> (Engine, usize) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let engine = Engine::from_rules_parametrised(rules, FilterFormat::Standard, false, optimize);
+    let retained = ALLOCATED.load(Ordering::Relaxed).saturating_sub(before);
+    (engine, retained)
+}
+
+const OPTIMIZER_SETTINGS: &[(&str, bool)] = &[("optimized", true), ("unoptimized", false)];
+
+fn engine_build(c: &mut Criterion) {
+    let rules = load_rules();
+
+    let mut group = c.benchmark_group("engine-build");
+    group.sample_size(10);
+    for &(name, optimize) in OPTIMIZER_SETTINGS {
+        let (_, retained) = build_engine(&rules, optimize);
+        println!("{} engine: {:.1} MiB retained", name, retained as f64 / (1024. * 1024.));
+
+        group.bench_function(name, |b| b.iter(|| build_engine(&rules, optimize)));
+    }
+    group.finish();
+}
+
+fn engine_matching(c: &mut Criterion) {
+    let rules = load_rules();
+    let requests = load_requests();
+
+    let mut group = c.benchmark_group("engine-matching");
+    group.throughput(Throughput::Elements(requests.len() as u64));
+    group.sample_size(10);
+    for &(name, optimize) in OPTIMIZER_SETTINGS {
+        let (engine, _) = build_engine(&rules, optimize);
+        group.bench_function(name, |b| {
+            b.iter(|| requests.iter().filter(|r| engine.check_network_urls(&r.url, &r.frameUrl, &r.cpt).matched).count())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, tokenization, engine_build, engine_matching);
+criterion_main!(benches);
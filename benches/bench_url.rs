@@ -2,7 +2,7 @@ use criterion::*;
 
 use serde::{Deserialize, Serialize};
 
-use adblock::request::Request;
+use adblock::request::{Request, RequestParts};
 use adblock::url_parser::parse_url;
 
 #[allow(non_snake_case)]
@@ -124,9 +124,49 @@ fn request_new_throughput(c: &mut Criterion) {
     );
 }
 
+fn request_from_parts_throughput(c: &mut Criterion) {
+    let requests = load_requests();
+    let requests_len = requests.len() as u64;
+    let requests_parsed: Vec<_> = requests.iter().filter_map(|r| {
+        let parsed_url = parse_url(&r.url.to_ascii_lowercase())?;
+        let source_hostname = parse_url(&r.frameUrl.to_ascii_lowercase())
+            .map(|parsed_source| parsed_source.hostname().to_owned())
+            .unwrap_or_default();
+        Some((
+            r.cpt.clone(),
+            String::from(parsed_url.schema()),
+            String::from(parsed_url.hostname()),
+            String::from(&parsed_url.url[parsed_url.hostname_pos.1..]),
+            source_hostname,
+        ))
+    })
+    .collect();
+
+    c.bench(
+        "throughput-request",
+        Benchmark::new("from parts", move |b| {
+            b.iter(|| {
+                requests_parsed.iter().for_each(|r| {
+                    Request::from_parts(&RequestParts {
+                        scheme: &r.1,
+                        hostname: &r.2,
+                        path: &r.3,
+                        source_hostname: &r.4,
+                        request_type: &r.0,
+                        ..RequestParts::default()
+                    });
+                });
+            })
+        })
+        .throughput(Throughput::Elements(requests_len))
+        .sample_size(10),
+    );
+}
+
 criterion_group!(
     benches,
     request_new_throughput,
+    request_from_parts_throughput,
     request_extract_hostname,
     request_parsing_throughput
 );
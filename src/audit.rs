@@ -0,0 +1,137 @@
+//! Auditing of allowlists, i.e. lists of `@@` exception rules.
+//!
+//! Allowlists such as "acceptable ads" programs are mostly evaluated by how much they unblock. An
+//! `ExceptionAudit` compiles only the exceptions of the given lists, and reports which of them
+//! apply to a corpus of requests, regardless of whether any blocking rule would have matched.
+
+use crate::engine::Engine;
+use crate::lists::{FilterFormat, FilterSet, FilterSource};
+use crate::progress::{Cancelled, CancellationToken};
+use crate::request::Request;
+
+use std::collections::HashMap;
+
+/// Network exceptions compiled from a set of lists, for auditing.
+pub struct ExceptionAudit {
+    engine: Engine,
+}
+
+/// An exception that applied to at least one audited request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExceptionHit {
+    pub filter: String,
+    /// Locations of the rule in the audited lists
+    pub sources: Vec<FilterSource>,
+    /// Number of audited requests the exception applied to
+    pub requests: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExceptionAuditReport {
+    pub requests_checked: usize,
+    /// Requests that could not be parsed, and were not checked
+    pub requests_invalid: usize,
+    /// Exceptions that applied to any request, most frequent first
+    pub hits: Vec<ExceptionHit>,
+}
+
+impl ExceptionAudit {
+    /// Compiles the `@@` rules of each list, given as `(list_id, contents)`. All other rules are
+    /// ignored.
+    pub fn from_lists<'a>(lists: impl IntoIterator<Item = (&'a str, &'a str)>, format: FilterFormat) -> Self {
+        let mut filter_set = FilterSet::new(true);
+        for (list_id, contents) in lists {
+            filter_set.add_filter_list_with_id(list_id, contents, format);
+        }
+        filter_set.network_filters.retain(|filter| filter.is_exception());
+        filter_set.cosmetic_filters.clear();
+
+        Self {
+            // Optimizations would merge rules from different lists
+            engine: Engine::from_filter_set(filter_set, false),
+        }
+    }
+
+    /// Checks each request, given as `(url, source_url, request_type)`, against the exceptions.
+    pub fn audit<'a>(&self, requests: impl IntoIterator<Item = (&'a str, &'a str, &'a str)>) -> ExceptionAuditReport {
+        self.audit_with_cancellation(requests, None)
+            .expect("audits without a cancellation token cannot be cancelled")
+    }
+
+    /// Like `audit`, but stops with `Err(Cancelled)` once `cancellation` is cancelled.
+    pub fn audit_with_cancellation<'a>(
+        &self,
+        requests: impl IntoIterator<Item = (&'a str, &'a str, &'a str)>,
+        cancellation: Option<&CancellationToken>,
+    ) -> Result<ExceptionAuditReport, Cancelled> {
+        let mut report = ExceptionAuditReport::default();
+        let mut counts: HashMap<String, usize> = HashMap::new();
+
+        for (url, source_url, request_type) in requests {
+            cancellation.map_or(Ok(()), CancellationToken::check)?;
+            let request = match Request::from_urls(url, source_url, request_type) {
+                Ok(request) => request,
+                Err(_) => {
+                    report.requests_invalid += 1;
+                    continue;
+                }
+            };
+            report.requests_checked += 1;
+
+            if let Some(exception) = self.engine.blocker().check_parameterised(&request, false, true).exception {
+                *counts.entry(exception).or_insert(0) += 1;
+            }
+        }
+
+        report.hits = counts
+            .into_iter()
+            .map(|(filter, requests)| ExceptionHit {
+                sources: self.engine.filter_sources(&filter).into_iter().cloned().collect(),
+                filter,
+                requests,
+            })
+            .collect();
+        report.hits.sort_by(|a, b| b.requests.cmp(&a.requests).then_with(|| a.filter.cmp(&b.filter)));
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_triggered_exceptions() {
+        let allowlist = "! Title: Allowlist\n@@||ads.example.com^$script\n@@||unused.com^\n||blocked.com^";
+        let other = "! Title: Other\n@@||ads.example.com/ok^$image";
+        let audit = ExceptionAudit::from_lists(vec![("allowlist", allowlist), ("other", other)], FilterFormat::Standard);
+
+        let report = audit.audit(vec![
+            ("https://ads.example.com/a.js", "https://news.com", "script"),
+            ("https://ads.example.com/b.js", "https://news.com", "script"),
+            ("https://ads.example.com/ok/c.png", "https://news.com", "image"),
+            ("https://blocked.com/d.js", "https://news.com", "script"),
+            ("not a url", "", "script"),
+        ]);
+
+        assert_eq!(report.requests_checked, 4);
+        assert_eq!(report.requests_invalid, 1);
+        assert_eq!(report.hits, vec![
+            ExceptionHit {
+                filter: "@@||ads.example.com^$script".to_string(),
+                sources: vec![FilterSource { list_id: "allowlist".to_string(), line: 2, text: "@@||ads.example.com^$script".to_string() }],
+                requests: 2,
+            },
+            ExceptionHit {
+                filter: "@@||ads.example.com/ok^$image".to_string(),
+                sources: vec![FilterSource { list_id: "other".to_string(), line: 2, text: "@@||ads.example.com/ok^$image".to_string() }],
+                requests: 1,
+            },
+        ]);
+
+        assert_eq!(audit.audit_with_cancellation(vec![("https://ads.example.com/a.js", "https://news.com", "script")], Some(&CancellationToken::new())).unwrap().hits.len(), 1);
+        let cancelled = CancellationToken::new();
+        cancelled.cancel();
+        assert_eq!(audit.audit_with_cancellation(vec![("https://ads.example.com/a.js", "https://news.com", "script")], Some(&cancelled)), Err(Cancelled));
+    }
+}
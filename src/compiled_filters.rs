@@ -0,0 +1,90 @@
+//! Read-only views of the filters compiled into an `Engine`, as returned by
+//! `Engine::network_filters` and `Engine::cosmetic_filters`.
+//!
+//! These describe exactly what the engine enforces, after `$badfilter` rules, optimization and
+//! `$tag` activation were applied, so that external tooling can audit an engine without parsing
+//! its source lists again.
+
+use crate::cosmetic_filter_cache::SpecificFilterType;
+use crate::filters::network::NetworkFilter;
+use crate::utils::Hash;
+
+use std::borrow::Cow;
+
+/// The part of the engine a network filter is consulted by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NetworkFilterRole {
+    Block,
+    Exception,
+    Important,
+    Redirect,
+    Csp,
+    /// A filter with a `$tag` that is currently enabled
+    Tagged,
+    /// A `$generichide` exception, which disables generic cosmetic filters
+    GenericHide,
+    /// A `$specifichide` or `$elemhide` exception, which disables specific cosmetic filters
+    SpecificHide,
+    /// A `$popup` filter, only consulted by `Engine::check_popup`
+    Popup,
+    PopupException,
+    /// A `$dnsrewrite` filter or exception, only consulted by `Engine::check_dns_query`
+    DnsRewrite,
+    /// A `$removeparam` filter or exception, only consulted by `Engine::filter_url_params`
+    RemoveParam,
+    /// A `$removeheader` filter or exception, only consulted by `Blocker::check_removeheaders`
+    RemoveHeader,
+}
+
+/// A network filter compiled into an engine.
+#[derive(Clone, Copy)]
+pub struct CompiledNetworkFilter<'a> {
+    pub role: NetworkFilterRole,
+    pub filter: &'a NetworkFilter,
+}
+
+impl<'a> CompiledNetworkFilter<'a> {
+    /// Text of the rule as written in its list, if the engine was built in debug mode. For filters
+    /// fused by the optimizer, the rules are separated by ` <+> `.
+    pub fn raw_text(&self) -> Option<&'a str> {
+        self.filter.raw_line.as_deref()
+    }
+
+    /// Text of the filter reconstructed from its compiled form, available regardless of debug
+    /// mode, see `NetworkFilter::canonical_text`.
+    pub fn canonical_text(&self) -> String {
+        self.filter.canonical_text()
+    }
+
+    pub fn id(&self) -> Hash {
+        self.filter.id
+    }
+}
+
+/// A cosmetic filter compiled into an engine.
+#[derive(Debug, Clone)]
+pub struct CompiledCosmeticFilter<'a> {
+    /// Hash of the hostname or entity the filter is stored for, or `None` for generic filters.
+    /// Filters listing several hostnames are yielded once for each of them, and exceptions like
+    /// `~example.com##.ad` are yielded as the matching `Unhide` filter for `example.com`.
+    pub hostname_hash: Option<Hash>,
+    pub filter: Cow<'a, SpecificFilterType>,
+}
+
+impl<'a> CompiledCosmeticFilter<'a> {
+    /// Text of the filter without its hostnames, e.g. `##.ad` or `#@#+js(nobab)`.
+    pub fn canonical_text(&self) -> String {
+        match self.filter.as_ref() {
+            SpecificFilterType::Hide(selector) => format!("##{}", selector),
+            SpecificFilterType::Unhide(selector) => format!("#@#{}", selector),
+            SpecificFilterType::Style(selector, style) => format!("##{}:style({})", selector, style),
+            SpecificFilterType::UnhideStyle(selector, style) => format!("#@#{}:style({})", selector, style),
+            SpecificFilterType::ScriptInject(script) | SpecificFilterType::TrustedScriptInject(script) => format!("##+js({})", script),
+            SpecificFilterType::UnhideScriptInject(script) => format!("#@#+js({})", script),
+            SpecificFilterType::Procedural(procedural) => format!("##{}", procedural),
+            SpecificFilterType::UnhideProcedural(procedural) => format!("#@#{}", procedural),
+            SpecificFilterType::DisableScripting => "##^script".to_string(),
+            SpecificFilterType::UnhideDisableScripting => "#@#^script".to_string(),
+        }
+    }
+}
@@ -3,6 +3,7 @@
 use crate::filters::network::{NetworkFilter, NetworkFilterMask};
 use crate::filters::cosmetic::CosmeticFilter;
 use crate::lists::ParsedFilter;
+use crate::conversion::{ConversionReason, ConversionReport};
 
 use once_cell::sync::Lazy;
 use regex::Regex;
@@ -167,6 +168,175 @@ pub enum CbRuleCreationFailure {
     /// Cosmetic rules with scriptlet injections (i.e. `+js(...)`) cannot be represented in content
     /// blocking syntax.
     ScriptletInjectionsNotSupported,
+    /// Cosmetic rules with procedural operators (e.g. `:has-text(...)`) cannot be represented in
+    /// content blocking syntax.
+    ProceduralCosmeticFiltersNotSupported,
+    /// Cosmetic rules that disable scripting (i.e. `##^script`) cannot be represented in content
+    /// blocking syntax.
+    DisableScriptingNotSupported,
+    /// Network rules with specifichide options cannot be supported in content blocking syntax.
+    NetworkSpecifichideUnsupported,
+    /// Network rules with ctag options cannot be supported in content blocking syntax.
+    NetworkClientTagUnsupported,
+    /// Network rules with dnstype or dnsrewrite options only apply to DNS queries.
+    NetworkDnsModifiersUnsupported,
+    /// Cosmetic exceptions without hostnames (i.e. `#@#.selector`) cannot be represented in
+    /// content blocking syntax.
+    CosmeticGenericExceptionUnsupported,
+    /// Network rules with removeparam options cannot be supported in content blocking syntax.
+    NetworkRemoveParamUnsupported,
+    /// Network rules with removeheader options cannot be supported in content blocking syntax.
+    NetworkRemoveHeaderUnsupported,
+    /// Network rules with denyallow options cannot be supported in content blocking syntax.
+    NetworkDenyAllowUnsupported,
+    /// Network rules with entity domains (i.e. `$domain=example.*`) cannot be represented in
+    /// content blocking syntax.
+    NetworkEntitiesUnsupported,
+}
+
+impl From<&CbRuleCreationFailure> for ConversionReason {
+    fn from(failure: &CbRuleCreationFailure) -> Self {
+        match failure {
+            CbRuleCreationFailure::NeedsDebugMode => ConversionReason::NeedsDebugMode,
+            CbRuleCreationFailure::UnlessAndIfDomainTogetherUnsupported => ConversionReason::IncludedAndExcludedDomains,
+            CbRuleCreationFailure::NoSupportedNetworkOptions(_) => ConversionReason::UnsupportedRequestTypes,
+            CbRuleCreationFailure::NetworkRedirectUnsupported => ConversionReason::Redirect,
+            CbRuleCreationFailure::NetworkGenerichideUnsupported => ConversionReason::GenericHide,
+            CbRuleCreationFailure::NetworkBadFilterUnsupported => ConversionReason::BadFilter,
+            CbRuleCreationFailure::NetworkCspUnsupported => ConversionReason::Csp,
+            CbRuleCreationFailure::OptimizedRulesUnsupported => ConversionReason::OptimizedRule,
+            CbRuleCreationFailure::CosmeticEntitiesUnsupported => ConversionReason::CosmeticEntities,
+            CbRuleCreationFailure::CosmeticStyleRulesNotSupported => ConversionReason::CosmeticStyle,
+            CbRuleCreationFailure::ScriptletInjectionsNotSupported => ConversionReason::Scriptlet,
+            CbRuleCreationFailure::ProceduralCosmeticFiltersNotSupported => ConversionReason::ProceduralCosmetic,
+            CbRuleCreationFailure::DisableScriptingNotSupported => ConversionReason::DisableScripting,
+            CbRuleCreationFailure::NetworkSpecifichideUnsupported => ConversionReason::SpecificHide,
+            CbRuleCreationFailure::NetworkClientTagUnsupported => ConversionReason::ClientTags,
+            CbRuleCreationFailure::NetworkDnsModifiersUnsupported => ConversionReason::DnsModifiers,
+            CbRuleCreationFailure::CosmeticGenericExceptionUnsupported => ConversionReason::GenericCosmeticException,
+            CbRuleCreationFailure::NetworkRemoveParamUnsupported => ConversionReason::RemoveParam,
+            CbRuleCreationFailure::NetworkRemoveHeaderUnsupported => ConversionReason::RemoveHeader,
+            CbRuleCreationFailure::NetworkDenyAllowUnsupported => ConversionReason::DenyAllow,
+            CbRuleCreationFailure::NetworkEntitiesUnsupported => ConversionReason::DomainEntities,
+        }
+    }
+}
+
+/// Whether converting `filter` leaves out some of the request types it applies to.
+pub(crate) fn drops_some_request_types(filter: &NetworkFilter) -> bool {
+    // Keep in sync with the types skipped in `TryFrom<NetworkFilter> for CbRuleEquivalent`
+    let unsupported = NetworkFilterMask::FROM_OBJECT
+        | NetworkFilterMask::FROM_OTHER
+        | NetworkFilterMask::FROM_PING
+        | NetworkFilterMask::FROM_WEBSOCKET;
+    !filter.mask.contains(NetworkFilterMask::FROM_ANY)
+        && filter.mask.intersects(unsupported)
+        && filter.mask.intersects(NetworkFilterMask::FROM_ANY - unsupported)
+}
+
+/// Content blocking rules converted from a `FilterSet`, each with the text of the filter it was
+/// converted from, before they are ordered into rule lists.
+#[derive(Default)]
+pub(crate) struct CbConversion {
+    pub network: Vec<(CbRule, String)>,
+    pub cosmetic: Vec<(CbRule, String)>,
+    pub filters_used: Vec<String>,
+    pub report: ConversionReport,
+}
+
+/// Category of a content blocking rule list, for apps shipping one content blocker per category.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CbCategory {
+    Ads,
+    Privacy,
+    Cosmetic,
+}
+
+/// Limit on the number of rules of a single content blocker enforced by recent versions of
+/// Safari.
+pub const DEFAULT_RULE_BUDGET: usize = 150_000;
+
+/// A list of content blocking rules, to be loaded as a single content blocker.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CbRuleList {
+    pub category: CbCategory,
+    /// Position of the list among those of the same category, starting from 0
+    pub index: usize,
+    pub rules: Vec<CbRule>,
+}
+
+impl CbRuleList {
+    /// Stable name of the list, e.g. `privacy-1` for the second privacy list.
+    pub fn name(&self) -> String {
+        let category = match self.category {
+            CbCategory::Ads => "ads",
+            CbCategory::Privacy => "privacy",
+            CbCategory::Cosmetic => "cosmetic",
+        };
+        format!("{}-{}", category, self.index)
+    }
+
+    /// The rules as a JSON array, in the format expected by Safari.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&self.rules)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CbPartitionError {
+    /// See `CbRuleCreationFailure::NeedsDebugMode`.
+    NeedsDebugMode,
+    /// Every list of a category repeats all of the exceptions that may apply to it, so they must
+    /// leave room for at least one other rule.
+    ExceptionsExceedBudget { category: CbCategory, exceptions: usize, rule_budget: usize },
+}
+
+/// Divides rules into lists of at most `rule_budget` rules each.
+///
+/// Content blockers are evaluated independently, so an `ignore-previous-rules` rule only applies
+/// to rules of the same list. Each list therefore ends with all exceptions that may apply to its
+/// rules: network exceptions from any category for ads and privacy lists, followed by
+/// `ignore_previous_fp_documents`, and cosmetic exceptions for cosmetic lists. The other rules of
+/// each category are split into consecutive chunks in the order they are given, so the same input
+/// always results in the same lists. Categories without such rules get no list.
+pub fn partition(network: Vec<(CbCategory, CbRule)>, cosmetic: Vec<CbRule>, rule_budget: usize) -> Result<Vec<CbRuleList>, CbPartitionError> {
+    fn is_exception(cb_rule: &CbRule) -> bool {
+        cb_rule.action.typ == CbType::IgnorePreviousRules
+    }
+
+    let (network_exceptions, network): (Vec<_>, Vec<_>) = network.into_iter()
+        .partition(|(_, cb_rule)| is_exception(cb_rule));
+    let mut network_exceptions: Vec<CbRule> = network_exceptions.into_iter().map(|(_, cb_rule)| cb_rule).collect();
+    network_exceptions.push(ignore_previous_fp_documents());
+    let (cosmetic_exceptions, cosmetic): (Vec<_>, Vec<_>) = cosmetic.into_iter().partition(is_exception);
+
+    let mut categorized = vec![
+        (CbCategory::Ads, vec![], &network_exceptions),
+        (CbCategory::Privacy, vec![], &network_exceptions),
+        (CbCategory::Cosmetic, cosmetic, &cosmetic_exceptions),
+    ];
+    for (category, cb_rule) in network.into_iter() {
+        let index = if category == CbCategory::Privacy { 1 } else { 0 };
+        categorized[index].1.push(cb_rule);
+    }
+
+    let mut lists = vec![];
+    for (category, rules, exceptions) in categorized.into_iter() {
+        if rules.is_empty() {
+            continue;
+        }
+        if exceptions.len() >= rule_budget {
+            return Err(CbPartitionError::ExceptionsExceedBudget { category, exceptions: exceptions.len(), rule_budget });
+        }
+        for (index, chunk) in rules.chunks(rule_budget - exceptions.len()).enumerate() {
+            let mut list_rules = chunk.to_vec();
+            list_rules.extend(exceptions.iter().cloned());
+            lists.push(CbRuleList { category, index, rules: list_rules });
+        }
+    }
+
+    Ok(lists)
 }
 
 impl TryFrom<ParsedFilter> for CbRuleEquivalent {
@@ -236,19 +406,37 @@ impl TryFrom<NetworkFilter> for CbRuleEquivalent {
         static SPECIAL_CHARS: Lazy<Regex> = Lazy::new(|| Regex::new(r##"([.+?^${}()|\[\]])"##).unwrap());
         static REPLACE_WILDCARDS: Lazy<Regex> = Lazy::new(|| Regex::new(r##"\*"##).unwrap());
         static TRAILING_SEPARATOR: Lazy<Regex> = Lazy::new(|| Regex::new(r##"\^$"##).unwrap());
-        if let Some(raw_line) = v.raw_line {
+        if let Some(raw_line) = v.raw_line.as_ref() {
             if v.redirect.is_some() {
                 return Err(CbRuleCreationFailure::NetworkRedirectUnsupported);
             }
             if v.mask.contains(NetworkFilterMask::GENERIC_HIDE) {
                 return Err(CbRuleCreationFailure::NetworkGenerichideUnsupported);
             }
+            if v.mask.contains(NetworkFilterMask::SPECIFIC_HIDE) {
+                return Err(CbRuleCreationFailure::NetworkSpecifichideUnsupported);
+            }
             if v.mask.contains(NetworkFilterMask::BAD_FILTER) {
                 return Err(CbRuleCreationFailure::NetworkBadFilterUnsupported);
             }
             if v.mask.contains(NetworkFilterMask::IS_CSP) {
                 return Err(CbRuleCreationFailure::NetworkCspUnsupported);
             }
+            if v.is_removeparam() {
+                return Err(CbRuleCreationFailure::NetworkRemoveParamUnsupported);
+            }
+            if v.is_removeheader() {
+                return Err(CbRuleCreationFailure::NetworkRemoveHeaderUnsupported);
+            }
+            if v.has_denyallow() {
+                return Err(CbRuleCreationFailure::NetworkDenyAllowUnsupported);
+            }
+            if v.has_client_tags() {
+                return Err(CbRuleCreationFailure::NetworkClientTagUnsupported);
+            }
+            if v.has_dns_types() || v.is_dns_rewrite() {
+                return Err(CbRuleCreationFailure::NetworkDnsModifiersUnsupported);
+            }
 
             let load_type = if v.mask.contains(NetworkFilterMask::THIRD_PARTY | NetworkFilterMask::FIRST_PARTY) {
                 vec![]
@@ -342,6 +530,9 @@ impl TryFrom<NetworkFilter> for CbRuleEquivalent {
                     domains_start
                 }.split('|');
 
+                if domains.clone().any(|domain| domain.ends_with(".*")) {
+                    return Err(CbRuleCreationFailure::NetworkEntitiesUnsupported);
+                }
                 domains.for_each(|domain| if domain.starts_with('~') {
                         unless_domain.push(format!("*{}", &domain["~".len()..]));
                     } else {
@@ -469,6 +660,12 @@ impl TryFrom<CosmeticFilter> for CbRule {
         if v.mask.contains(CosmeticFilterMask::SCRIPT_INJECT) {
             return Err(CbRuleCreationFailure::ScriptletInjectionsNotSupported);
         }
+        if v.mask.contains(CosmeticFilterMask::DISABLE_SCRIPTING) {
+            return Err(CbRuleCreationFailure::DisableScriptingNotSupported);
+        }
+        if v.procedural.is_some() {
+            return Err(CbRuleCreationFailure::ProceduralCosmeticFiltersNotSupported);
+        }
 
         if let Some(raw_line) = v.raw_line {
             let mut hostnames_vec = vec![];
@@ -497,6 +694,9 @@ impl TryFrom<CosmeticFilter> for CbRule {
             if hostnames_vec.is_some() && not_hostnames_vec.is_some() {
                 return Err(CbRuleCreationFailure::UnlessAndIfDomainTogetherUnsupported);
             }
+            if hostnames_vec.is_none() && v.mask.contains(CosmeticFilterMask::UNHIDE) {
+                return Err(CbRuleCreationFailure::CosmeticGenericExceptionUnsupported);
+            }
 
             let (unless_domain, if_domain) = if v.mask.contains(CosmeticFilterMask::UNHIDE) {
                 (hostnames_vec, not_hostnames_vec)
@@ -1049,3 +1249,53 @@ mod ab2cb_tests {
         }]"####).expect("content blocking rule under test could not be deserialized"));
     }
 }
+
+#[cfg(test)]
+mod partition_tests {
+    use super::*;
+    use crate::lists::{FilterFormat, FilterSet};
+    use std::collections::HashMap;
+
+    fn filter_set() -> FilterSet {
+        let mut set = FilterSet::new(true);
+        set.add_filter_list_with_id("easylist", "||ads1.com^\n||ads2.com^\n||ads3.com^\n@@||ads.com/ok^\nexample.com##.ad\n", FilterFormat::Standard);
+        set.add_filter_list_with_id("easyprivacy", "||tracker.com^\n", FilterFormat::Standard);
+        set
+    }
+
+    #[test]
+    fn partitions_by_category_and_budget() {
+        let mut list_categories = HashMap::new();
+        list_categories.insert("easyprivacy".to_string(), CbCategory::Privacy);
+
+        let (lists, report) = filter_set().into_content_blocking_partitioned(&list_categories, 4).unwrap();
+        assert_eq!(report.rules_total, 6);
+        assert_eq!(lists.iter().map(|list| (list.name(), list.rules.len())).collect::<Vec<_>>(), vec![
+            ("ads-0".to_string(), 4),
+            ("ads-1".to_string(), 3),
+            ("privacy-0".to_string(), 3),
+            ("cosmetic-0".to_string(), 1),
+        ]);
+        // Every network list ends with the network exceptions
+        for list in lists.iter().filter(|list| list.category != CbCategory::Cosmetic) {
+            let exceptions = &list.rules[list.rules.len() - 2..];
+            assert_eq!(exceptions[0].trigger.url_filter, "^[^:]+:(//)?([^/]+\\.)?ads\\.com/ok");
+            assert_eq!(exceptions[1], ignore_previous_fp_documents());
+            assert!(list.rules[..list.rules.len() - 2].iter().all(|rule| rule.action.typ == CbType::Block));
+        }
+        assert!(lists[3].to_json().unwrap().contains("css-display-none"));
+
+        // Partitioning is deterministic
+        let (again, _) = filter_set().into_content_blocking_partitioned(&list_categories, 4).unwrap();
+        assert_eq!(lists, again);
+
+        assert_eq!(
+            filter_set().into_content_blocking_partitioned(&list_categories, 2).unwrap_err(),
+            CbPartitionError::ExceptionsExceedBudget { category: CbCategory::Ads, exceptions: 2, rule_budget: 2 },
+        );
+        assert_eq!(
+            FilterSet::new(false).into_content_blocking_partitioned(&list_categories, DEFAULT_RULE_BUDGET).unwrap_err(),
+            CbPartitionError::NeedsDebugMode,
+        );
+    }
+}
@@ -0,0 +1,224 @@
+//! Reports on the fidelity of exporting rules to other formats, like Safari content blocking
+//! rules or hosts files.
+//!
+//! Most formats can only express a subset of adblock syntax. Exporters return a
+//! `ConversionReport` alongside their output, listing each rule that could not be converted
+//! exactly, so that the loss can be quantified per list.
+
+use serde::Serialize;
+
+use std::collections::{BTreeMap, HashSet};
+
+/// What happened to a rule that could not be converted exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConversionOutcome {
+    /// The rule has no equivalent in the output
+    Dropped,
+    /// The rule was converted, but matches more or fewer requests than the original
+    Approximated,
+    /// The rule was converted into several output rules
+    Split,
+}
+
+/// Machine-readable reason for a conversion outcome.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConversionReason {
+    /// The rule was parsed without debug information, which the exporter requires
+    NeedsDebugMode,
+    Redirect,
+    GenericHide,
+    SpecificHide,
+    BadFilter,
+    Csp,
+    ClientTags,
+    /// The rule was merged with others by the optimizer
+    OptimizedRule,
+    /// The rule has both included and excluded `$domain` values
+    IncludedAndExcludedDomains,
+    /// None of the request types of the rule are supported
+    UnsupportedRequestTypes,
+    /// Some of the request types of the rule are not supported, and were left out
+    SomeRequestTypesUnsupported,
+    /// The rule applies to documents as well as other types of requests
+    DocumentRequests,
+    CosmeticEntities,
+    CosmeticStyle,
+    Scriptlet,
+    ProceduralCosmetic,
+    DisableScripting,
+    /// The format cannot express exceptions
+    Exception,
+    /// The format only blocks entire hostnames
+    NotHostnameOnly,
+    /// The format only has network rules
+    Cosmetic,
+    /// The format has no way to block popups
+    Popup,
+    /// The format applies to exact hostnames, whereas the rule also applies to subdomains
+    SubdomainsNotCovered,
+    /// The rule only applies once its `$tag` is enabled at runtime
+    Tag,
+    /// The rule has `$domain` values with entities, e.g. `google.*`
+    DomainEntities,
+    /// The format only accepts ASCII patterns
+    NonAsciiPattern,
+    /// The rule only applies to DNS queries, through `$dnstype` or `$dnsrewrite`
+    DnsModifiers,
+    /// The rule is a cosmetic exception for all sites, e.g. `#@#.ad`
+    GenericCosmeticException,
+    /// The rule removes query parameters from URLs with `$removeparam`, rather than blocking
+    RemoveParam,
+    /// The rule removes HTTP headers with `$removeheader`, rather than blocking
+    RemoveHeader,
+    /// The rule excludes some request domains with `$denyallow`
+    DenyAllow,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConversionIssue {
+    /// Position of the rule among all rules given to the exporter. A rule can have several issues.
+    pub rule_index: usize,
+    /// Text of the rule, if it was parsed in debug mode
+    pub rule: Option<String>,
+    pub outcome: ConversionOutcome,
+    pub reason: ConversionReason,
+    /// For a rule cancelled by a `$badfilter` rule, the text of that rule, if it was parsed in
+    /// debug mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cancelled_by: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ConversionReport {
+    /// Number of rules given to the exporter
+    pub rules_total: usize,
+    /// Rules that could not be converted exactly, in input order
+    pub issues: Vec<ConversionIssue>,
+}
+
+impl ConversionReport {
+    pub(crate) fn record(&mut self, rule_index: usize, rule: Option<&str>, outcome: ConversionOutcome, reason: ConversionReason) {
+        self.issues.push(ConversionIssue {
+            rule_index,
+            rule: rule.map(str::to_string),
+            outcome,
+            reason,
+            cancelled_by: None,
+        });
+    }
+
+    /// Records a rule dropped because the `$badfilter` rule `cancelled_by` cancels it.
+    pub(crate) fn record_cancelled(&mut self, rule_index: usize, rule: Option<&str>, cancelled_by: Option<&str>) {
+        self.issues.push(ConversionIssue {
+            rule_index,
+            rule: rule.map(str::to_string),
+            outcome: ConversionOutcome::Dropped,
+            reason: ConversionReason::BadFilter,
+            cancelled_by: cancelled_by.map(str::to_string),
+        });
+    }
+
+    /// Number of issues with the given outcome.
+    pub fn count(&self, outcome: ConversionOutcome) -> usize {
+        self.issues.iter().filter(|issue| issue.outcome == outcome).count()
+    }
+
+    /// Number of issues for each outcome and reason.
+    pub fn counts_by_reason(&self) -> BTreeMap<(ConversionOutcome, ConversionReason), usize> {
+        let mut counts = BTreeMap::new();
+        for issue in self.issues.iter() {
+            *counts.entry((issue.outcome, issue.reason)).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Number of rules converted exactly, as a fraction of all rules. `1.0` if there were none.
+    pub fn exact_fraction(&self) -> f64 {
+        if self.rules_total == 0 {
+            return 1.0;
+        }
+        let inexact = self.issues.iter().map(|issue| issue.rule_index).collect::<HashSet<_>>().len();
+        self.rules_total.saturating_sub(inexact) as f64 / self.rules_total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lists::{FilterFormat, FilterSet};
+
+    fn filter_set(rules: &[&str]) -> FilterSet {
+        let mut set = FilterSet::new(true);
+        set.add_filters(&rules.iter().map(|r| r.to_string()).collect::<Vec<_>>(), FilterFormat::Standard);
+        set
+    }
+
+    #[test]
+    fn hosts_report() {
+        let set = filter_set(&[
+            "||ads.com^",
+            "||ads.com^$important",
+            "@@||cdn.ads.com^",
+            "/banner/*",
+            "||tracker.net^$popup",
+            "example.com##.ad",
+        ]);
+        let (lines, report) = set.to_hosts();
+        assert_eq!(lines, vec!["0.0.0.0 ads.com"]);
+        assert_eq!(report.rules_total, 6);
+        assert_eq!(report.count(ConversionOutcome::Dropped), 4);
+        assert_eq!(report.count(ConversionOutcome::Approximated), 2);
+
+        let counts = report.counts_by_reason();
+        assert_eq!(counts.get(&(ConversionOutcome::Dropped, ConversionReason::Exception)), Some(&1));
+        assert_eq!(counts.get(&(ConversionOutcome::Dropped, ConversionReason::NotHostnameOnly)), Some(&1));
+        assert_eq!(counts.get(&(ConversionOutcome::Dropped, ConversionReason::Popup)), Some(&1));
+        assert_eq!(counts.get(&(ConversionOutcome::Dropped, ConversionReason::Cosmetic)), Some(&1));
+        assert!(report.issues.iter().any(|issue| issue.rule.as_deref() == Some("/banner/*")));
+        assert_eq!(report.exact_fraction(), 0.0);
+
+        // Rules cancelled by `$badfilter`, and rules that only apply to some tags or clients, are
+        // not blocked unconditionally
+        let set = filter_set(&[
+            "||ads.com^",
+            "||ads.com^$badfilter",
+            "||tracker.net^$tag=strict",
+            "||cdn.net^$ctag=kids",
+            "||example.com^",
+        ]);
+        let (lines, report) = set.to_hosts();
+        assert_eq!(lines, vec!["0.0.0.0 example.com"]);
+        let counts = report.counts_by_reason();
+        assert_eq!(counts.get(&(ConversionOutcome::Dropped, ConversionReason::BadFilter)), Some(&2));
+        assert_eq!(report.issues[0].cancelled_by.as_deref(), Some("||ads.com^$badfilter"));
+        assert_eq!(report.issues[1].cancelled_by, None);
+        assert_eq!(counts.get(&(ConversionOutcome::Dropped, ConversionReason::Tag)), Some(&1));
+        assert_eq!(counts.get(&(ConversionOutcome::Dropped, ConversionReason::ClientTags)), Some(&1));
+    }
+
+    #[cfg(feature = "content-blocking")]
+    #[test]
+    fn content_blocking_report() {
+        use crate::lists::RuleTypes;
+
+        let set = filter_set(&[
+            "||ads.com^",
+            "||ads.com^$redirect=noop.js",
+            "||ads.com^$script,ping",
+            "||ads.com^$script,subdocument",
+            "example.com##+js(nowebrtc)",
+        ]);
+        let (_, filters_used, report) = set.into_content_blocking_with_report(RuleTypes::All).unwrap();
+        assert_eq!(filters_used.len(), 3);
+        assert_eq!(report.rules_total, 5);
+        assert_eq!(report.issues.iter().map(|issue| (issue.rule_index, issue.outcome, issue.reason)).collect::<Vec<_>>(), vec![
+            (1, ConversionOutcome::Dropped, ConversionReason::Redirect),
+            (2, ConversionOutcome::Approximated, ConversionReason::SomeRequestTypesUnsupported),
+            (3, ConversionOutcome::Split, ConversionReason::DocumentRequests),
+            (4, ConversionOutcome::Dropped, ConversionReason::Scriptlet),
+        ]);
+        assert_eq!(report.exact_fraction(), 0.2);
+    }
+}
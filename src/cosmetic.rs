@@ -0,0 +1,16 @@
+//! Cosmetic filtering: the rules hiding page elements and injecting scriptlets, and the resources
+//! returned for a page by `Engine::url_cosmetic_resources` and
+//! `Engine::hidden_class_id_selectors`.
+//!
+//! This gathers the public types of `filters::cosmetic` and `cosmetic_filter_cache`, so that
+//! downstream code does not depend on where they are implemented.
+
+pub use crate::cosmetic_filter_cache::{
+    CosmeticPolicy,
+    ScriptletsAndFlags,
+    SelectorSpecificity,
+    SpecificFilterType,
+    StylesheetOptions,
+    UrlSpecificResources,
+};
+pub use crate::filters::cosmetic::{CosmeticFilter, CosmeticFilterError, CosmeticFilterLocationType};
@@ -1,9 +1,14 @@
+use crate::compiled_filters::CompiledCosmeticFilter;
 use crate::filters::cosmetic::CosmeticFilter;
 use crate::filters::cosmetic::CosmeticFilterMask;
-use crate::resources::{Resource, ScriptletResourceStorage};
+use crate::filters::procedural::ProceduralFilter;
+use crate::progress::{Cancelled, PROGRESS_INTERVAL};
+use crate::resources::{add_all, Resource, RejectedResource, ScriptletResourceStorage};
 use crate::utils::Hash;
 
+use std::borrow::Cow;
 use std::collections::{HashSet, HashMap};
+use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 
@@ -24,13 +29,105 @@ use serde::{Deserialize, Serialize};
 /// `generichide` is set to true if there is a corresponding `$generichide` exception network
 /// filter. If so, the page should not query for additional generic rules using
 /// `hidden_class_id_selectors`.
-#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+///
+/// `procedural_filters` is a set of compiled procedural filters, i.e. rules using operators like
+/// `:has-text()` that cannot be expressed in CSS. These need to be applied by a content script;
+/// see the `filters::procedural` module for a description of their format.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct UrlSpecificResources {
     pub hide_selectors: HashSet<String>,
     pub style_selectors: HashMap<String, Vec<String>>,
     pub exceptions: HashSet<String>,
     pub injected_script: String,
     pub generichide: bool,
+    pub procedural_filters: HashSet<ProceduralFilter>,
+}
+
+/// Scriptlet injections and page-level flags for a particular URL. This is a subset of
+/// `UrlSpecificResources` that can be computed without any selector lookups, for embedders that
+/// need to make decisions before the page starts loading.
+///
+/// `disable_scripting` is set to true if JavaScript should be disabled on the page, either through
+/// a `##^script` cosmetic rule or a `$csp=script-src 'none'` network rule.
+#[derive(Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ScriptletsAndFlags {
+    pub injected_script: String,
+    pub generichide: bool,
+    pub disable_scripting: bool,
+}
+
+/// Which kinds of cosmetic filtering may be applied to a particular page, as determined by the
+/// network exception rules that apply to it.
+///
+/// `generic_hide_allowed` is false if there is a corresponding `$generichide` or `$elemhide`
+/// exception. `specific_hide_allowed` is false if there is a corresponding `$specifichide` or
+/// `$elemhide` exception. If the page is excepted entirely using `$document`, all three are false.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct CosmeticPolicy {
+    pub generic_hide_allowed: bool,
+    pub specific_hide_allowed: bool,
+    pub scriptlets_allowed: bool,
+}
+
+impl Default for CosmeticPolicy {
+    fn default() -> Self {
+        Self {
+            generic_hide_allowed: true,
+            specific_hide_allowed: true,
+            scriptlets_allowed: true,
+        }
+    }
+}
+
+/// Least-recently-used cache of computed `UrlSpecificResources`, keyed by hostname and the
+/// `CosmeticPolicy` that applied to the page (which includes its `generichide` status). Lookups
+/// and insertions are constant-time; evicting the least recently used entry scans the cache, which
+/// is cheap for the small capacities this is intended for.
+#[derive(Debug)]
+pub(crate) struct ResourcesLruCache {
+    capacity: usize,
+    last_use: u64,
+    entries: HashMap<(String, CosmeticPolicy), (u64, UrlSpecificResources)>,
+}
+
+impl ResourcesLruCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            last_use: 0,
+            entries: HashMap::with_capacity(capacity),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn get(&mut self, hostname: &str, policy: &CosmeticPolicy) -> Option<UrlSpecificResources> {
+        self.last_use += 1;
+        let last_use = self.last_use;
+        self.entries.get_mut(&(hostname.to_string(), *policy)).map(|(used, resources)| {
+            *used = last_use;
+            resources.clone()
+        })
+    }
+
+    pub fn insert(&mut self, hostname: &str, policy: &CosmeticPolicy, resources: UrlSpecificResources) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = (hostname.to_string(), *policy);
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            let oldest = self.entries.iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.last_use += 1;
+        self.entries.insert(key, (self.last_use, resources));
+    }
 }
 
 impl UrlSpecificResources {
@@ -41,15 +138,184 @@ impl UrlSpecificResources {
             exceptions: HashSet::new(),
             injected_script: String::new(),
             generichide: false,
+            procedural_filters: HashSet::new(),
         }
     }
 }
 
-fn hostname_specific_rules(rules: &[&SpecificFilterType]) -> (HashSet<String>, HashMap<String, Vec<String>>, Vec<String>) {
+impl UrlSpecificResources {
+    /// Renders `hide_selectors` and `style_selectors` into one or more stylesheets, according to
+    /// the size limits in `options`.
+    pub fn stylesheets(&self, options: &StylesheetOptions) -> Vec<String> {
+        let mut hide_selectors = self.hide_selectors.iter().collect::<Vec<_>>();
+        hide_selectors.sort();
+
+        let mut style_selectors = self.style_selectors.iter().collect::<Vec<_>>();
+        style_selectors.sort();
+
+        let mut rules = hide_rules(hide_selectors, options);
+        style_selectors.into_iter().for_each(|(selector, styles)| {
+            styles.iter().for_each(|style| rules.push(format!("{}{{{}}}", selector, style)));
+        });
+
+        pack_stylesheets(rules, options.max_stylesheet_size)
+    }
+}
+
+/// Size limits and hiding style for stylesheets generated from cosmetic filters.
+///
+/// Some injection APIs reject very large stylesheets, like those that would be produced from all
+/// generic rules in EasyList at once.
+#[derive(Debug, Clone)]
+pub struct StylesheetOptions {
+    /// Maximum size of each stylesheet, in bytes. A single rule longer than this is still
+    /// emitted, in a stylesheet of its own.
+    pub max_stylesheet_size: usize,
+    /// Maximum number of selectors combined into a single rule. An invalid selector causes its
+    /// entire rule to be dropped by the browser, so smaller rules limit the effect of bad
+    /// selectors.
+    pub max_selectors_per_rule: usize,
+    /// How the selectors of hide rules are wrapped. Rules with custom styles are left as is.
+    pub selector_specificity: SelectorSpecificity,
+    /// Whether hide rules use `display:none !important` rather than `display:none`. Without
+    /// `!important`, the page's own styles win whenever their selectors are more specific.
+    pub important: bool,
+}
+
+impl Default for StylesheetOptions {
+    fn default() -> Self {
+        Self {
+            max_stylesheet_size: 64 * 1024,
+            max_selectors_per_rule: 1000,
+            selector_specificity: SelectorSpecificity::Unchanged,
+            important: true,
+        }
+    }
+}
+
+/// Specificity of the selectors of generated hide rules, relative to the selectors from the
+/// filters. Stylesheets injected by some WebViews are treated as author styles, and compete with
+/// the page's own styles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectorSpecificity {
+    Unchanged,
+    /// Each selector is wrapped in `:where(...)`, which has no specificity, so that any page style
+    /// for the same element takes precedence
+    Lowered,
+    /// Each selector is prefixed with `:root `, adding the specificity of a pseudo-class. Such
+    /// selectors never match the root element itself.
+    Raised,
+}
+
+impl StylesheetOptions {
+    fn hide_style(&self) -> &'static str {
+        if self.important {
+            "{display:none !important}"
+        } else {
+            "{display:none}"
+        }
+    }
+
+    fn push_selector(&self, rule: &mut String, selector: &str) {
+        match self.selector_specificity {
+            SelectorSpecificity::Unchanged => rule.push_str(selector),
+            SelectorSpecificity::Lowered => {
+                rule.push_str(":where(");
+                rule.push_str(selector);
+                rule.push(')');
+            }
+            SelectorSpecificity::Raised => {
+                rule.push_str(":root ");
+                rule.push_str(selector);
+            }
+        }
+    }
+
+    /// Length of `selector` once added by `push_selector`.
+    fn selector_len(&self, selector: &str) -> usize {
+        selector.len() + match self.selector_specificity {
+            SelectorSpecificity::Unchanged => 0,
+            SelectorSpecificity::Lowered => ":where()".len(),
+            SelectorSpecificity::Raised => ":root ".len(),
+        }
+    }
+}
+
+/// Renders the given selectors into stylesheets hiding the matching elements, e.g. for use with
+/// the output of `Engine::hidden_class_id_selectors`.
+pub fn hide_stylesheets<I, S>(selectors: I, options: &StylesheetOptions) -> Vec<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    pack_stylesheets(hide_rules(selectors, options), options.max_stylesheet_size)
+}
+
+/// Groups selectors into `display: none` rules, respecting the limits in `options`.
+fn hide_rules<I, S>(selectors: I, options: &StylesheetOptions) -> Vec<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let hide_style = options.hide_style();
+    let mut rules = vec![];
+    let mut rule = String::new();
+    let mut rule_selectors = 0;
+
+    for selector in selectors {
+        let selector = selector.as_ref();
+        if rule_selectors > 0
+            && (rule_selectors >= options.max_selectors_per_rule
+                || rule.len() + 1 + options.selector_len(selector) + hide_style.len() > options.max_stylesheet_size)
+        {
+            rule.push_str(hide_style);
+            rules.push(std::mem::take(&mut rule));
+            rule_selectors = 0;
+        }
+        if rule_selectors > 0 {
+            rule.push(',');
+        }
+        options.push_selector(&mut rule, selector);
+        rule_selectors += 1;
+    }
+
+    if rule_selectors > 0 {
+        rule.push_str(hide_style);
+        rules.push(rule);
+    }
+
+    rules
+}
+
+/// Greedily packs rules into as few stylesheets as possible, each no larger than `max_size`.
+fn pack_stylesheets(rules: Vec<String>, max_size: usize) -> Vec<String> {
+    let mut stylesheets = vec![];
+    let mut stylesheet = String::new();
+
+    for rule in rules {
+        if !stylesheet.is_empty() && stylesheet.len() + 1 + rule.len() > max_size {
+            stylesheets.push(std::mem::take(&mut stylesheet));
+        }
+        if !stylesheet.is_empty() {
+            stylesheet.push('\n');
+        }
+        stylesheet.push_str(&rule);
+    }
+
+    if !stylesheet.is_empty() {
+        stylesheets.push(stylesheet);
+    }
+
+    stylesheets
+}
+
+#[allow(clippy::type_complexity)]
+fn hostname_specific_rules(rules: &[&SpecificFilterType]) -> (HashSet<String>, HashMap<String, Vec<String>>, Vec<(String, bool)>, HashSet<ProceduralFilter>) {
     if rules.is_empty() {
-        (HashSet::default(), HashMap::default(), vec![])
+        (HashSet::default(), HashMap::default(), vec![], HashSet::default())
     } else {
         let mut script_rules = Vec::with_capacity(10);
+        let mut procedural_rules = HashSet::new();
 
         let mut hide_rules = HashSet::with_capacity(rules.len());
         let mut style_rules: HashMap<String, Vec<String>> = HashMap::with_capacity(rules.len());
@@ -68,17 +334,24 @@ fn hostname_specific_rules(rules: &[&SpecificFilterType]) -> (HashSet<String>, H
                         }
                     }
                     SpecificFilterType::ScriptInject(sel) => {
-                        script_rules.push(sel.to_owned());
+                        script_rules.push((sel.to_owned(), false));
+                    }
+                    SpecificFilterType::TrustedScriptInject(sel) => {
+                        script_rules.push((sel.to_owned(), true));
+                    }
+                    SpecificFilterType::Procedural(filter) => {
+                        procedural_rules.insert(filter.to_owned());
                     }
+                    SpecificFilterType::DisableScripting => (),
                     _ => unreachable!()
                 }
             });
 
-        (hide_rules, style_rules, script_rules)
+        (hide_rules, style_rules, script_rules, procedural_rules)
     }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub(crate) struct CosmeticFilterCache {
     pub(crate) simple_class_rules: HashSet<String>,
     pub(crate) simple_id_rules: HashSet<String>,
@@ -89,7 +362,14 @@ pub(crate) struct CosmeticFilterCache {
 
     pub(crate) misc_generic_selectors: HashSet<String>,
 
-    pub(crate) scriptlets: ScriptletResourceStorage,
+    pub(crate) scriptlets: Arc<ScriptletResourceStorage>,
+
+    /// Rules that only apply while their tag is enabled, see `FilterSet::add_tagged_filter_list`
+    #[serde(default)]
+    pub(crate) tagged_rules: TaggedRuleArena,
+    /// Tags whose rules are currently stored in the collections above
+    #[serde(default)]
+    pub(crate) tags_enabled: HashSet<String>,
 }
 
 impl CosmeticFilterCache {
@@ -105,10 +385,23 @@ impl CosmeticFilterCache {
             misc_generic_selectors: HashSet::new(),
 
             scriptlets: Default::default(),
+
+            tagged_rules: TaggedRuleArena::default(),
+            tags_enabled: HashSet::new(),
         }
     }
 
     pub fn from_rules(rules: Vec<CosmeticFilter>) -> Self {
+        Self::from_rules_with_checkpoint(rules, &mut |_| Ok(()))
+            .expect("checkpoints that never fail cannot cancel the build")
+    }
+
+    /// Like `from_rules`, calling `checkpoint` with the number of rules added so far every
+    /// `PROGRESS_INTERVAL` rules, and stopping as soon as it returns `Err(Cancelled)`.
+    fn from_rules_with_checkpoint(
+        rules: Vec<CosmeticFilter>,
+        checkpoint: &mut dyn FnMut(usize) -> Result<(), Cancelled>,
+    ) -> Result<Self, Cancelled> {
         let mut self_ = Self {
             simple_class_rules: HashSet::with_capacity(rules.len() / 2),
             simple_id_rules: HashSet::with_capacity(rules.len() / 2),
@@ -120,26 +413,129 @@ impl CosmeticFilterCache {
             misc_generic_selectors: HashSet::with_capacity(rules.len() / 30),
 
             scriptlets: Default::default(),
+
+            tagged_rules: TaggedRuleArena::default(),
+            tags_enabled: HashSet::new(),
         };
 
-        for rule in rules {
+        // Generic exceptions are added last, so that the generic rules they cancel out have
+        // already been stored and can be removed
+        let (generic_exceptions, rules): (Vec<_>, Vec<_>) = rules.into_iter()
+            .partition(|rule| !rule.has_hostname_constraint() && rule.mask.contains(CosmeticFilterMask::UNHIDE));
+
+        for (index, rule) in rules.into_iter().chain(generic_exceptions).enumerate() {
+            if index > 0 && index % PROGRESS_INTERVAL == 0 {
+                checkpoint(index)?;
+            }
             self_.add_filter(rule)
         }
 
-        self_
+        Ok(self_)
     }
 
+    /// Adds a rule to the cache. A generic exception such as `#@#.ad` removes any generic rule
+    /// with the same selector that was added before it, rather than being stored itself.
     pub fn add_filter(&mut self, rule: CosmeticFilter) {
         if rule.has_hostname_constraint() {
             if let Some(generic_rule) = rule.hidden_generic_rule() {
                 self.add_generic_filter(generic_rule);
             }
             self.specific_rules.store_rule(rule);
+        } else if rule.mask.contains(CosmeticFilterMask::UNHIDE) {
+            self.remove_generic_filter(&rule);
         } else {
             self.add_generic_filter(rule);
         }
     }
 
+    /// Removes a rule added with `add_filter`, returning whether it was found. A generic exception
+    /// can't be removed, since the rules it cancelled out are no longer known.
+    ///
+    /// Removing a rule that only excludes some hostnames, like `~example.com##.ad`, also removes
+    /// the generic rule it implies, see `CosmeticFilter::hidden_generic_rule`.
+    pub fn remove_filter(&mut self, rule: &CosmeticFilter) -> bool {
+        if rule.has_hostname_constraint() {
+            if let Some(generic_rule) = rule.hidden_generic_rule() {
+                self.remove_generic_filter(&generic_rule);
+            }
+            self.specific_rules.remove_rule(rule)
+        } else if rule.mask.contains(CosmeticFilterMask::UNHIDE) {
+            false
+        } else {
+            self.remove_generic_filter(rule)
+        }
+    }
+
+    /// Removes the generic rules with the same selector as `rule`, in whichever collection
+    /// `add_generic_filter` would have stored them, returning whether there were any.
+    fn remove_generic_filter(&mut self, rule: &CosmeticFilter) -> bool {
+        let key = match &rule.key {
+            Some(key) => key,
+            None => return self.misc_generic_selectors.remove(&rule.selector),
+        };
+        let (simple_rules, complex_rules) = if rule.mask.contains(CosmeticFilterMask::IS_CLASS_SELECTOR) {
+            (&mut self.simple_class_rules, &mut self.complex_class_rules)
+        } else {
+            (&mut self.simple_id_rules, &mut self.complex_id_rules)
+        };
+        if rule.mask.contains(CosmeticFilterMask::IS_SIMPLE) {
+            simple_rules.remove(key)
+        } else if let Some(bucket) = complex_rules.get_mut(key) {
+            let count = bucket.len();
+            bucket.retain(|selector| selector != &rule.selector);
+            let removed = bucket.len() != count;
+            if bucket.is_empty() {
+                complex_rules.remove(key);
+            }
+            removed
+        } else {
+            false
+        }
+    }
+
+    /// Like `from_rules`, with additional rules that only apply while their tag is enabled with
+    /// `use_tags`. Tagged generic rules are left out if a generic exception in `rules` cancels
+    /// them. `checkpoint` is called as in `from_rules_with_checkpoint`.
+    pub(crate) fn from_tagged_rules(
+        rules: Vec<CosmeticFilter>,
+        mut tagged_rules: Vec<(String, CosmeticFilter)>,
+        checkpoint: &mut dyn FnMut(usize) -> Result<(), Cancelled>,
+    ) -> Result<Self, Cancelled> {
+        let generic_exceptions: HashSet<&str> = rules.iter()
+            .filter(|rule| !rule.has_hostname_constraint() && rule.mask.contains(CosmeticFilterMask::UNHIDE))
+            .map(|rule| rule.selector.as_str())
+            .collect();
+        tagged_rules.retain(|(_, rule)| rule.has_hostname_constraint() || !generic_exceptions.contains(rule.selector.as_str()));
+
+        let mut self_ = Self::from_rules_with_checkpoint(rules, checkpoint)?;
+        self_.tagged_rules = tagged_rules.into_iter().collect();
+        Ok(self_)
+    }
+
+    /// Adds the tagged rules of newly enabled tags, and removes those of tags no longer enabled.
+    /// Only the rules of those tags are looked at.
+    pub(crate) fn use_tags(&mut self, tags_enabled: &HashSet<String>) {
+        let mut changed: Vec<(u32, bool)> = self.tagged_rules.by_tag.iter()
+            .filter(|(tag, _)| self.tags_enabled.contains(*tag) != tags_enabled.contains(*tag))
+            .flat_map(|(tag, indices)| {
+                let enabled = tags_enabled.contains(tag);
+                indices.iter().map(move |&index| (index, enabled))
+            })
+            .collect();
+        // Rules are added and removed in the order they were listed in, since a generic exception
+        // only cancels the rules added before it
+        changed.sort_unstable();
+        for (index, enabled) in changed {
+            let rule = self.tagged_rules.rules[index as usize].clone();
+            if enabled {
+                self.add_filter(rule);
+            } else {
+                self.remove_filter(&rule);
+            }
+        }
+        self.tags_enabled = tags_enabled.clone();
+    }
+
     /// Add a filter, assuming it has already been determined to be a generic rule
     fn add_generic_filter(&mut self, rule: CosmeticFilter) {
         if rule.mask.contains(CosmeticFilterMask::IS_CLASS_SELECTOR) {
@@ -173,6 +569,32 @@ impl CosmeticFilterCache {
         }
     }
 
+    /// Every filter stored in this cache; see `Engine::cosmetic_filters`. Generic filters come
+    /// first, sorted by selector, followed by specific filters in order of hostname hash.
+    pub(crate) fn compiled_filters(&self) -> Vec<CompiledCosmeticFilter<'_>> {
+        let mut generic: Vec<String> = self.simple_class_rules.iter().map(|class| format!(".{}", class))
+            .chain(self.simple_id_rules.iter().map(|id| format!("#{}", id)))
+            .chain(self.complex_class_rules.values().flatten().cloned())
+            .chain(self.complex_id_rules.values().flatten().cloned())
+            .chain(self.misc_generic_selectors.iter().cloned())
+            .collect();
+        generic.sort_unstable();
+
+        let mut specific: Vec<_> = self.specific_rules.buckets().collect();
+        specific.sort_unstable_by_key(|(hostname, _)| *hostname);
+
+        generic.into_iter()
+            .map(|selector| CompiledCosmeticFilter {
+                hostname_hash: None,
+                filter: Cow::Owned(SpecificFilterType::Hide(selector)),
+            })
+            .chain(specific.into_iter().flat_map(|(hostname, bucket)| bucket.map(move |filter| CompiledCosmeticFilter {
+                hostname_hash: Some(hostname),
+                filter: Cow::Borrowed(filter),
+            })))
+            .collect()
+    }
+
     pub fn hidden_class_id_selectors(&self, classes: &[String], ids: &[String], exceptions: &HashSet<String>) -> Vec<String> {
         let mut simple_classes = vec![];
         let mut simple_ids = vec![];
@@ -209,7 +631,9 @@ impl CosmeticFilterCache {
             .collect::<Vec<_>>()
     }
 
-    pub fn hostname_cosmetic_resources(&self, hostname: &str, generichide: bool) -> UrlSpecificResources {
+    /// Returns all hostname-specific rules that apply to `hostname` and are not excepted, along
+    /// with the exceptions that were found.
+    fn hostname_rules_that_apply(&self, hostname: &str) -> (Vec<&SpecificFilterType>, HostnameExceptionsBuilder) {
         let domain_str = {
             let (start, end) = crate::url_parser::get_host_domain(hostname);
             &hostname[start..end]
@@ -230,11 +654,109 @@ impl CosmeticFilterCache {
             exceptions.insert_if_exception(r);
         });
 
-        let rules_that_apply = rules_that_apply.iter().map(|r| r.to_owned()).filter(|r| {
+        let rules_that_apply = rules_that_apply.into_iter().filter(|r| {
             exceptions.allow_specific_rule(r)
         }).collect::<Vec<_>>();
 
-        let (hostname_hide_selectors, style_selectors, script_injections) = hostname_specific_rules(&rules_that_apply[..]);
+        (rules_that_apply, exceptions)
+    }
+
+    /// Concatenates the filled templates of the given scriptlet injections, silently discarding
+    /// any that can't be found. Each injection is paired with whether it came from a trusted
+    /// rule; privileged scriptlets from untrusted rules are discarded as well.
+    fn injected_script(&self, script_injections: &[(String, bool)]) -> String {
+        let mut injected_script = String::new();
+        script_injections.iter().for_each(|(s, trusted)| {
+            let filled_template = if *trusted {
+                self.scriptlets.get_trusted_scriptlet(&s)
+            } else {
+                self.scriptlets.get_scriptlet(&s)
+            };
+            if let Ok(filled_template) = filled_template {
+                injected_script += &filled_template;
+                injected_script += "\n";
+            }
+        });
+        injected_script
+    }
+
+    pub fn hostname_scriptlets_and_flags(&self, hostname: &str, generichide: bool) -> ScriptletsAndFlags {
+        let (rules_that_apply, _) = self.hostname_rules_that_apply(hostname);
+
+        let mut script_injections = vec![];
+        let mut disable_scripting = false;
+        rules_that_apply.into_iter().for_each(|rule| {
+            match rule {
+                SpecificFilterType::ScriptInject(script) => script_injections.push((script.to_owned(), false)),
+                SpecificFilterType::TrustedScriptInject(script) => script_injections.push((script.to_owned(), true)),
+                SpecificFilterType::DisableScripting => disable_scripting = true,
+                _ => (),
+            }
+        });
+
+        ScriptletsAndFlags {
+            injected_script: self.injected_script(&script_injections),
+            generichide,
+            disable_scripting,
+        }
+    }
+
+    /// Whether any generic cosmetic rules are loaded, which may apply on any site.
+    pub fn has_generic_rules(&self) -> bool {
+        !self.simple_class_rules.is_empty()
+            || !self.simple_id_rules.is_empty()
+            || !self.complex_class_rules.is_empty()
+            || !self.complex_id_rules.is_empty()
+            || !self.misc_generic_selectors.is_empty()
+    }
+
+    /// Whether any cosmetic rules specific to `hostname`, its parent domains or their entities
+    /// are loaded, other than exceptions. Only looks up the hashes of these domains, without
+    /// collecting the rules.
+    pub fn has_specific_rules_for(&self, hostname: &str) -> bool {
+        let domain_str = {
+            let (start, end) = crate::url_parser::get_host_domain(hostname);
+            &hostname[start..end]
+        };
+        let (request_entities, request_hostnames) = hostname_domain_hashes(hostname, domain_str);
+
+        request_entities.iter().chain(request_hostnames.iter()).any(|hash| {
+            self.specific_rules.retrieve(hash)
+                .map(|mut rules| rules.any(|rule| matches!(rule,
+                    SpecificFilterType::Hide(_)
+                    | SpecificFilterType::Style(_, _)
+                    | SpecificFilterType::ScriptInject(_)
+                    | SpecificFilterType::TrustedScriptInject(_)
+                    | SpecificFilterType::Procedural(_)
+                    | SpecificFilterType::DisableScripting
+                )))
+                .unwrap_or(false)
+        })
+    }
+
+    pub fn hostname_cosmetic_resources(&self, hostname: &str, generichide: bool) -> UrlSpecificResources {
+        self.hostname_cosmetic_resources_with_policy(hostname, &CosmeticPolicy {
+            generic_hide_allowed: !generichide,
+            ..Default::default()
+        })
+    }
+
+    /// Like `hostname_cosmetic_resources`, but leaves out any resources disallowed by `policy`.
+    pub fn hostname_cosmetic_resources_with_policy(&self, hostname: &str, policy: &CosmeticPolicy) -> UrlSpecificResources {
+        let generichide = !policy.generic_hide_allowed;
+
+        let (rules_that_apply, exceptions) = self.hostname_rules_that_apply(hostname);
+
+        let (mut hostname_hide_selectors, mut style_selectors, mut script_injections, mut procedural_filters) = hostname_specific_rules(&rules_that_apply[..]);
+
+        if !policy.specific_hide_allowed {
+            hostname_hide_selectors.clear();
+            style_selectors.clear();
+            procedural_filters.clear();
+        }
+        if !policy.scriptlets_allowed {
+            script_injections.clear();
+        }
 
         let hide_selectors = if generichide {
             hostname_hide_selectors
@@ -244,13 +766,7 @@ impl CosmeticFilterCache {
             hide_selectors
         };
 
-        let mut injected_script = String::new();
-        script_injections.iter().for_each(|s| {
-            if let Ok(filled_template) = self.scriptlets.get_scriptlet(&s) {
-                injected_script += &filled_template;
-                injected_script += "\n";
-            }
-        });
+        let injected_script = self.injected_script(&script_injections);
 
         UrlSpecificResources {
             hide_selectors,
@@ -258,25 +774,29 @@ impl CosmeticFilterCache {
             exceptions: exceptions.hide_exceptions,
             injected_script,
             generichide,
+            procedural_filters,
         }
     }
 
-    /// Sets the internal resources to be those provided, silently discarding errors.
-    ///
-    /// Use `add_resource` if error information is required.
-    pub fn use_resources(&mut self, resources: &[Resource]) {
+    /// Sets the internal resources to be those provided, returning the ones that could not be
+    /// added.
+    pub fn use_resources(&mut self, resources: &[Resource]) -> Vec<RejectedResource> {
         let mut scriptlets = ScriptletResourceStorage::default();
+        let rejected = add_all(resources, |resource| scriptlets.add_resource(resource));
 
-        resources.iter().for_each(|resource| {
-            let _result = scriptlets.add_resource(&resource);
-        });
+        self.scriptlets = Arc::new(scriptlets);
+        rejected
+    }
 
+    /// Uses scriptlets that may be shared with other caches. They are copied if resources are
+    /// added later.
+    pub(crate) fn use_shared_resources(&mut self, scriptlets: Arc<ScriptletResourceStorage>) {
         self.scriptlets = scriptlets;
     }
 
     /// Adds a single scriptlet resource.
     pub fn add_resource(&mut self, resource: &Resource) -> Result<(), crate::resources::AddResourceError> {
-        self.scriptlets.add_resource(resource)
+        Arc::make_mut(&mut self.scriptlets).add_resource(resource)
     }
 }
 
@@ -287,6 +807,8 @@ struct HostnameExceptionsBuilder {
     hide_exceptions: HashSet<String>,
     style_exceptions: HashSet<(String, String)>,
     script_inject_exceptions: HashSet<String>,
+    procedural_exceptions: HashSet<ProceduralFilter>,
+    disable_scripting_exception: bool,
 }
 
 impl HostnameExceptionsBuilder {
@@ -295,7 +817,7 @@ impl HostnameExceptionsBuilder {
         use SpecificFilterType as Rule;
 
         match rule {
-            Rule::Hide(_) | Rule::Style(_, _) | Rule::ScriptInject(_) => (),
+            Rule::Hide(_) | Rule::Style(_, _) | Rule::ScriptInject(_) | Rule::TrustedScriptInject(_) | Rule::Procedural(_) | Rule::DisableScripting => (),
             Rule::Unhide(sel) => {
                 self.hide_exceptions.insert(sel.clone());
             }
@@ -305,6 +827,12 @@ impl HostnameExceptionsBuilder {
             Rule::UnhideScriptInject(script) => {
                 self.script_inject_exceptions.insert(script.clone());
             }
+            Rule::UnhideProcedural(filter) => {
+                self.procedural_exceptions.insert(filter.clone());
+            }
+            Rule::UnhideDisableScripting => {
+                self.disable_scripting_exception = true;
+            }
         }
     }
 
@@ -321,25 +849,128 @@ impl HostnameExceptionsBuilder {
             SpecificFilterType::Hide(sel) => !self.hide_exceptions.contains(sel),
             SpecificFilterType::Style(sel, style) => !self.style_exceptions.contains(&(sel.to_string(), style.to_string())),
             SpecificFilterType::ScriptInject(sel) => !self.script_inject_exceptions.contains(sel),
+            SpecificFilterType::TrustedScriptInject(sel) => !self.script_inject_exceptions.contains(sel),
+            SpecificFilterType::Procedural(filter) => !self.procedural_exceptions.contains(filter),
+            SpecificFilterType::DisableScripting => !self.disable_scripting_exception,
             _ => false,
         }
     }
 }
 
-#[derive(Deserialize, Serialize, Default)]
+/// Rules of a `HostnameRuleDb` are stored once in an arena, and the bucket of each hostname refers
+/// to them by index. A rule listing many hostnames is then not copied into each of their buckets.
+/// Slots of removed rules are reused by the next rules stored.
+#[derive(Clone, Default)]
 pub struct HostnameRuleDb {
+    rules: Vec<SpecificFilterType>,
+    /// Number of buckets referring to each slot of `rules`
+    references: Vec<u32>,
+    /// Slots of `rules` that no bucket refers to anymore
+    free: Vec<u32>,
+    db: HashMap<Hash, Vec<u32>>,
+}
+
+/// Serialized form of a `HostnameRuleDb`, kept identical to the one used before rules were
+/// arena-allocated. Rules are written out for each bucket that refers to them.
+#[derive(Serialize)]
+struct SerializeHostnameRuleDb<'a> {
+    db: HashMap<Hash, Vec<&'a SpecificFilterType>>,
+}
+
+#[derive(Deserialize)]
+struct DeserializeHostnameRuleDb {
     db: HashMap<Hash, Vec<SpecificFilterType>>,
 }
 
+impl Serialize for HostnameRuleDb {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerializeHostnameRuleDb {
+            db: self.db
+                .iter()
+                .map(|(hostname, bucket)| (*hostname, bucket.iter().map(|&index| &self.rules[index as usize]).collect()))
+                .collect(),
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for HostnameRuleDb {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let serialized = DeserializeHostnameRuleDb::deserialize(deserializer)?;
+        let mut self_ = HostnameRuleDb::new();
+        for (hostname, bucket) in serialized.db {
+            for kind in bucket {
+                let index = self_.allocate(kind);
+                self_.store(&hostname, index);
+            }
+        }
+        Ok(self_)
+    }
+}
+
+/// Tagged rules of a `CosmeticFilterCache` are stored once in an arena, and each tag refers to its
+/// rules by index, so enabling or disabling a tag only visits the rules of that tag.
+#[derive(Clone, Default)]
+pub(crate) struct TaggedRuleArena {
+    /// Rules in the order they were listed in
+    rules: Vec<CosmeticFilter>,
+    by_tag: HashMap<String, Vec<u32>>,
+}
+
+impl TaggedRuleArena {
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns each rule along with its tag, in the order they were listed in.
+    fn iter(&self) -> impl Iterator<Item = (&str, &CosmeticFilter)> {
+        let mut tags = vec![""; self.rules.len()];
+        for (tag, indices) in self.by_tag.iter() {
+            indices.iter().for_each(|&index| tags[index as usize] = tag);
+        }
+        tags.into_iter().zip(self.rules.iter())
+    }
+}
+
+impl std::iter::FromIterator<(String, CosmeticFilter)> for TaggedRuleArena {
+    fn from_iter<I: IntoIterator<Item = (String, CosmeticFilter)>>(iter: I) -> Self {
+        let mut self_ = Self::default();
+        for (tag, rule) in iter {
+            self_.by_tag.entry(tag).or_insert_with(Vec::new).push(self_.rules.len() as u32);
+            self_.rules.push(rule);
+        }
+        self_
+    }
+}
+
+/// The serialized form is the list of rules along with their tags, kept identical to the one used
+/// before tagged rules were arena-allocated.
+impl Serialize for TaggedRuleArena {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for TaggedRuleArena {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Vec::<(String, CosmeticFilter)>::deserialize(deserializer)?.into_iter().collect())
+    }
+}
+
 /// Each hostname-specific filter can be pointed to by several different hostnames, and each
 /// hostname can correspond to several different filters. To effectively store and access those
-/// filters by hostname, all the non-hostname information for filters is stored in per-hostname
-/// "buckets" within a Vec, and each bucket is identified by its index. Hostname hashes are used as
-/// keys to get the indices of relevant buckets, which are in turn used to retrieve all the filters
-/// that apply.
+/// filters by hostname, the non-hostname information for each filter is stored in a bucket for
+/// every hostname or entity hash listed in the rule.
+///
+/// Lookups for a request hostname then only need to retrieve one bucket per suffix of the
+/// hostname (e.g. `a.b.example.co.uk`, `b.example.co.uk`, `example.co.uk`), plus one per
+/// entity (e.g. `a.b.example`, `b.example`, `example`), rather than scanning the hostname lists of
+/// all rules.
 impl HostnameRuleDb {
     pub fn new() -> Self {
         HostnameRuleDb {
+            rules: Vec::new(),
+            references: Vec::new(),
+            free: Vec::new(),
             db: HashMap::new(),
         }
     }
@@ -347,49 +978,79 @@ impl HostnameRuleDb {
     pub fn store_rule(&mut self, rule: CosmeticFilter) {
         let kind = SpecificFilterType::from(&rule);
 
-        if let Some(hostnames) = rule.hostnames {
-            hostnames.iter().for_each(|h| {
-                self.store(h, kind.clone())
-            });
-        }
-        if let Some(entities) = rule.entities {
-            entities.iter().for_each(|e| {
-                self.store(e, kind.clone())
-            });
+        let hostnames: Vec<Hash> = rule.hostnames.into_iter().chain(rule.entities).flatten().collect();
+        if !hostnames.is_empty() {
+            let index = self.allocate(kind.clone());
+            hostnames.iter().for_each(|h| self.store(h, index));
         }
 
-        let kind = kind.negated();
-
-        if let Some(not_hostnames) = rule.not_hostnames {
-            not_hostnames.iter().for_each(|h| {
-                self.store(h, kind.clone())
-            });
+        let not_hostnames: Vec<Hash> = rule.not_hostnames.into_iter().chain(rule.not_entities).flatten().collect();
+        if !not_hostnames.is_empty() {
+            let index = self.allocate(kind.negated());
+            not_hostnames.iter().for_each(|h| self.store(h, index));
         }
-        if let Some(not_entities) = rule.not_entities {
-            not_entities.iter().for_each(|e| {
-                self.store(e, kind.clone())
-            });
+    }
+
+    /// Removes one copy of `rule` from each bucket that `store_rule` would have put it in,
+    /// returning whether any was found. Only those buckets are looked at.
+    pub fn remove_rule(&mut self, rule: &CosmeticFilter) -> bool {
+        let kind = SpecificFilterType::from(rule);
+        let negated = kind.clone().negated();
+        let hostnames = rule.hostnames.iter().chain(rule.entities.iter()).flatten().map(|h| (h, &kind));
+        let not_hostnames = rule.not_hostnames.iter().chain(rule.not_entities.iter()).flatten().map(|h| (h, &negated));
+
+        let mut removed = false;
+        for (hostname, kind) in hostnames.chain(not_hostnames) {
+            if let Some(bucket) = self.db.get_mut(hostname) {
+                let rules = &self.rules;
+                if let Some(position) = bucket.iter().position(|&index| &rules[index as usize] == kind) {
+                    let index = bucket.remove(position);
+                    self.references[index as usize] -= 1;
+                    if self.references[index as usize] == 0 {
+                        self.free.push(index);
+                    }
+                    removed = true;
+                }
+                if bucket.is_empty() {
+                    self.db.remove(hostname);
+                }
+            }
         }
+        removed
     }
 
-    fn store(&mut self, hostname: &Hash, kind: SpecificFilterType) {
-        if let Some(bucket) = self.db.get_mut(hostname) {
-            bucket.push(kind);
+    /// Puts `kind` in a free slot of the arena, returning its index.
+    fn allocate(&mut self, kind: SpecificFilterType) -> u32 {
+        if let Some(index) = self.free.pop() {
+            self.rules[index as usize] = kind;
+            index
         } else {
-            self.db.insert(*hostname, vec![kind]);
+            self.rules.push(kind);
+            self.references.push(0);
+            (self.rules.len() - 1) as u32
         }
     }
 
-    pub fn retrieve<'a>(&'a self, hostname: &Hash) -> Option<&'a[SpecificFilterType]> {
-        if let Some(bucket) = self.db.get(hostname) {
-            Some(&bucket)
+    fn store(&mut self, hostname: &Hash, index: u32) {
+        self.references[index as usize] += 1;
+        if let Some(bucket) = self.db.get_mut(hostname) {
+            bucket.push(index);
         } else {
-            None
+            self.db.insert(*hostname, vec![index]);
         }
     }
+
+    pub fn retrieve<'a>(&'a self, hostname: &Hash) -> Option<impl Iterator<Item = &'a SpecificFilterType> + 'a> {
+        self.db.get(hostname).map(move |bucket| bucket.iter().map(move |&index| &self.rules[index as usize]))
+    }
+
+    /// Every bucket, with the hash of its hostname or entity.
+    pub(crate) fn buckets<'a>(&'a self) -> impl Iterator<Item = (Hash, impl Iterator<Item = &'a SpecificFilterType> + 'a)> + 'a {
+        self.db.iter().map(move |(hostname, bucket)| (*hostname, bucket.iter().map(move |&index| &self.rules[index as usize])))
+    }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum SpecificFilterType {
     // Parameter is the rule's selector
     Hide(String),
@@ -402,6 +1063,18 @@ pub enum SpecificFilterType {
     // Parameter is the rule's injected script
     ScriptInject(String),
     UnhideScriptInject(String),           // Barely happens in practice
+
+    // Parameter is the rule's compiled procedural filter
+    Procedural(ProceduralFilter),
+    UnhideProcedural(ProceduralFilter),
+
+    // `##^script`, disables scripting entirely
+    DisableScripting,
+    UnhideDisableScripting,
+
+    // Like `ScriptInject`, but from a trusted source, so privileged scriptlets are allowed.
+    // Exceptions are shared with `ScriptInject` through `UnhideScriptInject`.
+    TrustedScriptInject(String),
 }
 
 /// This implementation assumes the given rule has hostname or entity constraints, and that the
@@ -410,7 +1083,19 @@ impl From<&CosmeticFilter> for SpecificFilterType {
     fn from(rule: &CosmeticFilter) -> Self {
         let unhide = rule.mask.contains(CosmeticFilterMask::UNHIDE);
 
-        if let Some(ref style) = rule.style {
+        if rule.mask.contains(CosmeticFilterMask::DISABLE_SCRIPTING) {
+            if unhide {
+                SpecificFilterType::UnhideDisableScripting
+            } else {
+                SpecificFilterType::DisableScripting
+            }
+        } else if let Some(ref procedural) = rule.procedural {
+            if unhide {
+                SpecificFilterType::UnhideProcedural(procedural.clone())
+            } else {
+                SpecificFilterType::Procedural(procedural.clone())
+            }
+        } else if let Some(ref style) = rule.style {
             if unhide {
                 SpecificFilterType::UnhideStyle(rule.selector.clone(), style.clone())
             } else {
@@ -419,6 +1104,8 @@ impl From<&CosmeticFilter> for SpecificFilterType {
         } else if rule.mask.contains(CosmeticFilterMask::SCRIPT_INJECT) {
             if unhide {
                 SpecificFilterType::UnhideScriptInject(rule.selector.clone())
+            } else if rule.mask.contains(CosmeticFilterMask::IS_TRUSTED) {
+                SpecificFilterType::TrustedScriptInject(rule.selector.clone())
             } else {
                 SpecificFilterType::ScriptInject(rule.selector.clone())
             }
@@ -441,6 +1128,11 @@ impl SpecificFilterType {
             SpecificFilterType::UnhideStyle(sel, style) => SpecificFilterType::Style(sel, style),
             SpecificFilterType::ScriptInject(script) => SpecificFilterType::UnhideScriptInject(script),
             SpecificFilterType::UnhideScriptInject(script) => SpecificFilterType::ScriptInject(script),
+            SpecificFilterType::Procedural(filter) => SpecificFilterType::UnhideProcedural(filter),
+            SpecificFilterType::UnhideProcedural(filter) => SpecificFilterType::Procedural(filter),
+            SpecificFilterType::DisableScripting => SpecificFilterType::UnhideDisableScripting,
+            SpecificFilterType::UnhideDisableScripting => SpecificFilterType::DisableScripting,
+            SpecificFilterType::TrustedScriptInject(script) => SpecificFilterType::UnhideScriptInject(script),
 
         }
     }
@@ -555,6 +1247,7 @@ mod cosmetic_cache_tests {
                 aliases: vec![],
                 kind: ResourceType::Template,
                 content: base64::encode("set-constant.js, {{1}}, {{2}}"),
+                privileged: false,
             },
             Resource {
                 name: "nowebrtc.js".into(),
@@ -563,6 +1256,7 @@ mod cosmetic_cache_tests {
                     MimeType::ApplicationJavascript,
                 ),
                 content: base64::encode("nowebrtc.js"),
+                privileged: false,
             },
             Resource {
                 name: "window.open-defuser.js".into(),
@@ -571,6 +1265,7 @@ mod cosmetic_cache_tests {
                     MimeType::ApplicationJavascript,
                 ),
                 content: base64::encode("window.open-defuser.js"),
+                privileged: false,
             },
         ]);
 
@@ -692,4 +1387,202 @@ mod cosmetic_cache_tests {
         expected_hides.insert("a[href=\"bad.com\"]".to_owned());
         assert_eq!(hide_selectors, expected_hides);
     }
+
+    #[test]
+    fn generic_exceptions() {
+        let cfcache = cache_from_rules(vec![
+            "#@#.ad",
+            "##.ad",
+            "##.ad > .banner",
+            "##.sponsored .ad",
+            "###promo",
+            "##div > p",
+            "~example.com##a[href=\"bad.com\"]",
+            "example.com##.ad",
+            "#@#.sponsored .ad",
+            "#@##promo",
+            "#@#a[href=\"bad.com\"]",
+            "#@#.unused",
+        ]);
+
+        // The excepted selectors are gone from the generic rules altogether
+        assert!(!cfcache.simple_class_rules.contains("ad"));
+        assert!(!cfcache.simple_id_rules.contains("promo"));
+        assert_eq!(cfcache.complex_class_rules.get("ad"), Some(&vec![".ad > .banner".to_string()]));
+        assert_eq!(cfcache.complex_class_rules.get("sponsored"), None);
+        assert_eq!(cfcache.misc_generic_selectors, std::iter::once("div > p".to_string()).collect::<HashSet<_>>());
+
+        let out = cfcache.hidden_class_id_selectors(&["ad".into(), "sponsored".into()], &["promo".into()], &HashSet::new());
+        assert_eq!(out, [".ad > .banner"]);
+
+        // Specific rules are unaffected
+        let out = cfcache.hostname_cosmetic_resources("example.com", false);
+        assert!(out.hide_selectors.contains(".ad"));
+        assert!(!out.hide_selectors.contains("a[href=\"bad.com\"]"));
+    }
+
+    #[test]
+    fn procedural_filters() {
+        use crate::filters::procedural::{parse_procedural_selector, ProceduralAction};
+
+        let cfcache = cache_from_rules(vec![
+            "example.com,test.com##div.ad:has-text(Sponsored)",
+            "example.com##.banner:upward(2):remove()",
+            "sub.example.com#@#div.ad:has-text(Sponsored)",
+        ]);
+
+        let sponsored = parse_procedural_selector("div.ad:has-text(Sponsored)").unwrap().unwrap();
+        let banner = parse_procedural_selector(".banner:upward(2):remove()").unwrap().unwrap();
+        assert_eq!(banner.action, Some(ProceduralAction::Remove));
+
+        let out = cfcache.hostname_cosmetic_resources("example.com", false);
+        let mut expected = UrlSpecificResources::empty();
+        expected.procedural_filters.insert(sponsored.clone());
+        expected.procedural_filters.insert(banner.clone());
+        assert_eq!(out, expected);
+
+        let out = cfcache.hostname_cosmetic_resources("sub.example.com", false);
+        let mut expected = UrlSpecificResources::empty();
+        expected.procedural_filters.insert(banner);
+        assert_eq!(out, expected);
+
+        // procedural filters are not affected by `generichide`
+        let out = cfcache.hostname_cosmetic_resources("test.com", true);
+        let mut expected = UrlSpecificResources::empty();
+        expected.procedural_filters.insert(sponsored);
+        expected.generichide = true;
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn stylesheet_chunking() {
+        let options = StylesheetOptions {
+            max_stylesheet_size: 60,
+            max_selectors_per_rule: 2,
+            ..Default::default()
+        };
+
+        assert_eq!(hide_stylesheets(Vec::<String>::new(), &options), Vec::<String>::new());
+
+        let out = hide_stylesheets(&[".a", ".b", ".c"], &options);
+        assert_eq!(out, vec![".a,.b{display:none !important}\n.c{display:none !important}"]);
+
+        let out = hide_stylesheets(&[".a", ".b", ".c", ".d"], &options);
+        assert_eq!(out, vec![".a,.b{display:none !important}", ".c,.d{display:none !important}"]);
+
+        // oversized selectors still get a stylesheet of their own
+        let long_selector = format!(".{}", "x".repeat(60));
+        let out = hide_stylesheets(&[".a", long_selector.as_str(), ".b"], &options);
+        assert_eq!(out, vec![
+            ".a{display:none !important}".to_string(),
+            format!("{}{{display:none !important}}", long_selector),
+            ".b{display:none !important}".to_string(),
+        ]);
+
+        let cfcache = cache_from_rules(vec![
+            "##div.ad",
+            "example.com##.b:style(color: red)",
+        ]);
+        let out = cfcache.hostname_cosmetic_resources("example.com", false).stylesheets(&StylesheetOptions::default());
+        assert_eq!(out, vec!["div.ad{display:none !important}\n.b{color: red}"]);
+    }
+
+    #[test]
+    fn stylesheet_specificity() {
+        let lowered = StylesheetOptions {
+            selector_specificity: SelectorSpecificity::Lowered,
+            important: false,
+            ..Default::default()
+        };
+        assert_eq!(hide_stylesheets(&[".a", "div > .b"], &lowered), vec![":where(.a),:where(div > .b){display:none}"]);
+
+        let raised = StylesheetOptions {
+            selector_specificity: SelectorSpecificity::Raised,
+            ..Default::default()
+        };
+        assert_eq!(hide_stylesheets(&[".a", "#b"], &raised), vec![":root .a,:root #b{display:none !important}"]);
+
+        // Wrapped selectors count towards the size limit
+        let small = StylesheetOptions {
+            max_stylesheet_size: 40,
+            ..raised
+        };
+        assert_eq!(hide_stylesheets(&[".a", ".b"], &small), vec![":root .a{display:none !important}", ":root .b{display:none !important}"]);
+    }
+
+    #[test]
+    fn hostname_suffix_lookups() {
+        let cfcache = cache_from_rules(vec![
+            "example.co.uk##.hostname",
+            "b.example.co.uk##.subdomain",
+            "example.*##.entity",
+            "a.b.example.*##.subentity",
+            "co.uk##.suffix",
+        ]);
+
+        let hide_selectors = |hostname: &str| {
+            let mut selectors = cfcache.hostname_cosmetic_resources(hostname, false).hide_selectors.into_iter().collect::<Vec<_>>();
+            selectors.sort();
+            selectors
+        };
+
+        assert_eq!(hide_selectors("example.co.uk"), vec![".entity", ".hostname"]);
+        assert_eq!(hide_selectors("a.b.example.co.uk"), vec![".entity", ".hostname", ".subdomain", ".subentity"]);
+        assert_eq!(hide_selectors("b.example.com"), vec![".entity"]);
+        assert_eq!(hide_selectors("a.b.example.de"), vec![".entity", ".subentity"]);
+        // public suffixes themselves are not looked up
+        assert_eq!(hide_selectors("other.co.uk"), Vec::<String>::new());
+        assert_eq!(hide_selectors("notexample.co.uk"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn hostname_rule_arena() {
+        let rule = CosmeticFilter::parse("a.com,b.com,c.com,~sub.a.com##.ad", false).unwrap();
+        let mut cfcache = cache_from_rules(vec!["d.com##.other"]);
+        cfcache.add_filter(rule.clone());
+
+        // The rule and its exception are stored once each, whatever the number of hostnames
+        assert_eq!(cfcache.specific_rules.rules.len(), 3);
+        assert_eq!(cfcache.specific_rules.db.values().flatten().count(), 5);
+
+        let mut encoded = Vec::new();
+        cfcache.specific_rules.serialize(&mut rmp_serde::Serializer::new(&mut encoded)).unwrap();
+        let decoded: HostnameRuleDb = Deserialize::deserialize(&mut rmp_serde::Deserializer::new(&encoded[..])).unwrap();
+        assert_eq!(decoded.db.values().flatten().count(), 5);
+        cfcache.specific_rules = decoded;
+        assert!(cfcache.hostname_cosmetic_resources("b.com", false).hide_selectors.contains(".ad"));
+        assert!(cfcache.hostname_cosmetic_resources("sub.a.com", false).exceptions.contains(".ad"));
+
+        // Slots of removed rules are reused
+        let slots = cfcache.specific_rules.rules.len();
+        for _ in 0..10 {
+            assert!(cfcache.remove_filter(&rule));
+            cfcache.add_filter(rule.clone());
+        }
+        assert_eq!(cfcache.specific_rules.rules.len(), slots);
+        assert!(cfcache.hostname_cosmetic_resources("c.com", false).hide_selectors.contains(".ad"));
+        assert!(cfcache.hostname_cosmetic_resources("d.com", false).hide_selectors.contains(".other"));
+    }
+
+    #[test]
+    fn tagged_rule_arena() {
+        let parse = |tag: &str, rule: &str| (tag.to_string(), CosmeticFilter::parse(rule, false).unwrap());
+        let tagged_rules = vec![parse("a", "##.ad"), parse("b", "#@#.ad"), parse("a", "##.banner"), parse("b", "example.com##.promo")];
+        let mut cfcache = CosmeticFilterCache::from_tagged_rules(vec![], tagged_rules.clone(), &mut |_| Ok(())).unwrap();
+
+        let mut encoded = Vec::new();
+        cfcache.tagged_rules.serialize(&mut rmp_serde::Serializer::new(&mut encoded)).unwrap();
+        let decoded: Vec<(String, CosmeticFilter)> = Deserialize::deserialize(&mut rmp_serde::Deserializer::new(&encoded[..])).unwrap();
+        let order = |rules: &[(String, CosmeticFilter)]| rules.iter().map(|(tag, rule)| (tag.clone(), rule.selector.clone())).collect::<Vec<_>>();
+        assert_eq!(order(&decoded), order(&tagged_rules));
+        cfcache.tagged_rules = Deserialize::deserialize(&mut rmp_serde::Deserializer::new(&encoded[..])).unwrap();
+
+        // The exception tagged `b` only cancels `.ad` because it comes after it
+        cfcache.use_tags(&["a".to_string(), "b".to_string()].iter().cloned().collect());
+        assert_eq!(cfcache.hidden_class_id_selectors(&["ad".into(), "banner".into()], &[], &HashSet::default()), vec![".banner"]);
+        assert!(cfcache.hostname_cosmetic_resources("example.com", false).hide_selectors.contains(".promo"));
+
+        cfcache.use_tags(&["a".to_string()].iter().cloned().collect());
+        assert!(cfcache.hostname_cosmetic_resources("example.com", false).hide_selectors.is_empty());
+    }
 }
@@ -4,20 +4,106 @@
 //!
 //! The format itself is split into two parts for historical reasons. Any new fields should be
 //! added to the _end_ of both `SerializeFormatRest` and `DeserializeFormatRest`.
+//!
+//! Serialized data starts with `FORMAT_MAGIC` and `FORMAT_VERSION`, followed by both parts,
+//! gzip-compressed. Data written before the format was versioned starts directly with the gzip
+//! header, and is still accepted, although ids of its filters with `$domain` options follow the
+//! scheme from before version 2.
 
 use std::collections::{HashSet, HashMap};
+use std::sync::Arc;
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "compression")]
 use flate2::write::GzEncoder;
+#[cfg(feature = "compression")]
 use flate2::read::GzDecoder;
+#[cfg(feature = "compression")]
 use flate2::Compression;
 use rmp_serde as rmps;
 
 use crate::blocker::{Blocker, NetworkFilterList};
 use crate::resources::{RedirectResourceStorage, ScriptletResourceStorage};
 use crate::filters::network::NetworkFilter;
-use crate::cosmetic_filter_cache::{CosmeticFilterCache, HostnameRuleDb};
+use crate::cosmetic_filter_cache::{CosmeticFilterCache, HostnameRuleDb, TaggedRuleArena};
+use crate::engine::EngineMetadata;
+use crate::progress::{Cancelled, CancellationToken};
+#[cfg(feature = "compression")]
 use crate::utils::is_eof_error;
 
+/// Marks data written by a version of this crate that also writes `FORMAT_VERSION`.
+#[cfg(feature = "compression")]
+const FORMAT_MAGIC: &[u8] = b"ABRS";
+
+/// Version of the serialized format. It must be increased whenever a change can't be handled
+/// through `#[serde(default)]` fields, so that older versions of this crate reject the new data
+/// instead of misreading it, and the other way around.
+///
+/// Version 2 changed how the ids of filters with `$domain` options are computed.
+pub const FORMAT_VERSION: u8 = 2;
+
+/// Number of bytes read or written between two cancellation checks while (de)serializing.
+#[cfg(feature = "compression")]
+const CANCELLATION_CHECK_BYTES: usize = 64 * 1024;
+
+/// Wraps the stream of a (de)serialization, failing reads and writes once `cancellation` is
+/// cancelled, so that it also stops in the middle of a long list of filters.
+#[cfg(feature = "compression")]
+struct CancellableIo<'c, T> {
+    inner: T,
+    cancellation: Option<&'c CancellationToken>,
+    unchecked_bytes: usize,
+}
+
+#[cfg(feature = "compression")]
+impl<'c, T> CancellableIo<'c, T> {
+    fn new(inner: T, cancellation: Option<&'c CancellationToken>) -> Self {
+        Self { inner, cancellation, unchecked_bytes: 0 }
+    }
+
+    fn checkpoint(&mut self, bytes: usize) -> std::io::Result<()> {
+        self.unchecked_bytes += bytes;
+        if self.unchecked_bytes < CANCELLATION_CHECK_BYTES {
+            return Ok(());
+        }
+        self.unchecked_bytes = 0;
+        match self.cancellation {
+            // Not `ErrorKind::Interrupted`, which readers and writers retry
+            Some(cancellation) if cancellation.is_cancelled() => Err(std::io::Error::new(std::io::ErrorKind::Other, "cancelled")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reports errors caused by a cancelled stream as `Cancelled`, and others through `other`.
+    fn map_error<E, F: From<Cancelled>>(&self, e: E, other: impl FnOnce(E) -> F) -> F {
+        if self.cancellation.map_or(false, CancellationToken::is_cancelled) {
+            F::from(Cancelled)
+        } else {
+            other(e)
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+impl<T: std::io::Write> std::io::Write for CancellableIo<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.checkpoint(buf.len())?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(feature = "compression")]
+impl<T: std::io::Read> std::io::Read for CancellableIo<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.checkpoint(read)?;
+        Ok(read)
+    }
+}
+
 /// Provides structural aggregration of referenced adblock engine data to allow for allocation-free
 /// serialization.
 ///
@@ -29,9 +115,13 @@ pub struct SerializeFormat<'a> {
 }
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum SerializationError {
     RmpSerdeError(rmps::encode::Error),
     GzError(std::io::Error),
+    /// The crate was built without the `compression` feature.
+    Unsupported,
+    Cancelled,
 }
 
 impl From<rmps::encode::Error> for SerializationError {
@@ -42,14 +132,37 @@ impl From<std::io::Error> for SerializationError {
     fn from(e: std::io::Error) -> Self { Self::GzError(e) }
 }
 
+impl From<Cancelled> for SerializationError {
+    fn from(_: Cancelled) -> Self { Self::Cancelled }
+}
+
 impl<'a> SerializeFormat<'a> {
     pub fn serialize(&self) -> Result<Vec<u8>, SerializationError> {
-        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
-        rmps::encode::write(&mut gz, &self.part1)?;
-        rmps::encode::write(&mut gz, &self.rest)?;
-        let compressed = gz.finish()?;
+        self.serialize_with_cancellation(None)
+    }
+
+    /// Like `serialize`, but stops with `SerializationError::Cancelled` once `cancellation` is
+    /// cancelled. It is checked between the parts of the format, and every
+    /// `CANCELLATION_CHECK_BYTES` bytes written while encoding them.
+    #[cfg(feature = "compression")]
+    pub fn serialize_with_cancellation(&self, cancellation: Option<&CancellationToken>) -> Result<Vec<u8>, SerializationError> {
+        let checkpoint = || cancellation.map_or(Ok(()), CancellationToken::check);
+        let mut header = FORMAT_MAGIC.to_vec();
+        header.push(FORMAT_VERSION);
+        let mut gz = CancellableIo::new(GzEncoder::new(header, Compression::default()), cancellation);
+        checkpoint()?;
+        rmps::encode::write(&mut gz, &self.part1).map_err(|e| gz.map_error(e, SerializationError::RmpSerdeError))?;
+        checkpoint()?;
+        rmps::encode::write(&mut gz, &self.rest).map_err(|e| gz.map_error(e, SerializationError::RmpSerdeError))?;
+        checkpoint()?;
+        let compressed = gz.inner.finish()?;
         Ok(compressed)
     }
+
+    #[cfg(not(feature = "compression"))]
+    pub fn serialize_with_cancellation(&self, _cancellation: Option<&CancellationToken>) -> Result<Vec<u8>, SerializationError> {
+        Err(SerializationError::Unsupported)
+    }
 }
 
 #[derive(Serialize)]
@@ -88,6 +201,22 @@ struct SerializeFormatRest<'a> {
     scriptlets: &'a ScriptletResourceStorage,
 
     generic_hide: &'a NetworkFilterList,
+
+    specific_hide: &'a NetworkFilterList,
+
+    metadata: &'a EngineMetadata,
+
+    popups: &'a NetworkFilterList,
+    popup_exceptions: &'a NetworkFilterList,
+
+    dns_rewrites: &'a NetworkFilterList,
+
+    removeparams: &'a NetworkFilterList,
+
+    removeheaders: &'a NetworkFilterList,
+
+    tagged_cosmetic_rules: &'a TaggedRuleArena,
+    cosmetic_tags_enabled: &'a HashSet<String>,
 }
 
 /// Structural representation of adblock engine data that can be built up from deserialization and
@@ -101,25 +230,61 @@ pub struct DeserializeFormat {
 }
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum DeserializationError {
     RmpSerdeError(rmps::decode::Error),
+    /// The crate was built without the `compression` feature.
+    Unsupported,
+    Cancelled,
+    /// The data was written in another version of the format, see `FORMAT_VERSION`.
+    UnsupportedVersion(u8),
 }
 
 impl From<rmps::decode::Error> for DeserializationError {
     fn from(e: rmps::decode::Error) -> Self { Self::RmpSerdeError(e) }
 }
 
+impl From<Cancelled> for DeserializationError {
+    fn from(_: Cancelled) -> Self { Self::Cancelled }
+}
+
 impl DeserializeFormat {
     pub fn deserialize(serialized: &[u8]) -> Result<Self, DeserializationError> {
-        let mut gz = GzDecoder::new(serialized);
-        let part1: DeserializeFormatPart1 = rmps::decode::from_read(&mut gz)?;
+        Self::deserialize_with_cancellation(serialized, None)
+    }
+
+    /// Like `deserialize`, but stops with `DeserializationError::Cancelled` once `cancellation` is
+    /// cancelled. It is checked between the parts of the format, and every
+    /// `CANCELLATION_CHECK_BYTES` bytes read while decoding them.
+    #[cfg(feature = "compression")]
+    pub fn deserialize_with_cancellation(serialized: &[u8], cancellation: Option<&CancellationToken>) -> Result<Self, DeserializationError> {
+        let checkpoint = || cancellation.map_or(Ok(()), CancellationToken::check);
+        let serialized = if serialized.starts_with(FORMAT_MAGIC) {
+            match serialized[FORMAT_MAGIC.len()..].split_first() {
+                Some((&version, _)) if version != FORMAT_VERSION => return Err(DeserializationError::UnsupportedVersion(version)),
+                Some((_, compressed)) => compressed,
+                None => &[],
+            }
+        } else {
+            serialized
+        };
+        let mut gz = CancellableIo::new(GzDecoder::new(serialized), cancellation);
+        checkpoint()?;
+        let part1: DeserializeFormatPart1 = rmps::decode::from_read(&mut gz)
+            .map_err(|e| gz.map_error(e, DeserializationError::RmpSerdeError))?;
+        checkpoint()?;
         let rest = match rmps::decode::from_read(&mut gz) {
             Ok(rest) => rest,
             Err(ref e) if is_eof_error(e) => Default::default(),
-            Err(e) => return Err(DeserializationError::RmpSerdeError(e)),
+            Err(e) => return Err(gz.map_error(e, DeserializationError::RmpSerdeError)),
         };
         Ok(Self { part1, rest })
     }
+
+    #[cfg(not(feature = "compression"))]
+    pub fn deserialize_with_cancellation(_serialized: &[u8], _cancellation: Option<&CancellationToken>) -> Result<Self, DeserializationError> {
+        Err(DeserializationError::Unsupported)
+    }
 }
 
 #[derive(Deserialize)]
@@ -170,11 +335,36 @@ struct DeserializeFormatRest {
 
     #[serde(default)]
     generic_hide: NetworkFilterList,
+
+    #[serde(default)]
+    specific_hide: NetworkFilterList,
+
+    #[serde(default)]
+    metadata: EngineMetadata,
+
+    #[serde(default)]
+    popups: NetworkFilterList,
+    #[serde(default)]
+    popup_exceptions: NetworkFilterList,
+
+    #[serde(default)]
+    dns_rewrites: NetworkFilterList,
+
+    #[serde(default)]
+    removeparams: NetworkFilterList,
+
+    #[serde(default)]
+    removeheaders: NetworkFilterList,
+
+    #[serde(default)]
+    tagged_cosmetic_rules: TaggedRuleArena,
+    #[serde(default)]
+    cosmetic_tags_enabled: HashSet<String>,
 }
 
-impl<'a> From<(&'a Blocker, &'a CosmeticFilterCache)> for SerializeFormat<'a> {
-    fn from(v: (&'a Blocker, &'a CosmeticFilterCache)) -> Self {
-        let (blocker, cfc) = v;
+impl<'a> From<(&'a Blocker, &'a CosmeticFilterCache, &'a EngineMetadata)> for SerializeFormat<'a> {
+    fn from(v: (&'a Blocker, &'a CosmeticFilterCache, &'a EngineMetadata)) -> Self {
+        let (blocker, cfc, metadata) = v;
         Self {
             part1: SerializeFormatPt1 {
                 csp: &blocker.csp,
@@ -191,7 +381,7 @@ impl<'a> From<(&'a Blocker, &'a CosmeticFilterCache)> for SerializeFormat<'a> {
                 _unused: true,
                 _unused2: true,
 
-                resources: &blocker.resources,
+                resources: &*blocker.resources,
             },
             rest: SerializeFormatRest {
                 simple_class_rules: &cfc.simple_class_rules,
@@ -203,16 +393,32 @@ impl<'a> From<(&'a Blocker, &'a CosmeticFilterCache)> for SerializeFormat<'a> {
 
                 misc_generic_selectors: &cfc.misc_generic_selectors,
 
-                scriptlets: &cfc.scriptlets,
+                scriptlets: &*cfc.scriptlets,
 
                 generic_hide: &blocker.generic_hide,
+
+                specific_hide: &blocker.specific_hide,
+
+                metadata,
+
+                popups: &blocker.popups,
+                popup_exceptions: &blocker.popup_exceptions,
+
+                dns_rewrites: &blocker.dns_rewrites,
+
+                removeparams: &blocker.removeparams,
+
+                removeheaders: &blocker.removeheaders,
+
+                tagged_cosmetic_rules: &cfc.tagged_rules,
+                cosmetic_tags_enabled: &cfc.tags_enabled,
             },
         }
     }
 }
 
-impl Into<(Blocker, CosmeticFilterCache)> for DeserializeFormat {
-    fn into(self) -> (Blocker, CosmeticFilterCache) {
+impl Into<(Blocker, CosmeticFilterCache, EngineMetadata)> for DeserializeFormat {
+    fn into(self) -> (Blocker, CosmeticFilterCache, EngineMetadata) {
         (Blocker {
             csp: self.part1.csp,
             exceptions: self.part1.exceptions,
@@ -223,16 +429,32 @@ impl Into<(Blocker, CosmeticFilterCache)> for DeserializeFormat {
 
             tags_enabled: Default::default(),
             tagged_filters_all: self.part1.tagged_filters_all,
+            tag_schedules: Default::default(),
+            clock: None,
+            scheduled_tags: Default::default(),
 
             hot_filters: Default::default(),
 
             enable_optimizations: self.part1.enable_optimizations,
 
-            resources: self.part1.resources,
-            #[cfg(feature = "object-pooling")]
-            pool: Default::default(),
+            resources: Arc::new(self.part1.resources),
 
             generic_hide: self.rest.generic_hide,
+            specific_hide: self.rest.specific_hide,
+
+            max_url_length: crate::blocker::DEFAULT_MAX_URL_LENGTH,
+            unknown_scheme_policy: Default::default(),
+
+            popups: self.rest.popups,
+            popup_exceptions: self.rest.popup_exceptions,
+
+            dns_rewrites: self.rest.dns_rewrites,
+
+            removeparams: self.rest.removeparams,
+
+            removeheaders: self.rest.removeheaders,
+
+            domain_option_hashes: Default::default(),
         }, CosmeticFilterCache {
             simple_class_rules: self.rest.simple_class_rules,
             simple_id_rules: self.rest.simple_id_rules,
@@ -243,7 +465,33 @@ impl Into<(Blocker, CosmeticFilterCache)> for DeserializeFormat {
 
             misc_generic_selectors: self.rest.misc_generic_selectors,
 
-            scriptlets: self.rest.scriptlets,
-        })
+            scriptlets: Arc::new(self.rest.scriptlets),
+
+            tagged_rules: self.rest.tagged_cosmetic_rules,
+            tags_enabled: self.rest.cosmetic_tags_enabled,
+        }, self.rest.metadata)
+    }
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn cancellable_io_stops_within_a_part() {
+        let token = CancellationToken::new();
+        let mut writer = CancellableIo::new(Vec::new(), Some(&token));
+        writer.write_all(&[0; CANCELLATION_CHECK_BYTES]).unwrap();
+        token.cancel();
+        assert!(writer.write_all(&[0; CANCELLATION_CHECK_BYTES]).is_err());
+        assert!(matches!(writer.map_error((), |()| SerializationError::Unsupported), SerializationError::Cancelled));
+
+        let data = vec![0; CANCELLATION_CHECK_BYTES * 2];
+        let mut reader = CancellableIo::new(&data[..], Some(&token));
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+
+        let mut reader = CancellableIo::new(&data[..], None);
+        assert_eq!(reader.read_to_end(&mut Vec::new()).unwrap(), data.len());
     }
 }
@@ -0,0 +1,112 @@
+//! Bounded cache of network request decisions, for pages that repeatedly request the same
+//! resources, e.g. a tracking pixel fired on every scroll event.
+
+use crate::blocker::BlockerResult;
+use crate::request::{Request, RequestType};
+use crate::utils::Hash;
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Everything about a request that can affect its decision.
+///
+/// The URL is kept as is rather than hashed: a 64-bit hash collision would otherwise return the
+/// decision made for an unrelated URL.
+///
+/// The source only matters through `$domain` options and whether the request is third-party. It
+/// is identified by those of its hostname's suffixes that appear in a `$domain` option, so that
+/// all the pages of a site share entries, unless a filter targets some of its subdomains.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(crate) struct DecisionKey {
+    url: String,
+    source_domain_hashes: Option<Vec<Hash>>,
+    request_type: RequestType,
+    is_third_party: Option<bool>,
+    client_tags: Option<Vec<Hash>>,
+    matched_rule: bool,
+    force_check_exceptions: bool,
+}
+
+impl DecisionKey {
+    /// `domain_option_hashes` should be those of the blocker making the decision, see
+    /// `Blocker::domain_option_hashes`.
+    pub fn new(request: &Request, domain_option_hashes: &HashSet<Hash>, matched_rule: bool, force_check_exceptions: bool) -> Self {
+        Self {
+            url: request.url.clone(),
+            source_domain_hashes: request.source_hostname_hashes.as_ref().map(|hashes| {
+                hashes.iter().copied().filter(|hash| domain_option_hashes.contains(hash)).collect()
+            }),
+            request_type: request.request_type.clone(),
+            is_third_party: request.is_third_party,
+            client_tags: request.client_tags.clone(),
+            matched_rule,
+            force_check_exceptions,
+        }
+    }
+}
+
+/// Holds the results of up to `capacity` distinct requests. Once full, the oldest entries are
+/// discarded first. Results are only valid for the engine state they were computed with, so the
+/// cache must be replaced whenever that changes.
+#[derive(Debug)]
+pub(crate) struct DecisionCache {
+    capacity: usize,
+    entries: HashMap<DecisionKey, BlockerResult>,
+    insertion_order: VecDeque<DecisionKey>,
+    hits: u64,
+    misses: u64,
+}
+
+impl DecisionCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::with_capacity(capacity),
+            insertion_order: VecDeque::with_capacity(capacity),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of lookups answered from the cache, and of those that were not.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    /// Cached decisions, oldest first, so that inserting them into another cache in this order
+    /// keeps the same ones once it is full.
+    pub fn entries(&self) -> impl Iterator<Item = (&DecisionKey, &BlockerResult)> {
+        self.insertion_order.iter().filter_map(move |key| self.entries.get(key).map(|result| (key, result)))
+    }
+
+    pub fn get(&mut self, key: &DecisionKey) -> Option<BlockerResult> {
+        match self.entries.get(key) {
+            Some(result) => {
+                self.hits += 1;
+                Some(result.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: DecisionKey, result: BlockerResult) {
+        if self.capacity == 0 || self.entries.contains_key(&key) {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            if let Some(oldest) = self.insertion_order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.insertion_order.push_back(key.clone());
+        self.entries.insert(key, result);
+    }
+}
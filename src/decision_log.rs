@@ -0,0 +1,189 @@
+//! Opt-in logging of blocking decisions, for collecting reports of mismatches from real-world use.
+//!
+//! URLs and filters are only recorded as hashes, so logs can be exported without revealing the
+//! browsing history of the user. URLs are hashed with SipHash, keyed with a secret that should be
+//! generated once per install with `DecisionLog::generate_secret` and kept on the device, so that
+//! short or guessable URLs can't be recovered from a log by hashing candidates. Whoever holds the
+//! secret can still find the decision made for a known URL with `DecisionLog::url_hash`.
+
+use crate::blocker::BlockerResult;
+use crate::request::{Request, RequestType};
+use crate::utils::Hash;
+
+use rmp_serde as rmps;
+use serde::{Deserialize, Serialize};
+use siphasher::sip::SipHasher24;
+
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::hash::{BuildHasher, Hasher};
+
+/// The outcome of checking a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Decision {
+    /// No filter matched
+    Allowed,
+    Blocked,
+    Redirected,
+    /// A blocking filter matched, but was overridden by an exception
+    Excepted,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DecisionLogEntry {
+    /// Keyed hash of the normalized URL, see `DecisionLog::url_hash`
+    pub url_hash: Hash,
+    pub request_type: RequestType,
+    pub decision: Decision,
+    /// Stable id of the filter that decided the outcome, i.e. the exception if there is one (see
+    /// `NetworkFilter::id`)
+    pub filter_hash: Option<Hash>,
+}
+
+/// A bounded log of sampled decisions. Once full, the oldest entries are discarded.
+#[derive(Debug, Clone)]
+pub struct DecisionLog {
+    capacity: usize,
+    sample_interval: u64,
+    secret: [u8; 16],
+    requests_seen: u64,
+    entries: VecDeque<DecisionLogEntry>,
+}
+
+impl DecisionLog {
+    /// Creates a log keeping up to `capacity` entries, and recording one in every
+    /// `sample_interval` requests. URLs are hashed with `secret`, see the module documentation.
+    pub fn new(capacity: usize, sample_interval: u64, secret: [u8; 16]) -> Self {
+        Self {
+            capacity,
+            sample_interval: sample_interval.max(1),
+            secret,
+            requests_seen: 0,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Returns a random secret for `DecisionLog::new`, drawn from the randomness the standard
+    /// library seeds its hash maps with.
+    pub fn generate_secret() -> [u8; 16] {
+        let mut secret = [0; 16];
+        for chunk in secret.chunks_mut(8) {
+            chunk.copy_from_slice(&RandomState::new().build_hasher().finish().to_le_bytes());
+        }
+        secret
+    }
+
+    /// Hash of `url` as recorded in `DecisionLogEntry::url_hash` by this log. `url` should be
+    /// normalized, as found in `Request::url`.
+    pub fn url_hash(&self, url: &str) -> Hash {
+        let (key0, key1) = self.secret.split_at(8);
+        let mut hasher = SipHasher24::new_with_keys(
+            u64::from_le_bytes(key0.try_into().unwrap()),
+            u64::from_le_bytes(key1.try_into().unwrap()),
+        );
+        hasher.write(url.as_bytes());
+        hasher.finish()
+    }
+
+    pub fn record(&mut self, request: &Request, result: &BlockerResult) {
+        self.requests_seen += 1;
+        if self.capacity == 0 || (self.requests_seen - 1) % self.sample_interval != 0 {
+            return;
+        }
+
+        let decision = if result.exception.is_some() {
+            Decision::Excepted
+        } else if result.redirect.is_some() {
+            Decision::Redirected
+        } else if result.matched {
+            Decision::Blocked
+        } else {
+            Decision::Allowed
+        };
+
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(DecisionLogEntry {
+            url_hash: self.url_hash(&request.url),
+            request_type: request.request_type.clone(),
+            decision,
+            filter_hash: result.exception_id.or(result.filter_id),
+        });
+    }
+
+    /// Recorded entries, oldest first.
+    pub fn entries(&self) -> impl Iterator<Item = &DecisionLogEntry> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Serializes the recorded entries, oldest first, in a compact binary format that can be read
+    /// back with `DecisionLog::import`.
+    pub fn export(&self) -> Result<Vec<u8>, rmps::encode::Error> {
+        rmps::to_vec(&self.entries)
+    }
+
+    pub fn import(exported: &[u8]) -> Result<Vec<DecisionLogEntry>, rmps::decode::Error> {
+        rmps::from_slice(exported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::filters::network::NetworkFilter;
+    use crate::lists::FilterFormat;
+
+    #[test]
+    fn records_sampled_decisions() {
+        let engine = Engine::from_rules_debug(&["||ads.com^".to_string(), "@@||ads.com/ok^".to_string()], FilterFormat::Standard);
+        let mut log = DecisionLog::new(2, 2, [7; 16]);
+
+        for url in ["https://ads.com/a.js", "https://example.com/skipped.js", "https://ads.com/ok/b.js", "https://example.com/skipped.js", "https://example.com/c.js"].iter() {
+            let request = Request::from_urls(url, "https://example.com", "script").unwrap();
+            log.record(&request, &engine.blocker().check(&request));
+        }
+
+        let entries: Vec<_> = log.entries().cloned().collect();
+        assert_eq!(entries, vec![
+            DecisionLogEntry {
+                url_hash: log.url_hash("https://ads.com/ok/b.js"),
+                request_type: RequestType::Script,
+                decision: Decision::Excepted,
+                filter_hash: Some(NetworkFilter::parse("@@||ads.com/ok^", false).unwrap().id),
+            },
+            DecisionLogEntry {
+                url_hash: log.url_hash("https://example.com/c.js"),
+                request_type: RequestType::Script,
+                decision: Decision::Allowed,
+                filter_hash: None,
+            },
+        ]);
+
+        let exported = log.export().unwrap();
+        assert_eq!(DecisionLog::import(&exported).unwrap(), entries);
+    }
+
+    #[test]
+    fn url_hashes_depend_on_the_secret() {
+        let url = "https://example.com/c.js";
+        assert_eq!(DecisionLog::new(1, 1, [7; 16]).url_hash(url), DecisionLog::new(1, 1, [7; 16]).url_hash(url));
+        assert_ne!(DecisionLog::new(1, 1, [7; 16]).url_hash(url), DecisionLog::new(1, 1, [8; 16]).url_hash(url));
+        assert_ne!(DecisionLog::new(1, 1, [7; 16]).url_hash(url), crate::utils::fast_hash(url));
+        assert_ne!(DecisionLog::generate_secret(), DecisionLog::generate_secret());
+    }
+}
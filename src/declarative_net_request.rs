@@ -0,0 +1,437 @@
+//! Transforms network filter rules into the `declarativeNetRequest` rule format used by Manifest
+//! V3 browser extensions.
+//!
+//! `declarativeNetRequest` picks the matching rule with the highest numeric `priority`, rather
+//! than following adblock semantics. `priority` assigns priorities such that the outcome of a
+//! request is the same as in `Blocker`:
+//!
+//! - `$important` blocking rules override everything else
+//! - exception rules override all other blocking rules, including more specific ones
+//! - blocking rules apply if nothing else matches
+//!
+//! Within each of these tiers, more specific rules get higher priorities. This never changes
+//! whether a request is blocked, but the rule reported as matched (e.g. by `getMatchedRules`) is
+//! the most specific one.
+
+use crate::filters::network::{FilterPart, NetworkFilter, NetworkFilterMask};
+use crate::conversion::ConversionReason;
+
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryFrom;
+
+/// Number of distinct priorities within a tier, see `priority`.
+pub const SPECIFICITY_LEVELS: u32 = 4;
+
+const BLOCK_TIER: u32 = 0;
+const EXCEPTION_TIER: u32 = 1;
+const IMPORTANT_TIER: u32 = 2;
+
+/// Rust representation of a single `declarativeNetRequest` rule.
+///
+/// This can be serialized with `serde_json` directly into the correct format.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DnrRule {
+    /// Unique within a ruleset, starting at 1. Assigned in order by
+    /// `FilterSet::into_declarative_net_request`, and 0 for rules converted individually.
+    pub id: u32,
+    pub priority: u32,
+    pub action: DnrAction,
+    pub condition: DnrCondition,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DnrAction {
+    #[serde(rename = "type")]
+    pub typ: DnrActionType,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DnrActionType {
+    Block,
+    Allow,
+    /// Allows the matching frame and every request it makes, used for `$document` exceptions
+    AllowAllRequests,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DnrResourceType {
+    MainFrame,
+    SubFrame,
+    Stylesheet,
+    Script,
+    Image,
+    Font,
+    Object,
+    Xmlhttprequest,
+    Ping,
+    Media,
+    Websocket,
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DnrDomainType {
+    FirstParty,
+    ThirdParty,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnrCondition {
+    /// Pattern in adblock syntax (`||`, `|`, `^` and `*`), matched against the request URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url_filter: Option<String>,
+    /// RE2 regular expression matched against the request URL. Can't be used with `url_filter`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regex_filter: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub is_url_filter_case_sensitive: Option<bool>,
+    /// Domains of the page making the request, including their subdomains.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initiator_domains: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub excluded_initiator_domains: Option<Vec<String>>,
+    /// If not specified, the rule matches all resource types except `main_frame`, which is also
+    /// the default for adblock rules.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource_types: Option<Vec<DnrResourceType>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain_type: Option<DnrDomainType>,
+}
+
+#[derive(Debug)]
+pub enum DnrRuleCreationFailure {
+    /// Only filter rules parsed in debug mode can be translated, since the original pattern is
+    /// needed.
+    NeedsDebugMode,
+    /// `Blocker`-internal `NetworkFilter`s can be represented in optimized form, but these cannot
+    /// be converted.
+    OptimizedRulesUnsupported,
+    NetworkRedirectUnsupported,
+    NetworkCspUnsupported,
+    NetworkGenerichideUnsupported,
+    NetworkSpecifichideUnsupported,
+    NetworkBadFilterUnsupported,
+    NetworkClientTagUnsupported,
+    /// Rules with `$tag` only apply once the tag is enabled at runtime.
+    NetworkTagUnsupported,
+    /// `$domain` values with entities (e.g. `google.*`) rather than hostnames.
+    EntitiesUnsupported,
+    /// URL filters must be ASCII. Hostnames should be given in punycode instead.
+    NonAsciiPattern,
+    /// `$dnstype` and `$dnsrewrite` only apply to DNS queries.
+    NetworkDnsModifiersUnsupported,
+    /// `$removeparam` rules rewrite URLs instead of blocking them, which isn't converted.
+    NetworkRemoveParamUnsupported,
+    NetworkRemoveHeaderUnsupported,
+    /// `$denyallow` domains are only stored as hashes, and can't be listed in the rule.
+    NetworkDenyAllowUnsupported,
+}
+
+impl From<&DnrRuleCreationFailure> for ConversionReason {
+    fn from(failure: &DnrRuleCreationFailure) -> Self {
+        match failure {
+            DnrRuleCreationFailure::NeedsDebugMode => ConversionReason::NeedsDebugMode,
+            DnrRuleCreationFailure::OptimizedRulesUnsupported => ConversionReason::OptimizedRule,
+            DnrRuleCreationFailure::NetworkRedirectUnsupported => ConversionReason::Redirect,
+            DnrRuleCreationFailure::NetworkCspUnsupported => ConversionReason::Csp,
+            DnrRuleCreationFailure::NetworkGenerichideUnsupported => ConversionReason::GenericHide,
+            DnrRuleCreationFailure::NetworkSpecifichideUnsupported => ConversionReason::SpecificHide,
+            DnrRuleCreationFailure::NetworkBadFilterUnsupported => ConversionReason::BadFilter,
+            DnrRuleCreationFailure::NetworkClientTagUnsupported => ConversionReason::ClientTags,
+            DnrRuleCreationFailure::NetworkTagUnsupported => ConversionReason::Tag,
+            DnrRuleCreationFailure::EntitiesUnsupported => ConversionReason::DomainEntities,
+            DnrRuleCreationFailure::NonAsciiPattern => ConversionReason::NonAsciiPattern,
+            DnrRuleCreationFailure::NetworkDnsModifiersUnsupported => ConversionReason::DnsModifiers,
+            DnrRuleCreationFailure::NetworkRemoveParamUnsupported => ConversionReason::RemoveParam,
+            DnrRuleCreationFailure::NetworkRemoveHeaderUnsupported => ConversionReason::RemoveHeader,
+            DnrRuleCreationFailure::NetworkDenyAllowUnsupported => ConversionReason::DenyAllow,
+        }
+    }
+}
+
+/// Priority of the `declarativeNetRequest` equivalent of `filter`, between 1 and
+/// `3 * SPECIFICITY_LEVELS`.
+///
+/// `$important` only has an effect on blocking rules; exceptions with `$important` are treated as
+/// regular exceptions, as they are by `Blocker`.
+pub fn priority(filter: &NetworkFilter) -> u32 {
+    let tier = if filter.is_exception() {
+        EXCEPTION_TIER
+    } else if filter.is_important() {
+        IMPORTANT_TIER
+    } else {
+        BLOCK_TIER
+    };
+
+    let mut specificity = 0;
+    if filter.opt_domains.is_some() {
+        specificity += 2;
+    }
+    if filter.is_hostname_anchor() {
+        specificity += 1;
+    }
+
+    1 + tier * SPECIFICITY_LEVELS + specificity
+}
+
+fn resource_types(mask: NetworkFilterMask) -> Option<Vec<DnrResourceType>> {
+    let includes_document = mask.contains(NetworkFilterMask::FROM_DOCUMENT);
+    if mask.contains(NetworkFilterMask::FROM_ANY) && !includes_document {
+        return None;
+    }
+
+    let mut types = vec![];
+    macro_rules! push_if_flag {
+        ($flag:ident, $target:ident) => {
+            if mask.contains(NetworkFilterMask::$flag) {
+                types.push(DnrResourceType::$target);
+            }
+        };
+    }
+    push_if_flag!(FROM_DOCUMENT, MainFrame);
+    push_if_flag!(FROM_SUBDOCUMENT, SubFrame);
+    push_if_flag!(FROM_STYLESHEET, Stylesheet);
+    push_if_flag!(FROM_SCRIPT, Script);
+    push_if_flag!(FROM_IMAGE, Image);
+    push_if_flag!(FROM_FONT, Font);
+    push_if_flag!(FROM_OBJECT, Object);
+    push_if_flag!(FROM_XMLHTTPREQUEST, Xmlhttprequest);
+    push_if_flag!(FROM_PING, Ping);
+    push_if_flag!(FROM_MEDIA, Media);
+    push_if_flag!(FROM_WEBSOCKET, Websocket);
+    push_if_flag!(FROM_OTHER, Other);
+    Some(types)
+}
+
+fn non_empty(v: Vec<String>) -> Option<Vec<String>> {
+    if !v.is_empty() {
+        Some(v)
+    } else {
+        None
+    }
+}
+
+impl TryFrom<NetworkFilter> for DnrRule {
+    type Error = DnrRuleCreationFailure;
+
+    fn try_from(v: NetworkFilter) -> Result<Self, Self::Error> {
+        let raw_line = v.raw_line.as_deref().ok_or(DnrRuleCreationFailure::NeedsDebugMode)?;
+        if let FilterPart::AnyOf(_) = v.filter {
+            return Err(DnrRuleCreationFailure::OptimizedRulesUnsupported);
+        }
+        if v.is_redirect() {
+            return Err(DnrRuleCreationFailure::NetworkRedirectUnsupported);
+        }
+        if v.is_csp() {
+            return Err(DnrRuleCreationFailure::NetworkCspUnsupported);
+        }
+        if v.is_removeparam() {
+            return Err(DnrRuleCreationFailure::NetworkRemoveParamUnsupported);
+        }
+        if v.is_removeheader() {
+            return Err(DnrRuleCreationFailure::NetworkRemoveHeaderUnsupported);
+        }
+        if v.has_denyallow() {
+            return Err(DnrRuleCreationFailure::NetworkDenyAllowUnsupported);
+        }
+        if v.is_generic_hide() {
+            return Err(DnrRuleCreationFailure::NetworkGenerichideUnsupported);
+        }
+        if v.is_specific_hide() {
+            return Err(DnrRuleCreationFailure::NetworkSpecifichideUnsupported);
+        }
+        if v.is_badfilter() {
+            return Err(DnrRuleCreationFailure::NetworkBadFilterUnsupported);
+        }
+        if v.has_client_tags() {
+            return Err(DnrRuleCreationFailure::NetworkClientTagUnsupported);
+        }
+        if v.has_dns_types() || v.is_dns_rewrite() {
+            return Err(DnrRuleCreationFailure::NetworkDnsModifiersUnsupported);
+        }
+        if v.tag.is_some() {
+            return Err(DnrRuleCreationFailure::NetworkTagUnsupported);
+        }
+
+        // Options are split off at the last `$`, as in `NetworkFilter::parse`
+        let pattern_start = if v.is_exception() { "@@".len() } else { 0 };
+        let pattern = match raw_line.rfind('$') {
+            Some(options_index) => &raw_line[pattern_start..options_index],
+            None => &raw_line[pattern_start..],
+        };
+
+        let (url_filter, regex_filter) = if v.is_complete_regex() {
+            (None, Some(pattern[1..pattern.len() - 1].to_string()))
+        } else {
+            if !pattern.is_ascii() {
+                return Err(DnrRuleCreationFailure::NonAsciiPattern);
+            }
+            let pattern = pattern.trim_start_matches('*').trim_end_matches('*');
+            (if pattern.is_empty() { None } else { Some(pattern.to_string()) }, None)
+        };
+
+        let mut initiator_domains = vec![];
+        let mut excluded_initiator_domains = vec![];
+        if v.opt_domains.is_some() || v.opt_not_domains.is_some() {
+            let domains = v.opt_domain_names.as_ref().ok_or(DnrRuleCreationFailure::NeedsDebugMode)?;
+            for domain in domains {
+                if domain.ends_with(".*") {
+                    return Err(DnrRuleCreationFailure::EntitiesUnsupported);
+                }
+                match domain.strip_prefix('~') {
+                    Some(excluded) => excluded_initiator_domains.push(excluded.to_string()),
+                    None => initiator_domains.push(domain.to_string()),
+                }
+            }
+        }
+
+        let domain_type = match (v.mask.contains(NetworkFilterMask::FIRST_PARTY), v.mask.contains(NetworkFilterMask::THIRD_PARTY)) {
+            (true, false) => Some(DnrDomainType::FirstParty),
+            (false, true) => Some(DnrDomainType::ThirdParty),
+            _ => None,
+        };
+
+        // `$document` exceptions allowlist whole pages, which `allowAllRequests` only does for
+        // frame requests
+        let (typ, resource_types) = if v.is_exception() && v.for_document() {
+            let mut frames = vec![DnrResourceType::MainFrame];
+            if v.mask.contains(NetworkFilterMask::FROM_SUBDOCUMENT) {
+                frames.push(DnrResourceType::SubFrame);
+            }
+            (DnrActionType::AllowAllRequests, Some(frames))
+        } else if v.is_exception() {
+            (DnrActionType::Allow, resource_types(v.mask))
+        } else {
+            (DnrActionType::Block, resource_types(v.mask))
+        };
+
+        Ok(DnrRule {
+            id: 0,
+            priority: priority(&v),
+            action: DnrAction { typ },
+            condition: DnrCondition {
+                url_filter,
+                regex_filter,
+                is_url_filter_case_sensitive: Some(v.mask.contains(NetworkFilterMask::MATCH_CASE)),
+                initiator_domains: non_empty(initiator_domains),
+                excluded_initiator_domains: non_empty(excluded_initiator_domains),
+                resource_types,
+                domain_type,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lists::{FilterFormat, FilterSet};
+    use std::convert::TryInto;
+
+    fn convert(rule: &str) -> DnrRule {
+        NetworkFilter::parse(rule, true).unwrap().try_into().unwrap()
+    }
+
+    /// Outcome in `declarativeNetRequest` of a request matched by all of the given rules: the
+    /// highest priority wins, and at equal priority `allow` wins over `block`.
+    fn winner(rules: &[&str]) -> DnrActionType {
+        rules.iter()
+            .map(|rule| convert(rule))
+            .max_by_key(|rule| (rule.priority, rule.action.typ == DnrActionType::Allow))
+            .unwrap()
+            .action
+            .typ
+    }
+
+    #[test]
+    fn converts_conditions() {
+        let rule = convert("@@||ads.com/banner^$script,image,third-party,domain=a.com|~b.a.com,match-case");
+        assert_eq!(rule.action.typ, DnrActionType::Allow);
+        assert_eq!(rule.condition, DnrCondition {
+            url_filter: Some("||ads.com/banner^".to_string()),
+            regex_filter: None,
+            is_url_filter_case_sensitive: Some(true),
+            initiator_domains: Some(vec!["a.com".to_string()]),
+            excluded_initiator_domains: Some(vec!["b.a.com".to_string()]),
+            resource_types: Some(vec![DnrResourceType::Script, DnrResourceType::Image]),
+            domain_type: Some(DnrDomainType::ThirdParty),
+        });
+
+        let rule = convert("/ad[0-9]+\\.js/$document");
+        assert_eq!(rule.condition.url_filter, None);
+        assert_eq!(rule.condition.regex_filter, Some("ad[0-9]+\\.js".to_string()));
+        assert_eq!(rule.condition.resource_types, Some(vec![DnrResourceType::MainFrame]));
+
+        assert_eq!(convert("||ads.com^").condition.resource_types, None);
+
+        // Domains come from the parsed rule, so the last `$domain` option wins as it does when
+        // matching
+        let rule = convert("||ads.com^$domain=a.com,script,domain=b.com|~c.b.com");
+        assert_eq!(rule.condition.initiator_domains, Some(vec!["b.com".to_string()]));
+        assert_eq!(rule.condition.excluded_initiator_domains, Some(vec!["c.b.com".to_string()]));
+        assert!(matches!(
+            TryInto::<DnrRule>::try_into(NetworkFilter::parse("||ads.com^$domain=google.*", true).unwrap()),
+            Err(DnrRuleCreationFailure::EntitiesUnsupported)
+        ));
+
+        let rule = convert("@@||news.com^$document");
+        assert_eq!(rule.action.typ, DnrActionType::AllowAllRequests);
+        assert_eq!(rule.condition.resource_types, Some(vec![DnrResourceType::MainFrame]));
+        assert_eq!(convert("@@||news.com^$document,subdocument").condition.resource_types, Some(vec![DnrResourceType::MainFrame, DnrResourceType::SubFrame]));
+        assert_eq!(convert("@@||news.com^$script").action.typ, DnrActionType::Allow);
+        assert!(matches!(
+            TryInto::<DnrRule>::try_into(NetworkFilter::parse("||ads.com^$csp=script-src 'none'", true).unwrap()),
+            Err(DnrRuleCreationFailure::NetworkCspUnsupported)
+        ));
+    }
+
+    #[test]
+    fn priorities_follow_adblock_semantics() {
+        // Exceptions override blocking rules, even more specific ones
+        assert_eq!(winner(&["||ads.com^", "@@||ads.com/ok^"]), DnrActionType::Allow);
+        assert_eq!(winner(&["||ads.com^$domain=a.com", "@@/ok/*"]), DnrActionType::Allow);
+        assert_eq!(winner(&["||ads.com^$script,domain=a.com|b.com", "@@*$script"]), DnrActionType::Allow);
+
+        // `$important` blocking rules override exceptions, even more specific ones
+        assert_eq!(winner(&["/ads/*$important", "@@||ads.com/ads/$domain=a.com"]), DnrActionType::Block);
+        assert_eq!(winner(&["||ads.com^$important", "@@||ads.com^$important"]), DnrActionType::Block);
+
+        // More specific rules are preferred within a tier
+        assert!(convert("||ads.com^$domain=a.com").priority > convert("||ads.com^").priority);
+        assert!(convert("||ads.com^").priority > convert("/ads/*").priority);
+        assert!(convert("@@/ok/*").priority > convert("||ads.com^$domain=a.com").priority);
+        assert!(convert("||ads.com^$important").priority > convert("@@||ads.com^$domain=a.com").priority);
+    }
+
+    #[test]
+    fn converts_filter_sets() {
+        let mut set = FilterSet::new(true);
+        set.add_filters(&[
+            "||ads.com^".to_string(),
+            "@@||ads.com/ok^".to_string(),
+            "||tracker.com^".to_string(),
+            "||tracker.com^$badfilter".to_string(),
+            "||ads.com^$redirect=noop.js".to_string(),
+            "example.com##.ad".to_string(),
+        ], FilterFormat::Standard);
+        let (rules, report) = set.into_declarative_net_request().unwrap();
+
+        assert_eq!(rules.iter().map(|rule| (rule.id, rule.condition.url_filter.as_deref().unwrap())).collect::<Vec<_>>(), vec![
+            (1, "||ads.com^"),
+            (2, "||ads.com/ok^"),
+        ]);
+        assert_eq!(report.rules_total, 6);
+        assert_eq!(report.issues.iter().map(|issue| issue.reason).collect::<Vec<_>>(), vec![
+            ConversionReason::BadFilter,
+            ConversionReason::Redirect,
+            ConversionReason::Cosmetic,
+        ]);
+        assert_eq!(report.issues[0].rule.as_deref(), Some("||tracker.com^"));
+        assert_eq!(report.issues[0].cancelled_by.as_deref(), Some("||tracker.com^$badfilter"));
+    }
+}
@@ -0,0 +1,128 @@
+//! Utilities for comparing the behavior of two engines, e.g. before and after a list update or an
+//! optimizer change.
+
+use serde::Serialize;
+
+use crate::blocker::BlockerResult;
+use crate::engine::Engine;
+use crate::progress::{Cancelled, CancellationToken};
+
+/// The outcome of a network request check, ignoring which rules were responsible for it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum Decision {
+    Allow,
+    Block,
+    /// Contains the redirect resource, in the same format as `BlockerResult::redirect`.
+    Redirect(String),
+}
+
+impl From<&BlockerResult> for Decision {
+    fn from(result: &BlockerResult) -> Self {
+        match (&result.redirect, result.matched) {
+            (Some(redirect), _) => Self::Redirect(redirect.to_owned()),
+            (None, true) => Self::Block,
+            (None, false) => Self::Allow,
+        }
+    }
+}
+
+/// A request for which two engines made different decisions.
+#[derive(Debug, Serialize)]
+pub struct DecisionChange {
+    pub url: String,
+    pub source_url: String,
+    pub request_type: String,
+    pub before: BlockerResult,
+    pub after: BlockerResult,
+}
+
+impl DecisionChange {
+    pub fn decision_before(&self) -> Decision {
+        Decision::from(&self.before)
+    }
+
+    pub fn decision_after(&self) -> Decision {
+        Decision::from(&self.after)
+    }
+}
+
+/// Replays each of the given `(url, source_url, request_type)` requests through both engines, and
+/// returns every request where the decision of `engine_b` differs from that of `engine_a`.
+///
+/// Requests for which only the responsible rules changed, e.g. because they were merged by the
+/// optimizer, are not reported.
+pub fn diff_engines(engine_a: &Engine, engine_b: &Engine, requests: &[(&str, &str, &str)]) -> Vec<DecisionChange> {
+    diff_engines_with_cancellation(engine_a, engine_b, requests, None)
+        .expect("diffs without a cancellation token cannot be cancelled")
+}
+
+/// Like `diff_engines`, but stops with `Err(Cancelled)` once `cancellation` is cancelled.
+pub fn diff_engines_with_cancellation(
+    engine_a: &Engine,
+    engine_b: &Engine,
+    requests: &[(&str, &str, &str)],
+    cancellation: Option<&CancellationToken>,
+) -> Result<Vec<DecisionChange>, Cancelled> {
+    let mut changes = vec![];
+    for (url, source_url, request_type) in requests {
+        cancellation.map_or(Ok(()), CancellationToken::check)?;
+        let before = engine_a.check_network_urls(url, source_url, request_type);
+        let after = engine_b.check_network_urls(url, source_url, request_type);
+        if Decision::from(&before) == Decision::from(&after) {
+            continue;
+        }
+        changes.push(DecisionChange {
+            url: url.to_string(),
+            source_url: source_url.to_string(),
+            request_type: request_type.to_string(),
+            before,
+            after,
+        });
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lists::FilterFormat;
+
+    #[test]
+    fn reports_changed_decisions() {
+        let engine_a = Engine::from_rules_debug(&[
+            "||ads.com^".to_string(),
+            "||tracker.com^".to_string(),
+            "||cdn.com/ad.js".to_string(),
+        ], FilterFormat::Standard);
+        let engine_b = Engine::from_rules_debug(&[
+            "||ads.com^$script".to_string(),
+            "||tracker.com^".to_string(),
+            "||cdn.com/ad.js".to_string(),
+            "@@||cdn.com/ad.js$domain=example.com".to_string(),
+            "||new.com^".to_string(),
+        ], FilterFormat::Standard);
+
+        let requests = [
+            ("https://ads.com/banner.png", "https://example.com", "image"),
+            ("https://ads.com/ad.js", "https://example.com", "script"),
+            ("https://tracker.com/t.js", "https://example.com", "script"),
+            ("https://cdn.com/ad.js", "https://example.com", "script"),
+            ("https://new.com/", "https://example.com", "xmlhttprequest"),
+        ];
+
+        let changes = diff_engines(&engine_a, &engine_b, &requests);
+        let summary = changes
+            .iter()
+            .map(|change| (change.url.as_str(), change.decision_before(), change.decision_after()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(summary, vec![
+            ("https://ads.com/banner.png", Decision::Block, Decision::Allow),
+            ("https://cdn.com/ad.js", Decision::Block, Decision::Allow),
+            ("https://new.com/", Decision::Allow, Decision::Block),
+        ]);
+        assert_eq!(changes[1].after.exception, Some("@@||cdn.com/ad.js$domain=example.com".to_string()));
+
+        assert!(diff_engines(&engine_a, &engine_a, &requests).is_empty());
+    }
+}
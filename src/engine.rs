@@ -1,15 +1,160 @@
-use crate::blocker::{Blocker, BlockerError, BlockerOptions, BlockerResult};
-use crate::cosmetic_filter_cache::{CosmeticFilterCache, UrlSpecificResources};
-use crate::lists::{FilterFormat, FilterSet};
-use crate::request::Request;
-use crate::resources::{Resource, RedirectResource};
+use crate::blocker::{Blocker, BlockerError, BlockerOptions, BlockerResult, DnsQueryResult, FilterHitStats, UnknownSchemePolicy};
+use crate::compiled_filters::{CompiledCosmeticFilter, CompiledNetworkFilter};
+use crate::cosmetic_filter_cache::{CosmeticFilterCache, CosmeticPolicy, ResourcesLruCache, ScriptletsAndFlags, UrlSpecificResources};
+use crate::decision_cache::{DecisionCache, DecisionKey};
+use crate::decision_log::{DecisionLog, DecisionLogEntry};
+use crate::filters::network::NetworkFilter;
+use crate::hostname_index::HostnameIndex;
+use crate::lists::{FilterFormat, FilterParseError, FilterSet, FilterSource, LoadReport};
+use crate::optimizer::OptimizationReport;
+use crate::pipeline::DecisionHook;
+use crate::popup::{PopupDecision, PopupHeuristics, PopupRequest, PopupTracker};
+use crate::progress::{BuildPhase, BuildProgress, Cancelled, CancellationToken, PROGRESS_INTERVAL};
+use crate::referrer::{self, ReferrerAction, ReferrerDecision, ReferrerPolicy};
+use crate::request::{Request, RequestParts, RequestType};
+use crate::resources::{Resource, RedirectResource, RejectedResource};
+use crate::registry::SharedResources;
+use crate::schedule::{Clock, Schedule};
+use crate::tracking_params;
+use crate::utils::Hash;
+
+use rmp_serde as rmps;
+use serde::{Deserialize, Serialize};
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Describes when and from which lists the rules of an `Engine` were compiled. It is stored in the
+/// serialized format, so that embedders loading a cached engine can decide whether it is too old
+/// to be used without refreshing its lists first.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EngineMetadata {
+    /// Time at which the rules were compiled, in seconds since the Unix epoch. `None` for engines
+    /// created without rules, or deserialized from data written by an older version.
+    pub compiled_at: Option<u64>,
+    /// `! Version:` header of each list added through `FilterSet::add_filter_list_with_id` (or
+    /// one of the soft-fail loaders), keyed by list id.
+    pub list_versions: HashMap<String, String>,
+    /// Number of rules that were left out because a `$badfilter` rule cancelled them.
+    #[serde(default)]
+    pub cancelled_filters: usize,
+}
+
+/// Whether a frame is allowlisted by a `$document` exception, as returned by `Engine::frame_state`.
+/// Every request made by an allowlisted frame is allowed, see `Engine::check_network_urls_in_frame`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FrameState {
+    /// The `$document` exception allowlisting the frame or one of its ancestors. Like
+    /// `BlockerResult::exception`, it is only the text of the rule in debug mode, and its
+    /// canonical text otherwise.
+    pub exception: Option<String>,
+    /// Stable identifier of `exception`, see `NetworkFilter::id`.
+    pub exception_id: Option<Hash>,
+}
+
+impl FrameState {
+    pub fn is_allowlisted(&self) -> bool {
+        self.exception_id.is_some()
+    }
+}
+
+/// Why a rule could not be added to or removed from an `Engine`, see `Engine::add_filter`.
+#[derive(Debug)]
+pub enum FilterUpdateError {
+    Parse(FilterParseError),
+    Blocker(BlockerError),
+}
+
+impl From<FilterParseError> for FilterUpdateError {
+    fn from(e: FilterParseError) -> Self {
+        Self::Parse(e)
+    }
+}
 
-use std::collections::HashSet;
+impl From<BlockerError> for FilterUpdateError {
+    fn from(e: BlockerError) -> Self {
+        Self::Blocker(e)
+    }
+}
+
+/// How an `Engine` trades memory use and startup time against matching latency, see
+/// `Engine::set_build_profile`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildProfile {
+    /// Regexes are compiled when a request first needs them, and no caches are enabled.
+    Balanced,
+    /// For servers that prefer a predictable worst-case latency over startup time and memory use.
+    /// The regexes of all network filters are compiled upfront, again whenever new rules are
+    /// deserialized, and the decision and cosmetic resources caches are enabled with
+    /// `LOW_LATENCY_CACHE_CAPACITY` entries each.
+    LowLatency,
+}
+
+impl Default for BuildProfile {
+    fn default() -> Self {
+        Self::Balanced
+    }
+}
+
+/// Capacity of the caches enabled by `BuildProfile::LowLatency`.
+pub const LOW_LATENCY_CACHE_CAPACITY: usize = 4096;
+
+/// What an `Engine` learned about its client's traffic, saved with `Engine::warm_state` so that a
+/// restarted client can resume with it through `Engine::restore_warm_state`, rather than starting
+/// cold. URLs are only kept as hashes.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WarmState {
+    /// Hits recorded with `Engine::record_hits`, which can keep being added to after a restart.
+    pub hit_stats: FilterHitStats,
+    /// Fingerprint of the blocker that made `decisions`, see `Blocker::fingerprint`.
+    fingerprint: Hash,
+    decisions: Vec<(DecisionKey, BlockerResult)>,
+}
+
+impl WarmState {
+    /// Number of cached decisions held by this state.
+    pub fn decision_count(&self) -> usize {
+        self.decisions.len()
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, rmps::encode::Error> {
+        rmps::to_vec(self)
+    }
+
+    pub fn deserialize(serialized: &[u8]) -> Result<Self, rmps::decode::Error> {
+        rmps::from_slice(serialized)
+    }
+}
 
 /// Main adblocking engine that allows efficient querying of resources to block.
+///
+/// Cloning an `Engine` is cheap: clones share the same rules in memory, so separate handles can
+/// be given to e.g. the network layer and the cosmetic filtering code. Modifying a clone (by
+/// changing its tags or resources, or deserializing into it) copies the affected parts first, and
+/// never affects the other clones.
+#[derive(Clone)]
 pub struct Engine {
-    pub blocker: Blocker,
-    cosmetic_cache: CosmeticFilterCache,
+    /// The network blocker of this engine, shared with its clones.
+    ///
+    /// Since 0.4.0, this is an `Arc<Blocker>` rather than a plain `Blocker`, which breaks code that
+    /// replaces or mutates it directly: wrap a new blocker in `Arc::new`, or use `Arc::make_mut`
+    /// to modify it. Read access, like `engine.blocker.check(&request)`, is unaffected; prefer
+    /// `Engine::blocker()` in new code, which doesn't depend on how the blocker is stored.
+    pub blocker: Arc<Blocker>,
+    cosmetic_cache: Arc<CosmeticFilterCache>,
+    metadata: Arc<EngineMetadata>,
+    // Not serialized
+    filter_sources: Arc<HashMap<String, Vec<FilterSource>>>,
+    resources_cache: Option<Arc<Mutex<ResourcesLruCache>>>,
+    decision_log: Option<Arc<Mutex<DecisionLog>>>,
+    decision_cache: Option<Arc<Mutex<DecisionCache>>>,
+    profile: BuildProfile,
+    strip_tracking_params: bool,
+    referrer_policy: ReferrerPolicy,
+    hooks: Vec<Arc<dyn DecisionHook>>,
 }
 
 impl Default for Engine {
@@ -30,8 +175,17 @@ impl Engine {
         };
 
         Self {
-            blocker: Blocker::new(vec![], &blocker_options),
-            cosmetic_cache: CosmeticFilterCache::new(),
+            blocker: Arc::new(Blocker::new(vec![], &blocker_options)),
+            cosmetic_cache: Arc::new(CosmeticFilterCache::new()),
+            metadata: Arc::new(EngineMetadata::default()),
+            filter_sources: Arc::new(HashMap::new()),
+            resources_cache: None,
+            decision_log: None,
+            decision_cache: None,
+            profile: BuildProfile::default(),
+            strip_tracking_params: false,
+            referrer_policy: ReferrerPolicy::default(),
+            hooks: Vec::new(),
         }
     }
 
@@ -53,65 +207,404 @@ impl Engine {
         Self::from_filter_set(filter_set, optimize)
     }
 
+    /// Loads several filter lists, with optimizations enabled and without debug information. Lists
+    /// that could not be obtained (passed as an `Err`) and rules that fail to parse are skipped
+    /// instead of preventing the engine from being built; the returned report describes them. See
+    /// `FilterSet::add_filter_lists_soft_fail`.
+    pub fn from_filter_lists_soft_fail<'a, E: fmt::Display>(
+        lists: impl IntoIterator<Item = (&'a str, Result<&'a str, E>)>,
+        format: FilterFormat,
+    ) -> (Self, LoadReport) {
+        let mut filter_set = FilterSet::new(false);
+        let report = filter_set.add_filter_lists_soft_fail(lists, format);
+        (Self::from_filter_set(filter_set, true), report)
+    }
+
     /// Loads rules from the given `FilterSet`. It is recommended to use a `FilterSet` when adding
     /// rules from multiple sources.
     pub fn from_filter_set(set: FilterSet, optimize: bool) -> Self {
-        let FilterSet { network_filters, cosmetic_filters, .. } = set;
+        Self::build(set, optimize, BuildProgress::default(), &mut |_| (), None)
+            .expect("builds without a cancellation token cannot be cancelled")
+    }
+
+    /// Like `from_filter_set`, with the given `BuildProfile`.
+    pub fn from_filter_set_with_profile(set: FilterSet, optimize: bool, profile: BuildProfile) -> Self {
+        let mut engine = Self::from_filter_set(set, optimize);
+        engine.set_build_profile(profile);
+        engine
+    }
+
+    /// Switches to another `BuildProfile`. Switching to `BuildProfile::LowLatency` compiles the
+    /// regexes of all network filters and enables its caches, while switching back to
+    /// `BuildProfile::Balanced` disables the caches. Compiled regexes are kept either way.
+    pub fn set_build_profile(&mut self, profile: BuildProfile) {
+        self.profile = profile;
+        let cache_capacity = match profile {
+            BuildProfile::Balanced => 0,
+            BuildProfile::LowLatency => {
+                self.blocker.precompile_regexes();
+                LOW_LATENCY_CACHE_CAPACITY
+            }
+        };
+        self.set_decision_cache_capacity(cache_capacity);
+        self.set_cosmetic_resources_cache_capacity(cache_capacity);
+    }
+
+    pub fn build_profile(&self) -> BuildProfile {
+        self.profile
+    }
+
+    /// Like `from_filter_set`, but calls `progress` at the start of each phase, and every
+    /// `PROGRESS_INTERVAL` rules while compiling, which is also when `cancellation` is checked.
+    /// Stops with `BlockerError::Cancelled` at the first checkpoint after it is cancelled.
+    pub fn from_filter_set_with_progress(
+        set: FilterSet,
+        optimize: bool,
+        progress: &mut dyn FnMut(&BuildProgress),
+        cancellation: Option<&CancellationToken>,
+    ) -> Result<Self, BlockerError> {
+        Self::build(set, optimize, BuildProgress::default(), progress, cancellation)
+    }
+
+    /// Parses the given lists, identified as in `FilterSet::add_filter_list_with_id`, and builds
+    /// an engine from them. `progress` is called at the start of each phase, every
+    /// `PROGRESS_INTERVAL` lines while parsing and every `PROGRESS_INTERVAL` rules while
+    /// compiling, which is also when `cancellation` is checked.
+    pub fn from_filter_lists_with_progress<'a>(
+        lists: impl IntoIterator<Item = (&'a str, &'a str)>,
+        format: FilterFormat,
+        debug: bool,
+        optimize: bool,
+        progress: &mut dyn FnMut(&BuildProgress),
+        cancellation: Option<&CancellationToken>,
+    ) -> Result<Self, BlockerError> {
+        let cancelled = || cancellation.map_or(false, CancellationToken::is_cancelled);
+        let mut filter_set = FilterSet::new(debug);
+        let mut status = BuildProgress::default();
+        progress(&status);
+
+        for (list_id, filter_list) in lists {
+            let completed = filter_set.add_filter_list_with_report(list_id, filter_list, format, false, None, &mut |_| {
+                status.lines_parsed += 1;
+                if status.lines_parsed % PROGRESS_INTERVAL != 0 {
+                    return true;
+                }
+                progress(&status);
+                !cancelled()
+            });
+            if !completed || cancelled() {
+                return Err(BlockerError::Cancelled);
+            }
+        }
+
+        Self::build(filter_set, optimize, status, progress, cancellation)
+    }
+
+    fn build(
+        set: FilterSet,
+        optimize: bool,
+        mut status: BuildProgress,
+        progress: &mut dyn FnMut(&BuildProgress),
+        cancellation: Option<&CancellationToken>,
+    ) -> Result<Self, BlockerError> {
+        let cancelled = || cancellation.map_or(false, CancellationToken::is_cancelled);
+        let FilterSet { network_filters, cosmetic_filters, popup_filters, tagged_cosmetic_filters, sources, list_versions, .. } = set;
 
         let blocker_options = BlockerOptions {
             enable_optimizations: optimize,
         };
 
-        Self {
-            blocker: Blocker::new(network_filters, &blocker_options),
-            cosmetic_cache: CosmeticFilterCache::from_rules(cosmetic_filters),
+        let network_filter_count = network_filters.len() + popup_filters.len();
+        status.filters_total = network_filter_count + cosmetic_filters.len();
+        status.phase = BuildPhase::CompilingNetworkFilters;
+        progress(&status);
+        if cancelled() {
+            return Err(BlockerError::Cancelled);
         }
+        let (mut blocker, cancelled_filters) = Blocker::new_with_cancelled_count(network_filters, &blocker_options, &mut |compiled| {
+            status.filters_compiled = compiled;
+            progress(&status);
+            if cancelled() { Err(Cancelled) } else { Ok(()) }
+        })?;
+        blocker.set_popup_filters(popup_filters);
+
+        status.filters_compiled = network_filter_count;
+        status.phase = BuildPhase::CompilingCosmeticFilters;
+        progress(&status);
+        if cancelled() {
+            return Err(BlockerError::Cancelled);
+        }
+        let cosmetic_cache = CosmeticFilterCache::from_tagged_rules(cosmetic_filters, tagged_cosmetic_filters, &mut |compiled| {
+            status.filters_compiled = network_filter_count + compiled;
+            progress(&status);
+            if cancelled() { Err(Cancelled) } else { Ok(()) }
+        })?;
+
+        status.filters_compiled = status.filters_total;
+        status.phase = BuildPhase::Done;
+        progress(&status);
+
+        Ok(Self {
+            blocker: Arc::new(blocker),
+            cosmetic_cache: Arc::new(cosmetic_cache),
+            metadata: Arc::new(EngineMetadata {
+                compiled_at: SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs()),
+                list_versions,
+                cancelled_filters,
+            }),
+            filter_sources: Arc::new(sources),
+            resources_cache: None,
+            decision_log: None,
+            decision_cache: None,
+            profile: BuildProfile::default(),
+            strip_tracking_params: false,
+            referrer_policy: ReferrerPolicy::default(),
+            hooks: Vec::new(),
+        })
     }
 
-    /// Serializes the `Engine` into a binary format so that it can be quickly reloaded later.
+    /// Serializes the `Engine` into a binary format so that it can be quickly reloaded later. The
+    /// data is tagged with the version of the format, see `data_format::FORMAT_VERSION`.
+    ///
+    /// Requires the `compression` feature, and returns `BlockerError::Unsupported` otherwise.
     pub fn serialize(&self) -> Result<Vec<u8>, BlockerError> {
-        use crate::data_format::SerializeFormat;
+        self.serialize_with_cancellation(None)
+    }
+
+    /// Like `serialize`, but stops with `BlockerError::Cancelled` once `cancellation` is
+    /// cancelled.
+    pub fn serialize_with_cancellation(&self, cancellation: Option<&CancellationToken>) -> Result<Vec<u8>, BlockerError> {
+        use crate::data_format::{SerializeFormat, SerializationError};
 
-        let serialize_format = SerializeFormat::from((&self.blocker, &self.cosmetic_cache));
+        let serialize_format = SerializeFormat::from((&*self.blocker, &*self.cosmetic_cache, &*self.metadata));
 
-        serialize_format.serialize().map_err(|_e| {
-            BlockerError::SerializationError
+        serialize_format.serialize_with_cancellation(cancellation).map_err(|e| match e {
+            SerializationError::Unsupported => BlockerError::Unsupported,
+            SerializationError::Cancelled => BlockerError::Cancelled,
+            _ => BlockerError::SerializationError,
         })
     }
 
-    /// Deserialize the `Engine` from the binary format generated by `Engine::serialize`.
+    /// Deserialize the `Engine` from the binary format generated by `Engine::serialize`. Data
+    /// written in another version of the format is rejected with
+    /// `BlockerError::DeserializationError`, leaving the engine unchanged.
+    ///
+    /// Requires the `compression` feature, and returns `BlockerError::Unsupported` otherwise.
     pub fn deserialize(&mut self, serialized: &[u8]) -> Result<(), BlockerError> {
-        use crate::data_format::DeserializeFormat;
+        self.deserialize_with_cancellation(serialized, None)
+    }
+
+    /// Like `deserialize`, but stops with `BlockerError::Cancelled` once `cancellation` is
+    /// cancelled, leaving the engine unchanged.
+    pub fn deserialize_with_cancellation(&mut self, serialized: &[u8], cancellation: Option<&CancellationToken>) -> Result<(), BlockerError> {
+        use crate::data_format::{DeserializeFormat, DeserializationError};
         let current_tags = self.blocker.tags_enabled();
-        let deserialize_format = DeserializeFormat::deserialize(serialized).map_err(|_e| {
-            BlockerError::DeserializationError
+        let max_url_length = self.blocker.max_url_length;
+        let unknown_scheme_policy = self.blocker.unknown_scheme_policy;
+        let deserialize_format = DeserializeFormat::deserialize_with_cancellation(serialized, cancellation).map_err(|e| match e {
+            DeserializationError::Unsupported => BlockerError::Unsupported,
+            DeserializationError::Cancelled => BlockerError::Cancelled,
+            _ => BlockerError::DeserializationError,
         })?;
-        let (blocker, cosmetic_cache) = deserialize_format.into();
-        self.blocker = blocker;
-        self.blocker.use_tags(&current_tags.iter().map(|s| &**s).collect::<Vec<_>>());
-        self.cosmetic_cache = cosmetic_cache;
+        let (mut blocker, mut cosmetic_cache, metadata): (Blocker, CosmeticFilterCache, EngineMetadata) = deserialize_format.into();
+        blocker.use_tags(&current_tags.iter().map(|s| &**s).collect::<Vec<_>>());
+        cosmetic_cache.use_tags(&current_tags.into_iter().collect());
+        blocker.set_max_url_length(max_url_length);
+        blocker.set_unknown_scheme_policy(unknown_scheme_policy);
+        blocker.tag_schedules = self.blocker.tag_schedules.clone();
+        blocker.clock = self.blocker.clock.clone();
+        self.blocker = Arc::new(blocker);
+        self.cosmetic_cache = Arc::new(cosmetic_cache);
+        self.metadata = Arc::new(metadata);
+        // the serialized rules may have come from different lists
+        self.filter_sources = Arc::new(HashMap::new());
+        self.invalidate_resources_cache();
+        self.invalidate_decision_cache();
+        if self.profile == BuildProfile::LowLatency {
+            self.blocker.precompile_regexes();
+        }
         Ok(())
     }
 
+    /// Returns information about when and from which lists this engine's rules were compiled.
+    pub fn metadata(&self) -> &EngineMetadata {
+        &self.metadata
+    }
+
+    /// Returns how long ago this engine's rules were compiled, or `None` if that is unknown (see
+    /// `EngineMetadata::compiled_at`). An engine deserialized from a cached copy keeps the age of
+    /// the original.
+    pub fn age(&self) -> Option<Duration> {
+        let compiled_at = UNIX_EPOCH + Duration::from_secs(self.metadata.compiled_at?);
+        // A compile time in the future (e.g. after a clock change) counts as brand new
+        Some(SystemTime::now().duration_since(compiled_at).unwrap_or_default())
+    }
+
+    /// Returns true if this engine's rules were compiled more than `max_age` ago, or if their age
+    /// is unknown. Embedders can use this to force a refresh of cached engines.
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        self.age().map(|age| age > max_age).unwrap_or(true)
+    }
+
+    /// Performs the lazy initialization work for a sample of `(url, source_url, request_type)`
+    /// requests, such as the ones usually made on startup, ahead of time. This compiles the
+    /// regexes of all filters that are candidates for these requests. For `document` requests,
+    /// cosmetic resources are also computed, filling the cache enabled with
+    /// `set_cosmetic_resources_cache_capacity`, if any.
+    ///
+    /// Intended to be run in the background after loading an engine, so that the first
+    /// navigation doesn't absorb this cost. Requests that fail to parse are ignored.
+    pub fn prewarm(&self, sample_requests: &[(&str, &str, &str)]) {
+        for (url, source_url, request_type) in sample_requests {
+            if let Ok(request) = Request::from_urls(&url, &source_url, &request_type) {
+                self.blocker.prewarm(&request);
+                if request.request_type == RequestType::Document {
+                    self.url_cosmetic_resources(url);
+                }
+            }
+        }
+    }
+
+    /// Records which network filters match a request, as described in `Blocker::record_hits`.
+    /// Meant to be called on a sample of real traffic, with the statistics later passed to
+    /// `reorder_by_hits`. Requests that fail to parse are ignored.
+    pub fn record_hits(&self, url: &str, source_url: &str, request_type: &str, stats: &mut FilterHitStats) {
+        if let Ok(request) = Request::from_urls(&url, &source_url, &request_type) {
+            self.blocker.record_hits(&request, stats);
+        }
+    }
+
+    /// Evaluates the most frequently matching filters first, based on statistics collected with
+    /// `record_hits`. This only affects performance; matching results stay the same. The new
+    /// order is kept when the engine is serialized.
+    pub fn reorder_by_hits(&mut self, stats: &FilterHitStats) {
+        self.blocker_mut().reorder_by_hits(stats);
+    }
+
+    /// Saves `hit_stats` along with the contents of the decision cache, see `WarmState`. Meant to
+    /// be persisted when the client shuts down, next to the serialized engine.
+    pub fn warm_state(&self, hit_stats: &FilterHitStats) -> WarmState {
+        let decisions = self.decision_cache.as_ref()
+            .map(|cache| cache.lock().unwrap().entries().map(|(key, result)| (key.clone(), result.clone())).collect())
+            .unwrap_or_default();
+        WarmState {
+            hit_stats: hit_stats.clone(),
+            fingerprint: self.blocker.fingerprint(),
+            decisions,
+        }
+    }
+
+    /// Resumes from a `WarmState` saved by a previous instance: filters are reordered by its hit
+    /// statistics, as with `reorder_by_hits`, and its decisions are put back into the decision
+    /// cache, replacing its current contents. Decisions are only restored if this engine has the
+    /// same filters, tags, resources and options as the one that made them, and if the decision
+    /// cache is enabled. Returns the number of decisions restored.
+    pub fn restore_warm_state(&mut self, state: &WarmState) -> usize {
+        if state.hit_stats.total() > 0 {
+            self.reorder_by_hits(&state.hit_stats);
+        }
+        self.invalidate_decision_cache();
+        let cache = match self.decision_cache.as_ref() {
+            Some(cache) if !state.decisions.is_empty() && state.fingerprint == self.blocker.fingerprint() => cache,
+            _ => return 0,
+        };
+        let mut cache = cache.lock().unwrap();
+        for (key, result) in state.decisions.iter() {
+            cache.insert(key.clone(), result.clone());
+        }
+        state.decisions.len().min(cache.capacity())
+    }
+
+    /// Iterates over the network filters compiled into this engine, e.g. to audit what it
+    /// enforces. Filters with a `$tag` are only included while their tag is enabled, and filters
+    /// fused by the optimizer are yielded once.
+    pub fn network_filters(&self) -> impl Iterator<Item = CompiledNetworkFilter<'_>> {
+        self.blocker.compiled_filters()
+    }
+
+    /// Iterates over the cosmetic filters compiled into this engine, in a stable order: generic
+    /// filters sorted by selector, then specific filters grouped by hostname hash.
+    pub fn cosmetic_filters(&self) -> impl Iterator<Item = CompiledCosmeticFilter<'_>> {
+        self.cosmetic_cache.compiled_filters().into_iter()
+    }
+
+    /// Optimizes the network filters of this engine, as engines built with optimizations enabled
+    /// already are, and reports what changed. Building an engine without optimizations and then
+    /// calling this shows whether optimizing a set of lists is worth its cost.
+    pub fn optimize(&mut self) -> OptimizationReport {
+        self.blocker_mut().optimize()
+    }
+
     /// Check if a request for a network resource from `url`, of type `request_type`, initiated by
     /// `source_url`, should be blocked.
     pub fn check_network_urls(&self, url: &str, source_url: &str, request_type: &str) -> BlockerResult {
+        self.check_network_urls_with_client_tags(url, source_url, request_type, &[])
+    }
+
+    /// Like `check_network_urls`, for a request made by a client with the given tags. Filters with
+    /// the `$ctag` option only apply to clients with one of their listed tags, and never to clients
+    /// with one of their negated `~tag`s, which allows a single engine to serve different classes
+    /// of devices.
+    pub fn check_network_urls_with_client_tags(&self, url: &str, source_url: &str, request_type: &str, client_tags: &[&str]) -> BlockerResult {
         Request::from_urls(&url, &source_url, &request_type)
-        .map(|request| {
-            self.blocker.check(&request)
+        .map(|mut request| {
+            if !client_tags.is_empty() {
+                request.set_client_tags(client_tags);
+            }
+            self.check_request(&request, false, false)
         })
         .unwrap_or_else(|_e| {
             BlockerResult {
                 matched: false,
                 important: false,
                 redirect: None,
+                redirect_loop: false,
                 exception: None,
                 filter: None,
-                error: Some("Error parsing request".to_owned())
+                error: Some("Error parsing request".to_owned()),
+                exception_id: None,
+                filter_id: None,
             }
         })
     }
 
+    /// Determines whether the frame loading `frame_url` is allowlisted, which is the case if it
+    /// matches a `$document` exception, or if its `parent` frame is allowlisted. `parent` should be
+    /// `None` for top-level documents.
+    ///
+    /// `$subdocument` exceptions only allow the frame itself to be loaded, and don't allowlist it.
+    pub fn frame_state(&self, frame_url: &str, parent: Option<&FrameState>) -> FrameState {
+        if let Some(parent) = parent.filter(|parent| parent.is_allowlisted()) {
+            return parent.clone();
+        }
+        Request::from_urls(frame_url, frame_url, "document")
+            .ok()
+            .and_then(|request| {
+                let request = self.normalize(&request);
+                self.blocker.document_exception(&request)
+            })
+            .map(|exception| FrameState {
+                exception: Some(exception.to_string()),
+                exception_id: Some(exception.id),
+            })
+            .unwrap_or_default()
+    }
+
+    /// Like `check_network_urls`, for a request made by a frame in the given state. Requests from
+    /// allowlisted frames are neither blocked nor redirected, except by `$important` filters, and
+    /// the `$document` exception is reported instead.
+    pub fn check_network_urls_in_frame(&self, url: &str, source_url: &str, request_type: &str, frame: &FrameState) -> BlockerResult {
+        let mut result = self.check_network_urls(url, source_url, request_type);
+        if frame.is_allowlisted() && result.matched && !result.important {
+            result.matched = false;
+            result.redirect = None;
+            result.redirect_loop = false;
+            result.exception = frame.exception.clone();
+            result.exception_id = frame.exception_id;
+        }
+        result
+    }
+
     pub fn check_network_urls_with_hostnames(
         &self,
         url: &str,
@@ -121,7 +614,14 @@ impl Engine {
         third_party_request: Option<bool>
     ) -> BlockerResult {
         let request = Request::from_urls_with_hostname(url, hostname, source_hostname, request_type, third_party_request);
-        self.blocker.check(&request)
+        self.check_request(&request, false, false)
+    }
+
+    /// Like `check_network_urls_with_hostnames`, for a request whose URL was already parsed by the
+    /// caller, e.g. a browser. This skips parsing URLs entirely, which is a notable part of the
+    /// cost of checking a request.
+    pub fn check_network_request_parts(&self, parts: &RequestParts) -> BlockerResult {
+        self.check_request(&Request::from_parts(parts), false, false)
     }
 
     pub fn check_network_urls_with_hostnames_subset(
@@ -135,14 +635,347 @@ impl Engine {
         force_check_exceptions: bool,
     ) -> BlockerResult {
         let request = Request::from_urls_with_hostname(url, hostname, source_hostname, request_type, third_party_request);
-        self.blocker.check_parameterised(&request, previously_matched_rule, force_check_exceptions)
+        self.check_request(&request, previously_matched_rule, force_check_exceptions)
+    }
+
+    pub(crate) fn check_request(&self, request: &Request, matched_rule: bool, force_check_exceptions: bool) -> BlockerResult {
+        self.check_normalized_request(&self.normalize(request), matched_rule, force_check_exceptions)
+    }
+
+    /// Like `check_request`, for a request that already went through `normalize`.
+    pub(crate) fn check_normalized_request(&self, request: &Request, matched_rule: bool, force_check_exceptions: bool) -> BlockerResult {
+        let result = self.network_stage(request, || self.check_request_with_cache(request, matched_rule, force_check_exceptions));
+        if let Some(log) = self.decision_log.as_ref() {
+            log.lock().unwrap().record(request, &result);
+        }
+        result
+    }
+
+    /// Runs the normalization stage of the hooks on `request`, which is only copied if any hooks
+    /// were added. Every method deciding something about a request goes through this first, see
+    /// the `pipeline` module.
+    pub(crate) fn normalize<'a>(&self, request: &'a Request) -> Cow<'a, Request> {
+        if self.hooks.is_empty() {
+            return Cow::Borrowed(request);
+        }
+        let mut request = request.clone();
+        self.hooks.iter().for_each(|hook| hook.normalize(&mut request));
+        Cow::Owned(request)
+    }
+
+    /// Runs the network filter stage of the hooks around `check`, which consults the filters.
+    fn network_stage(&self, request: &Request, check: impl FnOnce() -> BlockerResult) -> BlockerResult {
+        let mut result = match self.hooks.iter().find_map(|hook| hook.before_network(request)) {
+            Some(result) => result,
+            None => check(),
+        };
+        self.hooks.iter().for_each(|hook| hook.after_network(request, &mut result));
+        result
+    }
+
+    fn check_request_with_cache(&self, request: &Request, matched_rule: bool, force_check_exceptions: bool) -> BlockerResult {
+        match self.decision_cache.as_ref() {
+            // Decisions for scheduled tags depend on the time, so they can't be cached
+            Some(cache) if !self.blocker.has_tag_schedules() => {
+                let key = DecisionKey::new(request, self.blocker.domain_option_hashes(), matched_rule, force_check_exceptions);
+                // The cache is only locked for the lookup and insertion, so that other threads
+                // using this engine aren't held up while a miss is being matched
+                if let Some(result) = cache.lock().unwrap().get(&key) {
+                    return result;
+                }
+                let result = self.blocker.check_parameterised(request, matched_rule, force_check_exceptions);
+                cache.lock().unwrap().insert(key, result.clone());
+                result
+            }
+            _ => self.blocker.check_parameterised(request, matched_rule, force_check_exceptions),
+        }
+    }
+
+    /// Starts recording one in every `sample_interval` network request decisions, keeping the
+    /// `capacity` most recent ones. URLs and filters are only recorded as hashes, with URLs keyed
+    /// by the per-install `secret` (see `DecisionLog::generate_secret`). Clones of this engine made
+    /// afterwards record into the same log.
+    pub fn enable_decision_log(&mut self, capacity: usize, sample_interval: u64, secret: [u8; 16]) {
+        self.decision_log = Some(Arc::new(Mutex::new(DecisionLog::new(capacity, sample_interval, secret))));
+    }
+
+    pub fn disable_decision_log(&mut self) {
+        self.decision_log = None;
+    }
+
+    /// Removes and returns the decisions recorded so far, oldest first.
+    pub fn take_decisions(&self) -> Vec<DecisionLogEntry> {
+        self.decision_log.as_ref()
+            .map(|log| {
+                let mut log = log.lock().unwrap();
+                let entries = log.entries().cloned().collect();
+                log.clear();
+                entries
+            })
+            .unwrap_or_default()
+    }
+
+    /// Exports the decisions recorded so far in a compact format, see `DecisionLog::export`.
+    /// Returns `None` if decision logging is not enabled.
+    pub fn export_decision_log(&self) -> Option<Vec<u8>> {
+        self.decision_log.as_ref().and_then(|log| log.lock().unwrap().export().ok())
+    }
+
+    /// Enables caching the decisions of up to `capacity` distinct network requests, to speed up
+    /// pages that make the same requests many times. Requests are identified by their URL, the
+    /// site of the page making them, their type and party, and any client tags. Pages of the same
+    /// site share entries, unless a `$domain` option tells their hostnames apart. Once full, the
+    /// oldest decisions are discarded first. A `capacity` of 0 disables the cache.
+    ///
+    /// The cache is cleared whenever the engine's rules, tags, resources or options change, as
+    /// long as they are changed through the `Engine`. It is bypassed while any tag schedules are
+    /// set. Clones of an engine share its cache until either of them is modified.
+    pub fn set_decision_cache_capacity(&mut self, capacity: usize) {
+        self.decision_cache = if capacity > 0 {
+            Some(Arc::new(Mutex::new(DecisionCache::new(capacity))))
+        } else {
+            None
+        };
+    }
+
+    /// Number of `(hits, misses)` of the cache enabled with `set_decision_cache_capacity` since it
+    /// was last cleared, or `None` if it is disabled.
+    pub fn decision_cache_stats(&self) -> Option<(u64, u64)> {
+        self.decision_cache.as_ref().map(|cache| cache.lock().unwrap().stats())
+    }
+
+    fn invalidate_decision_cache(&mut self) {
+        // As for `invalidate_resources_cache`, unmodified clones keep the shared cache
+        if let Some(capacity) = self.decision_cache.as_ref().map(|cache| cache.lock().unwrap().capacity()) {
+            self.set_decision_cache_capacity(capacity);
+        }
+    }
+
+    /// The network blocker of this engine.
+    pub fn blocker(&self) -> &Blocker {
+        &self.blocker
+    }
+
+    /// The blocker, copied first if it is shared with clones of this engine. Any cached decisions
+    /// are discarded, since they may no longer apply once the blocker is modified.
+    fn blocker_mut(&mut self) -> &mut Blocker {
+        self.invalidate_decision_cache();
+        Arc::make_mut(&mut self.blocker)
+    }
+
+    /// Sets how requests with schemes other than `http`, `https`, `ws`, `wss` and `ftp` are
+    /// handled. By default, they are never blocked.
+    pub fn set_unknown_scheme_policy(&mut self, policy: UnknownSchemePolicy) {
+        self.blocker_mut().set_unknown_scheme_policy(policy);
+    }
+
+    /// Check if DNS lookups for `hostname` should be blocked.
+    ///
+    /// Only filters that apply to entire hostnames, like `||ads.example.com^`, are evaluated.
+    /// Filters that depend on the path, request type, party or initiating page are ignored, as
+    /// they cannot be decided from a hostname alone.
+    pub fn check_hostname(&self, hostname: &str) -> BlockerResult {
+        self.check_hostname_with_client_tags(hostname, &[])
+    }
+
+    /// Like `check_hostname`, for a lookup made by a client with the given tags, which are
+    /// matched against the `$ctag` option of filters.
+    pub fn check_hostname_with_client_tags(&self, hostname: &str, client_tags: &[&str]) -> BlockerResult {
+        let hostname = hostname.trim_end_matches('.');
+        Request::from_hostname(hostname)
+        .map(|mut request| {
+            if !client_tags.is_empty() {
+                request.set_client_tags(client_tags);
+            }
+            let request = self.normalize(&request);
+            self.network_stage(&request, || self.blocker.check_hostname(&request))
+        })
+        .unwrap_or_else(|_e| {
+            BlockerResult {
+                matched: false,
+                important: false,
+                redirect: None,
+                redirect_loop: false,
+                exception: None,
+                filter: None,
+                error: Some("Error parsing hostname".to_owned()),
+                exception_id: None,
+                filter_id: None,
+            }
+        })
+    }
+
+    /// Returns `url` without the query parameters removed by `$removeparam` filters, e.g. for
+    /// tracking parameters like `utm_source`, or `None` if it should be loaded unchanged. See
+    /// `Blocker::filter_url_params`. If enabled with `set_tracking_param_stripping`, the
+    /// well-known parameters of `tracking_params::TRACKING_PARAMS` are removed as well.
+    ///
+    /// Hooks are not run, as the returned URL is derived from `url` itself rather than from a
+    /// normalized request.
+    pub fn filter_url_params(&self, url: &str, source_url: &str, request_type: &str) -> Option<String> {
+        let filtered = Request::from_urls(url, source_url, request_type)
+            .ok()
+            .and_then(|request| self.blocker.filter_url_params(&request, url));
+        if !self.strip_tracking_params {
+            return filtered;
+        }
+        match filtered {
+            Some(filtered) => Some(tracking_params::strip_tracking_params(&filtered)),
+            None => tracking_params::strip(url),
+        }
+    }
+
+    /// Enables or disables removing well-known tracking parameters in `filter_url_params`,
+    /// regardless of any `$removeparam` filters. Disabled by default.
+    pub fn set_tracking_param_stripping(&mut self, enabled: bool) {
+        self.strip_tracking_params = enabled;
+    }
+
+    /// Decides what to do with the `Referer` header of a request for `url`, of type
+    /// `request_type`, initiated by `source_url`. A matching `$removeheader=referer` rule removes
+    /// the header; otherwise, the embedder's `ReferrerPolicy` applies. Requests that fail to parse
+    /// keep their header.
+    pub fn referrer_decision(&self, url: &str, source_url: &str, request_type: &str) -> ReferrerDecision {
+        let request = match Request::from_urls(url, source_url, request_type) {
+            Ok(request) => request,
+            Err(_) => return ReferrerDecision::default(),
+        };
+        let request = self.normalize(&request);
+        let removal = self.blocker.check_removeheaders(&request)
+            .into_iter()
+            .find(|filter| filter.removeheader.as_deref().map_or(false, referrer::is_referrer_header));
+        let mut decision = match removal {
+            Some(filter) => ReferrerDecision {
+                action: ReferrerAction::Remove,
+                filter: Some(filter.to_string()),
+                filter_id: Some(filter.id),
+            },
+            None => ReferrerDecision {
+                action: if request.is_third_party == Some(true) {
+                    self.referrer_policy.third_party
+                } else {
+                    self.referrer_policy.first_party
+                },
+                ..Default::default()
+            },
+        };
+        self.hooks.iter().for_each(|hook| hook.after_headers(&request, &mut decision));
+        decision
+    }
+
+    /// Adds a hook run at each stage of this engine's decisions, after any hooks added before it.
+    /// See the `pipeline` module. Clones of this engine made afterwards share the hook.
+    pub fn add_hook(&mut self, hook: Arc<dyn DecisionHook>) {
+        self.hooks.push(hook);
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.hooks.clear();
+    }
+
+    /// Sets the embedder's policy for `Referer` headers, used by `referrer_decision` for requests
+    /// without a matching `$removeheader` rule.
+    pub fn set_referrer_policy(&mut self, policy: ReferrerPolicy) {
+        self.referrer_policy = policy;
+    }
+
+    /// Checks a DNS query for records of type `record_type`, like `A` or `AAAA`, against filters for
+    /// entire hostnames, including the `$dnstype` and `$dnsrewrite` options of AdGuard Home lists.
+    /// These options are only parsed by a `FilterSet` configured with
+    /// `ParseOptions::dns_modifiers`.
+    pub fn check_dns_query(&self, hostname: &str, record_type: &str) -> DnsQueryResult {
+        let hostname = hostname.trim_end_matches('.');
+        match Request::from_hostname(hostname) {
+            Ok(mut request) => {
+                request.set_dns_type(record_type);
+                let request = self.normalize(&request);
+                let rewrites = self.blocker.check_dns_rewrites(&request);
+                let blocker_result = self.network_stage(&request, || self.blocker.check_hostname(&request));
+                DnsQueryResult {
+                    blocker_result,
+                    rewrites: rewrites.iter().filter_map(|filter| filter.dns_rewrite.clone()).collect(),
+                    rewrite_filter_ids: rewrites.iter().map(|filter| filter.id).collect(),
+                }
+            }
+            Err(_) => DnsQueryResult {
+                blocker_result: BlockerResult {
+                    error: Some("Error parsing hostname".to_owned()),
+                    ..BlockerResult::default()
+                },
+                rewrites: vec![],
+                rewrite_filter_ids: vec![],
+            },
+        }
+    }
+
+    /// Decides whether a page may open a popup, by checking the target URL against `$popup`
+    /// filters, then applying `heuristics`. A matching `$popup` exception allows the popup
+    /// regardless of the heuristics. Targets that cannot be parsed, like `about:blank`, are only
+    /// subject to the heuristics.
+    pub fn check_popup(&self, popup: &PopupRequest, heuristics: &PopupHeuristics) -> PopupDecision {
+        self.popup_decision(popup, heuristics, None)
+    }
+
+    /// Like `check_popup`, but also enforces `PopupHeuristics::max_popups_per_window` for the
+    /// opener. Popups allowed by the heuristics are recorded in `tracker` as opened at `now`.
+    pub fn check_popup_with_tracker(&self, popup: &PopupRequest, heuristics: &PopupHeuristics, tracker: &mut PopupTracker, now: Instant) -> PopupDecision {
+        self.popup_decision(popup, heuristics, Some((tracker, now)))
+    }
+
+    fn popup_decision(&self, popup: &PopupRequest, heuristics: &PopupHeuristics, tracker: Option<(&mut PopupTracker, Instant)>) -> PopupDecision {
+        if let Ok(request) = Request::from_urls(popup.target_url, popup.opener_url, "popup") {
+            let request = self.normalize(&request);
+            let result = self.blocker.check_popup(&request);
+            if result.exception_id.is_some() {
+                return PopupDecision::Allow;
+            }
+            if let Some(filter_id) = result.filter_id {
+                return PopupDecision::BlockedByFilter { filter: result.filter, filter_id };
+            }
+        }
+
+        if heuristics.require_user_gesture && !popup.user_gesture {
+            PopupDecision::NoUserGesture
+        } else if heuristics.max_tabs_per_opener.map_or(false, |max| popup.opener_tab_count >= max) {
+            PopupDecision::TooManyTabs
+        } else {
+            match (tracker, heuristics.max_popups_per_window) {
+                (Some((tracker, now)), Some(max)) => {
+                    if tracker.try_open(popup.opener_url, max, heuristics.rate_window, now) {
+                        PopupDecision::Allow
+                    } else {
+                        PopupDecision::RateLimited
+                    }
+                }
+                _ => PopupDecision::Allow,
+            }
+        }
+    }
+
+    /// Returns the list locations that a rule was loaded from, if they were recorded using
+    /// `FilterSet::add_filter_list_with_id` in debug mode. `filter` can also be a combination of
+    /// rules merged by optimization, as returned in `BlockerResult::filter`.
+    pub fn filter_sources(&self, filter: &str) -> Vec<&FilterSource> {
+        let mut rules = filter.split(" <+> ").map(str::trim).collect::<Vec<_>>();
+        rules.sort_unstable();
+        rules.dedup();
+
+        rules
+            .into_iter()
+            .flat_map(|rule| self.filter_sources.get(rule).into_iter().flatten())
+            .collect()
+    }
+
+    /// Builds an index of the network filters specific to each hostname, e.g. to list the rules
+    /// affecting a site. The index doesn't reflect later changes to this engine, like enabled tags.
+    pub fn hostname_index(&self) -> HostnameIndex {
+        HostnameIndex::new(&self.blocker)
     }
 
     /// Check if a given filter has been previously added to this `Engine`.
     ///
     /// Note that only network filters are currently supported by this method.
     pub fn filter_exists(&self, filter: &str) -> bool {
-        use crate::filters::network::NetworkFilter;
         let filter_parsed = NetworkFilter::parse(filter, false);
         match filter_parsed.map(|f| self.blocker.filter_exists(&f)) {
             Ok(exists) => exists,
@@ -154,28 +987,129 @@ impl Engine {
         }
     }
 
+    /// Adds a single rule to this engine, e.g. one created by the user, without rebuilding it. The
+    /// rule is indexed like any other, but never optimized, and keeps its text as in debug mode.
+    /// `$badfilter` rules can't be added this way, and privileged scriptlets are rejected as in
+    /// `FilterSet::add_filter`.
+    pub fn add_filter(&mut self, filter: &str, format: FilterFormat) -> Result<(), FilterUpdateError> {
+        let mut filter_set = FilterSet::new(true);
+        filter_set.add_filter(filter, format)?;
+        self.update_filters(filter_set, false).pop().unwrap_or(Ok(()))
+    }
+
+    /// Removes a single rule from this engine, whether it was added with `add_filter` or part of
+    /// the lists it was built from. Fails with `BlockerError::FilterNotFound` for rules fused with
+    /// others by the optimizer, and for generic cosmetic exceptions like `#@#.ad`.
+    pub fn remove_filter(&mut self, filter: &str, format: FilterFormat) -> Result<(), FilterUpdateError> {
+        let mut filter_set = FilterSet::new(true);
+        filter_set.add_filter(filter, format)?;
+        self.update_filters(filter_set, true).pop().unwrap_or(Ok(()))
+    }
+
+    /// Adds every rule of `filter_list` as with `add_filter`, returning how many were added.
+    /// Rules that can't be parsed or added are skipped.
+    pub fn add_filter_list(&mut self, filter_list: &str, format: FilterFormat) -> usize {
+        let mut filter_set = FilterSet::new(true);
+        filter_set.add_filter_list(filter_list, format);
+        self.update_filters(filter_set, false).iter().filter(|result| result.is_ok()).count()
+    }
+
+    /// Removes every rule of `filter_list` as with `remove_filter`, returning how many were
+    /// removed.
+    pub fn remove_filter_list(&mut self, filter_list: &str, format: FilterFormat) -> usize {
+        let mut filter_set = FilterSet::new(true);
+        filter_set.add_filter_list(filter_list, format);
+        self.update_filters(filter_set, true).iter().filter(|result| result.is_ok()).count()
+    }
+
+    /// Adds or removes the rules of `filter_set` in place, returning the outcome for each rule.
+    fn update_filters(&mut self, filter_set: FilterSet, remove: bool) -> Vec<Result<(), FilterUpdateError>> {
+        let mut results = Vec::new();
+        if !filter_set.network_filters.is_empty() || !filter_set.popup_filters.is_empty() {
+            let blocker = self.blocker_mut();
+            for filter in filter_set.network_filters {
+                let result = if remove { blocker.remove_filter(&filter) } else { blocker.add_filter(filter) };
+                results.push(result.map_err(FilterUpdateError::from));
+            }
+            for filter in filter_set.popup_filters {
+                let result = if remove { blocker.remove_popup_filter(&filter) } else { blocker.add_popup_filter(filter) };
+                results.push(result.map_err(FilterUpdateError::from));
+            }
+        }
+        if !filter_set.cosmetic_filters.is_empty() {
+            let cosmetic_cache = Arc::make_mut(&mut self.cosmetic_cache);
+            for filter in filter_set.cosmetic_filters {
+                if !remove {
+                    cosmetic_cache.add_filter(filter);
+                    results.push(Ok(()));
+                } else if cosmetic_cache.remove_filter(&filter) {
+                    results.push(Ok(()));
+                } else {
+                    results.push(Err(BlockerError::FilterNotFound.into()));
+                }
+            }
+            self.invalidate_resources_cache();
+        }
+        results
+    }
+
     /// Sets this engine's tags to be _only_ the ones provided in `tags`.
     ///
     /// Tags can be used to cheaply enable or disable network rules with a corresponding `$tag`
-    /// option.
+    /// option, as well as the rules of lists added with `FilterSet::add_tagged_filter_list`.
     pub fn use_tags(&mut self, tags: &[&str]) {
-        self.blocker.use_tags(tags);
+        self.blocker_mut().use_tags(tags);
+        self.sync_cosmetic_tags();
     }
 
     /// Sets this engine's tags to additionally include the ones provided in `tags`.
     ///
     /// Tags can be used to cheaply enable or disable network rules with a corresponding `$tag`
-    /// option.
+    /// option, as well as the rules of lists added with `FilterSet::add_tagged_filter_list`.
     pub fn enable_tags(&mut self, tags: &[&str]) {
-        self.blocker.enable_tags(tags);
+        self.blocker_mut().enable_tags(tags);
+        self.sync_cosmetic_tags();
     }
 
     /// Sets this engine's tags to no longer include the ones provided in `tags`.
     ///
     /// Tags can be used to cheaply enable or disable network rules with a corresponding `$tag`
-    /// option.
+    /// option, as well as the rules of lists added with `FilterSet::add_tagged_filter_list`.
     pub fn disable_tags(&mut self, tags: &[&str]) {
-        self.blocker.disable_tags(tags);
+        self.blocker_mut().disable_tags(tags);
+        self.sync_cosmetic_tags();
+    }
+
+    /// Enables the tagged cosmetic rules of the blocker's tags, and disables all others.
+    fn sync_cosmetic_tags(&mut self) {
+        if self.cosmetic_cache.tagged_rules.is_empty() {
+            return;
+        }
+        let tags_enabled: HashSet<String> = self.blocker.tags_enabled().into_iter().collect();
+        if tags_enabled != self.cosmetic_cache.tags_enabled {
+            Arc::make_mut(&mut self.cosmetic_cache).use_tags(&tags_enabled);
+            self.invalidate_resources_cache();
+        }
+    }
+
+    /// Restricts the network rules with the given `$tag` to apply only while `schedule` is active,
+    /// according to the clock set with `set_clock`. The tag is enabled if it wasn't already;
+    /// disabling it later overrides the schedule.
+    ///
+    /// Scheduled tags never apply while no clock is set.
+    pub fn set_tag_schedule(&mut self, tag: &str, schedule: Schedule) {
+        self.enable_tags(&[tag]);
+        self.blocker_mut().set_tag_schedule(tag, schedule);
+    }
+
+    /// Removes the schedule of the given tag, so that its rules apply whenever it is enabled.
+    pub fn remove_tag_schedule(&mut self, tag: &str) {
+        self.blocker_mut().remove_tag_schedule(tag);
+    }
+
+    /// Sets the clock consulted to decide whether scheduled tags currently apply.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.blocker_mut().set_clock(clock);
     }
 
     /// Checks if a given tag exists in this engine.
@@ -186,16 +1120,33 @@ impl Engine {
         self.blocker.tags_enabled().contains(&tag.to_owned())
     }
 
-    /// Sets this engine's resources to be _only_ the ones provided in `resources`.
-    pub fn use_resources(&mut self, resources: &[Resource]) {
-        self.blocker.use_resources(resources);
-        self.cosmetic_cache.use_resources(resources);
+    /// Sets this engine's resources to be _only_ the ones provided in `resources`. Resources that
+    /// cannot be added, e.g. because their content does not match their MIME type, are left out
+    /// and returned, once each; all others are used.
+    pub fn use_resources(&mut self, resources: &[Resource]) -> Vec<RejectedResource> {
+        let mut rejected = self.blocker_mut().use_resources(resources);
+        for scriptlet in Arc::make_mut(&mut self.cosmetic_cache).use_resources(resources) {
+            if !rejected.iter().any(|r| r.name == scriptlet.name) {
+                rejected.push(scriptlet);
+            }
+        }
+        self.invalidate_resources_cache();
+        rejected
+    }
+
+    /// Sets this engine's resources to be the ones in `resources`, sharing them in memory with
+    /// other engines using the same `SharedResources`.
+    pub fn use_shared_resources(&mut self, resources: &SharedResources) {
+        self.blocker_mut().use_shared_resources(resources.redirects.clone());
+        Arc::make_mut(&mut self.cosmetic_cache).use_shared_resources(resources.scriptlets.clone());
+        self.invalidate_resources_cache();
     }
 
     /// Sets this engine's resources to additionally include `resource`.
     pub fn add_resource(&mut self, resource: Resource) -> Result<(), crate::resources::AddResourceError> {
-        self.blocker.add_resource(&resource)?;
-        self.cosmetic_cache.add_resource(&resource)?;
+        self.blocker_mut().add_resource(&resource)?;
+        Arc::make_mut(&mut self.cosmetic_cache).add_resource(&resource)?;
+        self.invalidate_resources_cache();
         Ok(())
     }
 
@@ -221,22 +1172,163 @@ impl Engine {
     /// `hidden_class_id_selectors` to obtain any stylesheets consisting of generic rules (if the
     /// returned `generichide` value is false).
     pub fn url_cosmetic_resources(&self, url: &str) -> UrlSpecificResources {
+        self.url_cosmetic_resources_with_parent(url, None)
+    }
+
+    /// Like `url_cosmetic_resources`, for a frame that may inherit the origin of its parent.
+    /// Frames loading `about:blank` or `about:srcdoc`, such as sandboxed same-origin frames, get
+    /// the resources of `parent_url`, which should be the URL of the closest ancestor frame that
+    /// doesn't itself have such a URL (often the top-level page). Other URLs are resolved as usual,
+    /// ignoring `parent_url`.
+    pub fn url_cosmetic_resources_with_parent(&self, url: &str, parent_url: Option<&str>) -> UrlSpecificResources {
+        let url = match parent_url {
+            Some(parent_url) if inherits_parent_origin(url) => parent_url,
+            _ => url,
+        };
         let request = Request::from_url(url);
         if request.is_err() {
             return UrlSpecificResources::empty();
         }
         let request = request.unwrap();
+        let request = self.normalize(&request);
 
-        let generichide = self.blocker.check_generic_hide(&request);
-        self.cosmetic_cache.hostname_cosmetic_resources(&request.hostname, generichide)
+        let mut resources = self.hostname_cosmetic_resources(&request);
+        self.hooks.iter().for_each(|hook| hook.after_cosmetic(&request, &mut resources));
+        resources
     }
+
+    /// The cosmetic resources of `request`'s hostname, going through the cache if enabled.
+    fn hostname_cosmetic_resources(&self, request: &Request) -> UrlSpecificResources {
+        let policy = self.blocker.cosmetic_policy(request);
+
+        if let Some(cache) = self.resources_cache.as_ref() {
+            if let Some(resources) = cache.lock().unwrap().get(&request.hostname, &policy) {
+                return resources;
+            }
+        }
+
+        let resources = self.cosmetic_cache.hostname_cosmetic_resources_with_policy(&request.hostname, &policy);
+
+        if let Some(cache) = self.resources_cache.as_ref() {
+            cache.lock().unwrap().insert(&request.hostname, &policy, resources.clone());
+        }
+
+        resources
+    }
+
+    /// Enables caching of the results of `url_cosmetic_resources` for up to `capacity` sites, so
+    /// that repeated navigations to the same site skip recomputing its selectors. Results are
+    /// cached per hostname and cosmetic policy (e.g. whether `$generichide` applies), and the
+    /// least recently used ones are discarded first. A `capacity` of 0 disables the cache.
+    ///
+    /// The cache is cleared whenever the engine's rules or resources change. Clones of an engine
+    /// share its cache until either of them is modified.
+    pub fn set_cosmetic_resources_cache_capacity(&mut self, capacity: usize) {
+        self.resources_cache = if capacity > 0 {
+            Some(Arc::new(Mutex::new(ResourcesLruCache::new(capacity))))
+        } else {
+            None
+        };
+    }
+
+    fn invalidate_resources_cache(&mut self) {
+        // A new cache is created rather than clearing the shared one, which may still be used by
+        // unmodified clones of this engine
+        if let Some(capacity) = self.resources_cache.as_ref().map(|cache| cache.lock().unwrap().capacity()) {
+            self.set_cosmetic_resources_cache_capacity(capacity);
+        }
+    }
+
+    /// Whether any cosmetic rules may apply on pages of `hostname`, so that embedders can skip
+    /// injecting content scripts entirely on other sites. This is answered from the hostname index
+    /// of the cosmetic rules, without evaluating any of them.
+    ///
+    /// Generic rules may apply on every site, so this is always `true` while any are loaded.
+    /// Exceptions, such as `$generichide` or `#@#` rules, are not taken into account, so the
+    /// answer may be `true` for sites where they end up disabling every rule.
+    pub fn has_cosmetic_filters_for(&self, hostname: &str) -> bool {
+        self.cosmetic_cache.has_generic_rules() || self.cosmetic_cache.has_specific_rules_for(hostname)
+    }
+
+    /// Returns which kinds of cosmetic filtering are allowed on a particular url, according to any
+    /// `$generichide`, `$specifichide`, `$elemhide` or `$document` exceptions that apply to it.
+    pub fn cosmetic_policy(&self, url: &str) -> CosmeticPolicy {
+        match Request::from_url(url) {
+            Ok(request) => {
+                let request = self.normalize(&request);
+                self.blocker.cosmetic_policy(&request)
+            }
+            Err(_) => CosmeticPolicy::default(),
+        }
+    }
+
+    /// Returns the scriptlets to inject and page-level flags for a particular url. Unlike
+    /// `url_cosmetic_resources`, this does not gather any CSS selectors, so it is cheap enough to
+    /// call before a page starts loading, e.g. to decide whether JavaScript should be enabled.
+    pub fn scriptlets_and_flags(&self, url: &str) -> ScriptletsAndFlags {
+        let request = match Request::from_urls(url, url, "document") {
+            Ok(request) => request,
+            Err(_) => return ScriptletsAndFlags::default(),
+        };
+        let request = self.normalize(&request);
+
+        let policy = self.blocker.cosmetic_policy(&request);
+        if !policy.scriptlets_allowed {
+            return ScriptletsAndFlags { generichide: true, ..Default::default() };
+        }
+
+        let generichide = !policy.generic_hide_allowed;
+        let mut result = self.cosmetic_cache.hostname_scriptlets_and_flags(&request.hostname, generichide);
+
+        if !result.disable_scripting {
+            result.disable_scripting = self.blocker.get_csp_directives(&request)
+                .map(|csp| csp_disables_scripting(&csp))
+                .unwrap_or(false);
+        }
+
+        result
+    }
+}
+
+/// Checks whether any of the policies in a `Content-Security-Policy` header value disallow all
+/// scripts.
+fn csp_disables_scripting(csp: &str) -> bool {
+    csp.split(|c| c == ',' || c == ';')
+        .map(|directive| directive.split_whitespace().collect::<Vec<_>>())
+        .any(|directive| directive == ["script-src", "'none'"])
 }
 
 
+/// Whether a frame with the given URL has the origin of its parent, rather than one of its own.
+fn inherits_parent_origin(url: &str) -> bool {
+    let without_fragment = url.split('#').next().unwrap_or_default();
+    let without_query = without_fragment.split('?').next().unwrap_or_default();
+    without_query.eq_ignore_ascii_case("about:blank") || without_query.eq_ignore_ascii_case("about:srcdoc")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::resources::{ResourceType, MimeType};
+    use crate::resources::{AddResourceError, ResourceType, MimeType};
+    use crate::filters::network::ParseOptions;
+
+    #[test]
+    fn builds_off_thread() {
+        fn assert_send<T: Send>() {}
+        assert_send::<FilterSet>();
+        assert_send::<LoadReport>();
+        assert_send::<Engine>();
+
+        let filter_set = std::thread::spawn(|| {
+            let mut filter_set = FilterSet::new(true);
+            filter_set.add_filters(&["||ads.com^".to_string(), "example.com##.ad".to_string()], FilterFormat::Standard);
+            filter_set
+        }).join().unwrap();
+        let engine = std::thread::spawn(move || Engine::from_filter_set(filter_set, true)).join().unwrap();
+
+        assert!(engine.check_network_urls("https://ads.com/pixel.png", "https://example.com", "image").matched);
+        assert!(engine.url_cosmetic_resources("https://example.com").hide_selectors.contains(".ad"));
+    }
 
     #[test]
     fn tags_enable_adds_tags() {
@@ -296,6 +1388,294 @@ mod tests {
         });
     }
 
+    #[test]
+    fn scheduled_tags() {
+        use crate::schedule::{LocalTime, ScheduleWindow};
+
+        struct TestClock(Mutex<LocalTime>);
+        impl Clock for TestClock {
+            fn now(&self) -> LocalTime {
+                *self.0.lock().unwrap()
+            }
+        }
+
+        let filters = vec![
+            String::from("||social.com^$tag=school-hours"),
+            String::from("||video.com^$removeparam=t,tag=school-hours"),
+            String::from("@@||classroom.com^$document,tag=school-hours"),
+            String::from("||ads.com^"),
+        ];
+        let mut engine = Engine::from_rules(&filters, FilterFormat::Standard);
+        let schedule = Schedule::new().with_window(ScheduleWindow::WEEKDAYS, (8, 0), (15, 0));
+        engine.set_tag_schedule("school-hours", schedule);
+        assert!(engine.tag_exists("school-hours"));
+
+        let blocked = |engine: &Engine, url: &str| engine.check_network_urls(url, "", "").matched;
+
+        // No clock yet
+        assert!(!blocked(&engine, "https://social.com/feed"));
+        assert!(blocked(&engine, "https://ads.com/ad.js"));
+
+        let clock = Arc::new(TestClock(Mutex::new(LocalTime::new(1, 9, 30))));
+        engine.set_clock(clock.clone());
+        assert!(blocked(&engine, "https://social.com/feed"));
+        assert!(engine.check_hostname("social.com").matched);
+        assert!(engine.check_dns_query("social.com", "A").blocker_result.matched);
+        assert_eq!(engine.filter_url_params("https://video.com/?t=60", "", "document"), Some(String::from("https://video.com/")));
+        assert!(!engine.cosmetic_policy("https://classroom.com/").scriptlets_allowed);
+
+        *clock.0.lock().unwrap() = LocalTime::new(1, 16, 0);
+        assert!(!blocked(&engine, "https://social.com/feed"));
+        assert!(!engine.check_hostname("social.com").matched);
+        assert!(!engine.check_dns_query("social.com", "A").blocker_result.matched);
+        assert_eq!(engine.filter_url_params("https://video.com/?t=60", "", "document"), None);
+        assert!(engine.cosmetic_policy("https://classroom.com/").scriptlets_allowed);
+        assert!(blocked(&engine, "https://ads.com/ad.js"));
+
+        engine.remove_tag_schedule("school-hours");
+        assert!(blocked(&engine, "https://social.com/feed"));
+    }
+
+    #[test]
+    fn frames_inherit_parent_resources() {
+        let engine = Engine::from_rules(&["example.com##.ad".to_string()], FilterFormat::Standard);
+        let hides_ad = |url, parent_url| engine.url_cosmetic_resources_with_parent(url, parent_url).hide_selectors.contains(".ad");
+
+        assert!(hides_ad("about:blank", Some("https://www.example.com/page")));
+        assert!(hides_ad("about:srcdoc", Some("https://example.com")));
+        assert!(hides_ad("about:blank#frame", Some("https://example.com")));
+        assert!(!hides_ad("about:blank", None));
+        assert!(!hides_ad("about:blank", Some("https://other.com")));
+        // Frames with URLs of their own are unaffected by their parent
+        assert!(!hides_ad("https://other.com/frame", Some("https://example.com")));
+        assert!(hides_ad("https://example.com/frame", Some("https://other.com")));
+    }
+
+    #[test]
+    fn has_cosmetic_filters_for() {
+        let engine = Engine::from_rules(&[
+            "example.com##.ad".to_string(),
+            "google.*##+js(set-constant, ads, false)".to_string(),
+            "other.com#@#.ad".to_string(),
+            "||ads.com^".to_string(),
+        ], FilterFormat::Standard);
+        assert!(engine.has_cosmetic_filters_for("example.com"));
+        assert!(engine.has_cosmetic_filters_for("www.example.com"));
+        assert!(engine.has_cosmetic_filters_for("www.google.de"));
+        assert!(!engine.has_cosmetic_filters_for("other.com"));
+        assert!(!engine.has_cosmetic_filters_for("ads.com"));
+
+        let engine = Engine::from_rules(&["##.banner".to_string()], FilterFormat::Standard);
+        assert!(engine.has_cosmetic_filters_for("ads.com"));
+    }
+
+    #[test]
+    fn compiled_filters() {
+        use crate::compiled_filters::NetworkFilterRole;
+
+        let mut engine = Engine::from_rules(&[
+            "||ads.com^$script".to_owned(),
+            "@@||ads.com/ok.js".to_owned(),
+            "||tracker.net^$tag=trackers".to_owned(),
+            "example.com,~sub.example.com##.ad".to_owned(),
+            "example.com##+js(nobab)".to_owned(),
+            "example.com##div:has-text(Sponsored):remove()".to_owned(),
+            "###banner".to_owned(),
+        ], FilterFormat::Standard);
+
+        let network: Vec<_> = engine.network_filters().map(|filter| (filter.role, filter.canonical_text())).collect();
+        assert_eq!(network, vec![
+            // `^` at the end of a hostname compiles to a right anchor
+            (NetworkFilterRole::Block, "||ads.com|$script".to_owned()),
+            (NetworkFilterRole::Exception, "@@||ads.com/ok.js".to_owned()),
+        ]);
+        assert!(engine.network_filters().all(|filter| filter.raw_text().is_none()));
+
+        engine.enable_tags(&["trackers"]);
+        assert!(engine.network_filters().any(|filter| filter.role == NetworkFilterRole::Tagged));
+
+        let cosmetic: Vec<_> = engine.cosmetic_filters().map(|filter| (filter.hostname_hash, filter.canonical_text())).collect();
+        let example = crate::utils::fast_hash("example.com");
+        assert_eq!(cosmetic[0], (None, "###banner".to_owned()));
+        assert!(cosmetic.contains(&(Some(example), "##.ad".to_owned())));
+        assert!(cosmetic.contains(&(Some(example), "##+js(nobab)".to_owned())));
+        assert!(cosmetic.contains(&(Some(example), "##div:has-text(Sponsored):remove()".to_owned())));
+        assert!(cosmetic.contains(&(Some(crate::utils::fast_hash("sub.example.com")), "#@#.ad".to_owned())));
+    }
+
+    #[test]
+    fn optimization_report() {
+        let mut engine = Engine::from_rules_parametrised(&[
+            "/static/ad-".to_owned(),
+            "/static/ad.".to_owned(),
+            "||tracker.net^".to_owned(),
+        ], FilterFormat::Standard, false, false);
+
+        let report = engine.optimize();
+        assert_eq!(report.filters_before, 3);
+        assert_eq!(report.filters_after, 2);
+        assert_eq!(report.filters_fused, 2);
+        assert_eq!(report.largest_bucket_before, 2);
+        assert_eq!(report.largest_bucket_after, 1);
+        assert!(engine.check_network_urls("https://example.com/static/ad.js", "https://example.com", "script").matched);
+    }
+
+    #[test]
+    fn decision_cache() {
+        let mut engine = Engine::from_rules_debug(&[
+            "||tracker.com/pixel^".to_string(),
+            "@@||tracker.com/pixel^$domain=sub.site.com".to_string(),
+            "||tracker.com/beacon^$tag=strict".to_string(),
+        ], FilterFormat::Standard);
+        engine.set_decision_cache_capacity(2);
+
+        for _ in 0..3 {
+            assert!(engine.check_network_urls("https://tracker.com/pixel", "https://www.site.com", "image").matched);
+        }
+        assert_eq!(engine.decision_cache_stats(), Some((2, 1)));
+
+        // Subdomains of the same site are cached separately
+        let excepted = engine.check_network_urls("https://tracker.com/pixel", "https://sub.site.com", "image");
+        assert!(excepted.exception.is_some());
+        assert!(engine.check_network_urls("https://tracker.com/pixel", "https://sub.site.com", "script").exception.is_some());
+        assert_eq!(engine.decision_cache_stats(), Some((2, 3)));
+
+        // The oldest decision was discarded
+        assert!(engine.check_network_urls("https://tracker.com/pixel", "https://www.site.com", "image").matched);
+        assert_eq!(engine.decision_cache_stats(), Some((2, 4)));
+
+        // Other pages of the site share its entries
+        assert!(engine.check_network_urls("https://tracker.com/pixel", "https://cdn.site.com", "image").matched);
+        assert_eq!(engine.decision_cache_stats(), Some((3, 4)));
+
+        // Changing tags clears the cache
+        assert!(!engine.check_network_urls("https://tracker.com/beacon", "https://www.site.com", "ping").matched);
+        engine.enable_tags(&["strict"]);
+        assert_eq!(engine.decision_cache_stats(), Some((0, 0)));
+        assert!(engine.check_network_urls("https://tracker.com/beacon", "https://www.site.com", "ping").matched);
+
+        engine.set_decision_cache_capacity(0);
+        assert_eq!(engine.decision_cache_stats(), None);
+    }
+
+    #[test]
+    fn decision_cache_tagged_domains() {
+        let mut engine = Engine::from_rules_debug(&[
+            "||tracker.com/pixel^".to_string(),
+            "@@||tracker.com/pixel^$domain=sub.site.com,tag=relaxed".to_string(),
+        ], FilterFormat::Standard);
+        engine.set_decision_cache_capacity(8);
+        assert!(engine.check_network_urls("https://tracker.com/pixel", "https://www.site.com", "image").matched);
+
+        // Domains of tagged filters are known before their tag is enabled, so they are not
+        // computed again
+        engine.enable_tags(&["relaxed"]);
+        assert!(engine.blocker.domain_option_hashes.get().is_some());
+        assert!(engine.check_network_urls("https://tracker.com/pixel", "https://www.site.com", "image").matched);
+        assert!(engine.check_network_urls("https://tracker.com/pixel", "https://sub.site.com", "image").exception.is_some());
+
+        // Domains of added filters tell their hostnames apart
+        engine.add_filter("@@||tracker.com/pixel^$domain=cdn.site.com", FilterFormat::Standard).unwrap();
+        assert!(engine.blocker.domain_option_hashes.get().is_none());
+        assert!(engine.check_network_urls("https://tracker.com/pixel", "https://www.site.com", "image").matched);
+        assert!(engine.check_network_urls("https://tracker.com/pixel", "https://cdn.site.com", "image").exception.is_some());
+    }
+
+    #[test]
+    fn warm_state() {
+        let rules = [
+            "||tracker.com/pixel^".to_string(),
+            "/ads/*".to_string(),
+            "||ads.com/ads/$tag=strict".to_string(),
+        ];
+        let mut engine = Engine::from_rules(&rules, FilterFormat::Standard);
+        engine.set_decision_cache_capacity(8);
+        let mut stats = FilterHitStats::new();
+        for url in ["https://tracker.com/pixel", "https://example.com/ads/1.js", "https://example.com/page"].iter() {
+            engine.check_network_urls(url, "https://www.site.com", "image");
+            engine.record_hits(url, "https://www.site.com", "image", &mut stats);
+        }
+        let state = WarmState::deserialize(&engine.warm_state(&stats).serialize().unwrap()).unwrap();
+        assert_eq!(state.decision_count(), 3);
+        assert_eq!(state.hit_stats.total(), 2);
+
+        // A restarted client with the same rules resumes with warm caches
+        let mut restarted = Engine::from_rules(&rules, FilterFormat::Standard);
+        restarted.set_decision_cache_capacity(8);
+        assert_eq!(restarted.restore_warm_state(&state), 3);
+        assert!(restarted.check_network_urls("https://tracker.com/pixel", "https://www.site.com", "image").matched);
+        assert!(!restarted.check_network_urls("https://example.com/page", "https://www.site.com", "image").matched);
+        assert_eq!(restarted.decision_cache_stats(), Some((2, 0)));
+
+        // Decisions made by different rules are not reused, but hit statistics still are
+        let mut changed = Engine::from_rules(&rules, FilterFormat::Standard);
+        changed.set_decision_cache_capacity(8);
+        changed.enable_tags(&["strict"]);
+        assert_eq!(changed.restore_warm_state(&state), 0);
+        assert!(changed.check_network_urls("https://example.com/ads/1.js", "https://www.site.com", "image").matched);
+        assert_eq!(changed.decision_cache_stats(), Some((0, 1)));
+
+        let mut uncached = Engine::from_rules(&rules, FilterFormat::Standard);
+        assert_eq!(uncached.restore_warm_state(&state), 0);
+    }
+
+    #[test]
+    fn build_progress() {
+        use crate::progress::{BuildPhase, CancellationToken, PROGRESS_INTERVAL};
+
+        let list = (0..PROGRESS_INTERVAL * 2 + 10)
+            .map(|i| if i % 2 == 0 { format!("||ads{}.com^", i) } else { format!("ads{}.com##.banner", i) })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let lists = vec![("main", list.as_str()), ("extra", "||tracker.com^")];
+
+        let mut reports = vec![];
+        let engine = Engine::from_filter_lists_with_progress(lists.clone(), FilterFormat::Standard, false, true, &mut |p| reports.push(p.clone()), None).unwrap();
+        assert!(engine.check_network_urls("https://tracker.com/a.js", "https://example.com", "script").matched);
+
+        let phases = reports.iter().map(|p| (p.phase, p.lines_parsed, p.filters_compiled)).collect::<Vec<_>>();
+        let total_lines = PROGRESS_INTERVAL * 2 + 11;
+        let network_filters = PROGRESS_INTERVAL + 6;
+        assert_eq!(phases, vec![
+            (BuildPhase::ParsingLists, 0, 0),
+            (BuildPhase::ParsingLists, PROGRESS_INTERVAL, 0),
+            (BuildPhase::ParsingLists, PROGRESS_INTERVAL * 2, 0),
+            (BuildPhase::CompilingNetworkFilters, total_lines, 0),
+            (BuildPhase::CompilingNetworkFilters, total_lines, PROGRESS_INTERVAL),
+            (BuildPhase::CompilingNetworkFilters, total_lines, network_filters),
+            (BuildPhase::CompilingCosmeticFilters, total_lines, network_filters),
+            (BuildPhase::CompilingCosmeticFilters, total_lines, network_filters + PROGRESS_INTERVAL),
+            (BuildPhase::Done, total_lines, total_lines),
+        ]);
+        assert_eq!(reports.last().unwrap().filters_total, total_lines);
+
+        // Cancelling from the callback stops at the next checkpoint
+        let token = CancellationToken::new();
+        let mut last_phase = None;
+        let result = Engine::from_filter_lists_with_progress(lists, FilterFormat::Standard, false, true, &mut |p| {
+            last_phase = Some((p.phase, p.lines_parsed));
+            if p.lines_parsed == PROGRESS_INTERVAL {
+                token.cancel();
+            }
+        }, Some(&token));
+        assert!(matches!(result, Err(BlockerError::Cancelled)));
+        assert_eq!(last_phase, Some((BuildPhase::ParsingLists, PROGRESS_INTERVAL)));
+
+        // Compilation checks the token as often as parsing does
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_filter_list(&list, FilterFormat::Standard);
+        let token = CancellationToken::new();
+        let mut last_report = None;
+        let result = Engine::from_filter_set_with_progress(filter_set, true, &mut |p| {
+            last_report = Some((p.phase, p.filters_compiled));
+            if p.filters_compiled == PROGRESS_INTERVAL {
+                token.cancel();
+            }
+        }, Some(&token));
+        assert!(matches!(result, Err(BlockerError::Cancelled)));
+        assert_eq!(last_report, Some((BuildPhase::CompilingNetworkFilters, PROGRESS_INTERVAL)));
+    }
+
     #[test]
     fn exception_tags_inactive_by_default() {
         let filters = vec![
@@ -347,6 +1727,356 @@ mod tests {
         });
     }
 
+    #[test]
+    fn check_hostname_works() {
+        let filters = vec![
+            String::from("||ads.example.com^"),
+            String::from("||tagged.com^$tag=stuff"),
+            String::from("||example.com/banner.png"),
+        ];
+
+        let mut engine = Engine::from_rules(&filters, FilterFormat::Standard);
+        assert!(engine.check_hostname("ads.example.com").matched);
+        assert!(engine.check_hostname("ADS.example.com.").matched);
+        assert!(!engine.check_hostname("example.com").matched);
+        assert!(!engine.check_hostname("tagged.com").matched);
+
+        engine.enable_tags(&["stuff"]);
+        assert!(engine.check_hostname("tagged.com").matched);
+
+        let result = engine.check_hostname("");
+        assert!(!result.matched);
+        assert!(result.error.is_some());
+    }
+
+    #[test]
+    fn check_hostname_denyallow() {
+        let filters = vec![
+            String::from("||example.org^$denyallow=cdn.example.org|example.net"),
+            String::from("@@||allowed.example.org^"),
+            String::from("@@||example.org/path.js"),
+        ];
+
+        let engine = Engine::from_rules(&filters, FilterFormat::Standard);
+        assert!(engine.check_hostname("example.org").matched);
+        assert!(engine.check_hostname("www.example.org").matched);
+        assert!(!engine.check_hostname("cdn.example.org").matched);
+        assert!(!engine.check_hostname("img.cdn.example.org").matched);
+        assert!(!engine.check_hostname("example.net").matched);
+
+        let result = engine.check_hostname("allowed.example.org");
+        assert!(!result.matched);
+        assert!(result.exception.is_some());
+
+        // the URL path agrees with the hostname fast path
+        for hostname in ["www.example.org", "cdn.example.org", "allowed.example.org"].iter() {
+            let url = format!("https://{}/", hostname);
+            assert_eq!(engine.check_network_urls(&url, "", "other").matched, engine.check_hostname(hostname).matched);
+        }
+    }
+
+    #[test]
+    fn clones_share_rules() {
+        let filters = vec![
+            String::from("||ads.com^"),
+            String::from("||tagged.com^$tag=stuff"),
+        ];
+        let engine = Engine::from_rules(&filters, FilterFormat::Standard);
+        let mut clone = engine.clone();
+        assert!(Arc::ptr_eq(&engine.blocker, &clone.blocker));
+        assert!(clone.check_network_urls("https://ads.com/ad.js", "https://example.com", "script").matched);
+
+        // modifying a clone does not affect the original
+        clone.enable_tags(&["stuff"]);
+        assert!(!Arc::ptr_eq(&engine.blocker, &clone.blocker));
+        assert!(clone.check_network_urls("https://tagged.com/ad.js", "https://example.com", "script").matched);
+        assert!(!engine.check_network_urls("https://tagged.com/ad.js", "https://example.com", "script").matched);
+        assert!(engine.check_network_urls("https://ads.com/ad.js", "https://example.com", "script").matched);
+    }
+
+    #[test]
+    fn client_tags() {
+        let filters = vec![
+            String::from("||games.com^$ctag=device_tv|device_phone"),
+            String::from("||social.com^$ctag=~user_admin"),
+            String::from("||ads.com^"),
+            String::from("@@||ads.com^$ctag=device_pc"),
+        ];
+
+        let engine = Engine::from_rules(&filters, FilterFormat::Standard);
+        assert!(!engine.check_hostname("games.com").matched);
+        assert!(engine.check_hostname_with_client_tags("games.com", &["device_tv"]).matched);
+        assert!(!engine.check_hostname_with_client_tags("games.com", &["device_pc"]).matched);
+
+        assert!(engine.check_hostname("social.com").matched);
+        assert!(engine.check_network_urls("https://social.com/", "https://example.com", "script").matched);
+        assert!(engine.check_hostname_with_client_tags("social.com", &["device_pc"]).matched);
+        assert!(!engine.check_hostname_with_client_tags("social.com", &["device_pc", "user_admin"]).matched);
+
+        assert!(engine.check_network_urls("https://ads.com/ad.js", "https://example.com", "script").matched);
+        assert!(!engine.check_network_urls_with_client_tags("https://ads.com/ad.js", "https://example.com", "script", &["device_pc"]).matched);
+    }
+
+    #[test]
+    fn check_request_parts() {
+        let engine = Engine::from_rules(&[String::from("||ads.com/banner$third-party")], FilterFormat::Standard);
+        let parts = RequestParts {
+            scheme: "https",
+            hostname: "ads.com",
+            path: "/banner.png",
+            source_hostname: "example.com",
+            request_type: "image",
+            ..RequestParts::default()
+        };
+        assert!(engine.check_network_request_parts(&parts).matched);
+        assert!(!engine.check_network_request_parts(&RequestParts { source_hostname: "www.ads.com", ..parts }).matched);
+        assert!(!engine.check_network_request_parts(&RequestParts { path: "/logo.png", ..parts }).matched);
+    }
+
+    #[test]
+    fn allowlisted_frames() {
+        let filters = vec![
+            String::from("||ads.com^"),
+            String::from("||tracker.com^$important"),
+            String::from("@@||trusted.com^$document"),
+            String::from("@@||frames.com^$subdocument"),
+        ];
+        let engine = Engine::from_rules_debug(&filters, FilterFormat::Standard);
+
+        let top = engine.frame_state("https://trusted.com/page", None);
+        assert_eq!(top.exception.as_deref(), Some("@@||trusted.com^$document"));
+        let result = engine.check_network_urls_in_frame("https://ads.com/ad.js", "https://trusted.com/page", "script", &top);
+        assert!(!result.matched);
+        assert_eq!(result.exception_id, top.exception_id);
+        assert!(engine.check_network_urls_in_frame("https://tracker.com/t.js", "https://trusted.com/page", "script", &top).matched);
+
+        // Frames nested in an allowlisted frame are allowlisted too
+        let nested = engine.frame_state("https://other.com/frame", Some(&top));
+        assert!(nested.is_allowlisted());
+        assert!(!engine.check_network_urls_in_frame("https://ads.com/ad.js", "https://other.com/frame", "script", &nested).matched);
+
+        let frame = engine.frame_state("https://frames.com/", None);
+        assert!(!frame.is_allowlisted());
+        assert!(engine.check_network_urls_in_frame("https://ads.com/ad.js", "https://frames.com/", "script", &frame).matched);
+
+        let plain = engine.frame_state("https://example.com/", None);
+        assert_eq!(plain, FrameState::default());
+        assert!(engine.check_network_urls_in_frame("https://ads.com/ad.js", "https://example.com/", "script", &plain).matched);
+    }
+
+    #[test]
+    fn dns_queries() {
+        let mut filter_set = FilterSet::new(true);
+        filter_set.set_parse_options(ParseOptions { dns_modifiers: true, ..ParseOptions::default() });
+        filter_set.add_filters(&[
+            String::from("||ipv6.com^$dnstype=AAAA"),
+            String::from("||local.com^$dnsrewrite=127.0.0.1"),
+            String::from("||local.com^$dnsrewrite=NOERROR;A;127.0.0.2"),
+            String::from("@@||local.com^$dnsrewrite=127.0.0.2"),
+            String::from("||alias.com^$dnsrewrite=target.net"),
+            String::from("@@||unaliased.alias.com^$dnsrewrite"),
+        ], FilterFormat::Standard);
+        let engine = Engine::from_filter_set(filter_set, true);
+
+        assert!(engine.check_dns_query("ipv6.com", "aaaa").blocker_result.matched);
+        assert!(!engine.check_dns_query("ipv6.com", "A").blocker_result.matched);
+        assert!(!engine.check_hostname("ipv6.com").matched);
+
+        let result = engine.check_dns_query("local.com", "A");
+        assert!(!result.blocker_result.matched);
+        assert_eq!(result.rewrites.iter().map(|r| r.to_string()).collect::<Vec<_>>(), vec!["NOERROR;A;127.0.0.1"]);
+        assert_eq!(result.rewrite_filter_ids.len(), 1);
+        assert!(!engine.check_network_urls("https://local.com/", "", "other").matched);
+
+        assert_eq!(engine.check_dns_query("www.alias.com", "A").rewrites[0].value.as_deref(), Some("target.net"));
+        assert!(engine.check_dns_query("unaliased.alias.com", "A").rewrites.is_empty());
+    }
+
+    #[test]
+    fn filter_sources_trace_lines() {
+        let mut filter_set = FilterSet::new(true);
+        filter_set.add_filter_list_with_id("first", "! Title: First\n||ads.com^\n  ||tracker.com^\n", FilterFormat::Standard);
+        filter_set.add_filter_list_with_id("second", "||tracker.com^\nexample.com##.ad", FilterFormat::Standard);
+        let engine = Engine::from_filter_set(filter_set, true);
+
+        let result = engine.check_network_urls("https://tracker.com/t.js", "https://example.com", "script");
+        let sources = engine.filter_sources(&result.filter.unwrap());
+        assert_eq!(sources, vec![
+            &FilterSource { list_id: "first".to_string(), line: 3, text: "  ||tracker.com^".to_string() },
+            &FilterSource { list_id: "second".to_string(), line: 1, text: "||tracker.com^".to_string() },
+        ]);
+
+        assert_eq!(engine.filter_sources("example.com##.ad").len(), 1);
+        assert!(engine.filter_sources("||unknown.com^").is_empty());
+
+        // nothing is recorded outside of debug mode
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_filter_list_with_id("first", "||ads.com^", FilterFormat::Standard);
+        let engine = Engine::from_filter_set(filter_set, true);
+        assert!(engine.filter_sources("||ads.com^").is_empty());
+    }
+
+    #[test]
+    fn scriptlets_and_flags_disable_scripting() {
+        let filters = vec![
+            String::from("example.com##^script"),
+            String::from("sub.example.com#@#^script"),
+            String::from("||csp.com^$csp=script-src 'none'"),
+            String::from("||other.com^$csp=img-src 'none'"),
+            String::from("@@||hidden.com^$generichide"),
+        ];
+        let engine = Engine::from_rules(&filters, FilterFormat::Standard);
+
+        assert!(engine.scriptlets_and_flags("https://example.com/page").disable_scripting);
+        assert!(engine.scriptlets_and_flags("https://www.example.com/").disable_scripting);
+        assert!(!engine.scriptlets_and_flags("https://sub.example.com/").disable_scripting);
+        assert!(engine.scriptlets_and_flags("https://csp.com/").disable_scripting);
+        assert!(!engine.scriptlets_and_flags("https://other.com/").disable_scripting);
+        assert_eq!(engine.scriptlets_and_flags("https://hidden.com/"), ScriptletsAndFlags {
+            injected_script: String::new(),
+            generichide: true,
+            disable_scripting: false,
+        });
+        assert_eq!(engine.scriptlets_and_flags("not a url"), ScriptletsAndFlags::default());
+    }
+
+    #[test]
+    fn cosmetic_policy_limits_resources() {
+        let filters = vec![
+            String::from("##a[href*=\"ads\"]"),
+            String::from("example.com,specific.com,elem.com,allowlisted.com##.specific"),
+            String::from("@@||specific.com^$specifichide"),
+            String::from("@@||elem.com^$elemhide"),
+            String::from("@@||allowlisted.com^$document"),
+        ];
+        let engine = Engine::from_rules(&filters, FilterFormat::Standard);
+
+        assert_eq!(engine.cosmetic_policy("https://example.com"), CosmeticPolicy::default());
+        assert_eq!(engine.cosmetic_policy("https://allowlisted.com"), CosmeticPolicy {
+            generic_hide_allowed: false,
+            specific_hide_allowed: false,
+            scriptlets_allowed: false,
+        });
+
+        let hide_selectors = |url: &str| {
+            let mut selectors = engine.url_cosmetic_resources(url).hide_selectors.into_iter().collect::<Vec<_>>();
+            selectors.sort();
+            selectors
+        };
+
+        assert_eq!(hide_selectors("https://example.com"), vec![".specific", "a[href*=\"ads\"]"]);
+        assert_eq!(hide_selectors("https://specific.com"), vec!["a[href*=\"ads\"]"]);
+        assert_eq!(hide_selectors("https://elem.com"), Vec::<String>::new());
+        assert_eq!(hide_selectors("https://allowlisted.com"), Vec::<String>::new());
+        assert!(engine.url_cosmetic_resources("https://elem.com").generichide);
+    }
+
+    #[test]
+    fn cached_cosmetic_resources() {
+        let filters = vec![
+            String::from("##.generic"),
+            String::from("example.com##.specific"),
+            String::from("example.com##+js(nowebrtc.js)"),
+            String::from("@@||example.com/nogeneric$generichide"),
+        ];
+        let mut engine = Engine::from_rules(&filters, FilterFormat::Standard);
+        let uncached = engine.clone();
+        engine.set_cosmetic_resources_cache_capacity(1);
+
+        for url in &["https://example.com", "https://example.com", "https://example.com/nogeneric", "https://example.com", "https://other.com"] {
+            assert_eq!(engine.url_cosmetic_resources(url), uncached.url_cosmetic_resources(url), "{}", url);
+        }
+        assert!(engine.url_cosmetic_resources("https://example.com/nogeneric").generichide);
+        assert!(!engine.url_cosmetic_resources("https://example.com/page").generichide);
+
+        // results computed before a resource update are discarded
+        assert_eq!(engine.url_cosmetic_resources("https://example.com").injected_script, "");
+        engine.add_resource(Resource {
+            name: "nowebrtc.js".to_owned(),
+            aliases: vec![],
+            kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+            content: base64::encode("nowebrtc();"),
+            privileged: false,
+        }).unwrap();
+        assert_eq!(engine.url_cosmetic_resources("https://example.com").injected_script, "nowebrtc();\n");
+    }
+
+    #[test]
+    fn prewarm_compiles_candidate_regexes() {
+        let filters = vec![
+            String::from("/ads/banner*.png"),
+            String::from("/tracker/pixel*.gif"),
+        ];
+        let engine = Engine::from_rules(&filters, FilterFormat::Standard);
+        let compiled = |engine: &Engine| engine.blocker.filters.iter_filters()
+            .filter(|filter| filter.is_regex_compiled())
+            .count();
+        assert_eq!(compiled(&engine), 0);
+
+        engine.prewarm(&[("https://example.com/ads/banner1.png", "https://example.com", "image"), ("not a url", "", "image")]);
+        assert_eq!(compiled(&engine), 1);
+        assert!(engine.check_network_urls("https://example.com/ads/banner1.png", "https://example.com", "image").matched);
+    }
+
+    #[test]
+    fn low_latency_profile() {
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_filters(&[
+            String::from("/ads/banner*.png"),
+            String::from("/tracker/pixel*.gif"),
+            String::from("||plain.com^"),
+            String::from("||tagged.com/*/x.js$tag=t"),
+        ], FilterFormat::Standard);
+        let mut engine = Engine::from_filter_set_with_profile(filter_set, false, BuildProfile::LowLatency);
+        assert_eq!(engine.build_profile(), BuildProfile::LowLatency);
+
+        assert_eq!(engine.blocker.filters.iter_filters().filter(|filter| filter.is_regex_compiled()).count(), 2);
+        assert!(engine.blocker.tagged_filters_all.iter().all(|filter| filter.is_regex_compiled()));
+        assert_eq!(engine.decision_cache_stats(), Some((0, 0)));
+        assert!(engine.check_network_urls("https://example.com/tracker/pixel1.gif", "https://example.com", "image").matched);
+
+        engine.set_build_profile(BuildProfile::Balanced);
+        assert_eq!(engine.decision_cache_stats(), None);
+    }
+
+    #[test]
+    fn trusted_scriptlets() {
+        use crate::lists::FilterSet;
+
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_trusted_filter_list_with_id("trusted", "example.com##+js(trusted-set-cookie, consent, yes)", FilterFormat::Standard);
+        let untrusted = "example.com##+js(trusted-set-cookie, consent, no)\nexample.com##+js(tsc, consent, no)\nexception.com##+js(trusted-set-cookie, consent, yes)\nexception.com#@#+js(trusted-set-cookie, consent, yes)";
+        filter_set.add_filter_list_with_id("untrusted", untrusted, FilterFormat::Standard);
+        assert!(filter_set.add_filter("example.com##+js(trusted-set-cookie, consent, no)", FilterFormat::Standard).is_err());
+        filter_set.add_trusted_filter("exception.com##+js(trusted-set-cookie, consent, yes)", FilterFormat::Standard).unwrap();
+        filter_set.add_filter("storage.com##+js(set-local-storage-item, consent)", FilterFormat::Standard).unwrap();
+        filter_set.add_trusted_filter("own.com##+js(set-local-storage-item, consent)", FilterFormat::Standard).unwrap();
+
+        let mut engine = Engine::from_filter_set(filter_set, true);
+        engine.add_resource(Resource {
+            name: "trusted-set-cookie.js".to_owned(),
+            aliases: vec!["tsc.js".to_owned()],
+            kind: ResourceType::Template,
+            content: base64::encode("setCookie('{{1}}', '{{2}}');"),
+            privileged: false,
+        }).unwrap();
+        engine.add_resource(Resource {
+            name: "set-local-storage-item.js".to_owned(),
+            aliases: vec![],
+            kind: ResourceType::Template,
+            content: base64::encode("setItem('{{1}}');"),
+            privileged: true,
+        }).unwrap();
+
+        // untrusted rules can neither name a privileged scriptlet directly nor through an alias
+        assert_eq!(engine.url_cosmetic_resources("https://example.com").injected_script, "setCookie('consent', 'yes');\n");
+        // resources marked as privileged are only injected for trusted rules
+        assert_eq!(engine.url_cosmetic_resources("https://storage.com").injected_script, "");
+        assert_eq!(engine.url_cosmetic_resources("https://own.com").injected_script, "setItem('consent');\n");
+        // exceptions for privileged scriptlets are allowed from any source
+        assert_eq!(engine.url_cosmetic_resources("https://exception.com").injected_script, "");
+    }
+
     #[test]
     fn serialization_retains_tags() {
         let filters = vec![
@@ -380,6 +2110,309 @@ mod tests {
         });
     }
 
+    #[test]
+    fn earliest_matching_filter_wins() {
+        let url = "https://example.com/ads/banner.gif";
+        let first_matching = |rules: &[&str]| {
+            let rules: Vec<String> = rules.iter().map(|r| r.to_string()).collect();
+            let engine = Engine::from_rules_debug(&rules, FilterFormat::Standard);
+            let result = engine.check_network_urls(url, "", "");
+
+            let serialized = engine.serialize().unwrap();
+            let mut deserialized_engine = Engine::default();
+            deserialized_engine.deserialize(&serialized).unwrap();
+            let deserialized_result = deserialized_engine.check_network_urls(url, "", "");
+            assert_eq!(result.filter, deserialized_result.filter);
+
+            result.filter
+        };
+
+        assert_eq!(first_matching(&["/ads/banner.gif", "||example.com/ads/"]), Some("/ads/banner.gif".to_string()));
+        assert_eq!(first_matching(&["||example.com/ads/", "/ads/banner.gif"]), Some("||example.com/ads/".to_string()));
+    }
+
+    #[test]
+    fn serialization_retains_metadata() {
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_filter_list_with_id("easylist", "! Version: 202610161200\n||ads.com^", FilterFormat::Standard);
+        filter_set.add_filter_list_with_id("custom", "||tracker.com^", FilterFormat::Standard);
+        let engine = Engine::from_filter_set(filter_set, true);
+
+        assert!(engine.metadata().compiled_at.is_some());
+        assert_eq!(engine.metadata().list_versions.get("easylist").map(String::as_str), Some("202610161200"));
+        assert_eq!(engine.metadata().list_versions.get("custom"), None);
+        assert!(engine.age().unwrap() < Duration::from_secs(60));
+        assert!(!engine.is_stale(Duration::from_secs(60)));
+
+        let serialized = engine.serialize().unwrap();
+        let mut deserialized_engine = Engine::default();
+        assert!(deserialized_engine.age().is_none());
+        assert!(deserialized_engine.is_stale(Duration::from_secs(60)));
+        deserialized_engine.deserialize(&serialized).unwrap();
+        assert_eq!(deserialized_engine.metadata(), engine.metadata());
+    }
+
+    #[test]
+    fn serialization_format_version() {
+        let engine = Engine::from_rules(&["||ads.com^".to_string()], FilterFormat::Standard);
+        let serialized = engine.serialize().unwrap();
+        assert_eq!(serialized[4], crate::data_format::FORMAT_VERSION);
+
+        let mut other_version = serialized.clone();
+        other_version[4] += 1;
+        let mut deserialized_engine = Engine::default();
+        assert_eq!(deserialized_engine.deserialize(&other_version), Err(BlockerError::DeserializationError));
+        assert!(!deserialized_engine.check_network_urls("https://ads.com/", "", "").matched);
+
+        // Data written before the format was versioned has no header
+        deserialized_engine.deserialize(&serialized[5..]).unwrap();
+        assert!(deserialized_engine.check_network_urls("https://ads.com/", "", "").matched);
+    }
+
+    #[test]
+    fn incremental_filter_updates() {
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_filter_list("||ads.com^\n||tracker.net/*/pixel.gif\nexample.com##.ad\n##.banner", FilterFormat::Standard);
+        let mut engine = Engine::from_filter_set(filter_set, true);
+        let blocked = |engine: &Engine, url: &str| engine.check_network_urls(url, "https://example.com", "image").matched;
+
+        assert!(!blocked(&engine, "https://user.org/img.png"));
+        engine.add_filter("||user.org/img.png", FilterFormat::Standard).unwrap();
+        assert!(blocked(&engine, "https://user.org/img.png"));
+        assert_eq!(engine.check_network_urls("https://user.org/img.png", "https://example.com", "image").filter.as_deref(), Some("||user.org/img.png"));
+        assert!(matches!(engine.add_filter("||user.org/img.png", FilterFormat::Standard), Err(FilterUpdateError::Blocker(BlockerError::FilterExists))));
+        assert!(matches!(engine.add_filter("||ads.com^$badfilter", FilterFormat::Standard), Err(FilterUpdateError::Blocker(BlockerError::BadFilterAddUnsupported))));
+        assert!(matches!(engine.add_filter("##+js(trusted-set-cookie, a, b)", FilterFormat::Standard), Err(FilterUpdateError::Parse(_))));
+
+        engine.remove_filter("||user.org/img.png", FilterFormat::Standard).unwrap();
+        assert!(!blocked(&engine, "https://user.org/img.png"));
+        engine.remove_filter("||tracker.net/*/pixel.gif", FilterFormat::Standard).unwrap();
+        assert!(!blocked(&engine, "https://tracker.net/a/pixel.gif"));
+        assert!(blocked(&engine, "https://ads.com/ad.png"));
+        assert!(matches!(engine.remove_filter("||other.com^", FilterFormat::Standard), Err(FilterUpdateError::Blocker(BlockerError::FilterNotFound))));
+
+        engine.add_filter("example.com##.user-hidden", FilterFormat::Standard).unwrap();
+        let selectors = engine.url_cosmetic_resources("https://example.com").hide_selectors;
+        assert!(selectors.contains(".user-hidden") && selectors.contains(".ad"));
+        engine.remove_filter("example.com##.ad", FilterFormat::Standard).unwrap();
+        engine.remove_filter("##.banner", FilterFormat::Standard).unwrap();
+        assert!(!engine.url_cosmetic_resources("https://example.com").hide_selectors.contains(".ad"));
+        assert!(engine.hidden_class_id_selectors(&[String::from("banner")], &[], &HashSet::new()).is_empty());
+        assert!(matches!(engine.remove_filter("#@#.banner", FilterFormat::Standard), Err(FilterUpdateError::Blocker(BlockerError::FilterNotFound))));
+
+        assert_eq!(engine.add_filter_list("||a.com^\n! comment\nb.com##.x\n||c.com^$popup", FilterFormat::Standard), 3);
+        assert!(blocked(&engine, "https://a.com/"));
+        assert_eq!(engine.remove_filter_list("||a.com^\nb.com##.x\n||c.com^$popup\n||d.com^", FilterFormat::Standard), 3);
+        assert!(!blocked(&engine, "https://a.com/"));
+        assert!(engine.network_filters().all(|filter| filter.canonical_text() != "||a.com^"));
+    }
+
+    #[test]
+    fn decision_hooks() {
+        use crate::pipeline::DecisionHook;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// Blocks everything on `intranet.corp` and unwraps `https://proxy.corp/?u=` URLs
+        struct Policy;
+        impl DecisionHook for Policy {
+            fn normalize(&self, request: &mut Request) {
+                if let Some(url) = request.url.strip_prefix("https://proxy.corp/?u=") {
+                    *request = Request::from_urls(url, "https://example.com", "script").unwrap();
+                }
+            }
+            fn before_network(&self, request: &Request) -> Option<BlockerResult> {
+                if request.hostname == "intranet.corp" {
+                    Some(BlockerResult { matched: true, filter: Some(String::from("policy")), ..Default::default() })
+                } else {
+                    None
+                }
+            }
+            fn after_headers(&self, _request: &Request, decision: &mut ReferrerDecision) {
+                decision.action = decision.action.max(ReferrerAction::TrimToOrigin);
+            }
+            fn after_cosmetic(&self, _request: &Request, resources: &mut UrlSpecificResources) {
+                resources.hide_selectors.insert(String::from(".policy-banner"));
+            }
+        }
+
+        struct Counter(AtomicUsize);
+        impl DecisionHook for Counter {
+            fn after_network(&self, _request: &Request, result: &mut BlockerResult) {
+                if result.matched {
+                    self.0.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+
+        let mut engine = Engine::from_rules(&[String::from("||ads.com^"), String::from("ads.com##.ad")], FilterFormat::Standard);
+        let counter = Arc::new(Counter(AtomicUsize::new(0)));
+        engine.add_hook(Arc::new(Policy));
+        engine.add_hook(counter.clone());
+
+        let result = engine.check_network_urls("https://intranet.corp/", "https://example.com", "document");
+        assert!(result.matched);
+        assert_eq!(result.filter.as_deref(), Some("policy"));
+        assert!(engine.check_network_urls("https://proxy.corp/?u=https://ads.com/ad.js", "https://example.com", "script").matched);
+        assert!(!engine.check_network_urls("https://example.com/app.js", "https://example.com", "script").matched);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+
+        assert_eq!(engine.referrer_decision("https://cdn.org/a.js", "https://example.com/", "script").action, ReferrerAction::TrimToOrigin);
+        assert!(engine.url_cosmetic_resources("https://example.com").hide_selectors.contains(".policy-banner"));
+
+        // Other entry points go through the same stages
+        assert!(engine.check_hostname("intranet.corp").matched);
+        assert!(engine.check_dns_query("intranet.corp", "A").blocker_result.matched);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 4);
+        assert!(engine.url_cosmetic_resources("https://proxy.corp/?u=https://ads.com/").hide_selectors.contains(".ad"));
+
+        engine.clear_hooks();
+        assert!(!engine.check_network_urls("https://intranet.corp/", "https://example.com", "document").matched);
+        assert!(!engine.url_cosmetic_resources("https://example.com").hide_selectors.contains(".policy-banner"));
+    }
+
+    #[test]
+    fn tagged_filter_lists() {
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_filter_list("||ads.com^\nexample.com##.banner", FilterFormat::Standard);
+        filter_set.add_tagged_filter_list("privacy", "||tracker.net^\n@@||ads.com/ok.js\n||own-tag.org^$tag=other\n##.cookie-wall\nexample.com##.newsletter\n#@#.sidebar", FilterFormat::Standard);
+        filter_set.add_tagged_filter_list("annoyances", "##.sidebar", FilterFormat::Standard);
+        let mut engine = Engine::from_filter_set(filter_set, true);
+        let blocked = |engine: &Engine, url: &str| engine.check_network_urls(url, "https://example.com", "script").matched;
+        let hidden = |engine: &Engine| engine.url_cosmetic_resources("https://example.com").hide_selectors;
+
+        assert!(!blocked(&engine, "https://tracker.net/t.js"));
+        assert!(blocked(&engine, "https://ads.com/ok.js"));
+        assert!(!hidden(&engine).contains(".newsletter"));
+        assert!(engine.hidden_class_id_selectors(&[String::from("cookie-wall")], &[], &HashSet::new()).is_empty());
+
+        engine.use_tags(&["privacy"]);
+        assert!(blocked(&engine, "https://tracker.net/t.js"));
+        assert!(!blocked(&engine, "https://ads.com/ok.js"));
+        assert!(!blocked(&engine, "https://own-tag.org/a.js"));
+        assert!(hidden(&engine).contains(".newsletter") && hidden(&engine).contains(".banner"));
+        assert_eq!(engine.hidden_class_id_selectors(&[String::from("cookie-wall")], &[], &HashSet::new()), vec![".cookie-wall"]);
+
+        let serialized = engine.serialize().unwrap();
+        let mut deserialized = Engine::default();
+        deserialized.deserialize(&serialized).unwrap();
+        assert!(!hidden(&deserialized).contains(".newsletter"));
+        deserialized.enable_tags(&["privacy"]);
+        assert!(hidden(&deserialized).contains(".newsletter"));
+
+        engine.disable_tags(&["privacy"]);
+        assert!(!blocked(&engine, "https://tracker.net/t.js"));
+        assert!(!hidden(&engine).contains(".newsletter"));
+        assert!(hidden(&engine).contains(".banner"));
+
+        // generic exceptions from tagged lists apply regardless of tags
+        engine.use_tags(&["annoyances"]);
+        assert!(engine.hidden_class_id_selectors(&[String::from("sidebar")], &[], &HashSet::new()).is_empty());
+    }
+
+    #[test]
+    fn referrer_decisions() {
+        let mut filter_set = FilterSet::new(true);
+        filter_set.add_filters(&[
+            String::from("||tracker.net^$removeheader=referer"),
+            String::from("||cdn.org^$removeheader=request:referer,script"),
+            String::from("@@||tracker.net/ok/$removeheader"),
+            String::from("||other.com^$removeheader=location"),
+        ], FilterFormat::Standard);
+        let mut engine = Engine::from_filter_set(filter_set, true);
+
+        let decision = engine.referrer_decision("https://tracker.net/pixel.gif", "https://example.com/page", "image");
+        assert_eq!(decision.action, ReferrerAction::Remove);
+        assert_eq!(decision.filter.as_deref(), Some("||tracker.net^$removeheader=referer"));
+        assert_eq!(decision.apply("https://example.com/page"), None);
+        assert_eq!(engine.referrer_decision("https://cdn.org/lib.js", "https://example.com/", "script").action, ReferrerAction::Remove);
+        assert_eq!(engine.referrer_decision("https://cdn.org/img.png", "https://example.com/", "image").action, ReferrerAction::Keep);
+        assert_eq!(engine.referrer_decision("https://tracker.net/ok/pixel.gif", "https://example.com/", "image").action, ReferrerAction::Keep);
+        assert_eq!(engine.referrer_decision("https://other.com/", "https://example.com/", "document").action, ReferrerAction::Keep);
+        assert_eq!(engine.referrer_decision("not a url", "https://example.com/", "image"), ReferrerDecision::default());
+
+        engine.set_referrer_policy(ReferrerPolicy { third_party: ReferrerAction::TrimToOrigin, ..Default::default() });
+        let decision = engine.referrer_decision("https://cdn.org/img.png", "https://example.com/page?id=1", "image");
+        assert_eq!((decision.action, decision.filter), (ReferrerAction::TrimToOrigin, None));
+        assert_eq!(engine.referrer_decision("https://example.com/img.png", "https://example.com/page", "image").action, ReferrerAction::Keep);
+        assert_eq!(engine.referrer_decision("https://tracker.net/pixel.gif", "https://example.com/", "image").action, ReferrerAction::Remove);
+    }
+
+    #[test]
+    fn tracking_param_stripping() {
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_filters(&[String::from("$removeparam=ref")], FilterFormat::Standard);
+        let mut engine = Engine::from_filter_set(filter_set, true);
+
+        let url = "https://example.com/?ref=x&utm_source=y&id=1";
+        assert_eq!(engine.filter_url_params(url, "https://example.com", "document"), Some(String::from("https://example.com/?utm_source=y&id=1")));
+        assert_eq!(engine.filter_url_params("https://example.com/?fbclid=1", "https://example.com", "document"), None);
+
+        engine.set_tracking_param_stripping(true);
+        assert_eq!(engine.filter_url_params(url, "https://example.com", "document"), Some(String::from("https://example.com/?id=1")));
+        assert_eq!(engine.filter_url_params("https://example.com/?fbclid=1", "https://example.com", "document"), Some(String::from("https://example.com/")));
+        // applies regardless of the request type
+        assert_eq!(engine.filter_url_params("https://example.com/a.png?gclid=1", "https://example.com", "image"), Some(String::from("https://example.com/a.png")));
+        assert_eq!(engine.filter_url_params("https://example.com/?id=1", "https://example.com", "document"), None);
+    }
+
+    #[test]
+    fn counts_badfilter_cancelled_rules() {
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_filter_list_with_id("list", "||ads.com^\n||ads.com^$badfilter\n||ads.com^$image\n||other.com^$badfilter", FilterFormat::Standard);
+        let engine = Engine::from_filter_set(filter_set, true);
+
+        assert_eq!(engine.metadata().cancelled_filters, 1);
+        assert!(!engine.check_network_urls("https://ads.com/ad.js", "https://example.com", "script").matched);
+        assert!(engine.check_network_urls("https://ads.com/ad.png", "https://example.com", "image").matched);
+    }
+
+    #[test]
+    fn cancellable_serialization() {
+        let engine = Engine::from_rules(&["||ads.com^".to_string()], FilterFormat::Standard);
+        let token = CancellationToken::new();
+        let serialized = engine.serialize_with_cancellation(Some(&token)).unwrap();
+
+        let mut deserialized = Engine::default();
+        token.cancel();
+        assert_eq!(engine.serialize_with_cancellation(Some(&token)), Err(BlockerError::Cancelled));
+        assert_eq!(deserialized.deserialize_with_cancellation(&serialized, Some(&token)), Err(BlockerError::Cancelled));
+        assert!(!deserialized.check_network_urls("https://ads.com/a.js", "https://example.com", "script").matched);
+
+        deserialized.deserialize_with_cancellation(&serialized, Some(&CancellationToken::new())).unwrap();
+        assert!(deserialized.check_network_urls("https://ads.com/a.js", "https://example.com", "script").matched);
+    }
+
+    #[test]
+    fn serialization_retains_unicode_cosmetics() {
+        let filters = vec![
+            String::from("##.広告枠"),
+            String::from("itmedia.co.jp##div.colBox:has-text(広告)"),
+            String::from("яндекс.рф##div[aria-label=\"Реклама\"]"),
+            String::from("2chmatome.jp##.entry:style(content: \"広告\")"),
+        ];
+        let engine = Engine::from_rules(&filters, FilterFormat::Standard);
+        let serialized = engine.serialize().unwrap();
+        let mut deserialized_engine = Engine::default();
+        deserialized_engine.deserialize(&serialized).unwrap();
+
+        for engine in &[engine, deserialized_engine] {
+            assert_eq!(
+                engine.hidden_class_id_selectors(&["広告枠".to_string()], &[], &HashSet::new()),
+                vec![".広告枠".to_string()],
+            );
+
+            let resources = engine.url_cosmetic_resources("https://www.itmedia.co.jp/news/");
+            let procedural = resources.procedural_filters.into_iter().next().unwrap();
+            assert_eq!(procedural.selector[1], crate::filters::procedural::ProceduralOperator::HasText("広告".to_string()));
+
+            let resources = engine.url_cosmetic_resources("https://яндекс.рф/search");
+            assert_eq!(resources.hide_selectors, vec!["div[aria-label=\"Реклама\"]".to_string()].into_iter().collect::<HashSet<_>>());
+
+            let resources = engine.url_cosmetic_resources("https://2chmatome.jp/");
+            assert_eq!(resources.style_selectors.get(".entry"), Some(&vec!["content: \"広告\"".to_string()]));
+        }
+    }
+
     #[test]
     fn deserialization_backwards_compatible_plain() {
         // deserialization_generate_simple();
@@ -471,12 +2504,14 @@ mod tests {
                 aliases: vec![],
                 kind: ResourceType::Mime(MimeType::TextPlain),
                 content: base64::encode(""),
+                privileged: false,
             },
             Resource {
                 name: "noopcss".to_string(),
                 aliases: vec![],
                 kind: ResourceType::Mime(MimeType::TextPlain),
                 content: base64::encode(""),
+                privileged: false,
             },
         ];
         engine.use_resources(&resources);
@@ -496,6 +2531,7 @@ mod tests {
             aliases: vec![],
             kind: ResourceType::Mime(MimeType::TextPlain),
             content: "".to_owned(),
+            privileged: false,
         }).unwrap();
 
         let url = "http://example.com/ad-banner.gif";
@@ -504,6 +2540,44 @@ mod tests {
         assert_eq!(matched_rule.redirect, Some("data:text/plain;base64,".to_owned()), "Expected redirect to contain resource");
     }
 
+    #[test]
+    fn use_resources_returns_rejected_resources() {
+        let mut engine = Engine::from_rules(&[
+            "ad-banner$redirect=nooptext".to_owned()
+        ], FilterFormat::Standard);
+
+        let rejected = engine.use_resources(&[
+            Resource {
+                name: "nooptext".to_owned(),
+                aliases: vec![],
+                kind: ResourceType::Mime(MimeType::TextPlain),
+                content: base64::encode(""),
+                privileged: false,
+            },
+            Resource {
+                name: "1x1.gif".to_owned(),
+                aliases: vec![],
+                kind: ResourceType::Mime(MimeType::ImageGif),
+                content: base64::encode("<html>"),
+                privileged: false,
+            },
+            Resource {
+                name: "noop.js".to_owned(),
+                aliases: vec![],
+                kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+                content: "not base64".to_owned(),
+                privileged: false,
+            },
+        ]);
+        assert_eq!(rejected, vec![
+            RejectedResource { name: "1x1.gif".to_owned(), error: AddResourceError::ContentMismatch },
+            RejectedResource { name: "noop.js".to_owned(), error: AddResourceError::InvalidBase64Content },
+        ]);
+        assert!(engine.get_resource("nooptext").is_some());
+        assert!(engine.get_resource("1x1.gif").is_none());
+        assert!(engine.check_network_urls("http://example.com/ad-banner.gif", "", "").redirect.is_some());
+    }
+
     #[test]
     fn redirect_resource_lookup_works() {
         let script = base64::encode(r#"
@@ -520,6 +2594,7 @@ mod tests {
             aliases: vec![],
             kind: ResourceType::Mime(MimeType::ApplicationJavascript),
             content: script.to_owned(),
+            privileged: false,
         }).unwrap();
         let inserted_resource = engine.get_resource("noopjs");
         assert!(inserted_resource.is_some());
@@ -570,6 +2645,7 @@ mod tests {
             aliases: vec![],
             kind: ResourceType::Mime(MimeType::ApplicationJavascript),
             content: base64::encode("window.addthis = undefined"),
+            privileged: false,
         }).unwrap();
 
         let result = engine.check_network_urls("https://s7.addthis.com/js/250/addthis_widget.js?pub=resto", "https://www.rhmodern.com/catalog/product/product.jsp?productId=prod14970086&categoryId=cat7150028", "script");
@@ -5,6 +5,7 @@ use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+use crate::filters::procedural::{parse_procedural_selector, ProceduralFilter, ProceduralOperator};
 use crate::utils::Hash;
 
 use css_validation::{is_valid_css_selector, is_valid_css_style};
@@ -22,6 +23,11 @@ pub enum CosmeticFilterError {
     GenericStyle,
     DoubleNegation,
     EmptyRule,
+    GenericProcedural,
+    GenericDisableScripting,
+    InvalidProceduralOperator,
+    InvalidProceduralArgument,
+    UntrustedScriptlet,
 }
 
 bitflags::bitflags! {
@@ -34,6 +40,9 @@ bitflags::bitflags! {
         const IS_CLASS_SELECTOR = 1 << 3;
         const IS_ID_SELECTOR = 1 << 4;
         const IS_SIMPLE = 1 << 5;
+        const DISABLE_SCRIPTING = 1 << 6;
+        /// Set on rules from trusted sources, which may inject privileged scriptlets.
+        const IS_TRUSTED = 1 << 7;
 
         // Careful with checking for NONE - will always match
         const NONE = 0;
@@ -52,6 +61,10 @@ pub struct CosmeticFilter {
     pub selector: String,
     pub key: Option<String>,
     pub style: Option<String>,
+    /// Compiled form of the rule, if its selector uses procedural operators like `:has-text()`.
+    /// In that case, `selector` holds the original, uncompiled selector text.
+    #[serde(default)]
+    pub procedural: Option<ProceduralFilter>,
 }
 
 pub enum CosmeticFilterLocationType {
@@ -175,7 +188,8 @@ impl CosmeticFilter {
             index_after_colon = colon_index + 1;
             let content_after_colon = &line[index_after_colon..];
             if content_after_colon.starts_with("style") {
-                if content_after_colon.chars().nth(5) == Some('(') && content_after_colon.chars().nth(content_after_colon.len() - 1) == Some(')') {
+                // Compare bytes rather than chars, since the style may contain non-ASCII text
+                if content_after_colon[5..].starts_with('(') && content_after_colon.ends_with(')') {
                     *selector = &line[suffix_start_index..colon_index];
                     *style = Some(content_after_colon[6..content_after_colon.len()-1].to_string());
                 } else {
@@ -211,9 +225,6 @@ impl CosmeticFilter {
             let mut suffix_start_index = after_sharp_index + 1;
 
             if line[after_sharp_index..].starts_with('@') {
-                if sharp_index == 0 {
-                    return Err(CosmeticFilterError::GenericUnhide);
-                }
                 mask |= CosmeticFilterMask::UNHIDE;
                 suffix_start_index += 1;
             }
@@ -240,17 +251,51 @@ impl CosmeticFilter {
                 return Err(CosmeticFilterError::EmptyRule);
             }
             let mut style = None;
+            let mut procedural = None;
             if line.len() - suffix_start_index > 4 && line[suffix_start_index..].starts_with("+js(") && line.ends_with(')') {
                 if sharp_index == 0 {
                     return Err(CosmeticFilterError::GenericScriptInject);
                 }
                 mask |= CosmeticFilterMask::SCRIPT_INJECT;
                 selector = &line[suffix_start_index + 4..line.len() - 1];
+            } else if selector.trim() == "^script" {
+                // uBO's blanket rule for removing all scripts, i.e. disabling scripting
+                if hostnames.is_none() && entities.is_none() {
+                    return Err(CosmeticFilterError::GenericDisableScripting);
+                }
+                mask |= CosmeticFilterMask::DISABLE_SCRIPTING;
+                selector = selector.trim();
+            } else if selector.starts_with('^') {
+                // HTML filtering
+                return Err(CosmeticFilterError::UnsupportedSyntax);
             } else {
-                CosmeticFilter::parse_after_sharp_nonscript(line, suffix_start_index, &mut selector, &mut style)?;
+                procedural = parse_procedural_selector(selector)?;
+                if procedural.is_none() {
+                    CosmeticFilter::parse_after_sharp_nonscript(line, suffix_start_index, &mut selector, &mut style)?;
+                }
             }
 
-            if !mask.contains(CosmeticFilterMask::SCRIPT_INJECT) && !is_valid_css_selector(selector) {
+            if let Some(procedural) = procedural.as_ref() {
+                // Procedural filters are expensive to apply, so they are only supported on
+                // specific hostnames
+                if hostnames.is_none() && entities.is_none() {
+                    return Err(CosmeticFilterError::GenericProcedural);
+                }
+                if let Some(ProceduralOperator::CssSelector(css)) = procedural.selector.first() {
+                    if !is_valid_css_selector(css) {
+                        return Err(CosmeticFilterError::InvalidCssSelector);
+                    }
+                }
+                // Negations test their leading selector against each element directly, so it
+                // must be a complete selector too
+                if !negated_selectors_valid(&procedural.selector) {
+                    return Err(CosmeticFilterError::InvalidCssSelector);
+                }
+            } else if !mask.intersects(CosmeticFilterMask::SCRIPT_INJECT | CosmeticFilterMask::DISABLE_SCRIPTING)
+                // An unterminated `+js(` body is kept as a selector, but it isn't one
+                && !selector.starts_with("+js(")
+                && !is_valid_css_selector(selector)
+            {
                 return Err(CosmeticFilterError::InvalidCssSelector);
             } else if let Some(ref style) = style {
                 if !is_valid_css_style(style) {
@@ -268,7 +313,7 @@ impl CosmeticFilter {
                 mask |= CosmeticFilterMask::IS_UNICODE;
             }
 
-            let key = if !mask.contains(CosmeticFilterMask::SCRIPT_INJECT) {
+            let key = if !mask.contains(CosmeticFilterMask::SCRIPT_INJECT) && procedural.is_none() {
                 if selector.starts_with('.') {
                     let key = key_from_selector(selector)?;
                     mask |= CosmeticFilterMask::IS_CLASS_SELECTOR;
@@ -304,6 +349,7 @@ impl CosmeticFilter {
                 selector: String::from(selector),
                 key,
                 style,
+                procedural,
             })
         } else {
             Err(CosmeticFilterError::MissingSharp)
@@ -403,10 +449,83 @@ pub fn get_hostname_hashes_from_labels(hostname: &str, domain: &str) -> Vec<Hash
     get_hashes_from_labels(hostname, hostname.len(), hostname.len() - domain.len())
 }
 
+/// Checks the leading CSS selector of every `:not()` operator, including nested ones.
+fn negated_selectors_valid(operators: &[ProceduralOperator]) -> bool {
+    operators.iter().all(|op| match op {
+        ProceduralOperator::Not(nested) => {
+            let leading_valid = match nested.first() {
+                Some(ProceduralOperator::CssSelector(css)) => is_valid_css_selector(css),
+                _ => true,
+            };
+            leading_valid && negated_selectors_valid(nested)
+        }
+        ProceduralOperator::Has(nested) => negated_selectors_valid(nested),
+        _ => true,
+    })
+}
+
 #[cfg(not(feature="css-validation"))]
 mod css_validation {
-    pub fn is_valid_css_selector(_selector: &str) -> bool {
-        true
+    /// Lightweight structural check of a CSS selector list, used when the full `css-validation`
+    /// parser is not compiled in. It doesn't validate individual selectors, but rejects anything
+    /// that could break out of a stylesheet rule or invalidate a whole block of injected
+    /// selectors: unbalanced quotes, brackets or parentheses, `{`, `}` or `;` outside of strings,
+    /// empty entries in the list, and dangling combinators.
+    pub fn is_valid_css_selector(selector: &str) -> bool {
+        let mut quote: Option<char> = None;
+        let mut escaped = false;
+        let mut bracket_depth = 0;
+        let mut paren_depth = 0;
+        let mut entry_start = 0;
+
+        let entry_valid = |entry: &str| {
+            let entry = entry.trim();
+            let is_combinator = |c: char| c == '>' || c == '+' || c == '~';
+            // A trailing combinator character preceded by an odd number of backslashes is
+            // escaped, e.g. `#id\>`, and is part of the last compound selector instead
+            let last_char_start = entry.char_indices().last().map(|(i, _)| i).unwrap_or(0);
+            let trailing_escaped = entry[..last_char_start]
+                .bytes()
+                .rev()
+                .take_while(|b| *b == b'\\')
+                .count() % 2 == 1;
+            !entry.is_empty()
+                && !entry.starts_with(is_combinator)
+                && (trailing_escaped || !entry.ends_with(is_combinator))
+        };
+
+        for (i, c) in selector.char_indices() {
+            if escaped {
+                escaped = false;
+            } else if let Some(q) = quote {
+                if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            } else {
+                match c {
+                    '\\' => escaped = true,
+                    '"' | '\'' => quote = Some(c),
+                    '{' | '}' | ';' => return false,
+                    '[' => bracket_depth += 1,
+                    '(' => paren_depth += 1,
+                    ']' if bracket_depth == 0 => return false,
+                    ')' if paren_depth == 0 => return false,
+                    ']' => bracket_depth -= 1,
+                    ')' => paren_depth -= 1,
+                    ',' if bracket_depth == 0 && paren_depth == 0 => {
+                        if !entry_valid(&selector[entry_start..i]) {
+                            return false;
+                        }
+                        entry_start = i + 1;
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        quote.is_none() && !escaped && bracket_depth == 0 && paren_depth == 0 && entry_valid(&selector[entry_start..])
     }
 
     pub fn is_valid_css_style(_style: &str) -> bool {
@@ -1228,19 +1347,188 @@ mod parse_tests {
                 ..Default::default()
             }
         );
+        // Japanese filters
+        check_parse_result(
+            "##.広告枠",
+            CosmeticFilterBreakdown {
+                selector: ".広告枠".to_string(),
+                is_unicode: true,
+                is_class_selector: true,
+                key: Some("広告枠".to_string()),
+                ..Default::default()
+            }
+        );
+        check_parse_result(
+            "2chmatome.jp##.entry:style(content: \"広告\")",
+            CosmeticFilterBreakdown {
+                selector: ".entry".to_string(),
+                hostnames: sort_hash_domains(vec!["2chmatome.jp"]),
+                style: Some("content: \"広告\"".to_string()),
+                is_class_selector: true,
+                key: Some("entry".to_string()),
+                ..Default::default()
+            }
+        );
+        // RuAdList filters
+        check_parse_result(
+            "яндекс.рф##div[aria-label=\"Реклама\"]",
+            CosmeticFilterBreakdown {
+                selector: "div[aria-label=\"Реклама\"]".to_string(),
+                hostnames: sort_hash_domains(vec!["xn--d1acpjx3f.xn--p1ai"]),
+                is_unicode: true,
+                ..Default::default()
+            }
+        );
+
+        use crate::filters::procedural::ProceduralOperator as Op;
+        let rule = CosmeticFilter::parse("itmedia.co.jp##div.colBox:has-text(広告)", false).unwrap();
+        assert_eq!(rule.selector, "div.colBox:has-text(広告)");
+        assert!(rule.mask.contains(CosmeticFilterMask::IS_UNICODE));
+        assert_eq!(rule.procedural.unwrap().selector, vec![
+            Op::CssSelector("div.colBox".to_string()),
+            Op::HasText("広告".to_string()),
+        ]);
+        let rule = CosmeticFilter::parse("mail.ru##.news-item:has-text(/Реклама|Партнёрский материал/i)", false).unwrap();
+        assert_eq!(rule.procedural.unwrap().selector, vec![
+            Op::CssSelector(".news-item".to_string()),
+            Op::HasText("/Реклама|Партнёрский материал/i".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn malformed_selectors() {
+        for rule in &[
+            "example.com##div { display: block }",
+            "##.ad;",
+            "##a[href=\"x\"",
+            "##div)",
+            "##a[href=\"x]",
+            "##.a,,.b",
+            "##.a, .b,",
+            "##div >",
+            "##> div",
+            "example.com##.ad { x }:has-text(x)",
+        ] {
+            assert_eq!(CosmeticFilter::parse(rule, false).err(), Some(CosmeticFilterError::InvalidCssSelector), "{}", rule);
+        }
+
+        for rule in &[
+            "##a[href*=\"{x};\"]",
+            "##.a, .b > .c",
+            "##div:not(.x) + span ~ p",
+            "##div[data-ad='a]b']",
+            "masala.com###oas-mpu-left\\<\\/div\\>",
+        ] {
+            assert!(CosmeticFilter::parse(rule, false).is_ok(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn generic_exceptions() {
+        check_parse_result(
+            "#@#.selector",
+            CosmeticFilterBreakdown {
+                selector: ".selector".to_string(),
+                is_class_selector: true,
+                key: Some("selector".to_string()),
+                unhide: true,
+                ..Default::default()
+            }
+        );
+        check_parse_result(
+            "#@#div > p",
+            CosmeticFilterBreakdown {
+                selector: "div > p".to_string(),
+                unhide: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(CosmeticFilter::parse("#@#+js(nowebrtc.js)", false).err(), Some(CosmeticFilterError::GenericScriptInject));
+        assert_eq!(CosmeticFilter::parse("#@#.ad:style(color: red)", false).err(), Some(CosmeticFilterError::GenericStyle));
+        assert_eq!(CosmeticFilter::parse("#@#^script", false).err(), Some(CosmeticFilterError::GenericDisableScripting));
     }
 
     #[test]
     fn unsupported() {
-        assert!(CosmeticFilter::parse("yandex.*##.serp-item:if(:scope > div.organic div.organic__subtitle:matches-css-after(content: /[Рр]еклама/))", false).is_err());
-        assert!(CosmeticFilter::parse(r#"facebook.com,facebookcorewwwi.onion##.ego_column:if(a[href^="/campaign/landing"])"#, false).is_err());
-        assert!(CosmeticFilter::parse(r#"thedailywtf.com##.article-body > div:has(a[href*="utm_medium"])"#, false).is_err());
         assert!(CosmeticFilter::parse(r#"readcomiconline.to##^script:has-text(this[atob)"#, false).is_err());
-        assert!(CosmeticFilter::parse("twitter.com##article:has-text(/Promoted|Gesponsert|Реклама|Promocionado/):xpath(../..)", false).is_err());
         assert!(CosmeticFilter::parse("##", false).is_err());
         assert!(CosmeticFilter::parse("", false).is_err());
     }
 
+    #[test]
+    fn disable_scripting() {
+        let rule = CosmeticFilter::parse("example.com,test.*##^script", false).unwrap();
+        assert!(rule.mask.contains(CosmeticFilterMask::DISABLE_SCRIPTING));
+        assert_eq!(rule.selector, "^script");
+        assert_eq!(rule.key, None);
+
+        let rule = CosmeticFilter::parse("example.com#@#^script", false).unwrap();
+        assert!(rule.mask.contains(CosmeticFilterMask::DISABLE_SCRIPTING | CosmeticFilterMask::UNHIDE));
+
+        assert_eq!(CosmeticFilter::parse("##^script", false).err(), Some(CosmeticFilterError::GenericDisableScripting));
+        assert_eq!(CosmeticFilter::parse("~example.com##^script", false).err(), Some(CosmeticFilterError::GenericDisableScripting));
+        // other HTML filters remain unsupported
+        assert_eq!(CosmeticFilter::parse("example.com##^script:has-text(ads)", false).err(), Some(CosmeticFilterError::UnsupportedSyntax));
+    }
+
+    #[test]
+    fn procedural() {
+        use crate::filters::procedural::{ProceduralAction, ProceduralOperator as Op};
+
+        let rule = CosmeticFilter::parse("yandex.*##.serp-item:if(:scope > div.organic div.organic__subtitle:matches-css-after(content: /[Рр]еклама/))", false).unwrap();
+        assert_eq!(rule.selector, ".serp-item:if(:scope > div.organic div.organic__subtitle:matches-css-after(content: /[Рр]еклама/))");
+        assert_eq!(rule.key, None);
+        assert_eq!(rule.procedural, Some(ProceduralFilter {
+            selector: vec![
+                Op::CssSelector(".serp-item".to_string()),
+                Op::Has(vec![
+                    Op::CssSelector(":scope > div.organic div.organic__subtitle".to_string()),
+                    Op::MatchesCssAfter("content: /[Рр]еклама/".to_string()),
+                ]),
+            ],
+            action: None,
+        }));
+
+        let rule = CosmeticFilter::parse(r#"facebook.com,facebookcorewwwi.onion##.ego_column:if(a[href^="/campaign/landing"])"#, false).unwrap();
+        assert!(rule.procedural.is_some());
+        let rule = CosmeticFilter::parse(r#"thedailywtf.com##.article-body > div:has(a[href*="utm_medium"])"#, false).unwrap();
+        assert!(rule.procedural.is_some());
+
+        let rule = CosmeticFilter::parse("twitter.com##article:has-text(/Promoted|Gesponsert|Реклама|Promocionado/):xpath(../..)", false).unwrap();
+        assert_eq!(rule.procedural, Some(ProceduralFilter {
+            selector: vec![
+                Op::CssSelector("article".to_string()),
+                Op::HasText("/Promoted|Gesponsert|Реклама|Promocionado/".to_string()),
+                Op::Xpath("../..".to_string()),
+            ],
+            action: None,
+        }));
+
+        let rule = CosmeticFilter::parse("example.com##.ad:has-text(Sponsored):style(opacity: 0 !important)", false).unwrap();
+        assert_eq!(rule.style, None);
+        assert_eq!(rule.procedural.unwrap().action, Some(ProceduralAction::Style("opacity: 0 !important".to_string())));
+
+        // plain selectors are unaffected
+        let rule = CosmeticFilter::parse("example.com##.ad:style(opacity: 0)", false).unwrap();
+        assert_eq!(rule.procedural, None);
+        assert_eq!(rule.style, Some("opacity: 0".to_string()));
+
+        let rule = CosmeticFilter::parse("example.com##div.post:not(.pinned:has-text(Sponsored))", false).unwrap();
+        assert_eq!(rule.procedural, Some(ProceduralFilter {
+            selector: vec![
+                Op::CssSelector("div.post".to_string()),
+                Op::Not(vec![
+                    Op::CssSelector(".pinned".to_string()),
+                    Op::HasText("Sponsored".to_string()),
+                ]),
+            ],
+            action: None,
+        }));
+
+        assert_eq!(CosmeticFilter::parse("##.ad:has-text(Sponsored)", false).err(), Some(CosmeticFilterError::GenericProcedural));
+        assert_eq!(CosmeticFilter::parse("~example.com##.ad:remove()", false).err(), Some(CosmeticFilterError::GenericProcedural));
+    }
+
     #[test]
     fn hidden_generic() {
         let rule = CosmeticFilter::parse("##.selector", false).unwrap();
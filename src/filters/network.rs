@@ -1,9 +1,9 @@
 use regex::{Regex, RegexSet};
 use serde::{Deserialize, Serialize};
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 
 use std::fmt;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 
 use crate::request;
 use crate::utils;
@@ -21,18 +21,138 @@ pub enum NetworkFilterError {
     NegatedRedirection,
     NegatedTag,
     NegatedGenericHide,
+    NegatedSpecificHide,
+    NegatedElemHide,
+    NegatedCsp,
+    NegatedDomain,
+    NegatedClientTag,
     GenericHideWithoutException,
+    SpecificHideWithoutException,
     EmptyRedirection,
     UnrecognisedOption,
     NoRegex,
     FullRegexUnsupported,
     RegexParsingError(regex::Error),
     PunycodeError,
+    DuplicateOption,
+    /// `NetworkFilter::parse_popup` was given a rule without the `$popup` option.
+    NotPopup,
+    NegatedDnsType,
+    NegatedDnsRewrite,
+    /// A `$dnsrewrite` value that is neither a known response code, an IP address, a hostname,
+    /// nor of the form `rcode;type;value`.
+    InvalidDnsRewrite,
+    /// `$~removeparam`. Parameters to keep are given with `$removeparam=~name` instead.
+    NegatedRemoveParam,
+    NegatedRemoveHeader,
+    /// A `$removeheader` filter without a header name. Only exceptions may omit it.
+    EmptyRemoveHeader,
+    NegatedDenyAllow,
+    /// A `$denyallow` value that is empty, or has a negated or wildcard domain.
+    InvalidDenyAllow,
+}
+
+/// How options given more than once in a filter are handled, e.g. `$domain=a.com,domain=b.com`.
+/// Repeating an option without a value, like `$script,script`, is always accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DuplicateOptions {
+    /// The last occurrence replaces the previous ones. This is the default.
+    LastWins,
+    /// The filter is rejected with `NetworkFilterError::DuplicateOption`.
+    Error,
+}
+
+/// Settings affecting how network filters are parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ParseOptions {
+    pub duplicate_options: DuplicateOptions,
+    /// Whether to accept the `$dnstype` and `$dnsrewrite` options of AdGuard Home lists, which
+    /// only apply to DNS queries. Otherwise, they are rejected as unrecognised.
+    pub dns_modifiers: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            duplicate_options: DuplicateOptions::LastWins,
+            dns_modifiers: false,
+        }
+    }
+}
+
+/// DNS response codes which can be given on their own as the value of `$dnsrewrite`.
+const DNS_RCODES: [&str; 4] = ["NOERROR", "NXDOMAIN", "REFUSED", "SERVFAIL"];
+
+/// The response to a DNS query given by a `$dnsrewrite` filter, e.g. `NOERROR;A;1.2.3.4`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DnsRewrite {
+    /// Response code, like `NOERROR` or `NXDOMAIN`. Always uppercase.
+    pub rcode: String,
+    /// Type of the answer record, like `A` or `CNAME`. Always uppercase. `None` for responses
+    /// without an answer.
+    pub record_type: Option<String>,
+    /// Value of the answer record, like `1.2.3.4` or `example.net`.
+    pub value: Option<String>,
+}
+
+impl DnsRewrite {
+    /// Parses the value of a `$dnsrewrite` option, either in full as `rcode;type;value`, or in one
+    /// of its short forms: a response code, an IPv4 or IPv6 address for an `A` or `AAAA` answer,
+    /// or a hostname for a `CNAME` answer.
+    pub fn parse(value: &str) -> Result<Self, NetworkFilterError> {
+        let parts: Vec<&str> = value.split(';').map(str::trim).collect();
+        match parts.as_slice() {
+            [rcode, record_type, record_value] => {
+                let rcode = rcode.to_ascii_uppercase();
+                if rcode.is_empty() || !rcode.chars().all(|c| c.is_ascii_alphabetic()) {
+                    return Err(NetworkFilterError::InvalidDnsRewrite);
+                }
+                let non_empty = |part: &str| if part.is_empty() { None } else { Some(part.to_string()) };
+                Ok(Self {
+                    rcode,
+                    record_type: non_empty(*record_type).map(|t| t.to_ascii_uppercase()),
+                    value: non_empty(*record_value),
+                })
+            }
+            [value] => {
+                let answer = |record_type: &str, value: String| Self {
+                    rcode: "NOERROR".to_string(),
+                    record_type: Some(record_type.to_string()),
+                    value: Some(value),
+                };
+                let uppercase = value.to_ascii_uppercase();
+                if DNS_RCODES.contains(&uppercase.as_str()) {
+                    Ok(Self { rcode: uppercase, record_type: None, value: None })
+                } else if let Ok(ip) = value.parse::<std::net::IpAddr>() {
+                    Ok(answer(if ip.is_ipv4() { "A" } else { "AAAA" }, ip.to_string()))
+                } else if !value.is_empty() && value.split('.').all(|label| {
+                    !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                }) {
+                    Ok(answer("CNAME", value.to_ascii_lowercase()))
+                } else {
+                    Err(NetworkFilterError::InvalidDnsRewrite)
+                }
+            }
+            _ => Err(NetworkFilterError::InvalidDnsRewrite),
+        }
+    }
+}
+
+impl fmt::Display for DnsRewrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{};{};{}",
+            self.rcode,
+            self.record_type.as_deref().unwrap_or_default(),
+            self.value.as_deref().unwrap_or_default(),
+        )
+    }
 }
 
 bitflags::bitflags! {
     #[derive(Serialize, Deserialize)]
-    pub struct NetworkFilterMask: u32 {
+    pub struct NetworkFilterMask: u64 {
         const FROM_IMAGE = 1; // 1 << 0;
         const FROM_MEDIA = 1 << 1;
         const FROM_OBJECT = 1 << 2;
@@ -54,9 +174,21 @@ bitflags::bitflags! {
         const _EXPLICIT_CANCEL = 1 << 26;   // Unused
         const BAD_FILTER = 1 << 27;
         const GENERIC_HIDE = 1 << 30;
+        const SPECIFIC_HIDE = 1 << 31;
 
         // full document rules tend to be handled differently
         const FROM_DOCUMENT = 1 << 29;
+        // `$~document`, which prevents exceptions from applying to documents
+        const EXCLUDE_DOCUMENT = 1 << 32;
+        // `$popup`, for pages opened in new tabs or windows. Not part of `FROM_ANY`, so that only
+        // popup filters apply to popups.
+        const FROM_POPUP = 1 << 33;
+        // `$dnsrewrite`, for filters answering DNS queries rather than blocking them
+        const IS_DNS_REWRITE = 1 << 34;
+        // `$removeparam`, for filters removing query parameters from URLs rather than blocking them
+        const IS_REMOVEPARAM = 1 << 35;
+        // `$removeheader`, for filters removing HTTP headers rather than blocking requests
+        const IS_REMOVEHEADER = 1 << 36;
 
         // Kind of pattern
         const IS_REGEX = 1 << 18;
@@ -115,12 +247,16 @@ impl From<&request::RequestType> for NetworkFilterMask {
             request::RequestType::Object => NetworkFilterMask::FROM_OBJECT,
             request::RequestType::Other => NetworkFilterMask::FROM_OTHER,
             request::RequestType::Ping => NetworkFilterMask::FROM_PING,
+            request::RequestType::Prefetch => NetworkFilterMask::FROM_OTHER,
             request::RequestType::Script => NetworkFilterMask::FROM_SCRIPT,
+            request::RequestType::SignedExchange => NetworkFilterMask::FROM_OTHER,
+            request::RequestType::Speculative => NetworkFilterMask::FROM_OTHER,
             request::RequestType::Stylesheet => NetworkFilterMask::FROM_STYLESHEET,
             request::RequestType::Subdocument => NetworkFilterMask::FROM_SUBDOCUMENT,
             request::RequestType::Websocket => NetworkFilterMask::FROM_WEBSOCKET,
             request::RequestType::Xlst => NetworkFilterMask::FROM_OTHER,
             request::RequestType::Xmlhttprequest => NetworkFilterMask::FROM_XMLHTTPREQUEST,
+            request::RequestType::Popup => NetworkFilterMask::FROM_POPUP,
         }
     }
 }
@@ -176,7 +312,13 @@ impl FilterPart {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Filters without a known list position (e.g. added individually, or deserialized from an older
+/// format) are ordered after all positioned filters.
+fn unpositioned() -> u32 {
+    u32::MAX
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct NetworkFilter {
     pub mask: NetworkFilterMask,
     pub filter: FilterPart,
@@ -190,6 +332,9 @@ pub struct NetworkFilter {
 
     pub raw_line: Option<String>,
 
+    // Stable identifier, computed from the parsed form of the filter rather than its text. Rules
+    // that only differ in formatting, like the order of their options, share the same id, which
+    // is the same on every machine. Filters merged by the optimizer keep the id of one of them.
     pub id: Hash,
     // Unused, kept to retain backwards-compatibility
     _fuzzy_signature: Option<Vec<Hash>>,
@@ -198,20 +343,103 @@ pub struct NetworkFilter {
     pub opt_domains_union: Option<Hash>,
     pub opt_not_domains_union: Option<Hash>,
 
-    // Regex compild lazily, using "Interior Mutability"
+    // Client tags from `$ctag`. The filter only matches clients with one of `opt_client_tags`,
+    // and never clients with one of `opt_not_client_tags`
+    #[serde(default)]
+    pub opt_client_tags: Option<Vec<Hash>>,
+    #[serde(default)]
+    pub opt_not_client_tags: Option<Vec<Hash>>,
+
+    // Position of the filter within the lists it was loaded from, used to pick a stable winner
+    // when several filters from the same list match a request
+    #[serde(default = "unpositioned")]
+    pub position: u32,
+
+    // DNS record types from `$dnstype`, in uppercase, which restrict the filter to DNS queries
+    // for (or not for) one of the given types
+    #[serde(default)]
+    pub opt_dns_types: Option<Vec<String>>,
+    #[serde(default)]
+    pub opt_not_dns_types: Option<Vec<String>>,
+    // Response given by a `$dnsrewrite` filter. `None` for exceptions disabling all rewrites.
+    #[serde(default)]
+    pub dns_rewrite: Option<DnsRewrite>,
+    // Query parameters removed by a `$removeparam` filter: a name, a `/regex/` matched against
+    // `name=value`, or either of these prefixed with `~` to remove every other parameter. `None`
+    // removes all parameters, or for exceptions, disables all removals.
+    #[serde(default)]
+    pub removeparam: Option<String>,
+    // Lowercased name of the header removed by a `$removeheader` filter, prefixed with `request:`
+    // for request headers as in AdGuard's syntax. `None` for exceptions disabling all removals.
+    #[serde(default)]
+    pub removeheader: Option<String>,
+    // Sorted hashes of the domains given to `$denyallow`. Requests to these domains, or to their
+    // subdomains, are never matched by the filter.
+    #[serde(default)]
+    pub opt_denyallow: Option<Vec<Hash>>,
+    // `$domain` values as written, sorted, with `~` for excluded domains. Only kept in debug mode,
+    // for exporters that need the domain names rather than their hashes.
+    #[serde(default)]
+    pub opt_domain_names: Option<Vec<String>>,
+
+    // Regex compiled lazily, using "Interior Mutability"
     // Arc (Atomic Reference Counter) allows for cloned NetworkFilters
-    // to point to the same RwLock and what is inside.
-    // RwLock allows for concurrent access when reading as well as writing
-    // from the inside.
-    // When the Regex hasn't been compiled, <None> is stored, afterwards Arc to Some<CompiledRegex>
-    // to avoid expensive cloning of the Regex itself.
+    // to point to the same OnceCell and what is inside.
+    // Once initialized, the OnceCell is read with a single atomic load and no locking, so threads
+    // matching concurrently never wait on each other. Only threads racing to compile the same
+    // regex block, until the first of them has finished compiling it.
+    // The compiled regex is stored in an Arc to avoid expensive cloning of the Regex itself.
+    #[serde(skip_serializing, skip_deserializing)]
+    regex: Arc<OnceCell<Arc<CompiledRegex>>>,
+
+    // Result of `get_tokens`, only kept for filters that are reused across builds by a
+    // `FilterParseCache`. Shared between clones, like `regex`.
     #[serde(skip_serializing, skip_deserializing)]
-    regex: Arc<RwLock<Option<Arc<CompiledRegex>>>>
+    tokens: Option<Arc<OnceCell<Vec<Vec<Hash>>>>>,
+
+    // `/regex/` value of `removeparam`, compiled lazily and shared between clones, like `regex`.
+    #[serde(skip_serializing, skip_deserializing)]
+    removeparam_regex: Arc<OnceCell<Option<Regex>>>,
 }
 
 impl NetworkFilter {
     #[allow(clippy::cognitive_complexity)]
     pub fn parse(line: &str, debug: bool) -> Result<Self, NetworkFilterError> {
+        Self::parse_with_options(line, debug, &ParseOptions::default())
+    }
+
+    /// Parses a rule with the `$popup` option, which applies to pages opened in new tabs or windows
+    /// rather than to requests. `parse` rejects such rules, so that they never affect requests.
+    /// The resulting filter only matches requests of type `RequestType::Popup`, regardless of any
+    /// other request type options.
+    pub fn parse_popup(line: &str, debug: bool) -> Result<Self, NetworkFilterError> {
+        let options_index = twoway::rfind_str(line, "$").ok_or(NetworkFilterError::NotPopup)?;
+        let (popup, others): (Vec<&str>, Vec<&str>) = split_options(strip_inline_comment(&line[options_index + 1..]))
+            .into_iter()
+            .map(str::trim)
+            .filter(|option| !option.is_empty())
+            .partition(|option| *option == "popup");
+        if popup.is_empty() {
+            return Err(NetworkFilterError::NotPopup);
+        }
+
+        let pattern = &line[..options_index];
+        let mut filter = if others.is_empty() {
+            Self::parse(pattern, debug)?
+        } else {
+            Self::parse(&format!("{}${}", pattern, others.join(",")), debug)?
+        };
+        filter.mask.set(NetworkFilterMask::FROM_ANY | NetworkFilterMask::FROM_DOCUMENT, false);
+        filter.mask.set(NetworkFilterMask::FROM_POPUP, true);
+        if debug {
+            filter.raw_line = Some(line.to_string());
+        }
+        filter.id = filter.compute_stable_id();
+        Ok(filter)
+    }
+
+    /// Like `parse`, with non-default `ParseOptions`.
+    pub fn parse_with_options(line: &str, debug: bool, parse_options: &ParseOptions) -> Result<Self, NetworkFilterError> {
         // Represent options as a bitmask
         let mut mask: NetworkFilterMask = NetworkFilterMask::THIRD_PARTY
             | NetworkFilterMask::FIRST_PARTY
@@ -221,7 +449,7 @@ impl NetworkFilter {
         // Temporary masks for positive (e.g.: $script) and negative (e.g.: $~script)
         // content type options.
         let mut cpt_mask_positive: NetworkFilterMask = NetworkFilterMask::NONE;
-        let mut cpt_mask_negative: NetworkFilterMask = NetworkFilterMask::FROM_ANY;
+        let mut cpt_mask_negative: NetworkFilterMask = NetworkFilterMask::FROM_ANY | NetworkFilterMask::FROM_DOCUMENT;
 
         let mut hostname: Option<String> = None;
 
@@ -229,6 +457,17 @@ impl NetworkFilter {
         let mut opt_not_domains: Option<Vec<Hash>> = None;
         let mut opt_domains_union: Option<Hash> = None;
         let mut opt_not_domains_union: Option<Hash> = None;
+        let mut opt_domain_names: Option<Vec<String>> = None;
+
+        let mut opt_client_tags: Option<Vec<Hash>> = None;
+        let mut opt_not_client_tags: Option<Vec<Hash>> = None;
+
+        let mut opt_dns_types: Option<Vec<String>> = None;
+        let mut opt_not_dns_types: Option<Vec<String>> = None;
+        let mut dns_rewrite: Option<DnsRewrite> = None;
+        let mut removeparam: Option<String> = None;
+        let mut removeheader: Option<String> = None;
+        let mut opt_denyallow: Option<Vec<Hash>> = None;
 
         let mut redirect: Option<String> = None;
         let mut csp: Option<String> = None;
@@ -258,36 +497,61 @@ impl NetworkFilter {
 
             // Parse Options
             let raw_options = &line[filter_index_end + 1..];    // safe, first character after '$' will be char boundary
-            let options = raw_options.split(',');
+            let options = split_options(strip_inline_comment(raw_options));
+            let mut options_with_values: Vec<&str> = Vec::new();
             for raw_option in options {
+                // Stray whitespace and empty options, e.g. from trailing commas, are ignored
+                let raw_option = raw_option.trim();
+                if raw_option.is_empty() {
+                    continue;
+                }
+
                 // Check for negation: ~option
                 let negation = raw_option.starts_with('~');
-                let maybe_negated_option = raw_option.trim_start_matches('~');
+                let maybe_negated_option = if negation { &raw_option[1..] } else { raw_option };
 
                 // Check for options: option=value1|value2
                 let mut option_and_values = maybe_negated_option.splitn(2, '=');
                 let (option, value) = (
-                    option_and_values.next().unwrap(),
-                    option_and_values.next().unwrap_or_default(),
+                    option_and_values.next().unwrap().trim(),
+                    option_and_values.next().unwrap_or_default().trim(),
                 );
 
+                if matches!(option, "domain" | "ctag" | "bug" | "tag" | "redirect" | "csp" | "dnstype" | "dnsrewrite" | "removeparam" | "removeheader" | "denyallow") {
+                    if options_with_values.contains(&option) && parse_options.duplicate_options == DuplicateOptions::Error {
+                        return Err(NetworkFilterError::DuplicateOption);
+                    }
+                    options_with_values.push(option);
+                }
+
                 match (option, negation) {
-                    ("domain", _) => {
-                        let mut option_values: Vec<&str> = value.split('|').collect();
+                    ("domain", true) => return Err(NetworkFilterError::NegatedDomain),
+                    ("domain", false) => {
+                        opt_domains = None;
+                        opt_not_domains = None;
+                        opt_domains_union = None;
+                        opt_not_domains_union = None;
+
+                        let mut option_values: Vec<&str> = value.split('|').map(str::trim).filter(|v| !v.is_empty()).collect();
                         // Some rules have duplicate domain options - avoid including duplicates
                         // Benchmarking doesn't indicate signficant performance degradation across the entire easylist
                         option_values.sort_unstable();
                         option_values.dedup();
+                        opt_domain_names = if debug {
+                            Some(option_values.iter().map(|v| v.to_string()).collect())
+                        } else {
+                            None
+                        };
                         let mut opt_domains_array: Vec<Hash> = vec![];
                         let mut opt_not_domains_array: Vec<Hash> = vec![];
 
                         for option_value in option_values {
                             if option_value.starts_with('~') {
                                 let domain = &option_value[1..];
-                                let domain_hash = utils::fast_hash(&domain);
+                                let domain_hash = utils::domain_option_hash(domain);
                                 opt_not_domains_array.push(domain_hash);
                             } else {
-                                let domain_hash = utils::fast_hash(&option_value);
+                                let domain_hash = utils::domain_option_hash(option_value);
                                 opt_domains_array.push(domain_hash);
                             }
                         }
@@ -303,6 +567,61 @@ impl NetworkFilter {
                             opt_not_domains = Some(opt_not_domains_array);
                         }
                     }
+                    ("ctag", true) => return Err(NetworkFilterError::NegatedClientTag),
+                    ("ctag", false) => {
+                        opt_client_tags = None;
+                        opt_not_client_tags = None;
+
+                        let mut client_tags: Vec<Hash> = vec![];
+                        let mut not_client_tags: Vec<Hash> = vec![];
+                        for option_value in value.split('|').map(str::trim) {
+                            if let Some(client_tag) = option_value.strip_prefix('~') {
+                                not_client_tags.push(utils::fast_hash(client_tag));
+                            } else if !option_value.is_empty() {
+                                client_tags.push(utils::fast_hash(option_value));
+                            }
+                        }
+                        client_tags.sort_unstable();
+                        client_tags.dedup();
+                        not_client_tags.sort_unstable();
+                        not_client_tags.dedup();
+                        if !client_tags.is_empty() {
+                            opt_client_tags = Some(client_tags);
+                        }
+                        if !not_client_tags.is_empty() {
+                            opt_not_client_tags = Some(not_client_tags);
+                        }
+                    }
+                    ("dnstype", _) | ("dnsrewrite", _) if !parse_options.dns_modifiers => {
+                        return Err(NetworkFilterError::UnrecognisedOption);
+                    }
+                    ("dnstype", true) => return Err(NetworkFilterError::NegatedDnsType),
+                    ("dnstype", false) => {
+                        let mut dns_types: Vec<String> = vec![];
+                        let mut not_dns_types: Vec<String> = vec![];
+                        for option_value in value.split('|').map(str::trim) {
+                            if let Some(dns_type) = option_value.strip_prefix('~') {
+                                not_dns_types.push(dns_type.to_ascii_uppercase());
+                            } else if !option_value.is_empty() {
+                                dns_types.push(option_value.to_ascii_uppercase());
+                            }
+                        }
+                        dns_types.sort_unstable();
+                        dns_types.dedup();
+                        not_dns_types.sort_unstable();
+                        not_dns_types.dedup();
+                        opt_dns_types = if dns_types.is_empty() { None } else { Some(dns_types) };
+                        opt_not_dns_types = if not_dns_types.is_empty() { None } else { Some(not_dns_types) };
+                    }
+                    ("dnsrewrite", true) => return Err(NetworkFilterError::NegatedDnsRewrite),
+                    ("dnsrewrite", false) => {
+                        mask.set(NetworkFilterMask::IS_DNS_REWRITE, true);
+                        dns_rewrite = None;
+                        // `@@...$dnsrewrite` disables all rewrites, like `@@...$csp`
+                        if !value.is_empty() || !mask.contains(NetworkFilterMask::IS_EXCEPTION) {
+                            dns_rewrite = Some(DnsRewrite::parse(value)?);
+                        }
+                    }
                     ("badfilter", false) => mask.set(NetworkFilterMask::BAD_FILTER, true),
                     ("badfilter", true) => return Err(NetworkFilterError::NegatedBadFilter),
                     // Note: `negation` should always be `false` here.
@@ -336,16 +655,66 @@ impl NetworkFilter {
 
                         redirect = Some(String::from(value));
                     }
-                    ("csp", _) => {
+                    ("csp", true) => return Err(NetworkFilterError::NegatedCsp),
+                    ("csp", false) => {
                         mask.set(NetworkFilterMask::IS_CSP, true);
-                        if !value.is_empty() {
+                        csp = None;
+                        // `@@...$csp=none` and `@@...$csp=*` disable all injections, like `@@...$csp`
+                        let wildcard = mask.contains(NetworkFilterMask::IS_EXCEPTION) && (value == "none" || value == "*");
+                        if !value.is_empty() && !wildcard {
                             csp = Some(String::from(value));
                         }
                     }
+                    // `$queryprune` is the former name of `$removeparam` in uBO
+                    ("removeparam", true) | ("queryprune", true) => return Err(NetworkFilterError::NegatedRemoveParam),
+                    ("removeparam", false) | ("queryprune", false) => {
+                        mask.set(NetworkFilterMask::IS_REMOVEPARAM, true);
+                        removeparam = None;
+                        if !value.is_empty() {
+                            let pattern = value.strip_prefix('~').unwrap_or(value);
+                            if pattern.len() > 1 && pattern.starts_with('/') && pattern.ends_with('/') {
+                                Regex::new(&pattern[1..pattern.len() - 1]).map_err(NetworkFilterError::RegexParsingError)?;
+                            }
+                            removeparam = Some(String::from(value));
+                        }
+                    }
+                    ("removeheader", true) => return Err(NetworkFilterError::NegatedRemoveHeader),
+                    ("removeheader", false) => {
+                        mask.set(NetworkFilterMask::IS_REMOVEHEADER, true);
+                        removeheader = None;
+                        if !value.is_empty() {
+                            removeheader = Some(value.to_ascii_lowercase());
+                        } else if !mask.contains(NetworkFilterMask::IS_EXCEPTION) {
+                            return Err(NetworkFilterError::EmptyRemoveHeader);
+                        }
+                    }
+                    ("denyallow", true) => return Err(NetworkFilterError::NegatedDenyAllow),
+                    ("denyallow", false) => {
+                        let domains: Vec<&str> = value.split('|').map(str::trim).collect();
+                        if domains.iter().any(|d| d.is_empty() || d.starts_with('~') || d.contains('*')) {
+                            return Err(NetworkFilterError::InvalidDenyAllow);
+                        }
+                        let mut hashes: Vec<Hash> = domains
+                            .iter()
+                            .map(|d| utils::domain_option_hash(&d.to_ascii_lowercase()))
+                            .collect();
+                        hashes.sort_unstable();
+                        hashes.dedup();
+                        opt_denyallow = Some(hashes);
+                    }
                     ("generichide", true) => return Err(NetworkFilterError::NegatedGenericHide),
                     ("generichide", false) => mask.set(NetworkFilterMask::GENERIC_HIDE, true),
                     ("ghide", true) => return Err(NetworkFilterError::NegatedGenericHide),
                     ("ghide", false) => mask.set(NetworkFilterMask::GENERIC_HIDE, true),
+                    ("specifichide", true) => return Err(NetworkFilterError::NegatedSpecificHide),
+                    ("specifichide", false) => mask.set(NetworkFilterMask::SPECIFIC_HIDE, true),
+                    ("shide", true) => return Err(NetworkFilterError::NegatedSpecificHide),
+                    ("shide", false) => mask.set(NetworkFilterMask::SPECIFIC_HIDE, true),
+                    // `$elemhide` disables both generic and specific element hiding
+                    ("elemhide", true) => return Err(NetworkFilterError::NegatedElemHide),
+                    ("elemhide", false) => mask.set(NetworkFilterMask::GENERIC_HIDE | NetworkFilterMask::SPECIFIC_HIDE, true),
+                    ("ehide", true) => return Err(NetworkFilterError::NegatedElemHide),
+                    ("ehide", false) => mask.set(NetworkFilterMask::GENERIC_HIDE | NetworkFilterMask::SPECIFIC_HIDE, true),
                     (_, negation) => {
                         // Handle content type options separatly
                         let mut option_mask = NetworkFilterMask::NONE;
@@ -358,7 +727,7 @@ impl NetworkFilter {
                             "script" => option_mask.set(NetworkFilterMask::FROM_SCRIPT, true),
                             "css" | "stylesheet" => option_mask.set(NetworkFilterMask::FROM_STYLESHEET, true),
                             "frame" | "subdocument" => option_mask.set(NetworkFilterMask::FROM_SUBDOCUMENT, true),
-                            "main_frame" | "document" => option_mask.set(NetworkFilterMask::FROM_DOCUMENT, true),
+                            "main_frame" | "document" | "doc" => option_mask.set(NetworkFilterMask::FROM_DOCUMENT, true),
                             "xhr" | "xmlhttprequest" => option_mask.set(NetworkFilterMask::FROM_XMLHTTPREQUEST, true),
                             "websocket" => option_mask.set(NetworkFilterMask::FROM_WEBSOCKET, true),
                             "font" => option_mask.set(NetworkFilterMask::FROM_FONT, true),
@@ -379,14 +748,23 @@ impl NetworkFilter {
             // --------------------------------------------------------------------- //
         }
 
+        // `$csp` and `$removeparam` filters only apply to documents unless other types are
+        // explicitly given
+        if mask.intersects(NetworkFilterMask::IS_CSP | NetworkFilterMask::IS_REMOVEPARAM) && cpt_mask_positive.is_empty() {
+            cpt_mask_positive = NetworkFilterMask::FROM_DOCUMENT | NetworkFilterMask::FROM_SUBDOCUMENT;
+        }
+
         if cpt_mask_positive.is_empty() {
-            mask |= cpt_mask_negative;
-        } else if cpt_mask_negative.contains(NetworkFilterMask::FROM_ANY) {
-            mask |= cpt_mask_positive;
+            // Negated types only narrow down the default types, which do not include documents
+            mask |= cpt_mask_negative & NetworkFilterMask::FROM_ANY;
         } else {
             mask |= cpt_mask_positive & cpt_mask_negative;
         }
 
+        if !cpt_mask_negative.contains(NetworkFilterMask::FROM_DOCUMENT) {
+            mask.set(NetworkFilterMask::EXCLUDE_DOCUMENT, true);
+        }
+
         // Identify kind of pattern
 
         if line[filter_index_start..].starts_with("||") {
@@ -406,7 +784,9 @@ impl NetworkFilter {
         let is_regex = check_is_regex(&line[filter_index_start..filter_index_end]);
         mask.set(NetworkFilterMask::IS_REGEX, is_regex);
 
-        if line[filter_index_start..filter_index_end].starts_with('/')
+        // A lone `/` is a plain pattern rather than an empty regex
+        if filter_index_end - filter_index_start > 2
+            && line[filter_index_start..filter_index_end].starts_with('/')
             && line[filter_index_start..filter_index_end].ends_with('/')
         {
             #[cfg(feature = "full-regex-handling")]
@@ -438,8 +818,10 @@ impl NetworkFilter {
                         mask.set(NetworkFilterMask::IS_HOSTNAME_REGEX, true);
                     }
 
-                    hostname = Some(String::from(&line[filter_index_start..first_separator_start]));
-                    filter_index_start = first_separator_start;
+                    // An explicit port is matched as part of the pattern, e.g. `:8080^`
+                    let hostname_end = filter_index_start + hostname_without_port(&line[filter_index_start..first_separator_start]).len();
+                    hostname = Some(String::from(&line[filter_index_start..hostname_end]));
+                    filter_index_start = hostname_end;
 
                     // If the only symbol remaining for the selector is '^' then ignore it
                     // but set the filter as right anchored since there should not be any
@@ -461,19 +843,13 @@ impl NetworkFilter {
             } else {
                 // Look for next /
                 let slash_index = twoway::find_str(&line[filter_index_start..], "/");
-                slash_index
-                    .map(|i| {
-                        hostname = Some(String::from(
-                            &line[filter_index_start..filter_index_start + i],
-                        ));
-                        filter_index_start += i;
-                        mask.set(NetworkFilterMask::IS_LEFT_ANCHOR, true);
-                    })
-                    .or_else(|| {
-                        hostname = Some(String::from(&line[filter_index_start..filter_index_end]));
-                        filter_index_start = filter_index_end;
-                        None
-                    });
+                let hostname_end = slash_index.map(|i| filter_index_start + i).unwrap_or(filter_index_end);
+                let hostname_end = filter_index_start + hostname_without_port(&line[filter_index_start..hostname_end]).len();
+                hostname = Some(String::from(&line[filter_index_start..hostname_end]));
+                if slash_index.is_some() || hostname_end < filter_index_end {
+                    mask.set(NetworkFilterMask::IS_LEFT_ANCHOR, true);
+                }
+                filter_index_start = hostname_end;
             }
         }
 
@@ -544,7 +920,8 @@ impl NetworkFilter {
                 &host
             };
 
-            let lowercase = hostname_normalised.to_lowercase();
+            // Only ASCII letters are folded here; IDNA mapping takes care of other characters
+            let lowercase = hostname_normalised.to_ascii_lowercase();
             let mut hostname = String::new();
             if lowercase.is_ascii() {
                 hostname.push_str(&lowercase);
@@ -561,7 +938,11 @@ impl NetworkFilter {
             return Err(NetworkFilterError::GenericHideWithoutException);
         }
 
-        Ok(NetworkFilter {
+        if mask.contains(NetworkFilterMask::SPECIFIC_HIDE) && !mask.contains(NetworkFilterMask::IS_EXCEPTION) {
+            return Err(NetworkFilterError::SpecificHideWithoutException);
+        }
+
+        let mut filter = NetworkFilter {
             bug,
             csp,
             filter: if let Some(simple_filter) = filter {
@@ -580,12 +961,26 @@ impl NetworkFilter {
                 None
             },
             redirect,
-            id: utils::fast_hash(&line),
+            id: 0,
             _fuzzy_signature: None,
             opt_domains_union,
             opt_not_domains_union,
-            regex: Arc::new(RwLock::new(None))
-        })
+            opt_client_tags,
+            opt_not_client_tags,
+            position: unpositioned(),
+            opt_dns_types,
+            opt_not_dns_types,
+            dns_rewrite,
+            removeparam,
+            removeheader,
+            opt_denyallow,
+            opt_domain_names,
+            regex: Arc::new(OnceCell::new()),
+            tokens: None,
+            removeparam_regex: Arc::new(OnceCell::new()),
+        };
+        filter.id = filter.compute_stable_id();
+        Ok(filter)
     }
 
     /// Given a hostname, produces an equivalent filter parsed from the form `"||hostname^"`, to
@@ -603,7 +998,7 @@ impl NetworkFilter {
         }
 
         // Normalize the hostname to punycode and parse it as a `||hostname^` rule.
-        let normalized_host = hostname.to_lowercase();
+        let normalized_host = hostname.to_ascii_lowercase();
         let normalized_host = normalized_host.trim_start_matches("www.");
 
         let mut hostname = "||".to_string();
@@ -633,6 +1028,65 @@ impl NetworkFilter {
         )
     }
 
+    /// Key used to choose between several matching filters of the same kind: the filter that
+    /// appeared first in the loaded lists wins, with the filter id as a final tie-breaker. This
+    /// keeps results stable across rebuilds and serialization round-trips, unlike the iteration
+    /// order of the internal token buckets.
+    pub(crate) fn precedence_key(&self) -> (u32, Hash) {
+        (self.position, self.id)
+    }
+
+    /// Sets the `$tag` of this filter, as if it had been given in the rule itself.
+    pub(crate) fn set_tag(&mut self, tag: &str) {
+        self.tag = Some(tag.to_string());
+        self.id = self.compute_stable_id();
+    }
+
+    /// Extends `get_id` with the options it doesn't cover, to identify the filter as a whole.
+    fn compute_stable_id(&self) -> Hash {
+        let mut hash = self.get_id();
+        let mut mix = |value: Hash| hash = hash.wrapping_mul(33) ^ value;
+        for (marker, value) in [(1, self.redirect.as_deref()), (2, self.tag.as_deref())].iter() {
+            if let Some(value) = value {
+                mix(*marker);
+                value.chars().for_each(|c| mix(c as Hash));
+            }
+        }
+        if let Some(bug) = self.bug {
+            mix(3);
+            mix(Hash::from(bug));
+        }
+        for (marker, tags) in [(4, &self.opt_client_tags), (5, &self.opt_not_client_tags)].iter() {
+            for tag in tags.iter().flatten() {
+                mix(*marker);
+                mix(*tag);
+            }
+        }
+        for (marker, dns_types) in [(6, &self.opt_dns_types), (7, &self.opt_not_dns_types)].iter() {
+            for dns_type in dns_types.iter().flatten() {
+                mix(*marker);
+                dns_type.chars().for_each(|c| mix(c as Hash));
+            }
+        }
+        if let Some(dns_rewrite) = self.dns_rewrite.as_ref() {
+            mix(8);
+            dns_rewrite.to_string().chars().for_each(|c| mix(c as Hash));
+        }
+        if let Some(removeparam) = self.removeparam.as_ref() {
+            mix(9);
+            removeparam.chars().for_each(|c| mix(c as Hash));
+        }
+        if let Some(removeheader) = self.removeheader.as_ref() {
+            mix(10);
+            removeheader.chars().for_each(|c| mix(c as Hash));
+        }
+        for domain in self.opt_denyallow.iter().flatten() {
+            mix(11);
+            mix(*domain);
+        }
+        hash
+    }
+
     pub fn get_id(&self) -> Hash {
         compute_filter_id(
             self.csp.as_deref(),
@@ -645,6 +1099,47 @@ impl NetworkFilter {
     }
 
     pub fn get_tokens(&self) -> Vec<Vec<Hash>> {
+        match &self.tokens {
+            Some(cache) => cache.get_or_init(|| self.compute_tokens()).clone(),
+            None => self.compute_tokens(),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_regex_compiled(&self) -> bool {
+        self.regex.get().is_some()
+    }
+
+    /// Makes this filter and any of its clones remember the result of `get_tokens`.
+    pub(crate) fn cache_tokens(&mut self) {
+        if self.tokens.is_none() {
+            self.tokens = Some(Arc::new(OnceCell::new()));
+        }
+    }
+
+    /// Detaches a clone of another filter from the lazily computed state it shares with the
+    /// original, so that the clone can be modified.
+    pub(crate) fn reset_cached_state(&mut self) {
+        self.regex = Arc::new(OnceCell::new());
+        self.tokens = None;
+        self.removeparam_regex = Arc::new(OnceCell::new());
+    }
+
+    /// The regex given to `$removeparam` as `/regex/` or `~/regex/`, compiled the first time it is
+    /// needed rather than for each request. `None` for parameter names and invalid regexes.
+    pub(crate) fn removeparam_regex(&self) -> Option<&Regex> {
+        self.removeparam_regex.get_or_init(|| {
+            let value = self.removeparam.as_deref()?;
+            let value = value.strip_prefix('~').unwrap_or(value);
+            if value.len() > 1 && value.starts_with('/') && value.ends_with('/') {
+                Regex::new(&value[1..value.len() - 1]).ok()
+            } else {
+                None
+            }
+        }).as_ref()
+    }
+
+    fn compute_tokens(&self) -> Vec<Vec<Hash>> {
         let mut tokens: Vec<Hash> = Vec::with_capacity(TOKENS_BUFFER_SIZE);
 
         // If there is only one domain and no domain negation, we also use this
@@ -672,6 +1167,11 @@ impl NetworkFilter {
                         utils::tokenize_filter(&f, skip_first_token, skip_last_token);
 
                     tokens.append(&mut filter_tokens);
+
+                    // Requests are lowercased, so case-sensitive parameters would not match
+                    if !self.match_case() {
+                        utils::tokenize_filter_query_params(&f, self.is_right_anchor(), &mut tokens);
+                    }
                 }
             }
             FilterPart::AnyOf(_) => (), // across AnyOf set of filters no single token is guaranteed to match to a request
@@ -748,6 +1248,20 @@ impl NetworkFilter {
         self.mask.contains(NetworkFilterMask::GENERIC_HIDE)
     }
 
+    pub fn is_specific_hide(&self) -> bool {
+        self.mask.contains(NetworkFilterMask::SPECIFIC_HIDE)
+    }
+
+    /// Whether this filter explicitly applies to documents, i.e. has the `$document` option.
+    pub fn for_document(&self) -> bool {
+        self.mask.contains(NetworkFilterMask::FROM_DOCUMENT)
+    }
+
+    /// Whether this filter applies to popups, see `NetworkFilter::parse_popup`.
+    pub fn for_popup(&self) -> bool {
+        self.mask.contains(NetworkFilterMask::FROM_POPUP)
+    }
+
     pub fn is_regex(&self) -> bool {
         self.mask.contains(NetworkFilterMask::IS_REGEX)
     }
@@ -756,6 +1270,12 @@ impl NetworkFilter {
         self.mask.contains(NetworkFilterMask::IS_COMPLETE_REGEX)
     }
 
+    /// Whether matching this filter requires compiling a regex, rather than only comparing
+    /// substrings of the request.
+    pub fn uses_regex(&self) -> bool {
+        self.is_regex() || self.is_complete_regex()
+    }
+
     fn is_plain(&self) -> bool {
         !self.is_regex()
     }
@@ -768,6 +1288,127 @@ impl NetworkFilter {
         self.bug.is_some()
     }
 
+    pub fn has_client_tags(&self) -> bool {
+        self.opt_client_tags.is_some() || self.opt_not_client_tags.is_some()
+    }
+
+    /// Whether this filter excludes some request domains with `$denyallow`.
+    pub fn has_denyallow(&self) -> bool {
+        self.opt_denyallow.is_some()
+    }
+
+    /// Whether this filter only applies to DNS queries for some record types, see `$dnstype`.
+    pub fn has_dns_types(&self) -> bool {
+        self.opt_dns_types.is_some() || self.opt_not_dns_types.is_some()
+    }
+
+    /// Whether this filter answers DNS queries with a `$dnsrewrite` response, or disables such
+    /// responses if it is an exception.
+    pub fn is_dns_rewrite(&self) -> bool {
+        self.mask.contains(NetworkFilterMask::IS_DNS_REWRITE)
+    }
+
+    /// Whether this filter removes query parameters from the URLs it matches, or disables such
+    /// removals if it is an exception.
+    pub fn is_removeparam(&self) -> bool {
+        self.mask.contains(NetworkFilterMask::IS_REMOVEPARAM)
+    }
+
+    /// Whether this filter removes an HTTP header from the requests or responses it matches, or
+    /// disables such removals if it is an exception.
+    pub fn is_removeheader(&self) -> bool {
+        self.mask.contains(NetworkFilterMask::IS_REMOVEHEADER)
+    }
+
+    /// Whether this filter applies to entire hostnames, irrespective of the request's path, type,
+    /// party, scheme or initiator, e.g. `||ads.example.com^`. Such filters can be evaluated with
+    /// nothing but a hostname, as is the case for DNS-level blocking.
+    pub fn is_hostname_only(&self) -> bool {
+        matches!(self.filter, FilterPart::Empty)
+            && self.is_hostname_anchor()
+            && !self.is_complete_regex()
+            && self.cpt_any()
+            && self.first_party()
+            && self.third_party()
+            && self.for_http()
+            && self.for_https()
+            && self.opt_domains.is_none()
+            && self.opt_not_domains.is_none()
+            && !self.is_redirect()
+            && !self.is_csp()
+            && !self.is_removeparam()
+            && !self.is_removeheader()
+            && !self.is_generic_hide()
+            && !self.is_specific_hide()
+            && !self.is_badfilter()
+    }
+
+    /// Returns the hostname of filters made of nothing but a hostname of several labels, e.g.
+    /// `||ads.example.com^`. Such filters can only match requests whose hostname contains it as
+    /// whole labels, so they can be found by looking up label-aligned parts of the request
+    /// hostname instead of its tokens.
+    pub(crate) fn indexed_hostname(&self) -> Option<&str> {
+        if !matches!(self.filter, FilterPart::Empty)
+            || !self.is_hostname_anchor()
+            || self.is_complete_regex()
+            || self.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)
+            || self.opt_domains.is_some()
+        {
+            return None;
+        }
+        self.hostname
+            .as_deref()
+            .filter(|hostname| hostname.contains('.') && !hostname.starts_with('.') && !hostname.ends_with('.'))
+    }
+
+    /// Determines whether this filter needs a non-empty URL path to match, and therefore cannot
+    /// match `request`, which must be hostname-only (see `Request::is_hostname_only`). This allows
+    /// skipping most path-based filters, including ones that would otherwise require a regex to
+    /// be compiled, without evaluating them.
+    pub fn requires_path(&self, request: &request::Request) -> bool {
+        self.pattern_requires_path() && self.path_follows_hostname(request)
+    }
+
+    /// The part of `requires_path` that only depends on the filter, so that it can be computed
+    /// once when building a `NetworkFilterList`: whether the pattern has a literal character after
+    /// a `/` following the filter's hostname.
+    pub(crate) fn pattern_requires_path(&self) -> bool {
+        if !self.is_hostname_anchor() || self.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX) || self.hostname.is_none() {
+            return false;
+        }
+
+        // Any literal character after a `/` in the pattern can't be matched.
+        fn part_requires_path(part: &str) -> bool {
+            part.find('/')
+                .map(|i| part[i + 1..].chars().any(|c| !matches!(c, '*' | '^' | '|')))
+                .unwrap_or(false)
+        }
+
+        match &self.filter {
+            FilterPart::Empty => false,
+            FilterPart::Simple(part) => part_requires_path(part),
+            FilterPart::AnyOf(parts) => parts.iter().all(|part| part_requires_path(part)),
+        }
+    }
+
+    /// The part of `requires_path` that depends on the request. The rest of the request hostname
+    /// may be lumped together with the filter pattern, so a `/` in the pattern can only stand for
+    /// the start of the path if the only `/` after the filter's hostname is the one at the very
+    /// end of the URL.
+    pub(crate) fn path_follows_hostname(&self, request: &request::Request) -> bool {
+        let hostname = match self.hostname.as_ref() {
+            Some(hostname) => hostname,
+            None => return false,
+        };
+        // The filter can't match if its hostname isn't in the URL, which `matches` checks
+        let start = match twoway::find_str(&request.url, hostname) {
+            Some(start) => start,
+            None => return false,
+        };
+        let url_after_hostname = &request.url[start + hostname.len()..];
+        url_after_hostname.find('/') == Some(url_after_hostname.len().saturating_sub(1))
+    }
+
     fn cpt_any(&self) -> bool {
         self.get_cpt_mask().contains(NetworkFilterMask::FROM_ANY)
     }
@@ -789,12 +1430,184 @@ impl NetworkFilter {
     }
 }
 
+/// The rule as written in its list if it was parsed in debug mode, or its `canonical_text`
+/// otherwise.
 impl fmt::Display for NetworkFilter {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match self.raw_line.as_ref() {
             Some(r) => write!(f, "{}", r.clone()),
-            None => write!(f, "NetworkFilter"),
+            None => write!(f, "{}", self.canonical_text()),
+        }
+    }
+}
+
+/// Names of the request type options, in the order they are listed in decoded options.
+const REQUEST_TYPE_OPTIONS: [(NetworkFilterMask, &str); 11] = [
+    (NetworkFilterMask::FROM_FONT, "font"),
+    (NetworkFilterMask::FROM_IMAGE, "image"),
+    (NetworkFilterMask::FROM_MEDIA, "media"),
+    (NetworkFilterMask::FROM_OBJECT, "object"),
+    (NetworkFilterMask::FROM_OTHER, "other"),
+    (NetworkFilterMask::FROM_PING, "ping"),
+    (NetworkFilterMask::FROM_SCRIPT, "script"),
+    (NetworkFilterMask::FROM_STYLESHEET, "stylesheet"),
+    (NetworkFilterMask::FROM_SUBDOCUMENT, "subdocument"),
+    (NetworkFilterMask::FROM_WEBSOCKET, "websocket"),
+    (NetworkFilterMask::FROM_XMLHTTPREQUEST, "xmlhttprequest"),
+];
+
+impl NetworkFilter {
+    /// Options of the filter, decoded from its mask and option fields in the syntax of filter
+    /// lists. Domain and client tag options are only stored as hashes, and are summarized by their
+    /// number of values, e.g. `domain=<2 values>`.
+    pub fn decoded_options(&self) -> Vec<String> {
+        let mut options = vec![];
+        if !self.cpt_any() {
+            options.extend(REQUEST_TYPE_OPTIONS
+                .iter()
+                .filter(|(flag, _)| self.mask.contains(*flag))
+                .map(|(_, name)| name.to_string()));
+        }
+        let flags = [
+            (self.for_document(), "document"),
+            (self.for_popup(), "popup"),
+            (self.mask.contains(NetworkFilterMask::EXCLUDE_DOCUMENT), "~document"),
+            (self.third_party() && !self.first_party(), "third-party"),
+            (self.first_party() && !self.third_party(), "first-party"),
+            (self.for_http() && !self.for_https(), "http-only"),
+            (self.for_https() && !self.for_http(), "https-only"),
+            (self.match_case(), "match-case"),
+            (self.is_important(), "important"),
+            (self.is_badfilter(), "badfilter"),
+            (self.is_generic_hide(), "generichide"),
+            (self.is_specific_hide(), "specifichide"),
+        ];
+        options.extend(flags.iter().filter(|(set, _)| *set).map(|(_, name)| name.to_string()));
+
+        let count = |values: &Option<Vec<Hash>>| values.as_ref().map(|values| values.len()).unwrap_or(0);
+        let included = count(&self.opt_domains);
+        let excluded = count(&self.opt_not_domains);
+        if included + excluded > 0 {
+            options.push(format!("domain=<{} values, {} excluded>", included + excluded, excluded));
+        }
+        let included = count(&self.opt_client_tags);
+        let excluded = count(&self.opt_not_client_tags);
+        if included + excluded > 0 {
+            options.push(format!("ctag=<{} values, {} excluded>", included + excluded, excluded));
+        }
+        if self.has_denyallow() {
+            options.push(format!("denyallow=<{} values>", count(&self.opt_denyallow)));
+        }
+        let mut dns_types: Vec<String> = self.opt_dns_types.iter().flatten().cloned().collect();
+        dns_types.extend(self.opt_not_dns_types.iter().flatten().map(|t| format!("~{}", t)));
+        if !dns_types.is_empty() {
+            options.push(format!("dnstype={}", dns_types.join("|")));
+        }
+        if self.is_dns_rewrite() {
+            match self.dns_rewrite.as_ref() {
+                Some(dns_rewrite) => options.push(format!("dnsrewrite={}", dns_rewrite)),
+                None => options.push("dnsrewrite".to_string()),
+            }
+        }
+        if let Some(redirect) = self.redirect.as_ref() {
+            options.push(format!("redirect={}", redirect));
+        }
+        if self.is_csp() {
+            match self.csp.as_ref() {
+                Some(csp) => options.push(format!("csp={}", csp)),
+                None => options.push("csp".to_string()),
+            }
+        }
+        if self.is_removeparam() {
+            match self.removeparam.as_ref() {
+                Some(removeparam) => options.push(format!("removeparam={}", removeparam)),
+                None => options.push("removeparam".to_string()),
+            }
+        }
+        if self.is_removeheader() {
+            match self.removeheader.as_ref() {
+                Some(removeheader) => options.push(format!("removeheader={}", removeheader)),
+                None => options.push("removeheader".to_string()),
+            }
+        }
+        if let Some(tag) = self.tag.as_ref() {
+            options.push(format!("tag={}", tag));
+        }
+        if let Some(bug) = self.bug {
+            options.push(format!("bug={}", bug));
         }
+        options
+    }
+
+    /// Text of the filter reconstructed from its compiled form, available regardless of debug
+    /// mode. Rules that compile to the same filter have the same canonical text. Options are
+    /// given as in `decoded_options`, so `$domain` values are only counted.
+    ///
+    /// This is computed on demand, so that filters parsed without debug information can still be
+    /// described without keeping `raw_line` in memory.
+    pub fn canonical_text(&self) -> String {
+        let mut text = String::new();
+        if self.is_exception() {
+            text.push_str("@@");
+        }
+        match self.hostname.as_ref() {
+            Some(hostname) if self.is_hostname_anchor() => {
+                text.push_str("||");
+                text.push_str(hostname);
+            }
+            _ => if self.is_left_anchor() {
+                text.push('|');
+            },
+        }
+        match &self.filter {
+            FilterPart::Empty => (),
+            FilterPart::Simple(pattern) if self.is_complete_regex() => {
+                text.push('/');
+                text.push_str(pattern);
+                text.push('/');
+            }
+            FilterPart::Simple(pattern) => text.push_str(pattern),
+            FilterPart::AnyOf(patterns) => text.push_str(&patterns.join(" <+> ")),
+        }
+        if self.is_right_anchor() {
+            text.push('|');
+        }
+        if text.is_empty() || text == "@@" {
+            text.push('*');
+        }
+        let options = self.decoded_options();
+        if !options.is_empty() {
+            text.push('$');
+            text.push_str(&options.join(","));
+        }
+        text
+    }
+
+    fn anchors(&self) -> Vec<&'static str> {
+        let anchors = [
+            (self.is_hostname_anchor(), "hostname"),
+            (self.is_left_anchor(), "left"),
+            (self.is_right_anchor(), "right"),
+        ];
+        anchors.iter().filter(|(set, _)| *set).map(|(_, name)| *name).collect()
+    }
+}
+
+impl fmt::Debug for NetworkFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = f.debug_struct("NetworkFilter");
+        if let Some(raw_line) = self.raw_line.as_ref() {
+            debug.field("raw_line", raw_line);
+        }
+        debug
+            .field("exception", &self.is_exception())
+            .field("hostname", &self.hostname)
+            .field("pattern", &self.filter)
+            .field("regex", &self.is_regex())
+            .field("anchors", &self.anchors())
+            .field("options", &self.decoded_options())
+            .field("id", &self.id)
+            .finish()
     }
 }
 
@@ -813,24 +1626,12 @@ impl NetworkMatchable for NetworkFilter {
         if !self.is_regex() && !self.is_complete_regex() {
             return Arc::new(CompiledRegex::MatchAll);
         }
-        // Create a new scope to contain the lifetime of the
-        // dynamic read borrow
-        {
-            let cache = self.regex.as_ref().read().unwrap();
-            if cache.is_some() {
-                return cache.as_ref().unwrap().clone();
-            }
-        }
-        let mut cache = self.regex.as_ref().write().unwrap();
-        let regex = compile_regex(
+        self.regex.get_or_init(|| Arc::new(compile_regex(
             &self.filter,
             self.is_right_anchor(),
             self.is_left_anchor(),
             self.is_complete_regex(),
-        );
-        let arc_regex = Arc::new(regex);
-        *cache = Some(arc_regex.clone());
-        arc_regex
+        ))).clone()
     }
 }
 
@@ -838,6 +1639,55 @@ impl NetworkMatchable for NetworkFilter {
 // Filter parsing
 // ---------------------------------------------------------------------------
 
+/// Removes a comment at the end of a filter's options, i.e. anything after whitespace followed by
+/// `#`, as in `||example.com^$script  # tracker`.
+fn strip_inline_comment(raw_options: &str) -> &str {
+    let mut previous_whitespace = false;
+    for (i, c) in raw_options.char_indices() {
+        if c == '#' && previous_whitespace {
+            return raw_options[..i].trim_end();
+        }
+        previous_whitespace = c.is_whitespace();
+    }
+    raw_options
+}
+
+/// Splits the options of a network filter on commas. The value of a `csp` option can itself
+/// contain several comma-separated policies, so any following parts that look like CSP directives
+/// rather than filter options are kept as part of that value.
+fn split_options(raw_options: &str) -> Vec<&str> {
+    fn is_csp_continuation(part: &str) -> bool {
+        let part = part.trim();
+        part.contains(char::is_whitespace)
+            || part.contains(';')
+            || part == "upgrade-insecure-requests"
+            || part == "block-all-mixed-content"
+            || part == "sandbox"
+    }
+
+    let mut options: Vec<&str> = Vec::new();
+    // Start of the current option, and whether it is a `csp` option
+    let mut current: Option<(usize, bool)> = None;
+    let mut start = 0;
+    for part in raw_options.split(',') {
+        let end = start + part.len();
+        current = match current {
+            Some((option_start, true)) if is_csp_continuation(part) => Some((option_start, true)),
+            _ => {
+                if let Some((option_start, _)) = current {
+                    options.push(&raw_options[option_start..start - 1]);
+                }
+                Some((start, part.starts_with("csp=")))
+            }
+        };
+        start = end + 1;
+    }
+    if let Some((option_start, _)) = current {
+        options.push(&raw_options[option_start..]);
+    }
+    options
+}
+
 fn compute_filter_id(
     csp: Option<&str>,
     mask: NetworkFilterMask,
@@ -855,13 +1705,17 @@ fn compute_filter_id(
         }
     };
 
+    // Each domain list is preceded by its own marker, so that `domain=a.com` and `domain=~a.com`
+    // get different ids
     if let Some(domains) = opt_domains {
+        hash = hash.wrapping_mul(33) ^ 1;
         for d in domains {
             hash = hash.wrapping_mul(33) ^ d;
         }
     };
 
     if let Some(domains) = opt_not_domains {
+        hash = hash.wrapping_mul(33) ^ 2;
         for d in domains {
             hash = hash.wrapping_mul(33) ^ d;
         }
@@ -995,6 +1849,9 @@ fn is_anchored_by_hostname(filter_hostname: &str, hostname: &str, wildcard_filte
             //   * (foo, foo.com)
             //   * (sub.foo, sub.foo.com)
             wildcard_filter_hostname || filter_hostname.ends_with('.') || hostname[filter_hostname_len..].starts_with('.')
+        } else if utils::is_ip_literal(hostname) {
+            // IP addresses can only be matched from their start, e.g. `||192.168.`
+            false
         } else if match_index == hostname_len - filter_hostname_len {
             // `filter_hostname` is a suffix of `hostname`.
             //
@@ -1004,7 +1861,7 @@ fn is_anchored_by_hostname(filter_hostname: &str, hostname: &str, wildcard_filte
             filter_hostname.starts_with('.') || hostname[match_index - 1..].starts_with('.')
         } else {
             // `filter_hostname` is infix of `hostname` and needs match full labels
-            (wildcard_filter_hostname || filter_hostname.ends_with('.') || hostname[filter_hostname_len..].starts_with('.'))
+            (wildcard_filter_hostname || filter_hostname.ends_with('.') || hostname[match_index + filter_hostname_len..].starts_with('.'))
                 && (filter_hostname.starts_with('.') || hostname[match_index - 1..].starts_with('.'))
         }
     }
@@ -1014,6 +1871,14 @@ fn is_anchored_by_hostname(filter_hostname: &str, hostname: &str, wildcard_filte
     }
 }
 
+/// Strips an explicit port from the hostname of a `||` filter, e.g. `example.com:8080`.
+fn hostname_without_port(hostname: &str) -> &str {
+    match hostname.rfind(':') {
+        Some(colon) if colon + 1 < hostname.len() && hostname[colon + 1..].bytes().all(|b| b.is_ascii_digit()) => &hostname[..colon],
+        _ => hostname,
+    }
+}
+
 fn get_url_after_hostname<'a>(url: &'a str, hostname: &str) -> &'a str {
     let start = twoway::find_str(url, hostname).unwrap_or_else(|| url.len() - hostname.len());
     &url[start + hostname.len()..]
@@ -1267,6 +2132,10 @@ fn check_pattern_hostname_anchor_filter(
 /// Efficiently checks if a certain network filter matches against a network
 /// request.
 fn check_pattern(filter: &NetworkFilter, request: &request::Request) -> bool {
+    // The end of a truncated URL is not the end of the real one
+    if filter.is_right_anchor() && request.is_truncated() {
+        return false;
+    }
     if filter.is_hostname_anchor() {
         if filter.is_regex() {
             check_pattern_hostname_anchor_regex_filter(filter, request)
@@ -1294,7 +2163,8 @@ fn check_pattern(filter: &NetworkFilter, request: &request::Request) -> bool {
 
 pub fn check_cpt_allowed(filter: &NetworkFilter, cpt: &request::RequestType) -> bool {
     match NetworkFilterMask::from(cpt) {
-        NetworkFilterMask::FROM_DOCUMENT => filter.get_cpt_mask().contains(NetworkFilterMask::FROM_DOCUMENT) || filter.is_exception(),
+        NetworkFilterMask::FROM_DOCUMENT => filter.mask.contains(NetworkFilterMask::FROM_DOCUMENT)
+            || (filter.is_exception() && !filter.mask.contains(NetworkFilterMask::EXCLUDE_DOCUMENT)),
         mask => filter.mask.contains(mask),
     }
 }
@@ -1309,6 +2179,9 @@ fn check_options(filter: &NetworkFilter, request: &request::Request) -> bool {
     if !check_cpt_allowed(&filter, &request.request_type)
         || (request.is_https && !filter.for_https())
         || (request.is_http && !filter.for_http())
+        // `ftp:` and other schemes are only matched by filters that aren't restricted to a scheme
+        || (!request.is_http && !request.is_https && request.request_type != request::RequestType::Websocket
+            && !(filter.for_http() && filter.for_https()))
         || (!filter.first_party() && request.is_first_party == Some(true))
         || (!filter.third_party() && request.is_third_party == Some(true))
     {
@@ -1321,6 +2194,35 @@ fn check_options(filter: &NetworkFilter, request: &request::Request) -> bool {
         return false;
     }
 
+    // Client must have one of these tags to match
+    if let Some(included_client_tags) = filter.opt_client_tags.as_ref() {
+        match request.client_tags.as_ref() {
+            Some(client_tags) if client_tags.iter().any(|t| utils::bin_lookup(&included_client_tags, *t)) => (),
+            _ => return false,
+        }
+    }
+
+    if let Some(excluded_client_tags) = filter.opt_not_client_tags.as_ref() {
+        if let Some(client_tags) = request.client_tags.as_ref() {
+            if client_tags.iter().any(|t| utils::bin_lookup(&excluded_client_tags, *t)) {
+                return false;
+            }
+        }
+    }
+
+    // Filters with `$dnstype` only match DNS queries, and only for the given record types
+    if filter.has_dns_types() {
+        let dns_type = match request.dns_type.as_ref() {
+            Some(dns_type) => dns_type,
+            None => return false,
+        };
+        if filter.opt_dns_types.as_ref().map_or(false, |types| !types.contains(dns_type))
+            || filter.opt_not_dns_types.as_ref().map_or(false, |types| types.contains(dns_type))
+        {
+            return false;
+        }
+    }
+
     // Source URL must be among these domains to match
     if let Some(included_domains) = filter.opt_domains.as_ref() {
         if let Some(source_hashes) = request.source_hostname_hashes.as_ref() {
@@ -1337,6 +2239,16 @@ fn check_options(filter: &NetworkFilter, request: &request::Request) -> bool {
         }
     }
 
+    // Requests to any of these domains, or to their subdomains, are not matched
+    if let Some(denyallow) = filter.opt_denyallow.as_ref() {
+        let hostname = request.hostname.as_str();
+        if utils::bin_lookup(denyallow, utils::fast_hash(hostname))
+            || hostname.match_indices('.').any(|(i, _)| utils::bin_lookup(denyallow, utils::fast_hash(&hostname[i + 1..])))
+        {
+            return false;
+        }
+    }
+
     if let Some(excluded_domains) = filter.opt_not_domains.as_ref() {
         if let Some(source_hashes) = request.source_hostname_hashes.as_ref() {
             // If the union of excluded domains is recorded
@@ -1942,6 +2854,102 @@ mod parse_tests {
             assert_eq!(filter.mask.contains(NetworkFilterMask::FROM_IMAGE), true);
             assert_eq!(filter.csp, Some(String::from(r#"self bar """#)));
         }
+        {
+            // parses multiple policies within one csp option
+            let filter = NetworkFilter::parse("||foo.com$csp=script-src 'self', upgrade-insecure-requests,image", true).unwrap();
+            assert_eq!(filter.csp, Some(String::from("script-src 'self', upgrade-insecure-requests")));
+            assert_eq!(filter.mask.contains(NetworkFilterMask::FROM_IMAGE), true);
+        }
+        {
+            // `none` disables all policies in exceptions
+            let filter = NetworkFilter::parse("@@||foo.com$csp=none", true).unwrap();
+            assert_eq!(filter.is_csp(), true);
+            assert_eq!(filter.csp, None);
+        }
+    }
+
+    #[test]
+    fn parses_removeparam() {
+        let filter = NetworkFilter::parse("||foo.com^$removeparam=utm_source", true).unwrap();
+        assert!(filter.is_removeparam());
+        assert_eq!(filter.removeparam, Some(String::from("utm_source")));
+        // only documents by default, like `$csp`
+        assert!(filter.for_document());
+        assert!(!filter.mask.contains(NetworkFilterMask::FROM_IMAGE));
+
+        let filter = NetworkFilter::parse("$removeparam=/^utm_/,xhr", true).unwrap();
+        assert_eq!(filter.removeparam, Some(String::from("/^utm_/")));
+        assert!(filter.mask.contains(NetworkFilterMask::FROM_XMLHTTPREQUEST));
+        assert!(!filter.for_document());
+
+        let filter = NetworkFilter::parse("@@||foo.com^$queryprune", true).unwrap();
+        assert!(filter.is_removeparam());
+        assert_eq!(filter.removeparam, None);
+        assert_eq!(filter.decoded_options(), vec!["subdocument", "document", "removeparam"]);
+
+        assert_ne!(
+            NetworkFilter::parse("$removeparam=a", true).unwrap().id,
+            NetworkFilter::parse("$removeparam=b", true).unwrap().id,
+        );
+        assert_eq!(NetworkFilter::parse("$~removeparam=a", true).err(), Some(NetworkFilterError::NegatedRemoveParam));
+        assert!(matches!(NetworkFilter::parse("$removeparam=~/(/", true), Err(NetworkFilterError::RegexParsingError(_))));
+    }
+
+    #[test]
+    fn removeparam_regex_compiled_once() {
+        let filter = NetworkFilter::parse("$removeparam=~/^utm_/", true).unwrap();
+        let clone = filter.clone();
+        assert!(clone.removeparam_regex().unwrap().is_match("utm_source=x"));
+        // Clones share the compiled regex
+        assert!(filter.removeparam_regex.get().is_some());
+
+        assert!(NetworkFilter::parse("$removeparam=utm_source", true).unwrap().removeparam_regex().is_none());
+    }
+
+    #[test]
+    fn parses_removeheader() {
+        let filter = NetworkFilter::parse("||foo.com^$removeheader=Referer", true).unwrap();
+        assert!(filter.is_removeheader());
+        assert_eq!(filter.removeheader, Some(String::from("referer")));
+        // unlike `$csp`, all request types by default
+        assert!(filter.mask.contains(NetworkFilterMask::FROM_IMAGE));
+        assert!(!filter.is_hostname_only());
+
+        let filter = NetworkFilter::parse("$removeheader=request:referer,third-party", true).unwrap();
+        assert_eq!(filter.removeheader, Some(String::from("request:referer")));
+        assert_eq!(filter.decoded_options(), vec!["third-party", "removeheader=request:referer"]);
+
+        let filter = NetworkFilter::parse("@@||foo.com^$removeheader", true).unwrap();
+        assert_eq!(filter.removeheader, None);
+
+        assert_ne!(
+            NetworkFilter::parse("$removeheader=referer", true).unwrap().id,
+            NetworkFilter::parse("$removeheader=location", true).unwrap().id,
+        );
+        assert_eq!(NetworkFilter::parse("||foo.com^$removeheader", true).err(), Some(NetworkFilterError::EmptyRemoveHeader));
+        assert_eq!(NetworkFilter::parse("$~removeheader=referer", true).err(), Some(NetworkFilterError::NegatedRemoveHeader));
+    }
+
+    #[test]
+    fn parses_denyallow() {
+        let filter = NetworkFilter::parse("||example.org^$denyallow=CDN.example.org|static.example.org", true).unwrap();
+        let mut expected = vec![utils::fast_hash("cdn.example.org"), utils::fast_hash("static.example.org")];
+        expected.sort_unstable();
+        assert_eq!(filter.opt_denyallow, Some(expected));
+        assert!(filter.is_hostname_only());
+        assert_eq!(filter.decoded_options(), vec!["denyallow=<2 values>"]);
+
+        let request = |hostname: &str| request::Request::from_hostname(hostname).unwrap();
+        assert!(filter.matches(&request("example.org")));
+        assert!(filter.matches(&request("ads.example.org")));
+        assert!(!filter.matches(&request("cdn.example.org")));
+        assert!(!filter.matches(&request("img.static.example.org")));
+
+        assert_ne!(filter.id, NetworkFilter::parse("||example.org^", true).unwrap().id);
+        assert_eq!(NetworkFilter::parse("||example.org^$~denyallow=a.com", true).err(), Some(NetworkFilterError::NegatedDenyAllow));
+        assert_eq!(NetworkFilter::parse("||example.org^$denyallow=~a.com", true).err(), Some(NetworkFilterError::InvalidDenyAllow));
+        assert_eq!(NetworkFilter::parse("||example.org^$denyallow=a.*", true).err(), Some(NetworkFilterError::InvalidDenyAllow));
+        assert_eq!(NetworkFilter::parse("||example.org^$denyallow=", true).err(), Some(NetworkFilterError::InvalidDenyAllow));
     }
 
     #[test]
@@ -2227,6 +3235,143 @@ mod parse_tests {
         }
     }
 
+    #[test]
+    fn parses_specific_hide_and_elem_hide() {
+        {
+            let filter = NetworkFilter::parse("@@||foo.com^$specifichide", true).unwrap();
+            assert_eq!(filter.is_specific_hide(), true);
+            assert_eq!(filter.is_generic_hide(), false);
+        }
+        {
+            let filter = NetworkFilter::parse("@@||foo.com^$shide", true).unwrap();
+            assert_eq!(filter.is_specific_hide(), true);
+            assert_eq!(filter.is_generic_hide(), false);
+        }
+        {
+            let filter = NetworkFilter::parse("@@||foo.com^$ehide", true).unwrap();
+            assert_eq!(filter.is_specific_hide(), true);
+            assert_eq!(filter.is_generic_hide(), true);
+        }
+        {
+            let filter = NetworkFilter::parse("@@||foo.com^$elemhide", true).unwrap();
+            assert_eq!(filter.is_specific_hide(), true);
+            assert_eq!(filter.is_generic_hide(), true);
+        }
+        assert_eq!(NetworkFilter::parse("||foo.com^$shide", true).err(), Some(NetworkFilterError::SpecificHideWithoutException));
+        assert_eq!(NetworkFilter::parse("||foo.com^$ehide", true).err(), Some(NetworkFilterError::GenericHideWithoutException));
+        assert_eq!(NetworkFilter::parse("@@||foo.com^$~shide", true).err(), Some(NetworkFilterError::NegatedSpecificHide));
+        assert_eq!(NetworkFilter::parse("@@||foo.com^$~elemhide", true).err(), Some(NetworkFilterError::NegatedElemHide));
+    }
+
+    #[test]
+    fn tolerates_malformed_options() {
+        let parse = |filter: &str| NetworkFilter::parse(filter, false).unwrap();
+        let expected = parse("||foo.com^$script,domain=a.com|b.com");
+        for filter in [
+            "||foo.com^$script, domain=a.com | b.com",
+            "||foo.com^$script,,domain=a.com|b.com,",
+            "||foo.com^$ script ,domain=a.com|b.com|",
+            "||foo.com^$script,domain=a.com|b.com  # tracker",
+        ].iter() {
+            let parsed = parse(filter);
+            assert_eq!(parsed.mask, expected.mask, "{}", filter);
+            assert_eq!(parsed.opt_domains, expected.opt_domains, "{}", filter);
+            assert_eq!(parsed.opt_not_domains, None, "{}", filter);
+        }
+        assert_eq!(parse("||foo.com^$").mask, parse("||foo.com^").mask);
+
+        // The last occurrence of an option wins by default
+        let filter = parse("||foo.com^$domain=a.com,redirect=x.js,domain=~b.com,redirect=y.js");
+        assert_eq!(filter.opt_domains, None);
+        assert_eq!(filter.opt_not_domains, Some(vec![utils::fast_hash("b.com")]));
+        assert_eq!(filter.redirect, Some("y.js".to_string()));
+
+        let strict = ParseOptions { duplicate_options: DuplicateOptions::Error, ..ParseOptions::default() };
+        assert_eq!(NetworkFilter::parse_with_options("||foo.com^$domain=a.com,domain=b.com", false, &strict).err(), Some(NetworkFilterError::DuplicateOption));
+        assert!(NetworkFilter::parse_with_options("||foo.com^$script,script,domain=a.com", false, &strict).is_ok());
+    }
+
+    #[test]
+    fn parses_client_tags() {
+        {
+            let filter = NetworkFilter::parse("||foo.com^$ctag=device_tv|~user_child|device_phone", true).unwrap();
+            let mut client_tags = vec![utils::fast_hash("device_tv"), utils::fast_hash("device_phone")];
+            client_tags.sort_unstable();
+            assert_eq!(filter.opt_client_tags, Some(client_tags));
+            assert_eq!(filter.opt_not_client_tags, Some(vec![utils::fast_hash("user_child")]));
+            assert_eq!(filter.has_client_tags(), true);
+        }
+        {
+            let filter = NetworkFilter::parse("||foo.com^$ctag=~device_tv", true).unwrap();
+            assert_eq!(filter.opt_client_tags, None);
+            assert_eq!(filter.opt_not_client_tags, Some(vec![utils::fast_hash("device_tv")]));
+        }
+        assert_eq!(NetworkFilter::parse("||foo.com^", true).unwrap().has_client_tags(), false);
+        assert_eq!(NetworkFilter::parse("||foo.com^$~ctag=device_tv", true).err(), Some(NetworkFilterError::NegatedClientTag));
+    }
+
+    #[test]
+    fn parses_dns_modifiers() {
+        let dns = ParseOptions { dns_modifiers: true, ..ParseOptions::default() };
+        let parse = |line: &str| NetworkFilter::parse_with_options(line, true, &dns);
+        {
+            let filter = parse("||foo.com^$dnstype=aaaa|~A|AAAA").unwrap();
+            assert_eq!(filter.opt_dns_types, Some(vec!["AAAA".to_string()]));
+            assert_eq!(filter.opt_not_dns_types, Some(vec!["A".to_string()]));
+            assert_eq!(filter.decoded_options(), vec!["dnstype=AAAA|~A"]);
+            assert!(filter.has_dns_types());
+            assert!(!filter.is_dns_rewrite());
+        }
+        {
+            let filter = parse("||foo.com^$dnsrewrite=1.2.3.4").unwrap();
+            assert!(filter.is_dns_rewrite());
+            assert_eq!(filter.decoded_options(), vec!["dnsrewrite=NOERROR;A;1.2.3.4"]);
+        }
+        let rewrite = |line: &str| parse(line).unwrap().dns_rewrite.map(|rewrite| rewrite.to_string());
+        assert_eq!(rewrite("||foo.com^$dnsrewrite=::1"), Some("NOERROR;AAAA;::1".to_string()));
+        assert_eq!(rewrite("||foo.com^$dnsrewrite=Example.net"), Some("NOERROR;CNAME;example.net".to_string()));
+        assert_eq!(rewrite("||foo.com^$dnsrewrite=nxdomain"), Some("NXDOMAIN;;".to_string()));
+        assert_eq!(rewrite("||foo.com^$dnsrewrite=noerror;mx;32 mail.foo.com"), Some("NOERROR;MX;32 mail.foo.com".to_string()));
+        assert_eq!(rewrite("@@||foo.com^$dnsrewrite"), None);
+        assert!(parse("@@||foo.com^$dnsrewrite").unwrap().is_dns_rewrite());
+
+        assert_eq!(parse("||foo.com^$dnsrewrite").err(), Some(NetworkFilterError::InvalidDnsRewrite));
+        assert_eq!(parse("||foo.com^$dnsrewrite=a;b").err(), Some(NetworkFilterError::InvalidDnsRewrite));
+        assert_eq!(parse("||foo.com^$~dnsrewrite=1.2.3.4").err(), Some(NetworkFilterError::NegatedDnsRewrite));
+        assert_eq!(parse("||foo.com^$~dnstype=A").err(), Some(NetworkFilterError::NegatedDnsType));
+        assert_eq!(NetworkFilter::parse("||foo.com^$dnstype=A", true).err(), Some(NetworkFilterError::UnrecognisedOption));
+        assert_eq!(NetworkFilter::parse("||foo.com^$dnsrewrite=1.2.3.4", true).err(), Some(NetworkFilterError::UnrecognisedOption));
+    }
+
+    #[test]
+    fn parses_negated_options() {
+        {
+            let filter = NetworkFilter::parse("||foo.com^$~third-party", true).unwrap();
+            assert_eq!(filter.first_party(), true);
+            assert_eq!(filter.third_party(), false);
+        }
+        {
+            let filter = NetworkFilter::parse("||foo.com^$~3p,~script", true).unwrap();
+            assert_eq!(filter.third_party(), false);
+            assert_eq!(filter.mask.contains(NetworkFilterMask::FROM_SCRIPT), false);
+            assert_eq!(filter.mask.contains(NetworkFilterMask::FROM_IMAGE), true);
+        }
+        {
+            // negated types do not discard an explicit `$document`
+            let filter = NetworkFilter::parse("@@||foo.com^$document,~script", true).unwrap();
+            assert_eq!(filter.for_document(), true);
+        }
+        {
+            let filter = NetworkFilter::parse("@@||foo.com^$~doc", true).unwrap();
+            assert_eq!(filter.for_document(), false);
+            assert_eq!(filter.mask.contains(NetworkFilterMask::EXCLUDE_DOCUMENT), true);
+            assert_eq!(filter.cpt_any(), true);
+        }
+        assert_eq!(NetworkFilter::parse("||foo.com^$~~script", true).err(), Some(NetworkFilterError::UnrecognisedOption));
+        assert_eq!(NetworkFilter::parse("||foo.com^$~csp=script-src 'none'", true).err(), Some(NetworkFilterError::NegatedCsp));
+        assert_eq!(NetworkFilter::parse("||foo.com^$~domain=bar.com", true).err(), Some(NetworkFilterError::NegatedDomain));
+    }
+
     #[test]
     fn parses_hosts_style() {
         {
@@ -2451,6 +3596,23 @@ mod parse_tests {
         }
     }
 
+    #[test]
+    fn regex_compiled_once_across_threads() {
+        let filter = NetworkFilter::parse("/ad[0-9]+\\.js/", true).unwrap();
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let filter = filter.clone();
+                std::thread::spawn(move || filter.get_regex())
+            })
+            .collect();
+        let regexes: Vec<_> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        for regex in &regexes {
+            assert!(Arc::ptr_eq(regex, &regexes[0]));
+            assert!(regex.is_match("https://example.com/ad123.js"));
+        }
+        assert!(Arc::ptr_eq(&filter.get_regex(), &regexes[0]));
+    }
+
     #[test]
     fn parse_empty_host_anchor_exception() {
         let filter_parsed = NetworkFilter::parse("@@||$domain=auth.wi-fi.ru", true);
@@ -2469,6 +3631,78 @@ mod parse_tests {
         assert_eq!(defaults, NetworkFilterBreakdown::from(&filter));
     }
 
+    #[test]
+    fn decoded_options() {
+        let filter = NetworkFilter::parse("@@||ads.example.com/banner^$script,image,third-party,domain=a.com|~b.a.com,important", false).unwrap();
+        assert_eq!(filter.decoded_options(), vec![
+            "image",
+            "script",
+            "third-party",
+            "important",
+            "domain=<2 values, 1 excluded>",
+        ]);
+        assert_eq!(filter.anchors(), vec!["hostname", "left"]);
+        assert_eq!(filter.to_string(), filter.canonical_text());
+
+        let debug = format!("{:?}", filter);
+        assert!(debug.contains("exception: true"), "{}", debug);
+        assert!(debug.contains("hostname: Some(\"ads.example.com\")"), "{}", debug);
+        assert!(!debug.contains("raw_line"), "{}", debug);
+
+        let filter = NetworkFilter::parse("/ads.js$redirect=noop.js,1p,match-case", true).unwrap();
+        assert_eq!(filter.decoded_options(), vec!["first-party", "match-case", "redirect=noop.js"]);
+        assert!(format!("{:?}", filter).contains("raw_line: \"/ads.js$redirect=noop.js,1p,match-case\""));
+        assert!(NetworkFilter::parse("||example.com^", false).unwrap().decoded_options().is_empty());
+    }
+
+    #[test]
+    fn displays_canonical_text_without_debug() {
+        let rule = "@@||ads.com/ok.js$script,third-party";
+        let filter = NetworkFilter::parse(rule, false).unwrap();
+        assert!(filter.raw_line.is_none());
+        assert_eq!(filter.to_string(), "@@||ads.com/ok.js$script,third-party");
+        assert_eq!(NetworkFilter::parse(rule, true).unwrap().to_string(), rule);
+
+        let filter = NetworkFilter::parse("||ads.com^", false).unwrap();
+        assert_eq!(filter.to_string(), "||ads.com|");
+        assert_eq!(NetworkFilter::parse("$image", false).unwrap().to_string(), "*$image");
+    }
+
+    #[test]
+    fn parses_popup_filters() {
+        let filter = NetworkFilter::parse_popup("||ads.com^$popup,third-party", true).unwrap();
+        assert!(filter.for_popup());
+        assert_eq!(filter.get_cpt_mask(), NetworkFilterMask::NONE);
+        assert_eq!(filter.decoded_options(), vec!["popup", "third-party"]);
+        assert_eq!(filter.raw_line, Some("||ads.com^$popup,third-party".to_string()));
+        assert_ne!(filter.id, NetworkFilter::parse("||ads.com^$third-party", true).unwrap().id);
+
+        assert!(NetworkFilter::parse_popup("@@||ads.com^$popup", false).unwrap().is_exception());
+        assert_eq!(NetworkFilter::parse_popup("||ads.com^$script", false).err(), Some(NetworkFilterError::NotPopup));
+        assert_eq!(NetworkFilter::parse_popup("||ads.com^", false).err(), Some(NetworkFilterError::NotPopup));
+        assert_eq!(NetworkFilter::parse_popup("||ads.com^$popup,woot", false).err(), Some(NetworkFilterError::UnrecognisedOption));
+    }
+
+    #[test]
+    fn stable_ids() {
+        let id = |line: &str| NetworkFilter::parse(line, false).unwrap().id;
+
+        assert_eq!(id("||ads.com^$script,image,domain=a.com|b.com"), id("||ads.com^$image,script,domain=b.com|a.com"));
+        assert_eq!(id("||ads.com^$script"), NetworkFilter::parse("||ads.com^$script", true).unwrap().id);
+        assert_eq!(id("||ads.com^$~third-party"), id("||ads.com^$first-party"));
+
+        assert_ne!(id("||ads.com^"), id("||ads.com^$script"));
+        assert_ne!(id("||ads.com^"), id("@@||ads.com^"));
+        assert_ne!(id("||ads.com^$redirect=noop.js"), id("||ads.com^$redirect=noop.txt"));
+        assert_ne!(id("||ads.com^$tag=a"), id("||ads.com^$tag=b"));
+        assert_ne!(id("||ads.com^$ctag=a"), id("||ads.com^$ctag=~a"));
+        assert_ne!(id("||ads.com^$domain=a.com"), id("||ads.com^$domain=~a.com"));
+        assert_ne!(id("||ads.com^$domain=a.com|~b.com"), id("||ads.com^$domain=~a.com|b.com"));
+        assert_ne!(
+            NetworkFilter::parse("||ads.com^$domain=a.com", false).unwrap().get_id(),
+            NetworkFilter::parse("||ads.com^$domain=~a.com", false).unwrap().get_id(),
+        );
+    }
 }
 
 #[cfg(test)]
@@ -2513,6 +3747,10 @@ mod match_tests {
         assert_eq!(is_anchored_by_hostname("foo.bar", "foo.bar.com", false), true);
         assert_eq!(is_anchored_by_hostname("foo.bar.", "foo.bar.com", false), true);
 
+        // Infix labels end where the filter hostname ends in the hostname
+        assert_eq!(is_anchored_by_hostname("cdn.example", "a.cdn.example.net", false), true);
+        assert_eq!(is_anchored_by_hostname("cdn.exam", "a.cdn.example.net", false), false);
+
         // does not match partial prefix
         // Single label
         assert_eq!(is_anchored_by_hostname("foo", "foobar.com", false), false);
@@ -2621,6 +3859,61 @@ mod match_tests {
         filter_match_url("||s.foo.com", "https://substrings.foo.com", false);
     }
 
+    #[test]
+    fn case_folding_is_ascii_only() {
+        filter_match_url("||IADS.com^", "https://iads.com/", true);
+        filter_match_url("/BANNER/", "https://example.com/banner/", true);
+
+        // Dotless and dotted capital i are never folded to `i`
+        filter_match_url("||ıads.com^", "https://iads.com/", false);
+        filter_match_url("||İads.com^", "https://iads.com/", false);
+        filter_match_url("/İMG/", "https://example.com/img/", false);
+
+        // Non-ASCII hostnames are normalized by IDNA mapping
+        filter_match_url("||\u{212A}ads.com^", "https://kads.com/", true);
+    }
+
+    #[test]
+    fn explicit_ports() {
+        // Filters without a port apply to all ports
+        filter_match_url("||example.com^", "http://example.com:8080/ads.js", true);
+        filter_match_url("||example.com/ads", "http://example.com:8080/ads.js", false);
+
+        filter_match_url("||example.com:8080^", "http://example.com:8080/ads.js", true);
+        filter_match_url("||example.com:8080^", "http://sub.example.com:8080/ads.js", true);
+        filter_match_url("||example.com:8080^", "http://example.com/ads.js", false);
+        filter_match_url("||example.com:8080^", "http://example.com:80801/ads.js", false);
+        filter_match_url("||example.com:8080/ads", "http://example.com:8080/ads.js", true);
+        filter_match_url("||example.com:8080/ads", "http://example.com:9090/ads.js", false);
+        filter_match_url("||[::1]:8080^", "http://[::1]:8080/ads.js", true);
+
+        let filter = NetworkFilter::parse("||example.com:8080^", true).unwrap();
+        assert_eq!(filter.hostname, Some("example.com".to_string()));
+    }
+
+    #[test]
+    fn ip_literal_hosts() {
+        filter_match_url("||192.168.1.1^", "http://192.168.1.1/ads.js", true);
+        filter_match_url("||192.168.", "http://192.168.1.1/ads.js", true);
+        filter_match_url("||168.1.1^", "http://192.168.1.1/ads.js", false);
+        filter_match_url("||1.1^", "http://192.168.1.1/ads.js", false);
+        filter_match_url("||[::1]^", "http://[::1]:8080/ads.js", true);
+
+        let check = |filter: &str, url: &str, source_url: &str| {
+            let filter = NetworkFilter::parse(filter, true).unwrap();
+            filter.matches(&request::Request::from_urls(url, source_url, "script").unwrap())
+        };
+
+        // Different addresses are different parties, even if they share trailing bytes
+        assert!(check("/ads.js$third-party", "http://10.0.1.1/ads.js", "http://192.168.1.1/"));
+        assert!(!check("/ads.js$third-party", "http://192.168.1.1/ads.js", "http://192.168.1.1/"));
+
+        assert!(check("/ads.js$domain=192.168.1.1", "https://cdn.com/ads.js", "http://192.168.1.1/"));
+        assert!(!check("/ads.js$domain=1.1", "https://cdn.com/ads.js", "http://192.168.1.1/"));
+        assert!(check("/ads.js$domain=::1", "https://cdn.com/ads.js", "http://[::1]/"));
+        assert!(check("/ads.js$domain=[::1]", "https://cdn.com/ads.js", "http://[::1]/"));
+    }
+
     #[test]
     fn check_hosts_style_works() {
         hosts_filter_match_url("foo.com", "https://foo.com/bar", true);
@@ -2658,6 +3951,29 @@ mod match_tests {
         filter_match_url("foo|", "https://bar.com/foo/baz", false);
     }
 
+    #[test]
+    fn matches_client_tags() {
+        let included = NetworkFilter::parse("||foo.com^$ctag=device_tv", true).unwrap();
+        let excluded = NetworkFilter::parse("||foo.com^$ctag=~user_child", true).unwrap();
+
+        let mut request = request::Request::from_url("https://foo.com/ad.js").unwrap();
+        assert_eq!(request.client_tags, None);
+        assert!(!included.matches(&request));
+        assert!(excluded.matches(&request));
+
+        request.set_client_tags(&[]);
+        assert!(!included.matches(&request));
+        assert!(excluded.matches(&request));
+
+        request.set_client_tags(&["device_tv"]);
+        assert!(included.matches(&request));
+        assert!(excluded.matches(&request));
+
+        request.set_client_tags(&["device_tv", "user_child"]);
+        assert!(included.matches(&request));
+        assert!(!excluded.matches(&request));
+    }
+
     #[test]
     // |pattern
     fn check_pattern_left_anchor_filter_works() {
@@ -2719,6 +4035,43 @@ mod match_tests {
         filter_match_url("||*com*^bar", "https://foo.com/bar", true);
     }
 
+    #[test]
+    fn requires_path_works() {
+        fn requires_path(filter: &str, url: &str) -> bool {
+            let network_filter = NetworkFilter::parse(filter, true).unwrap();
+            let request = request::Request::from_url(url).unwrap();
+            assert!(request.is_hostname_only());
+            let requires_path = network_filter.requires_path(&request);
+            // skipping a filter must never change the result
+            if requires_path {
+                assert!(!network_filter.matches(&request), "{} should not match {}", filter, url);
+            }
+            requires_path
+        }
+
+        assert!(requires_path("||foo.com/bar", "https://foo.com/"));
+        assert!(requires_path("||foo.com^*/bar", "https://foo.com/"));
+        assert!(requires_path("||foo.com/*.js", "https://foo.com/"));
+        assert!(requires_path("||foo/bar", "https://foo.com/"));
+
+        assert!(!requires_path("||foo.com", "https://foo.com/"));
+        assert!(!requires_path("||foo.com^", "https://foo.com/"));
+        assert!(!requires_path("||foo.com/", "https://foo.com/"));
+        assert!(!requires_path("||foo.com/*", "https://foo.com/"));
+        assert!(!requires_path("||foo.com^|", "https://foo.com/"));
+        assert!(!requires_path("/foo/bar", "https://foo.com/"));
+        assert!(!requires_path("||s/s.com", "https://s.com/"));
+        // The filter's hostname may be longer than the whole URL
+        assert!(!requires_path("||a-much-longer-hostname.com/bar", "https://a.com/"));
+    }
+
+    #[test]
+    fn single_slash_is_a_plain_pattern() {
+        let filter = NetworkFilter::parse("/", true).unwrap();
+        assert!(!filter.is_complete_regex());
+        filter_match_url("/", "https://foo.com/bar", true);
+    }
+
     #[test]
     fn check_pattern_hostname_anchor_regex_filter_works_realisitic() {
         filter_match_url("||vimeo.com^*?type=", "https://vimeo.com/ablincoln/fatal_attraction?type=pageview&target=%2F193641463", true);
@@ -2912,6 +4265,21 @@ mod match_tests {
             assert!(network_filter.matches(&request::Request::from_urls("http://example.net/adv", "http://bar.com", "").unwrap()) == true);
             assert!(network_filter.matches(&request::Request::from_urls("http://example.net/adv", "http://co.uk", "").unwrap()) == false);
         }
+        // entities match the registrable domain under any public suffix
+        {
+            let network_filter = NetworkFilter::parse("adv$domain=google.*|~mail.google.*", true).unwrap();
+            assert!(network_filter.matches(&request::Request::from_urls("http://example.net/adv", "http://google.com", "").unwrap()) == true);
+            assert!(network_filter.matches(&request::Request::from_urls("http://example.net/adv", "http://www.google.co.uk", "").unwrap()) == true);
+            assert!(network_filter.matches(&request::Request::from_urls("http://example.net/adv", "http://mail.google.de", "").unwrap()) == false);
+            assert!(network_filter.matches(&request::Request::from_urls("http://example.net/adv", "http://google.example.com", "").unwrap()) == false);
+            assert!(network_filter.matches(&request::Request::from_urls("http://example.net/adv", "http://notgoogle.com", "").unwrap()) == false);
+        }
+        {
+            let network_filter = NetworkFilter::parse("||ads.net^$domain=example.*", true).unwrap();
+            let blocker = crate::blocker::Blocker::new(vec![network_filter], &crate::blocker::BlockerOptions { enable_optimizations: false });
+            assert!(blocker.check(&request::Request::from_urls("https://ads.net/1.js", "https://example.fr/", "script").unwrap()).matched);
+            assert!(!blocker.check(&request::Request::from_urls("https://ads.net/1.js", "https://example.fr.other.com/", "script").unwrap()).matched);
+        }
     }
 
     #[test]
@@ -3041,6 +4409,19 @@ mod match_tests {
         }
     }
 
+    #[test]
+    fn check_negated_document_matches() {
+        let request = request::Request::from_urls("https://foo.com/", "https://foo.com/", "document").unwrap();
+        assert!(NetworkFilter::parse("@@||foo.com^", true).unwrap().matches(&request));
+        assert!(NetworkFilter::parse("@@||foo.com^$document", true).unwrap().matches(&request));
+        assert!(!NetworkFilter::parse("@@||foo.com^$~document", true).unwrap().matches(&request));
+        assert!(!NetworkFilter::parse("@@||foo.com^$~script,~document", true).unwrap().matches(&request));
+
+        let request = request::Request::from_urls("https://foo.com/ad.js", "https://foo.com/", "script").unwrap();
+        assert!(!NetworkFilter::parse("@@||foo.com^$~script,~document", true).unwrap().matches(&request));
+        assert!(NetworkFilter::parse("@@||foo.com^$~document", true).unwrap().matches(&request));
+    }
+
     #[test]
     fn check_url_path_regex_matches() {
         {
@@ -0,0 +1,615 @@
+//! Procedural cosmetic filters, i.e. cosmetic rules that use non-CSS operators like `:has-text()`
+//! or `:upward()` and therefore can't be applied through a stylesheet alone.
+//!
+//! Procedural filters are compiled into a flat list of `ProceduralOperator`s, optionally followed
+//! by a `ProceduralAction`. The serialized form is stable, so that content scripts written in any
+//! language can interpret it. For example, `example.com##div.ad:has-text(Sponsored):upward(2)`
+//! is serialized to JSON as:
+//!
+//! ```json
+//! {
+//!   "selector": [
+//!     { "type": "css-selector", "arg": "div.ad" },
+//!     { "type": "has-text", "arg": "Sponsored" },
+//!     { "type": "upward", "arg": "2" }
+//!   ],
+//!   "action": null
+//! }
+//! ```
+//!
+//! Operators are applied in order. The first operator selects elements from the document, and
+//! each subsequent one either filters or transforms the current set of elements.
+
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+
+use crate::filters::cosmetic::CosmeticFilterError;
+
+/// A single step of a procedural cosmetic filter.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", tag = "type", content = "arg")]
+pub enum ProceduralOperator {
+    /// A plain CSS selector. As the first operator, it is evaluated against the document.
+    /// Otherwise, it is evaluated relative to each currently selected element, as if prefixed
+    /// with `:scope` - e.g. `" > span"` or `":not(.visible)"`.
+    CssSelector(String),
+    /// Keep elements whose text content contains the argument. Arguments in the form `/.../`
+    /// (optionally followed by regex flags) are regular expressions.
+    HasText(String),
+    /// Keep elements that have at least one descendant matching the nested operators.
+    Has(Vec<ProceduralOperator>),
+    /// Keep elements that are not matched by the nested operators. The nested operators are
+    /// applied to each element on its own: a leading CSS selector is tested against the element
+    /// itself (as with `Element.matches`), and each following operator filters or transforms the
+    /// result. The element is kept if nothing remains.
+    ///
+    /// Only used when the negation contains procedural operators, e.g. `:not(:has-text(Ad))`;
+    /// plain CSS negations like `:not(.visible)` remain part of the surrounding CSS selector.
+    Not(Vec<ProceduralOperator>),
+    /// Keep elements for which the given CSS property matches, in `property: value` form. The
+    /// value may be a regular expression.
+    MatchesCss(String),
+    /// Like `MatchesCss`, but checks the `::before` pseudo-element.
+    MatchesCssBefore(String),
+    /// Like `MatchesCss`, but checks the `::after` pseudo-element.
+    MatchesCssAfter(String),
+    /// Keep elements with an attribute matching the argument, in `name="value"` form. Either part
+    /// may be a regular expression.
+    MatchesAttr(String),
+    /// Keep all elements if the page's path and query match the argument, or none otherwise.
+    MatchesPath(String),
+    /// Replace each element with its ancestor. A numeric argument selects the n-th ancestor,
+    /// otherwise the argument is a CSS selector for the closest matching ancestor.
+    Upward(String),
+    /// Replace each element with the results of the XPath expression, evaluated with that
+    /// element as context.
+    Xpath(String),
+    /// Keep elements whose text content is at least this many characters long.
+    MinTextLength(u32),
+    /// Re-apply the filter whenever any of the listed attributes changes on the selected elements,
+    /// or any attribute if the list is empty.
+    WatchAttr(Vec<String>),
+}
+
+/// What to do with elements selected by a procedural filter. If no action is given, the elements
+/// should be hidden.
+///
+/// Serialized like `ProceduralOperator`, as `{ "type": ..., "arg": ... }`, with no `arg` for
+/// `Remove`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ProceduralAction {
+    /// Remove the elements from the DOM entirely.
+    Remove,
+    /// Apply the given CSS style to the elements.
+    Style(String),
+}
+
+/// Serialized form of a `ProceduralAction`. Serde's adjacently tagged enums can't be read back
+/// from formats that write structs as arrays, like MessagePack, for variants without content.
+#[derive(Serialize, Deserialize)]
+struct ProceduralActionRepr {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    arg: Option<String>,
+}
+
+impl Serialize for ProceduralAction {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = match self {
+            ProceduralAction::Remove => ProceduralActionRepr { kind: "remove".to_string(), arg: None },
+            ProceduralAction::Style(style) => ProceduralActionRepr { kind: "style".to_string(), arg: Some(style.clone()) },
+        };
+        repr.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ProceduralAction {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let repr = ProceduralActionRepr::deserialize(deserializer)?;
+        match repr.kind.as_str() {
+            "remove" => Ok(ProceduralAction::Remove),
+            "style" => repr.arg.map(ProceduralAction::Style).ok_or_else(|| D::Error::missing_field("arg")),
+            other => Err(D::Error::unknown_variant(other, &["remove", "style"])),
+        }
+    }
+}
+
+/// A compiled procedural cosmetic filter.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ProceduralFilter {
+    pub selector: Vec<ProceduralOperator>,
+    pub action: Option<ProceduralAction>,
+}
+
+/// Operator names that are recognized as procedural, but can't currently be compiled.
+const UNSUPPORTED_OPERATORS: &[&str] = &[
+    "-abp-properties",
+    "matches-media",
+    "others",
+    "properties",
+    "remove-attr",
+    "remove-class",
+    "subject",
+];
+
+enum Step {
+    Operator(ProceduralOperator),
+    Action(ProceduralAction),
+}
+
+/// Compiles the `:name(arg)` operator into a step, or returns `Ok(None)` if `name` is not a
+/// procedural operator.
+fn compile_operator(name: &str, arg: &str) -> Result<Option<Step>, CosmeticFilterError> {
+    use ProceduralOperator as Op;
+
+    let non_empty = |arg: &str| {
+        if arg.trim().is_empty() {
+            Err(CosmeticFilterError::InvalidProceduralArgument)
+        } else {
+            Ok(arg.to_string())
+        }
+    };
+    let css_property = |arg: &str| {
+        if arg.contains(':') {
+            Ok(arg.trim().to_string())
+        } else {
+            Err(CosmeticFilterError::InvalidProceduralArgument)
+        }
+    };
+
+    let step = match name {
+        "has-text" | "contains" | "-abp-contains" => Step::Operator(Op::HasText(non_empty(arg)?)),
+        "has" | "-abp-has" | "if" => {
+            let (operators, action) = parse_operators(arg)?;
+            if action.is_some() {
+                return Err(CosmeticFilterError::InvalidProceduralOperator);
+            }
+            Step::Operator(Op::Has(operators))
+        }
+        "not" => {
+            // Plain CSS negations are left to the browser
+            if arg.trim().is_empty() {
+                return Ok(None);
+            }
+            let (operators, action) = parse_operators(arg)?;
+            if action.is_some() {
+                return Err(CosmeticFilterError::InvalidProceduralOperator);
+            }
+            if let [Op::CssSelector(_)] = operators.as_slice() {
+                return Ok(None);
+            }
+            Step::Operator(Op::Not(operators))
+        }
+        // `:if-not(x)` and `:not-has(x)` are shorthands for `:not(:has(x))`
+        "if-not" | "not-has" => {
+            let (operators, action) = parse_operators(arg)?;
+            if action.is_some() {
+                return Err(CosmeticFilterError::InvalidProceduralOperator);
+            }
+            Step::Operator(Op::Not(vec![Op::Has(operators)]))
+        }
+        "matches-css" => Step::Operator(Op::MatchesCss(css_property(arg)?)),
+        "matches-css-before" => Step::Operator(Op::MatchesCssBefore(css_property(arg)?)),
+        "matches-css-after" => Step::Operator(Op::MatchesCssAfter(css_property(arg)?)),
+        "matches-attr" => Step::Operator(Op::MatchesAttr(non_empty(arg)?)),
+        "matches-path" => Step::Operator(Op::MatchesPath(non_empty(arg)?)),
+        "nth-ancestor" | "upward" => {
+            let arg = arg.trim();
+            match arg.parse::<u32>() {
+                Ok(n) if (1..=256).contains(&n) => (),
+                // `nth-ancestor` only accepts numbers
+                _ if name == "nth-ancestor" => return Err(CosmeticFilterError::InvalidProceduralArgument),
+                Err(_) if !arg.is_empty() => (),
+                _ => return Err(CosmeticFilterError::InvalidProceduralArgument),
+            }
+            Step::Operator(Op::Upward(arg.to_string()))
+        }
+        "xpath" => Step::Operator(Op::Xpath(non_empty(arg)?)),
+        "min-text-length" => match arg.trim().parse::<u32>() {
+            Ok(n) if n > 0 => Step::Operator(Op::MinTextLength(n)),
+            _ => return Err(CosmeticFilterError::InvalidProceduralArgument),
+        },
+        "watch-attr" | "watch-attrs" => {
+            let attributes = arg.split(',')
+                .map(|attr| attr.trim())
+                .filter(|attr| !attr.is_empty())
+                .map(|attr| {
+                    if attr.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == ':') {
+                        Ok(attr.to_string())
+                    } else {
+                        Err(CosmeticFilterError::InvalidProceduralArgument)
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Step::Operator(Op::WatchAttr(attributes))
+        }
+        "remove" => {
+            if !arg.trim().is_empty() {
+                return Err(CosmeticFilterError::InvalidProceduralArgument);
+            }
+            Step::Action(ProceduralAction::Remove)
+        }
+        "style" => Step::Action(ProceduralAction::Style(non_empty(arg)?)),
+        _ if UNSUPPORTED_OPERATORS.contains(&name) => return Err(CosmeticFilterError::UnsupportedSyntax),
+        _ => return Ok(None),
+    };
+
+    Ok(Some(step))
+}
+
+/// Returns the index of the `)` matching the `(` at `open_index`.
+fn find_closing_paren(input: &str, open_index: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut escaped = false;
+    for (i, c) in input[open_index..].char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(open_index + i);
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Splits `input` into a list of operators and an optional trailing action.
+fn parse_operators(input: &str) -> Result<(Vec<ProceduralOperator>, Option<ProceduralAction>), CosmeticFilterError> {
+    let mut operators = vec![];
+    let mut action = None;
+
+    let mut css_start = 0;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut bracket_depth = 0;
+    let mut paren_depth = 0;
+
+    let mut index = 0;
+    while let Some(c) = input[index..].chars().next() {
+        let mut next_index = index + c.len_utf8();
+
+        if escaped {
+            escaped = false;
+        } else if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            } else if c == '\\' {
+                escaped = true;
+            }
+        } else {
+            match c {
+                '\\' => escaped = true,
+                '"' | '\'' => quote = Some(c),
+                '[' => bracket_depth += 1,
+                ']' => bracket_depth -= 1,
+                '(' => paren_depth += 1,
+                ')' => paren_depth -= 1,
+                ':' if bracket_depth == 0 && paren_depth == 0 => {
+                    let name_len = input[next_index..]
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+                        .unwrap_or(input.len() - next_index);
+                    let name = &input[next_index..next_index + name_len];
+                    let open_index = next_index + name_len;
+                    if !name.is_empty() && input[open_index..].starts_with('(') {
+                        let close_index = find_closing_paren(input, open_index)
+                            .ok_or(CosmeticFilterError::InvalidProceduralOperator)?;
+                        if let Some(step) = compile_operator(name, &input[open_index + 1..close_index])? {
+                            if action.is_some() {
+                                // actions must come last
+                                return Err(CosmeticFilterError::InvalidProceduralOperator);
+                            }
+                            let css = &input[css_start..index];
+                            if operators.is_empty() && !css.trim().is_empty() {
+                                operators.push(ProceduralOperator::CssSelector(css.trim().to_string()));
+                            } else if !css.trim().is_empty() {
+                                operators.push(ProceduralOperator::CssSelector(css.to_string()));
+                            }
+                            match step {
+                                Step::Operator(op) => operators.push(op),
+                                Step::Action(a) => action = Some(a),
+                            }
+                            next_index = close_index + 1;
+                            css_start = next_index;
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        index = next_index;
+    }
+
+    let css = &input[css_start..];
+    if !css.trim().is_empty() {
+        if action.is_some() {
+            return Err(CosmeticFilterError::InvalidProceduralOperator);
+        }
+        if operators.is_empty() {
+            operators.push(ProceduralOperator::CssSelector(css.trim().to_string()));
+        } else {
+            operators.push(ProceduralOperator::CssSelector(css.to_string()));
+        }
+    }
+
+    if operators.is_empty() {
+        return Err(CosmeticFilterError::InvalidProceduralOperator);
+    }
+
+    Ok((operators, action))
+}
+
+/// Compiles the part of a cosmetic filter rule after the `##` or `#@#` separator.
+///
+/// Returns `Ok(None)` if the selector doesn't need procedural handling, i.e. it is plain CSS,
+/// optionally followed by a `:style()` specifier.
+pub fn parse_procedural_selector(selector: &str) -> Result<Option<ProceduralFilter>, CosmeticFilterError> {
+    let (operators, action) = parse_operators(selector)?;
+
+    let only_css = operators.len() == 1 && matches!(operators[0], ProceduralOperator::CssSelector(_));
+    match action {
+        None | Some(ProceduralAction::Style(_)) if only_css => Ok(None),
+        action => Ok(Some(ProceduralFilter {
+            selector: operators,
+            action,
+        })),
+    }
+}
+
+/// Writes the operators back in filter list syntax, using the canonical name of each operator.
+fn write_operators(f: &mut fmt::Formatter, operators: &[ProceduralOperator]) -> fmt::Result {
+    use ProceduralOperator as Op;
+
+    for operator in operators {
+        match operator {
+            Op::CssSelector(css) => write!(f, "{}", css)?,
+            Op::HasText(arg) => write!(f, ":has-text({})", arg)?,
+            Op::Has(nested) => {
+                write!(f, ":has(")?;
+                write_operators(f, nested)?;
+                write!(f, ")")?;
+            }
+            Op::Not(nested) => {
+                write!(f, ":not(")?;
+                write_operators(f, nested)?;
+                write!(f, ")")?;
+            }
+            Op::MatchesCss(arg) => write!(f, ":matches-css({})", arg)?,
+            Op::MatchesCssBefore(arg) => write!(f, ":matches-css-before({})", arg)?,
+            Op::MatchesCssAfter(arg) => write!(f, ":matches-css-after({})", arg)?,
+            Op::MatchesAttr(arg) => write!(f, ":matches-attr({})", arg)?,
+            Op::MatchesPath(arg) => write!(f, ":matches-path({})", arg)?,
+            Op::Upward(arg) => write!(f, ":upward({})", arg)?,
+            Op::Xpath(arg) => write!(f, ":xpath({})", arg)?,
+            Op::MinTextLength(length) => write!(f, ":min-text-length({})", length)?,
+            Op::WatchAttr(attributes) => write!(f, ":watch-attr({})", attributes.join(","))?,
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for ProceduralFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_operators(f, &self.selector)?;
+        match &self.action {
+            None => Ok(()),
+            Some(ProceduralAction::Remove) => write!(f, ":remove()"),
+            Some(ProceduralAction::Style(style)) => write!(f, ":style({})", style),
+        }
+    }
+}
+
+#[cfg(test)]
+mod parse_tests {
+    use super::*;
+    use ProceduralOperator as Op;
+
+    fn css(s: &str) -> Op {
+        Op::CssSelector(s.to_string())
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for selector in [
+            "div.ad:has-text(Sponsored):upward(2)",
+            "article:not(:has-text(Ad)):remove()",
+            ".banner:matches-css(position: fixed):style(display: none !important)",
+            "div:min-text-length(100):watch-attr(class,style)",
+        ].iter() {
+            let filter = parse_procedural_selector(selector).unwrap().unwrap();
+            assert_eq!(filter.to_string(), *selector);
+        }
+    }
+
+    #[test]
+    fn plain_css_is_not_procedural() {
+        assert_eq!(parse_procedural_selector(".ad"), Ok(None));
+        assert_eq!(parse_procedural_selector("div:not(.a) > span:nth-child(2)"), Ok(None));
+        assert_eq!(parse_procedural_selector(r#"a[href^="http://x.com/:has-text("]"#), Ok(None));
+        assert_eq!(parse_procedural_selector(".ad:style(color: red)"), Ok(None));
+    }
+
+    #[test]
+    fn operator_chains() {
+        assert_eq!(
+            parse_procedural_selector("article:has-text(/Promoted|Реклама/):xpath(../..)"),
+            Ok(Some(ProceduralFilter {
+                selector: vec![
+                    css("article"),
+                    Op::HasText("/Promoted|Реклама/".to_string()),
+                    Op::Xpath("../..".to_string()),
+                ],
+                action: None,
+            })),
+        );
+        assert_eq!(
+            parse_procedural_selector("div.ad:upward(2) > span:remove()"),
+            Ok(Some(ProceduralFilter {
+                selector: vec![css("div.ad"), Op::Upward("2".to_string()), css(" > span")],
+                action: Some(ProceduralAction::Remove),
+            })),
+        );
+        assert_eq!(
+            parse_procedural_selector(".serp-item:if(:scope > div.organic:matches-css-after(content: /[Рр]еклама/))"),
+            Ok(Some(ProceduralFilter {
+                selector: vec![
+                    css(".serp-item"),
+                    Op::Has(vec![
+                        css(":scope > div.organic"),
+                        Op::MatchesCssAfter("content: /[Рр]еклама/".to_string()),
+                    ]),
+                ],
+                action: None,
+            })),
+        );
+        assert_eq!(
+            parse_procedural_selector("#main:has-text(a\\)b):style(opacity: 0)"),
+            Ok(Some(ProceduralFilter {
+                selector: vec![css("#main"), Op::HasText("a\\)b".to_string())],
+                action: Some(ProceduralAction::Style("opacity: 0".to_string())),
+            })),
+        );
+        assert_eq!(
+            parse_procedural_selector(".ad:remove()"),
+            Ok(Some(ProceduralFilter {
+                selector: vec![css(".ad")],
+                action: Some(ProceduralAction::Remove),
+            })),
+        );
+    }
+
+    #[test]
+    fn min_text_length_and_watch_attr() {
+        assert_eq!(
+            parse_procedural_selector("div.post:min-text-length(200)"),
+            Ok(Some(ProceduralFilter {
+                selector: vec![css("div.post"), Op::MinTextLength(200)],
+                action: None,
+            })),
+        );
+        assert_eq!(
+            parse_procedural_selector("div[id]:watch-attr(class, data-ad):has-text(Ad)"),
+            Ok(Some(ProceduralFilter {
+                selector: vec![
+                    css("div[id]"),
+                    Op::WatchAttr(vec!["class".to_string(), "data-ad".to_string()]),
+                    Op::HasText("Ad".to_string()),
+                ],
+                action: None,
+            })),
+        );
+        assert_eq!(
+            parse_procedural_selector("div:watch-attr()"),
+            Ok(Some(ProceduralFilter {
+                selector: vec![css("div"), Op::WatchAttr(vec![])],
+                action: None,
+            })),
+        );
+
+        assert_eq!(parse_procedural_selector("div:min-text-length(0)"), Err(CosmeticFilterError::InvalidProceduralArgument));
+        assert_eq!(parse_procedural_selector("div:min-text-length(-1)"), Err(CosmeticFilterError::InvalidProceduralArgument));
+        assert_eq!(parse_procedural_selector("div:min-text-length(abc)"), Err(CosmeticFilterError::InvalidProceduralArgument));
+        assert_eq!(parse_procedural_selector("div:watch-attr(class, [x])"), Err(CosmeticFilterError::InvalidProceduralArgument));
+
+        let json = serde_json::to_value(&Op::MinTextLength(20)).unwrap();
+        assert_eq!(json, serde_json::json!({ "type": "min-text-length", "arg": 20 }));
+        let json = serde_json::to_value(&Op::WatchAttr(vec!["class".to_string()])).unwrap();
+        assert_eq!(json, serde_json::json!({ "type": "watch-attr", "arg": ["class"] }));
+    }
+
+    #[test]
+    fn negation() {
+        assert_eq!(parse_procedural_selector("div:not(.visible):not(:nth-child(2))"), Ok(None));
+        assert_eq!(
+            parse_procedural_selector("div.post:not(:has-text(Sponsored)) > span"),
+            Ok(Some(ProceduralFilter {
+                selector: vec![
+                    css("div.post"),
+                    Op::Not(vec![Op::HasText("Sponsored".to_string())]),
+                    css(" > span"),
+                ],
+                action: None,
+            })),
+        );
+        assert_eq!(
+            parse_procedural_selector("li:not(.pinned:has(> a[href*=\"ads\"]))"),
+            Ok(Some(ProceduralFilter {
+                selector: vec![
+                    css("li"),
+                    Op::Not(vec![css(".pinned"), Op::Has(vec![css("> a[href*=\"ads\"]")])]),
+                ],
+                action: None,
+            })),
+        );
+        assert_eq!(
+            parse_procedural_selector("div:if-not(span.label)"),
+            Ok(Some(ProceduralFilter {
+                selector: vec![css("div"), Op::Not(vec![Op::Has(vec![css("span.label")])])],
+                action: None,
+            })),
+        );
+
+        let json = serde_json::to_value(&Op::Not(vec![Op::HasText("Ad".to_string())])).unwrap();
+        assert_eq!(json, serde_json::json!({ "type": "not", "arg": [{ "type": "has-text", "arg": "Ad" }] }));
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(parse_procedural_selector("div:has-text()"), Err(CosmeticFilterError::InvalidProceduralArgument));
+        assert_eq!(parse_procedural_selector("div:has-text(a"), Err(CosmeticFilterError::InvalidProceduralOperator));
+        assert_eq!(parse_procedural_selector("div:nth-ancestor(a)"), Err(CosmeticFilterError::InvalidProceduralArgument));
+        assert_eq!(parse_procedural_selector("div:upward(0)"), Err(CosmeticFilterError::InvalidProceduralArgument));
+        assert_eq!(parse_procedural_selector("div:matches-css(red)"), Err(CosmeticFilterError::InvalidProceduralArgument));
+        assert_eq!(parse_procedural_selector("div:remove(x)"), Err(CosmeticFilterError::InvalidProceduralArgument));
+        assert_eq!(parse_procedural_selector("div:remove():has-text(a)"), Err(CosmeticFilterError::InvalidProceduralOperator));
+        assert_eq!(parse_procedural_selector("div:remove() span"), Err(CosmeticFilterError::InvalidProceduralOperator));
+        assert_eq!(parse_procedural_selector("div:has(span:remove())"), Err(CosmeticFilterError::InvalidProceduralOperator));
+        assert_eq!(parse_procedural_selector(":remove()"), Err(CosmeticFilterError::InvalidProceduralOperator));
+        assert_eq!(parse_procedural_selector("div:others()"), Err(CosmeticFilterError::UnsupportedSyntax));
+        assert_eq!(parse_procedural_selector("div:not(span:remove())"), Err(CosmeticFilterError::InvalidProceduralOperator));
+        assert_eq!(parse_procedural_selector("div:not(:has-text())"), Err(CosmeticFilterError::InvalidProceduralArgument));
+    }
+
+    #[test]
+    fn serialization_format() {
+        let filter = parse_procedural_selector("div.ad:has-text(Sponsored):upward(2):remove()").unwrap().unwrap();
+        let json = serde_json::to_value(&filter).unwrap();
+        assert_eq!(json, serde_json::json!({
+            "selector": [
+                { "type": "css-selector", "arg": "div.ad" },
+                { "type": "has-text", "arg": "Sponsored" },
+                { "type": "upward", "arg": "2" }
+            ],
+            "action": { "type": "remove" }
+        }));
+        assert_eq!(serde_json::from_value::<ProceduralFilter>(json).unwrap(), filter);
+    }
+
+    #[test]
+    fn unit_action_round_trips() {
+        let json = serde_json::to_value(&ProceduralAction::Remove).unwrap();
+        assert_eq!(json, serde_json::json!({ "type": "remove" }));
+        assert_eq!(serde_json::from_value::<ProceduralAction>(json).unwrap(), ProceduralAction::Remove);
+
+        // as stored in serialized engines
+        let filter = parse_procedural_selector("div.ad:remove()").unwrap().unwrap();
+        assert_eq!(filter.action, Some(ProceduralAction::Remove));
+        let serialized = rmp_serde::to_vec(&filter).unwrap();
+        assert_eq!(rmp_serde::from_slice::<ProceduralFilter>(&serialized).unwrap(), filter);
+
+        let filter = parse_procedural_selector("div:has-text(ad):style(color: red)").unwrap().unwrap();
+        let json = serde_json::to_value(&filter.action).unwrap();
+        assert_eq!(json, serde_json::json!({ "type": "style", "arg": "color: red" }));
+        let serialized = rmp_serde::to_vec(&filter).unwrap();
+        assert_eq!(rmp_serde::from_slice::<ProceduralFilter>(&serialized).unwrap(), filter);
+    }
+}
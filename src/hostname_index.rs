@@ -0,0 +1,134 @@
+//! Reverse index from hostnames to the network filters specific to them, for inspecting the rules
+//! that apply to a site without having to check requests against them.
+
+use crate::blocker::{Blocker, FilterClass};
+use crate::filters::network::NetworkFilter;
+use crate::utils::{self, Hash};
+
+use std::collections::HashMap;
+
+/// How a filter relates to the hostname it was found for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SiteRelation {
+    /// The filter only applies to requests made from pages of the hostname, through `$domain`
+    SourceDomain,
+    /// The filter applies to requests to the hostname, e.g. `||ads.example.com^`
+    RequestHostname,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SiteFilter {
+    /// Text of the rule if the engine was created in debug mode, or the canonical text of the
+    /// filter otherwise, see `NetworkFilter::canonical_text`.
+    pub filter: String,
+    /// Stable id of the rule, see `NetworkFilter::id`
+    pub id: Hash,
+    pub exception: bool,
+    pub relation: SiteRelation,
+}
+
+/// Snapshot of the hostname-specific network filters of an engine. Filters without any hostname
+/// constraint apply to all sites, and are not indexed.
+pub struct HostnameIndex {
+    filters: Vec<(SiteFilter, Option<Vec<Hash>>)>,
+    by_hostname: HashMap<Hash, Vec<u32>>,
+}
+
+impl HostnameIndex {
+    pub(crate) fn new(blocker: &Blocker) -> Self {
+        let mut index = Self {
+            filters: vec![],
+            by_hostname: HashMap::new(),
+        };
+
+        for class in FilterClass::ALL.iter() {
+            for filter in blocker.list(*class).iter_filters() {
+                if let Some(domains) = filter.opt_domains.as_ref() {
+                    index.insert(filter, SiteRelation::SourceDomain, domains.iter().copied());
+                }
+                if filter.is_hostname_anchor() {
+                    if let Some(hostname) = filter.hostname.as_ref() {
+                        index.insert(filter, SiteRelation::RequestHostname, std::iter::once(utils::fast_hash(hostname)));
+                    }
+                }
+            }
+        }
+
+        index
+    }
+
+    fn insert(&mut self, filter: &NetworkFilter, relation: SiteRelation, hostnames: impl Iterator<Item = Hash>) {
+        let id = self.filters.len() as u32;
+        self.filters.push((SiteFilter {
+            filter: filter.to_string(),
+            id: filter.id,
+            exception: filter.is_exception(),
+            relation,
+        }, filter.opt_not_domains.clone()));
+        for hostname in hostnames {
+            self.by_hostname.entry(hostname).or_insert_with(Vec::new).push(id);
+        }
+    }
+
+    /// Returns the filters that are specific to `hostname` or one of its parent domains, and not
+    /// excluded from it with `$domain=~...`.
+    pub fn filters_for(&self, hostname: &str) -> Vec<&SiteFilter> {
+        let parents = if utils::is_ip_literal(hostname) { None } else { Some(hostname.match_indices('.')) };
+        let hostname_hashes: Vec<Hash> = std::iter::once(hostname)
+            .chain(parents.into_iter().flatten().map(|(i, _)| &hostname[i + 1..]))
+            .filter(|suffix| !suffix.is_empty())
+            .map(utils::fast_hash)
+            .collect();
+
+        let mut ids: Vec<u32> = hostname_hashes
+            .iter()
+            .flat_map(|hash| self.by_hostname.get(hash).into_iter().flatten().copied())
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+
+        ids.into_iter()
+            .map(|id| &self.filters[id as usize])
+            .filter(|(_, excluded)| match excluded {
+                Some(excluded) => hostname_hashes.iter().all(|hash| !utils::bin_lookup(excluded, *hash)),
+                None => true,
+            })
+            .map(|(filter, _)| filter)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::lists::FilterFormat;
+
+    #[test]
+    fn finds_site_specific_filters() {
+        let engine = Engine::from_rules_parametrised(&[
+            "/ads/*$domain=example.com|~shop.example.com".to_string(),
+            "@@/ads/ok.js$domain=news.example.com".to_string(),
+            "||tracker.example.com^".to_string(),
+            "/generic-ads/*".to_string(),
+        ], FilterFormat::Standard, true, false);
+        let index = engine.hostname_index();
+
+        let filters = |hostname: &str| {
+            let mut filters = index.filters_for(hostname).into_iter().map(|f| (f.filter.as_str(), f.exception, f.relation)).collect::<Vec<_>>();
+            filters.sort_by_key(|(filter, _, _)| *filter);
+            filters
+        };
+
+        assert_eq!(filters("news.example.com"), vec![
+            ("/ads/*$domain=example.com|~shop.example.com", false, SiteRelation::SourceDomain),
+            ("@@/ads/ok.js$domain=news.example.com", true, SiteRelation::SourceDomain),
+        ]);
+        assert_eq!(filters("shop.example.com"), vec![]);
+        assert_eq!(filters("a.tracker.example.com"), vec![
+            ("/ads/*$domain=example.com|~shop.example.com", false, SiteRelation::SourceDomain),
+            ("||tracker.example.com^", false, SiteRelation::RequestHostname),
+        ]);
+        assert_eq!(filters("example.org"), vec![]);
+    }
+}
@@ -0,0 +1,129 @@
+//! Composition of several engines, each holding rules from a source with a different priority.
+//!
+//! Deployments often combine rules with different levels of authority, e.g. enterprise policy,
+//! then the user's own rules, then subscribed lists. Compiling them into a single `Engine` would
+//! let an exception from a subscribed list unblock a request that the policy blocks. A
+//! `LayeredEngine` instead checks each layer in order of priority, and the first layer with a
+//! matching rule decides the outcome, regardless of any lower layers.
+
+use crate::blocker::BlockerResult;
+use crate::engine::Engine;
+use crate::lists::FilterSet;
+use crate::request::Request;
+
+/// A set of engines checked in order of priority.
+#[derive(Clone, Default)]
+pub struct LayeredEngine {
+    /// Highest priority first
+    layers: Vec<(String, Engine)>,
+}
+
+/// The outcome of checking a request against a `LayeredEngine`.
+#[derive(Debug)]
+pub struct LayeredResult {
+    /// Result of the layer that decided, or of the lowest layer if none did.
+    pub result: BlockerResult,
+    /// Name of the layer that decided the outcome, or `None` if no layer had a matching rule.
+    pub layer: Option<String>,
+}
+
+impl LayeredEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds a layer from each of the given filter sets, in order of decreasing priority.
+    pub fn from_filter_sets(filter_sets: Vec<(&str, FilterSet)>, optimize: bool) -> Self {
+        let mut layered = Self::new();
+        for (name, filter_set) in filter_sets {
+            layered.push_layer(name, Engine::from_filter_set(filter_set, optimize));
+        }
+        layered
+    }
+
+    /// Adds a layer with lower priority than all existing layers.
+    pub fn push_layer(&mut self, name: &str, engine: Engine) {
+        self.layers.push((name.to_string(), engine));
+    }
+
+    /// Names of the layers, highest priority first.
+    pub fn layer_names(&self) -> impl Iterator<Item = &str> {
+        self.layers.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Returns the engine of the named layer, e.g. to update its resources or tags.
+    pub fn layer_mut(&mut self, name: &str) -> Option<&mut Engine> {
+        self.layers.iter_mut().find(|(layer, _)| layer == name).map(|(_, engine)| engine)
+    }
+
+    /// Checks a request against each layer in turn, like `Engine::check_network_urls`.
+    ///
+    /// A layer decides the outcome as soon as any of its rules match: a blocking or redirect rule
+    /// blocks the request, and an exception allows it, even if a lower layer has a matching
+    /// `$important` rule. Exceptions are looked up in each layer even if none of its blocking
+    /// rules match, so that a layer holding only exceptions can still allow requests. Within a
+    /// layer, rules take precedence as they do in a single `Engine`.
+    pub fn check_network_urls(&self, url: &str, source_url: &str, request_type: &str) -> LayeredResult {
+        let request = match Request::from_urls(url, source_url, request_type) {
+            Ok(request) => request,
+            Err(_) => return LayeredResult {
+                result: BlockerResult {
+                    error: Some("Error parsing request".to_owned()),
+                    ..BlockerResult::default()
+                },
+                layer: None,
+            },
+        };
+
+        let mut result = BlockerResult::default();
+        for (name, engine) in self.layers.iter() {
+            result = engine.check_request(&request, false, true);
+            if result.matched || result.exception.is_some() {
+                return LayeredResult {
+                    result,
+                    layer: Some(name.clone()),
+                };
+            }
+        }
+        LayeredResult {
+            result,
+            layer: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lists::FilterFormat;
+
+    fn engine(rules: &[&str]) -> Engine {
+        Engine::from_rules_debug(&rules.iter().map(|r| r.to_string()).collect::<Vec<_>>(), FilterFormat::Standard)
+    }
+
+    #[test]
+    fn higher_layers_decide() {
+        let mut layered = LayeredEngine::new();
+        layered.push_layer("policy", engine(&["||blocked-by-policy.com^", "@@||allowed-by-policy.com^"]));
+        layered.push_layer("user", engine(&["@@||blocked-by-policy.com^", "||ads.com/user^", "@@||ads.com/allowed^"]));
+        layered.push_layer("lists", engine(&["||allowed-by-policy.com^$important", "||ads.com^", "@@||ads.com/user^"]));
+        assert_eq!(layered.layer_names().collect::<Vec<_>>(), vec!["policy", "user", "lists"]);
+
+        let check = |url: &str| {
+            let result = layered.check_network_urls(url, "https://example.com", "script");
+            (result.result.matched, result.layer)
+        };
+
+        assert_eq!(check("https://blocked-by-policy.com/a.js"), (true, Some("policy".to_string())));
+        assert_eq!(check("https://allowed-by-policy.com/a.js"), (false, Some("policy".to_string())));
+        assert_eq!(check("https://ads.com/user/a.js"), (true, Some("user".to_string())));
+        assert_eq!(check("https://ads.com/a.js"), (true, Some("lists".to_string())));
+        // An exception with no matching blocking rule in its layer still overrides lower layers
+        assert_eq!(check("https://ads.com/allowed/a.js"), (false, Some("user".to_string())));
+        assert_eq!(check("https://example.org/a.js"), (false, None));
+
+        let invalid = layered.check_network_urls("not a url", "", "script");
+        assert!(invalid.result.error.is_some());
+        assert_eq!(invalid.layer, None);
+    }
+}
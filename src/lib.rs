@@ -6,6 +6,7 @@
 pub mod utils;
 pub mod request;
 pub mod lists;
+pub mod parsed_list;
 pub mod filters;
 pub mod blocker;
 pub mod optimizer;
@@ -13,6 +14,33 @@ pub mod url_parser;
 pub mod engine;
 pub mod resources;
 pub mod cosmetic_filter_cache;
+pub mod cosmetic;
 pub mod data_format;
+pub mod proxy;
+pub mod refresh;
+pub mod diff;
+pub mod layers;
+pub mod hostname_index;
+pub mod audit;
+pub mod decision_log;
+mod decision_cache;
+pub mod registry;
+pub mod regional;
+pub mod schedule;
+pub mod progress;
+pub mod popup;
+pub mod conversion;
+pub mod preprocessor;
+pub mod similarity;
+pub mod tracking_params;
+pub mod referrer;
+pub mod pipeline;
+pub mod compiled_filters;
+pub mod prelude;
 #[cfg(feature = "content-blocking")]
 pub mod content_blocking;
+#[cfg(feature = "declarative-net-request")]
+pub mod declarative_net_request;
+
+// `utils` is hidden from the documentation, but its tokenizer is public API
+pub use utils::{tokenize, tokenize_filter, token_histogram};
@@ -1,10 +1,18 @@
 //! Parsing functions and collections for handling with multiple filter rules.
 
-use crate::filters::network::{NetworkFilter, NetworkFilterError};
-use crate::filters::cosmetic::{CosmeticFilter, CosmeticFilterError};
+use crate::filters::network::{NetworkFilter, NetworkFilterError, ParseOptions};
+use crate::filters::cosmetic::{CosmeticFilter, CosmeticFilterError, CosmeticFilterMask};
+use crate::progress::{Cancelled, CancellationToken, PROGRESS_INTERVAL};
+use crate::conversion::{ConversionOutcome, ConversionReason, ConversionReport};
+use crate::preprocessor::{LineOutcome, Preprocessor, PreprocessorOptions, UnknownDirectivePolicy};
+use crate::parsed_list::ParsedList;
 
 use itertools::{Either, Itertools};
 use serde::{Deserialize, Serialize};
+use crate::utils::{fast_hash, Hash};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
 
 /// iOS and macOS limit the number of content blocking rules that can be loaded. To better
 /// fine-tune content-blocking behavior, the types of rules converted can be restricted using this
@@ -43,6 +51,106 @@ impl RuleTypes {
     }
 }
 
+/// Location of a rule within the filter list it was loaded from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilterSource {
+    /// Identifier of the list, as given to `FilterSet::add_filter_list_with_id`.
+    pub list_id: String,
+    /// Line number within the list, starting from 1.
+    pub line: usize,
+    /// Original text of the line.
+    pub text: String,
+}
+
+/// A filter list that was left out entirely by `FilterSet::add_filter_lists_soft_fail`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedList {
+    pub list_id: String,
+    /// Description of the error that was reported for the list, e.g. a failed download.
+    pub reason: String,
+}
+
+/// A rule that could not be parsed by `FilterSet::add_filter_lists_soft_fail`.
+#[derive(Debug)]
+pub struct SkippedRule {
+    pub list_id: String,
+    /// Line number within the list, starting from 1.
+    pub line: usize,
+    pub text: String,
+    pub error: FilterParseError,
+}
+
+/// Describes what was left out when loading several filter lists with
+/// `FilterSet::add_filter_lists_soft_fail`.
+///
+/// Comments, empty lines and rules using syntax that is deliberately unsupported are not reported.
+#[derive(Debug, Default)]
+pub struct LoadReport {
+    pub skipped_lists: Vec<SkippedList>,
+    pub skipped_rules: Vec<SkippedRule>,
+    /// Number of network rules loaded from each list, by list id, that are matched with a regex
+    /// (see `NetworkFilter::uses_regex`). Each of these compiles its own regex once used, so
+    /// lists with many of them can take up a lot of memory.
+    pub regex_filters: HashMap<String, usize>,
+}
+
+impl LoadReport {
+    /// Whether every list and rule was loaded successfully.
+    pub fn is_complete(&self) -> bool {
+        self.skipped_lists.is_empty() && self.skipped_rules.is_empty()
+    }
+}
+
+/// How a line was handled by `FilterSet::import_user_rules`.
+#[derive(Debug)]
+pub enum ImportedLineKind {
+    Empty,
+    /// `!` comments, `#` comments as in hosts files, and `[Adblock Plus 2.0]`-style headers
+    Comment,
+    /// A rule in `FilterFormat::Standard` syntax
+    Rule,
+    /// An IP address followed by a hostname, as in hosts files
+    HostsEntry,
+    /// A hostname on its own, blocked like a hosts file entry
+    Domain,
+    /// A line in a `!#if` block whose condition is false
+    Excluded,
+    /// A line that could not be added, with the reason
+    Skipped(FilterParseError),
+}
+
+#[derive(Debug)]
+pub struct ImportedLine {
+    /// Line number within the dump, starting from 1.
+    pub line: usize,
+    pub text: String,
+    pub kind: ImportedLineKind,
+    /// The rule in `FilterFormat::Standard` syntax, for lines that were added.
+    pub normalized: Option<String>,
+}
+
+/// Describes how each line of a dump was handled by `FilterSet::import_user_rules`.
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub lines: Vec<ImportedLine>,
+}
+
+impl ImportReport {
+    /// Number of lines that were added as rules.
+    pub fn imported(&self) -> usize {
+        self.lines.iter().filter(|line| line.normalized.is_some()).count()
+    }
+
+    pub fn skipped(&self) -> impl Iterator<Item = &ImportedLine> {
+        self.lines.iter().filter(|line| matches!(line.kind, ImportedLineKind::Skipped(_)))
+    }
+}
+
+/// Rules longer than this many bytes are skipped by `FilterSet`, unless configured otherwise with
+/// `FilterSet::set_max_filter_length`. This bounds the cost of tokenizing, hashing, and compiling
+/// regexes for adversarial input, while comfortably fitting the longest rules in popular lists.
+pub const DEFAULT_MAX_FILTER_LENGTH: usize = 64 * 1024;
+
 /// Manages a set of rules to be added to an `Engine`.
 ///
 /// To be able to efficiently handle special options like `$badfilter`, and to allow optimizations,
@@ -53,6 +161,64 @@ pub struct FilterSet {
     debug: bool,
     pub(crate) network_filters: Vec<NetworkFilter>,
     pub(crate) cosmetic_filters: Vec<CosmeticFilter>,
+    /// `$popup` rules, which are kept apart from `network_filters` as they never apply to requests
+    pub(crate) popup_filters: Vec<NetworkFilter>,
+    /// Cosmetic rules of lists added with `add_tagged_filter_list`, along with their tag
+    pub(crate) tagged_cosmetic_filters: Vec<(String, CosmeticFilter)>,
+    /// Only populated in debug mode, keyed by the trimmed text of each rule.
+    pub(crate) sources: HashMap<String, Vec<FilterSource>>,
+    /// `! Version:` headers of lists added with an id, keyed by list id.
+    pub(crate) list_versions: HashMap<String, String>,
+    max_filter_length: usize,
+    max_regex_filters_per_list: Option<usize>,
+    parse_options: ParseOptions,
+    parse_cache: Option<FilterParseCache>,
+    preprocessor: Option<PreprocessorOptions>,
+}
+
+/// Key of a `FilterParseCache` entry: the hash of the rule's text, and everything else that
+/// affects its parsed representation.
+type ParseCacheKey = (Hash, FilterFormat, bool, bool, ParseOptions);
+
+/// Parsed rules kept from a previous build of a `FilterSet`, to speed up applying list updates.
+///
+/// Usually, only a small fraction of a list's lines change between updates. Passing the cache
+/// from the last build to `FilterSet::enable_parse_cache` lets unchanged lines reuse their parsed
+/// representation, as well as the tokens computed for network rules once they were added to an
+/// `Engine`, instead of parsing and tokenizing them again.
+#[derive(Clone, Default)]
+pub struct FilterParseCache {
+    previous: HashMap<ParseCacheKey, ParsedFilter>,
+    current: HashMap<ParseCacheKey, ParsedFilter>,
+}
+
+impl FilterParseCache {
+    fn get(&mut self, key: &ParseCacheKey) -> Option<ParsedFilter> {
+        if let Some(parsed) = self.current.get(key) {
+            return Some(parsed.clone());
+        }
+        let parsed = self.previous.remove(key)?;
+        self.current.insert(*key, parsed.clone());
+        Some(parsed)
+    }
+
+    fn insert(&mut self, key: ParseCacheKey, mut parsed: ParsedFilter) -> ParsedFilter {
+        if let ParsedFilter::Network(filter) = &mut parsed {
+            filter.cache_tokens();
+        }
+        self.current.insert(key, parsed.clone());
+        parsed
+    }
+
+    /// Number of rules in the cache.
+    pub fn len(&self) -> usize {
+        self.previous.len() + self.current.len()
+    }
+
+    /// Whether the cache has no rules.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 impl Default for FilterSet {
@@ -78,9 +244,65 @@ impl FilterSet {
             debug,
             network_filters: Vec::new(),
             cosmetic_filters: Vec::new(),
+            popup_filters: Vec::new(),
+            tagged_cosmetic_filters: Vec::new(),
+            sources: HashMap::new(),
+            list_versions: HashMap::new(),
+            max_filter_length: DEFAULT_MAX_FILTER_LENGTH,
+            max_regex_filters_per_list: None,
+            parse_options: ParseOptions::default(),
+            parse_cache: None,
+            preprocessor: None,
         }
     }
 
+    /// Reuses the parse results in `previous`, typically obtained with `take_parse_cache` from the
+    /// `FilterSet` built for an earlier version of the same lists, for any identical rules added
+    /// to this `FilterSet`. Use `FilterParseCache::default()` when there is no previous build.
+    pub fn enable_parse_cache(&mut self, previous: FilterParseCache) {
+        self.parse_cache = Some(FilterParseCache {
+            previous: previous.current,
+            current: HashMap::new(),
+        });
+    }
+
+    /// Returns the parse results of rules added since `enable_parse_cache` was called, to be
+    /// passed on to the next build. Rules from the previous cache that were not added again are
+    /// discarded. Parse caching is disabled afterwards.
+    pub fn take_parse_cache(&mut self) -> FilterParseCache {
+        self.parse_cache.take().map(|cache| FilterParseCache {
+            previous: HashMap::new(),
+            current: cache.current,
+        }).unwrap_or_default()
+    }
+
+    /// Sets the maximum length of rules that will be added to this `FilterSet`, in bytes. Longer
+    /// rules are skipped; `add_filter` reports them with `FilterParseError::TooLong`.
+    pub fn set_max_filter_length(&mut self, max_length: usize) {
+        self.max_filter_length = max_length;
+    }
+
+    /// Limits the number of network rules matched with a regex (see `NetworkFilter::uses_regex`)
+    /// that are loaded from each list added with an id, e.g. with `add_filter_list_with_id`. Once a
+    /// list reaches the limit, its remaining regex rules are skipped; `add_filter_lists_soft_fail`
+    /// reports them with `FilterParseError::TooManyRegexFilters`. There is no limit by default.
+    pub fn set_max_regex_filters_per_list(&mut self, max_regex_filters: Option<usize>) {
+        self.max_regex_filters_per_list = max_regex_filters;
+    }
+
+    /// Sets how network rules added to this `FilterSet` from now on are parsed, e.g. whether rules
+    /// with duplicated options are rejected.
+    pub fn set_parse_options(&mut self, parse_options: ParseOptions) {
+        self.parse_options = parse_options;
+    }
+
+    /// Enables evaluation of `!#if` preprocessor directives in lists added to this `FilterSet`
+    /// from now on, so that rules in blocks whose condition is false are left out. With `None`, the
+    /// default, directives are ignored and every rule is loaded.
+    pub fn set_preprocessor_options(&mut self, options: Option<PreprocessorOptions>) {
+        self.preprocessor = options;
+    }
+
     /// Adds the contents of an entire filter list to this `FilterSet`. Filters that cannot be
     /// parsed successfully are ignored.
     pub fn add_filter_list(&mut self, filter_list: &str, format: FilterFormat) {
@@ -88,24 +310,299 @@ impl FilterSet {
         self.add_filters(&rules, format);
     }
 
+    /// Like `add_filter_list`, but the rules of the list only apply while `tag` is enabled with
+    /// `Engine::use_tags` or `Engine::enable_tags`, as if each of them had a `$tag` option.
+    /// Network rules with their own `$tag` keep it.
+    ///
+    /// Cosmetic rules are tagged as well, except for exceptions without hostnames like `#@#.ad`,
+    /// which always apply. Tag schedules only affect network rules.
+    pub fn add_tagged_filter_list(&mut self, tag: &str, filter_list: &str, format: FilterFormat) {
+        let network_start = self.network_filters.len();
+        let popup_start = self.popup_filters.len();
+        let cosmetic_start = self.cosmetic_filters.len();
+        self.add_filter_list(filter_list, format);
+
+        self.network_filters[network_start..].iter_mut()
+            .chain(self.popup_filters[popup_start..].iter_mut())
+            .filter(|filter| filter.tag.is_none())
+            .for_each(|filter| filter.set_tag(tag));
+        let (generic_exceptions, tagged): (Vec<_>, Vec<_>) = self.cosmetic_filters.drain(cosmetic_start..)
+            .partition(|filter| !filter.has_hostname_constraint() && filter.mask.contains(CosmeticFilterMask::UNHIDE));
+        self.cosmetic_filters.extend(generic_exceptions);
+        self.tagged_cosmetic_filters.extend(tagged.into_iter().map(|filter| (tag.to_string(), filter)));
+    }
+
+    /// Like `add_filter_list`, but if this `FilterSet` is in debug mode, also records the list and
+    /// line number that each rule came from. These can later be retrieved using
+    /// `Engine::filter_sources`, e.g. to trace a match back to the exact line of a list.
+    pub fn add_filter_list_with_id(&mut self, list_id: &str, filter_list: &str, format: FilterFormat) {
+        self.add_filter_list_with_report(list_id, filter_list, format, false, None, &mut |_| true);
+    }
+
+    /// Like `add_filter_list_with_id`, but for lists from a trusted source, such as the embedder's
+    /// own rules. Only rules from trusted lists may inject privileged `trusted-` scriptlets.
+    pub fn add_trusted_filter_list_with_id(&mut self, list_id: &str, filter_list: &str, format: FilterFormat) {
+        self.add_filter_list_with_report(list_id, filter_list, format, true, None, &mut |_| true);
+    }
+
+    /// Like `add_filter_list_with_id`, but stops with `Err(Cancelled)` once `cancellation` is
+    /// cancelled, checking it before the first line and every `PROGRESS_INTERVAL` lines after.
+    /// Rules parsed before that remain in this `FilterSet`.
+    pub fn add_filter_list_with_cancellation(
+        &mut self,
+        list_id: &str,
+        filter_list: &str,
+        format: FilterFormat,
+        cancellation: Option<&CancellationToken>,
+    ) -> Result<(), Cancelled> {
+        let cancelled = || cancellation.map_or(false, CancellationToken::is_cancelled);
+        if self.add_filter_list_with_report(list_id, filter_list, format, false, None, &mut |line| (line - 1) % PROGRESS_INTERVAL != 0 || !cancelled()) {
+            Ok(())
+        } else {
+            Err(Cancelled)
+        }
+    }
+
+    /// Adds several filter lists at once, identified as in `add_filter_list_with_id`, while
+    /// tolerating failures. Lists that could not be obtained can be passed as an `Err` describing
+    /// the problem; they are skipped, along with any individual rules that fail to parse, and
+    /// everything else is still loaded. The returned report lists what was left out.
+    pub fn add_filter_lists_soft_fail<'a, E: fmt::Display>(
+        &mut self,
+        lists: impl IntoIterator<Item = (&'a str, Result<&'a str, E>)>,
+        format: FilterFormat,
+    ) -> LoadReport {
+        let mut report = LoadReport::default();
+        for (list_id, filter_list) in lists {
+            match filter_list {
+                Ok(filter_list) => {
+                    self.add_filter_list_with_report(list_id, filter_list, format, false, Some(&mut report), &mut |_| true);
+                }
+                Err(e) => report.skipped_lists.push(SkippedList {
+                    list_id: list_id.to_string(),
+                    reason: e.to_string(),
+                }),
+            }
+        }
+        report
+    }
+
+    /// Adds rules written by a user, e.g. exported from uBO's "My filters" pane, which may mix
+    /// hosts file entries, plain hostnames, comments in either style and rules in
+    /// `FilterFormat::Standard` syntax in a single dump. The format of each line is detected on its
+    /// own. Unlike lists added with `add_filter_list_with_id`, every line is reported, including
+    /// rules that use unsupported syntax.
+    ///
+    /// Sources are recorded under `list_id` in debug mode, as with `add_filter_list_with_id`.
+    /// `!#if` directives are evaluated as in lists.
+    pub fn import_user_rules(&mut self, list_id: &str, dump: &str) -> ImportReport {
+        let mut report = ImportReport::default();
+        let mut preprocessor = self.preprocessor.clone().map(Preprocessor::new);
+        for (i, text) in dump.lines().enumerate() {
+            let line = text.trim();
+            let kind = match preprocessor.as_mut().map(|preprocessor| preprocessor.process(line)) {
+                None | Some(LineOutcome::Include) => detect_user_line(line),
+                Some(LineOutcome::Exclude) => ImportedLineKind::Excluded,
+                Some(LineOutcome::Directive) => ImportedLineKind::Comment,
+                Some(LineOutcome::UnknownDirective) => match preprocessor.as_ref().map(Preprocessor::unknown_directives) {
+                    Some(UnknownDirectivePolicy::Report) => ImportedLineKind::Skipped(FilterParseError::UnknownDirective),
+                    _ => ImportedLineKind::Comment,
+                },
+            };
+            let result = match kind {
+                ImportedLineKind::Empty | ImportedLineKind::Comment | ImportedLineKind::Excluded | ImportedLineKind::Skipped(_) => None,
+                ImportedLineKind::Rule => Some(self.add_filter(line, FilterFormat::Standard).map(|()| line.to_string())),
+                _ => Some(self.add_filter(line, FilterFormat::Hosts).map(|()| {
+                    let hostname = self.network_filters.last().and_then(|filter| filter.hostname.as_deref()).unwrap_or_default();
+                    format!("||{}^", hostname)
+                })),
+            };
+            let (kind, normalized) = match result {
+                None => (kind, None),
+                Some(Ok(normalized)) => {
+                    if self.debug {
+                        self.sources.entry(line.to_string()).or_insert_with(Vec::new).push(FilterSource {
+                            list_id: list_id.to_string(),
+                            line: i + 1,
+                            text: text.to_string(),
+                        });
+                    }
+                    (kind, Some(normalized))
+                }
+                Some(Err(error)) => (ImportedLineKind::Skipped(error), None),
+            };
+            report.lines.push(ImportedLine {
+                line: i + 1,
+                text: text.to_string(),
+                kind,
+                normalized,
+            });
+        }
+        report
+    }
+
+    /// Adds the rules of a list, calling `on_line` with the number of each line before parsing it.
+    /// Stops and returns `false` as soon as `on_line` returns `false`.
+    pub(crate) fn add_filter_list_with_report(
+        &mut self,
+        list_id: &str,
+        filter_list: &str,
+        format: FilterFormat,
+        trusted: bool,
+        mut report: Option<&mut LoadReport>,
+        on_line: &mut dyn FnMut(usize) -> bool,
+    ) -> bool {
+        if let Some(version) = ListMetadata::from_list(filter_list).version {
+            self.list_versions.insert(list_id.to_string(), version);
+        }
+        let mut preprocessor = self.preprocessor.clone().map(Preprocessor::new);
+        let mut regex_filters = 0;
+        for (i, line) in filter_list.lines().enumerate() {
+            if !on_line(i + 1) {
+                return false;
+            }
+            let outcome = preprocessor.as_mut().map(|preprocessor| preprocessor.process(line));
+            let (network_count, popup_count) = (self.network_filters.len(), self.popup_filters.len());
+            let result = match outcome {
+                None | Some(LineOutcome::Include) => self.add_filter_with_trust(line, format, trusted),
+                Some(LineOutcome::Exclude) | Some(LineOutcome::Directive) => continue,
+                Some(LineOutcome::UnknownDirective) => match preprocessor.as_ref().map(Preprocessor::unknown_directives) {
+                    Some(UnknownDirectivePolicy::Report) => Err(FilterParseError::UnknownDirective),
+                    _ => continue,
+                },
+            };
+            let result = result.and_then(|()| self.limit_regex_filters(network_count, popup_count, &mut regex_filters));
+            match result {
+                Ok(()) => if self.debug {
+                    self.sources.entry(line.trim().to_string()).or_insert_with(Vec::new).push(FilterSource {
+                        list_id: list_id.to_string(),
+                        line: i + 1,
+                        text: line.to_string(),
+                    });
+                },
+                Err(FilterParseError::Empty) | Err(FilterParseError::Unsupported) => (),
+                Err(error) => if let Some(report) = report.as_mut() {
+                    report.skipped_rules.push(SkippedRule {
+                        list_id: list_id.to_string(),
+                        line: i + 1,
+                        text: line.to_string(),
+                        error,
+                    });
+                },
+            }
+        }
+        if let Some(report) = report {
+            *report.regex_filters.entry(list_id.to_string()).or_insert(0) += regex_filters;
+        }
+        true
+    }
+
+    /// Counts the network rule added since `self.network_filters` and `self.popup_filters` had the
+    /// given lengths towards `regex_filters`, if it is matched with a regex. If that exceeds the
+    /// limit set with `set_max_regex_filters_per_list`, the rule is removed again instead.
+    fn limit_regex_filters(&mut self, network_count: usize, popup_count: usize, regex_filters: &mut usize) -> Result<(), FilterParseError> {
+        let max_regex_filters = self.max_regex_filters_per_list;
+        let filters = if self.network_filters.len() > network_count {
+            &mut self.network_filters
+        } else if self.popup_filters.len() > popup_count {
+            &mut self.popup_filters
+        } else {
+            return Ok(());
+        };
+        if !filters.last().map_or(false, NetworkFilter::uses_regex) {
+            return Ok(());
+        }
+        if max_regex_filters.map_or(false, |max| *regex_filters >= max) {
+            filters.pop();
+            return Err(FilterParseError::TooManyRegexFilters);
+        }
+        *regex_filters += 1;
+        Ok(())
+    }
+
+    /// Adds the rules of a list parsed ahead of time, e.g. by a server. Rule sources are only
+    /// kept if both this `FilterSet` and `list` are in debug mode.
+    pub fn add_parsed_list(&mut self, list: ParsedList) {
+        if let Some(version) = list.version {
+            self.list_versions.insert(list.list_id, version);
+        }
+        self.network_filters.extend(list.network_filters);
+        self.cosmetic_filters.extend(list.cosmetic_filters);
+        self.popup_filters.extend(list.popup_filters);
+        if self.debug {
+            for (rule, sources) in list.sources {
+                self.sources.entry(rule).or_insert_with(Vec::new).extend(sources);
+            }
+        }
+    }
+
     /// Adds a collection of filter rules to this `FilterSet`. Filters that cannot be parsed
-    /// successfully are ignored.
+    /// successfully are ignored. `!#if` directives are evaluated across the collection, as for
+    /// the lines of a list.
     pub fn add_filters(&mut self, filters: &[String], format: FilterFormat) {
-        let (mut parsed_network_filters, mut parsed_cosmetic_filters) = parse_filters(&filters, self.debug, format);
-        self.network_filters.append(&mut parsed_network_filters);
-        self.cosmetic_filters.append(&mut parsed_cosmetic_filters);
+        let mut preprocessor = self.preprocessor.clone().map(Preprocessor::new);
+        for filter in filters {
+            if preprocessor.as_mut().map_or(true, |preprocessor| preprocessor.process(filter) == LineOutcome::Include) {
+                let _ = self.add_filter(filter, format);
+            }
+        }
     }
 
     /// Adds the string representation of a single filter rule to this `FilterSet`.
+    ///
+    /// Rules added this way are untrusted, so scriptlet injections of privileged `trusted-`
+    /// scriptlets are rejected with `CosmeticFilterError::UntrustedScriptlet`.
     pub fn add_filter(&mut self, filter: &str, format: FilterFormat) -> Result<(), FilterParseError> {
-        let filter_parsed = parse_filter(filter, self.debug, format);
-        match filter_parsed? {
+        self.add_filter_with_trust(filter, format, false)
+    }
+
+    /// Like `add_filter`, but for a rule from a trusted source, which may inject privileged
+    /// scriptlets.
+    pub fn add_trusted_filter(&mut self, filter: &str, format: FilterFormat) -> Result<(), FilterParseError> {
+        self.add_filter_with_trust(filter, format, true)
+    }
+
+    fn add_filter_with_trust(&mut self, filter: &str, format: FilterFormat, trusted: bool) -> Result<(), FilterParseError> {
+        if filter.len() > self.max_filter_length {
+            return Err(FilterParseError::TooLong);
+        }
+        let filter_parsed = match self.parse_cache.as_mut() {
+            Some(cache) => {
+                let cache_key = (fast_hash(filter), format, trusted, self.debug, self.parse_options);
+                match cache.get(&cache_key) {
+                    Some(parsed) => Ok(parsed),
+                    None => parse_filter_with_trust(filter, self.debug, format, &self.parse_options, trusted)
+                        .map(|parsed| cache.insert(cache_key, parsed)),
+                }
+            }
+            None => parse_filter_with_trust(filter, self.debug, format, &self.parse_options, trusted),
+        };
+        let filter_parsed = match filter_parsed {
+            Ok(parsed) => parsed,
+            Err(FilterParseError::Network(NetworkFilterError::UnrecognisedOption)) if format == FilterFormat::Standard => {
+                return self.add_popup_filter(filter);
+            }
+            Err(e) => return Err(e),
+        };
+        match filter_parsed {
             ParsedFilter::Network(filter) => self.network_filters.push(filter),
             ParsedFilter::Cosmetic(filter) => self.cosmetic_filters.push(filter),
         }
         Ok(())
     }
 
+    /// Adds a rule with an option unknown to `NetworkFilter::parse`, if that option is `$popup`.
+    fn add_popup_filter(&mut self, filter: &str) -> Result<(), FilterParseError> {
+        match NetworkFilter::parse_popup(filter.trim(), self.debug) {
+            Ok(filter) => {
+                self.popup_filters.push(filter);
+                Ok(())
+            }
+            Err(NetworkFilterError::NotPopup) => Err(NetworkFilterError::UnrecognisedOption.into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     /// Consumes this `FilterSet`, returning an equivalent list of content blocking rules and a
     /// corresponding new list containing the `String` representation of all filters that were
     /// successfully converted (as `FilterFormat::Standard` rules).
@@ -116,6 +613,73 @@ impl FilterSet {
     /// This function will fail if the `FilterSet` was not created in debug mode.
     #[cfg(feature = "content-blocking")]
     pub fn into_content_blocking(self, rule_types: RuleTypes) -> Result<(Vec<crate::content_blocking::CbRule>, Vec<String>), ()> {
+        self.into_content_blocking_with_report(rule_types)
+            .map(|(rules, filters_used, _)| (rules, filters_used))
+    }
+
+    /// Like `into_content_blocking`, but also returns a `ConversionReport` of the filters that
+    /// were dropped, approximated, or split into several content blocking rules.
+    #[cfg(feature = "content-blocking")]
+    pub fn into_content_blocking_with_report(self, rule_types: RuleTypes) -> Result<(Vec<crate::content_blocking::CbRule>, Vec<String>, ConversionReport), ()> {
+        use crate::content_blocking;
+
+        let loads_network_rules = rule_types.loads_network_rules();
+        let conversion = self.convert_to_content_blocking(rule_types)?;
+
+        let (mut ignore_previous_rules, mut other_rules): (Vec<_>, Vec<_>) = conversion.network.into_iter()
+            .chain(conversion.cosmetic.into_iter())
+            .map(|(cb_rule, _)| cb_rule)
+            .partition(|cb_rule| cb_rule.action.typ == content_blocking::CbType::IgnorePreviousRules);
+
+        other_rules.append(&mut ignore_previous_rules);
+
+        if loads_network_rules {
+            other_rules.push(content_blocking::ignore_previous_fp_documents());
+        }
+
+        Ok((other_rules, conversion.filters_used, conversion.report))
+    }
+
+    /// Consumes this `FilterSet`, returning content blocking rule lists of at most `rule_budget`
+    /// rules each, so that they can be loaded as separate content blockers. Safari enforces a hard
+    /// limit on the number of rules per content blocker.
+    ///
+    /// Network rules are categorized by the first list they were added from, using
+    /// `list_categories`, which maps list ids as given to `add_filter_list_with_id` to
+    /// `CbCategory::Ads` or `CbCategory::Privacy`. Rules from other lists are categorized as ads.
+    /// Cosmetic rules are always categorized as `CbCategory::Cosmetic`. See
+    /// `content_blocking::partition` for how rules are divided among the lists.
+    ///
+    /// This function will fail if the `FilterSet` was not created in debug mode, or if the
+    /// exceptions of a category don't fit within `rule_budget`.
+    #[cfg(feature = "content-blocking")]
+    pub fn into_content_blocking_partitioned(mut self, list_categories: &HashMap<String, crate::content_blocking::CbCategory>, rule_budget: usize) -> Result<(Vec<crate::content_blocking::CbRuleList>, ConversionReport), crate::content_blocking::CbPartitionError> {
+        use crate::content_blocking::{self, CbCategory, CbPartitionError};
+
+        let sources = std::mem::take(&mut self.sources);
+        let conversion = self.convert_to_content_blocking(RuleTypes::All)
+            .map_err(|_| CbPartitionError::NeedsDebugMode)?;
+
+        let categorized_network = conversion.network.into_iter()
+            .map(|(cb_rule, original_rule)| {
+                let category = sources.get(original_rule.trim())
+                    .and_then(|sources| sources.first())
+                    .and_then(|source| list_categories.get(&source.list_id))
+                    .copied()
+                    .unwrap_or(CbCategory::Ads);
+                (category, cb_rule)
+            })
+            .collect();
+        let cosmetic = conversion.cosmetic.into_iter().map(|(cb_rule, _)| cb_rule).collect();
+
+        let lists = content_blocking::partition(categorized_network, cosmetic, rule_budget)?;
+        Ok((lists, conversion.report))
+    }
+
+    /// Converts the filters of this `FilterSet` of the given types, keeping network and cosmetic
+    /// rules apart and in order.
+    #[cfg(feature = "content-blocking")]
+    fn convert_to_content_blocking(self, rule_types: RuleTypes) -> Result<crate::content_blocking::CbConversion, ()> {
         use std::convert::TryInto;
         use crate::content_blocking;
 
@@ -123,51 +687,172 @@ impl FilterSet {
             return Err(())
         }
 
-        let mut ignore_previous_rules = vec![];
-        let mut other_rules = vec![];
-
-        let mut filters_used = vec![];
+        let mut conversion = content_blocking::CbConversion::default();
+        let report = &mut conversion.report;
 
         if rule_types.loads_network_rules() {
-            self.network_filters.into_iter().for_each(|filter| {
+            for filter in self.network_filters.into_iter() {
+                let index = report.rules_total;
+                report.rules_total += 1;
                 let original_rule = filter.raw_line.clone().expect("All rules should be in debug mode");
-                if let Ok(equivalent) = TryInto::<content_blocking::CbRuleEquivalent>::try_into(filter) {
-                    filters_used.push(original_rule);
-                    equivalent.into_iter().for_each(|cb_rule| {
-                        match &cb_rule.action.typ {
-                            content_blocking::CbType::IgnorePreviousRules => ignore_previous_rules.push(cb_rule),
-                            _ => other_rules.push(cb_rule),
+                let drops_types = content_blocking::drops_some_request_types(&filter);
+                match TryInto::<content_blocking::CbRuleEquivalent>::try_into(filter) {
+                    Ok(equivalent) => {
+                        if let content_blocking::CbRuleEquivalent::SplitDocument(..) = equivalent {
+                            report.record(index, Some(&original_rule), ConversionOutcome::Split, ConversionReason::DocumentRequests);
                         }
-                    });
+                        if drops_types {
+                            report.record(index, Some(&original_rule), ConversionOutcome::Approximated, ConversionReason::SomeRequestTypesUnsupported);
+                        }
+                        conversion.network.extend(equivalent.into_iter().map(|cb_rule| (cb_rule, original_rule.clone())));
+                        conversion.filters_used.push(original_rule);
+                    }
+                    Err(failure) => report.record(index, Some(&original_rule), ConversionOutcome::Dropped, (&failure).into()),
                 }
-            });
+            }
+            for filter in self.popup_filters.iter() {
+                let index = report.rules_total;
+                report.rules_total += 1;
+                report.record(index, filter.raw_line.as_deref(), ConversionOutcome::Dropped, ConversionReason::Popup);
+            }
         }
 
         if rule_types.loads_cosmetic_rules() {
-            self.cosmetic_filters.into_iter().for_each(|filter| {
+            for filter in self.cosmetic_filters.into_iter() {
+                let index = report.rules_total;
+                report.rules_total += 1;
                 let original_rule = filter.raw_line.clone().expect("All rules should be in debug mode");
-                if let Ok(cb_rule) = TryInto::<content_blocking::CbRule>::try_into(filter) {
-                    filters_used.push(original_rule);
-                    match &cb_rule.action.typ {
-                        content_blocking::CbType::IgnorePreviousRules => ignore_previous_rules.push(cb_rule),
-                        _ => other_rules.push(cb_rule),
+                match TryInto::<content_blocking::CbRule>::try_into(filter) {
+                    Ok(cb_rule) => {
+                        conversion.cosmetic.push((cb_rule, original_rule.clone()));
+                        conversion.filters_used.push(original_rule);
                     }
+                    Err(failure) => report.record(index, Some(&original_rule), ConversionOutcome::Dropped, (&failure).into()),
                 }
-            });
+            }
         }
 
-        other_rules.append(&mut ignore_previous_rules);
+        Ok(conversion)
+    }
 
-        if rule_types.loads_network_rules() {
-            other_rules.push(content_blocking::ignore_previous_fp_documents());
+    /// Consumes this `FilterSet`, returning an equivalent list of `declarativeNetRequest` rules,
+    /// with ids in the order of the filters they were converted from, and a `ConversionReport`.
+    ///
+    /// `$badfilter` rules are applied rather than converted, as they would be by `Blocker`. The
+    /// rules they cancel are reported as dropped, along with the cancelling rule.
+    ///
+    /// This function will fail if the `FilterSet` was not created in debug mode.
+    #[cfg(feature = "declarative-net-request")]
+    pub fn into_declarative_net_request(self) -> Result<(Vec<crate::declarative_net_request::DnrRule>, ConversionReport), ()> {
+        use std::convert::TryInto;
+        use crate::declarative_net_request::DnrRule;
+
+        if !self.debug {
+            return Err(())
+        }
+
+        let mut report = ConversionReport {
+            rules_total: self.network_filters.len() + self.popup_filters.len() + self.cosmetic_filters.len(),
+            ..ConversionReport::default()
+        };
+
+        let badfilters = self.badfilters();
+
+        let mut rules = vec![];
+        for (index, filter) in self.network_filters.into_iter().enumerate() {
+            if filter.is_badfilter() {
+                continue;
+            }
+            if let Some(badfilter) = badfilters.get(&filter.get_id()) {
+                report.record_cancelled(index, filter.raw_line.as_deref(), badfilter.as_deref());
+                continue;
+            }
+            let original_rule = filter.raw_line.clone();
+            match TryInto::<DnrRule>::try_into(filter) {
+                Ok(mut rule) => {
+                    rule.id = rules.len() as u32 + 1;
+                    rules.push(rule);
+                }
+                Err(failure) => report.record(index, original_rule.as_deref(), ConversionOutcome::Dropped, (&failure).into()),
+            }
+        }
+        let offset = report.rules_total - self.popup_filters.len() - self.cosmetic_filters.len();
+        for (index, filter) in self.popup_filters.iter().enumerate() {
+            report.record(offset + index, filter.raw_line.as_deref(), ConversionOutcome::Dropped, ConversionReason::Popup);
+        }
+        let offset = offset + self.popup_filters.len();
+        for (index, filter) in self.cosmetic_filters.iter().enumerate() {
+            report.record(offset + index, filter.raw_line.as_deref(), ConversionOutcome::Dropped, ConversionReason::Cosmetic);
+        }
+
+        Ok((rules, report))
+    }
+
+    /// Ids of the filters cancelled by `$badfilter` rules, with the text of the cancelling rule.
+    fn badfilters(&self) -> HashMap<Hash, Option<String>> {
+        self.network_filters.iter()
+            .filter(|filter| filter.is_badfilter())
+            .map(|filter| (filter.get_id_without_badfilter(), filter.raw_line.clone()))
+            .collect()
+    }
+
+    /// Returns the filters of this `FilterSet` as lines of a hosts file, blocking each hostname
+    /// by resolving it to `0.0.0.0`, along with a `ConversionReport` of the filters that could not
+    /// be expressed that way.
+    ///
+    /// Only blocking filters with `NetworkFilter::is_hostname_only` are kept, leaving out those
+    /// cancelled by `$badfilter` and those limited to a `$tag` or `$ctag`. Hosts files don't apply
+    /// to subdomains, so each of the kept filters is reported as approximated.
+    pub fn to_hosts(&self) -> (Vec<String>, ConversionReport) {
+        let mut report = ConversionReport {
+            rules_total: self.network_filters.len() + self.popup_filters.len() + self.cosmetic_filters.len(),
+            ..ConversionReport::default()
+        };
+
+        let badfilters = self.badfilters();
+
+        let mut seen = std::collections::HashSet::new();
+        let mut lines = vec![];
+        for (index, filter) in self.network_filters.iter().enumerate() {
+            let rule = filter.raw_line.as_deref();
+            if filter.is_badfilter() {
+                report.record(index, rule, ConversionOutcome::Dropped, ConversionReason::BadFilter);
+            } else if let Some(badfilter) = badfilters.get(&filter.get_id()) {
+                report.record_cancelled(index, rule, badfilter.as_deref());
+            } else if filter.is_exception() {
+                report.record(index, rule, ConversionOutcome::Dropped, ConversionReason::Exception);
+            } else if filter.has_dns_types() || filter.is_dns_rewrite() {
+                report.record(index, rule, ConversionOutcome::Dropped, ConversionReason::DnsModifiers);
+            } else if filter.has_denyallow() {
+                report.record(index, rule, ConversionOutcome::Dropped, ConversionReason::DenyAllow);
+            } else if filter.tag.is_some() {
+                report.record(index, rule, ConversionOutcome::Dropped, ConversionReason::Tag);
+            } else if filter.has_client_tags() {
+                report.record(index, rule, ConversionOutcome::Dropped, ConversionReason::ClientTags);
+            } else if !filter.is_hostname_only() {
+                report.record(index, rule, ConversionOutcome::Dropped, ConversionReason::NotHostnameOnly);
+            } else if let Some(hostname) = filter.hostname.as_ref() {
+                report.record(index, rule, ConversionOutcome::Approximated, ConversionReason::SubdomainsNotCovered);
+                if seen.insert(hostname.as_str()) {
+                    lines.push(format!("0.0.0.0 {}", hostname));
+                }
+            }
+        }
+        let offset = self.network_filters.len();
+        for (index, filter) in self.popup_filters.iter().enumerate() {
+            report.record(offset + index, filter.raw_line.as_deref(), ConversionOutcome::Dropped, ConversionReason::Popup);
+        }
+        let offset = offset + self.popup_filters.len();
+        for (index, filter) in self.cosmetic_filters.iter().enumerate() {
+            report.record(offset + index, filter.raw_line.as_deref(), ConversionOutcome::Dropped, ConversionReason::Cosmetic);
         }
 
-        Ok((other_rules, filters_used))
+        (lines, report)
     }
 }
 
 /// Denotes the format of a particular list resource, which affects how its rules should be parsed.
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum FilterFormat {
     /// Rules should be parsed in ABP/uBO-style format.
     Standard,
@@ -184,6 +869,79 @@ pub enum FilterFormat {
     Hosts,
 }
 
+/// How long a filter list remains valid before it should be refreshed, as given by its
+/// `! Expires:` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExpiresInterval {
+    Hours(u16),
+    Days(u8),
+}
+
+impl ExpiresInterval {
+    pub fn as_duration(&self) -> std::time::Duration {
+        const HOUR: u64 = 60 * 60;
+        match self {
+            Self::Hours(hours) => std::time::Duration::from_secs(*hours as u64 * HOUR),
+            Self::Days(days) => std::time::Duration::from_secs(*days as u64 * 24 * HOUR),
+        }
+    }
+
+    /// Parses the value of an `Expires` header, e.g. `4 days` or `12 hours (update frequency)`.
+    fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split_whitespace();
+        let amount = parts.next()?.parse::<u16>().ok().filter(|amount| *amount > 0)?;
+        match parts.next()? {
+            "day" | "days" | "d" => u8::try_from(amount).ok().map(Self::Days),
+            "hour" | "hours" | "h" => Some(Self::Hours(amount)),
+            _ => None,
+        }
+    }
+}
+
+/// Information about a filter list, read from the header comments at the start of the list.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListMetadata {
+    /// `! Title:`
+    pub title: Option<String>,
+    /// `! Homepage:`
+    pub homepage: Option<String>,
+    /// `! Expires:`
+    pub expires: Option<ExpiresInterval>,
+    /// `! Version:`
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
+impl ListMetadata {
+    /// Reads the metadata from the header of a filter list. Only the comments preceding the first
+    /// rule are considered.
+    pub fn from_list(filter_list: &str) -> Self {
+        let mut metadata = Self::default();
+
+        for line in filter_list.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('[') {
+                continue;
+            }
+            let comment = match line.strip_prefix('!') {
+                Some(comment) => comment.trim(),
+                None => break,
+            };
+            if let Some(separator) = comment.find(':') {
+                let (key, value) = (comment[..separator].trim(), comment[separator + 1..].trim());
+                match key.to_ascii_lowercase().as_str() {
+                    "title" if metadata.title.is_none() => metadata.title = Some(value.to_string()),
+                    "homepage" if metadata.homepage.is_none() => metadata.homepage = Some(value.to_string()),
+                    "expires" if metadata.expires.is_none() => metadata.expires = ExpiresInterval::parse(value),
+                    "version" if metadata.version.is_none() => metadata.version = Some(value.to_string()),
+                    _ => (),
+                }
+            }
+        }
+
+        metadata
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum FilterType {
     Network,
@@ -192,6 +950,7 @@ pub enum FilterType {
 }
 
 /// Successful result of parsing a single filter rule
+#[derive(Clone)]
 pub enum ParsedFilter {
     Network(NetworkFilter),
     Cosmetic(CosmeticFilter),
@@ -209,6 +968,45 @@ impl From<CosmeticFilter> for ParsedFilter {
     }
 }
 
+/// Detects the format of a trimmed line of a user rule dump, for `FilterSet::import_user_rules`.
+/// Lines that are added are reported as `Rule`, `HostsEntry` or `Domain`.
+fn detect_user_line(line: &str) -> ImportedLineKind {
+    if line.is_empty() {
+        return ImportedLineKind::Empty;
+    }
+    if line.starts_with('!') || (line.starts_with('[') && line.ends_with(']')) {
+        return ImportedLineKind::Comment;
+    }
+    // `#` starts a comment in hosts files, but also cosmetic rules without domains, e.g. `##.ad`
+    // or `#@#.ad`. Separators followed by whitespace are taken as comments, e.g. `## Trackers`.
+    if let Some(rest) = line.strip_prefix('#') {
+        let is_cosmetic = ["#", "@#", "?#", "@?#", "$#", "@$#", "%#", "@%#"].iter().any(|separator| {
+            rest.strip_prefix(separator).map(|selector| !selector.is_empty() && !selector.starts_with(char::is_whitespace)).unwrap_or(false)
+        });
+        if !is_cosmetic {
+            return ImportedLineKind::Comment;
+        }
+        return ImportedLineKind::Rule;
+    }
+
+    let mut fields = line.split_whitespace();
+    if let (Some(first), Some(_)) = (fields.next(), fields.next()) {
+        if first.parse::<std::net::IpAddr>().is_ok() {
+            return ImportedLineKind::HostsEntry;
+        }
+    }
+
+    let is_hostname = line.contains('.')
+        && !line.starts_with(&['.', '-'][..])
+        && !line.ends_with(&['.', '-'][..])
+        && line.chars().all(|c| c.is_alphanumeric() || c == '.' || c == '-' || c == '_');
+    if is_hostname {
+        ImportedLineKind::Domain
+    } else {
+        ImportedLineKind::Rule
+    }
+}
+
 /// Unsuccessful result of parsing a single filter rule.
 #[derive(Debug)]
 pub enum FilterParseError {
@@ -216,6 +1014,14 @@ pub enum FilterParseError {
     Cosmetic(CosmeticFilterError),
     Unsupported,
     Empty,
+    /// The rule is longer than the limit configured with `FilterSet::set_max_filter_length`.
+    TooLong,
+    /// The rule is matched with a regex, and its list already has as many of these as configured
+    /// with `FilterSet::set_max_regex_filters_per_list`.
+    TooManyRegexFilters,
+    /// A preprocessor directive other than `!#if`, `!#else` and `!#endif`, reported according to
+    /// `PreprocessorOptions::unknown_directives`.
+    UnknownDirective,
 }
 
 impl From<NetworkFilterError> for FilterParseError {
@@ -230,12 +1036,49 @@ impl From<CosmeticFilterError> for FilterParseError {
     }
 }
 
+/// Returns true if the given rule injects a scriptlet that may only be used by trusted sources.
+/// Exceptions for such scriptlets are always allowed.
+fn injects_privileged_scriptlet(filter: &CosmeticFilter) -> bool {
+    filter.mask.contains(CosmeticFilterMask::SCRIPT_INJECT)
+        && !filter.mask.contains(CosmeticFilterMask::UNHIDE)
+        && crate::resources::parse_scriptlet_args(&filter.selector)
+            .first()
+            .map(|name| name.starts_with(crate::resources::TRUSTED_SCRIPTLET_PREFIX))
+            .unwrap_or(false)
+}
+
+/// `parse_filter`, followed by the checks and adjustments for rules from trusted or untrusted
+/// sources.
+fn parse_filter_with_trust(line: &str, debug: bool, format: FilterFormat, parse_options: &ParseOptions, trusted: bool) -> Result<ParsedFilter, FilterParseError> {
+    match parse_filter_with_options(line, debug, format, parse_options)? {
+        ParsedFilter::Cosmetic(mut filter) => {
+            if trusted {
+                filter.mask |= CosmeticFilterMask::IS_TRUSTED;
+            } else if injects_privileged_scriptlet(&filter) {
+                return Err(CosmeticFilterError::UntrustedScriptlet.into());
+            }
+            Ok(filter.into())
+        }
+        parsed => Ok(parsed),
+    }
+}
+
 /// Parse a single filter rule
 pub fn parse_filter(
     line: &str,
     debug: bool,
     format: FilterFormat,
 ) -> Result<ParsedFilter, FilterParseError> {
+    parse_filter_with_options(line, debug, format, &ParseOptions::default())
+}
+
+/// Like `parse_filter`, with non-default `ParseOptions` for network rules.
+pub fn parse_filter_with_options(
+    line: &str,
+    debug: bool,
+    format: FilterFormat,
+    parse_options: &ParseOptions,
+) -> Result<ParsedFilter, FilterParseError> {
 
     let filter = line.trim();
 
@@ -246,7 +1089,7 @@ pub fn parse_filter(
     match format {
         FilterFormat::Standard => {
             match detect_filter_type(filter) {
-                FilterType::Network => NetworkFilter::parse(filter, debug)
+                FilterType::Network => NetworkFilter::parse_with_options(filter, debug, parse_options)
                     .map(|f| f.into())
                     .map_err(|e| e.into()),
                 FilterType::Cosmetic => CosmeticFilter::parse(filter, debug)
@@ -317,6 +1160,110 @@ pub fn parse_filters(
     (network_filters, cosmetic_filters)
 }
 
+/// Coarse category of a single line from a filter list, as determined by `classify`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterKind {
+    /// Network rule that blocks or redirects requests, e.g. `||ads.example.com^`.
+    NetworkBlock,
+    /// Network exception, e.g. `@@||example.com/ads.js`.
+    NetworkException,
+    /// Element hiding rule, e.g. `example.com##.ad`, including procedural and style rules.
+    CosmeticHide,
+    /// Exception for any kind of cosmetic rule, e.g. `example.com#@#.ad` or
+    /// `example.com#@#+js(...)`.
+    CosmeticException,
+    /// Scriptlet injection, e.g. `example.com##+js(...)`.
+    Scriptlet,
+    /// HTML filtering rule, e.g. `example.com##^script` or `example.com$$script`.
+    HtmlFilter,
+    /// Comment or blank line.
+    Comment,
+    /// List header, like `[Adblock Plus 2.0]` or `! Title: EasyList`.
+    Metadata,
+    /// Syntax that does not fit any of the above, like AdGuard's `#$#` CSS injection or `!#if`
+    /// preprocessor directives, which are evaluated separately if enabled with
+    /// `FilterSet::set_preprocessor_options`.
+    Unsupported,
+}
+
+/// Determines the kind of a single line in `FilterFormat::Standard` syntax, without parsing it
+/// in full. The line is not validated, so lines classified as filters may still fail to parse.
+pub fn classify(filter_line: &str) -> FilterKind {
+    const METADATA_KEYS: &[&str] = &[
+        "title", "homepage", "expires", "version", "last modified", "last updated", "updated",
+        "description", "license", "licence", "checksum", "redirect",
+    ];
+
+    let filter = filter_line.trim();
+
+    if filter.is_empty() || filter == "!" || filter == "#" || filter.starts_with("# ") {
+        return FilterKind::Comment;
+    }
+    if filter.starts_with('[') && filter.ends_with(']') {
+        return FilterKind::Metadata;
+    }
+    if filter.starts_with("!#") {
+        return FilterKind::Unsupported;
+    }
+    if let Some(comment) = filter.strip_prefix('!') {
+        let is_metadata = comment.find(':').map(|separator| {
+            let key = comment[..separator].trim().to_ascii_lowercase();
+            METADATA_KEYS.contains(&key.as_str())
+        });
+        return if is_metadata == Some(true) { FilterKind::Metadata } else { FilterKind::Comment };
+    }
+
+    let network_kind = if filter.starts_with("@@") { FilterKind::NetworkException } else { FilterKind::NetworkBlock };
+    if filter.starts_with('|') || filter.starts_with("@@|") {
+        return network_kind;
+    }
+
+    // AdGuard HTML filtering: `$$` and `$@$`
+    if filter.contains("$@$") {
+        return FilterKind::CosmeticException;
+    } else if filter.contains("$$") {
+        return FilterKind::HtmlFilter;
+    }
+
+    if let Some(sharp_index) = filter.find('#') {
+        let after_sharp = &filter[sharp_index + 1..];
+        let (exception, separator_rest) = match after_sharp.strip_prefix('@') {
+            Some(rest) => (true, rest),
+            None => (false, after_sharp),
+        };
+
+        let body = if let Some(body) = separator_rest.strip_prefix('#') {
+            Some(body)
+        } else if let Some(body) = separator_rest.strip_prefix("?#") {
+            Some(body)
+        } else if let Some(body) = separator_rest.strip_prefix("%#") {
+            // AdGuard only supports scriptlets in this form; other uses inject arbitrary JS
+            if !body.starts_with("//scriptlet(") {
+                return FilterKind::Unsupported;
+            }
+            Some(body)
+        } else if separator_rest.starts_with("$#") {
+            return FilterKind::Unsupported;
+        } else {
+            None
+        };
+
+        if let Some(body) = body {
+            return if exception {
+                FilterKind::CosmeticException
+            } else if body.starts_with("+js(") || body.starts_with("//scriptlet(") {
+                FilterKind::Scriptlet
+            } else if body.starts_with('^') {
+                FilterKind::HtmlFilter
+            } else {
+                FilterKind::CosmeticHide
+            };
+        }
+    }
+
+    network_kind
+}
+
 /// Given a single line, checks if this would likely be a cosmetic filter, a
 /// network filter or something that is not supported. This check is performed
 /// before calling a more specific parser to create an instance of
@@ -466,4 +1413,225 @@ mod tests {
             FilterFormat::Standard,
         ).is_ok());
     }
+
+    #[test]
+    fn skips_long_filters() {
+        let long_filter = format!("||example.com/{}", "a".repeat(100));
+
+        let mut filter_set = FilterSet::new(false);
+        filter_set.set_max_filter_length(64);
+        assert!(matches!(filter_set.add_filter(&long_filter, FilterFormat::Standard), Err(FilterParseError::TooLong)));
+        filter_set.add_filters(&[long_filter.clone(), "||ads.com^".to_string()], FilterFormat::Standard);
+        assert_eq!(filter_set.network_filters.len(), 1);
+
+        let mut filter_set = FilterSet::new(false);
+        assert!(filter_set.add_filter(&long_filter, FilterFormat::Standard).is_ok());
+    }
+
+    #[test]
+    fn parse_cache_reuses_unchanged_rules() {
+        let mut filter_set = FilterSet::new(false);
+        filter_set.enable_parse_cache(FilterParseCache::default());
+        filter_set.add_filter_list("||ads.com^\n||old.com^\nexample.com##.ad", FilterFormat::Standard);
+        let cache = filter_set.take_parse_cache();
+        assert_eq!(cache.len(), 3);
+        assert!(filter_set.take_parse_cache().is_empty());
+
+        let mut updated = FilterSet::new(false);
+        updated.enable_parse_cache(cache);
+        updated.add_filter_list("||ads.com^\n||new.com^\nexample.com##.ad", FilterFormat::Standard);
+        // the same text from a different kind of source is parsed separately
+        updated.add_trusted_filter("example.com##.ad", FilterFormat::Standard).unwrap();
+        assert_eq!(updated.network_filters.len(), 2);
+        assert_eq!(updated.cosmetic_filters.len(), 2);
+        assert!(!updated.cosmetic_filters[0].mask.contains(CosmeticFilterMask::IS_TRUSTED));
+        assert!(updated.cosmetic_filters[1].mask.contains(CosmeticFilterMask::IS_TRUSTED));
+
+        // rules that were removed from the lists are dropped from the cache
+        assert_eq!(updated.take_parse_cache().len(), 4);
+
+        let engine = crate::engine::Engine::from_filter_set(updated, true);
+        assert!(engine.check_network_urls("https://ads.com/", "https://example.com/", "script").matched);
+        assert!(engine.check_network_urls("https://new.com/", "https://example.com/", "script").matched);
+        assert!(!engine.check_network_urls("https://old.com/", "https://example.com/", "script").matched);
+    }
+
+    #[test]
+    fn classifies_lines() {
+        let cases = [
+            ("||ads.example.com^", FilterKind::NetworkBlock),
+            ("/banner/*/img^$image", FilterKind::NetworkBlock),
+            ("@@||example.com/ads.js", FilterKind::NetworkException),
+            ("||example.com/#anchor", FilterKind::NetworkBlock),
+            ("example.com##.ad", FilterKind::CosmeticHide),
+            ("##.ad:style(display: none !important)", FilterKind::CosmeticHide),
+            ("example.com#?#div:has-text(Sponsored)", FilterKind::CosmeticHide),
+            ("example.com#@#.ad", FilterKind::CosmeticException),
+            ("example.com#@#+js(nobab)", FilterKind::CosmeticException),
+            ("example.com##+js(set-constant, ads, false)", FilterKind::Scriptlet),
+            ("example.com#%#//scriptlet('abort-on-property-read', 'ads')", FilterKind::Scriptlet),
+            ("example.com##^script:has-text(ads)", FilterKind::HtmlFilter),
+            ("example.com$$script[tag-content=\"ads\"]", FilterKind::HtmlFilter),
+            ("example.com$@$script[tag-content=\"ads\"]", FilterKind::CosmeticException),
+            ("! Some comment: with a colon", FilterKind::Comment),
+            ("# hosts comment", FilterKind::Comment),
+            ("   ", FilterKind::Comment),
+            ("[Adblock Plus 2.0]", FilterKind::Metadata),
+            ("! Title: EasyList", FilterKind::Metadata),
+            ("! Last modified: 01 Jan 2021 00:00 UTC", FilterKind::Metadata),
+            ("!#if env_mobile", FilterKind::Unsupported),
+            ("example.com#$#body { overflow: auto; }", FilterKind::Unsupported),
+            ("example.com#%#window.ads = false;", FilterKind::Unsupported),
+        ];
+
+        for (line, kind) in cases.iter() {
+            assert_eq!(classify(line), *kind, "{}", line);
+        }
+    }
+
+    #[test]
+    fn soft_fail_loading() {
+        let lists: Vec<(&str, Result<&str, String>)> = vec![
+            ("first", Ok("! comment\n||ads.com^\n||bad.com^$redirect=\nexample.com##.ad\nexample.com##.ad { display: none }\n")),
+            ("second", Err("HTTP 503".to_string())),
+            ("third", Ok("||tracker.com^")),
+        ];
+
+        let mut filter_set = FilterSet::new(false);
+        let report = filter_set.add_filter_lists_soft_fail(lists, FilterFormat::Standard);
+        assert_eq!(filter_set.network_filters.len(), 2);
+        assert_eq!(filter_set.cosmetic_filters.len(), 1);
+
+        assert!(!report.is_complete());
+        assert_eq!(report.skipped_lists, vec![SkippedList { list_id: "second".to_string(), reason: "HTTP 503".to_string() }]);
+        assert_eq!(report.skipped_rules.len(), 2);
+        assert_eq!(report.skipped_rules[0].list_id, "first");
+        assert_eq!(report.skipped_rules[0].line, 3);
+        assert!(matches!(report.skipped_rules[0].error, FilterParseError::Network(NetworkFilterError::EmptyRedirection)));
+        // Malformed selectors are reported instead of being injected into pages
+        assert_eq!(report.skipped_rules[1].line, 5);
+        assert!(matches!(report.skipped_rules[1].error, FilterParseError::Cosmetic(CosmeticFilterError::InvalidCssSelector)));
+
+        let mut filter_set = FilterSet::new(false);
+        let report = filter_set.add_filter_lists_soft_fail(vec![("list", Ok::<_, String>("||ads.com^"))], FilterFormat::Standard);
+        assert!(report.is_complete());
+    }
+
+    #[test]
+    fn limits_regex_filters() {
+        let list = "||ads.com^\n/banner/*/img^\n/^https?:\\/\\/ads\\./\n||tracker.com/*/pixel\n/ads/*/pop$popup\n";
+
+        let mut filter_set = FilterSet::new(false);
+        let report = filter_set.add_filter_lists_soft_fail(vec![("list", Ok::<_, String>(list))], FilterFormat::Standard);
+        assert!(report.is_complete());
+        assert_eq!(report.regex_filters.get("list"), Some(&4));
+        assert_eq!(filter_set.network_filters.len(), 4);
+        assert_eq!(filter_set.popup_filters.len(), 1);
+
+        let mut filter_set = FilterSet::new(false);
+        filter_set.set_max_regex_filters_per_list(Some(2));
+        let report = filter_set.add_filter_lists_soft_fail(vec![
+            ("list", Ok::<_, String>(list)),
+            ("other", Ok::<_, String>("/track/*/pixel")),
+        ], FilterFormat::Standard);
+        assert_eq!(report.regex_filters.get("list"), Some(&2));
+        assert_eq!(report.regex_filters.get("other"), Some(&1));
+        assert_eq!(report.skipped_rules.iter().map(|rule| rule.line).collect::<Vec<_>>(), vec![4, 5]);
+        assert!(report.skipped_rules.iter().all(|rule| matches!(rule.error, FilterParseError::TooManyRegexFilters)));
+        assert_eq!(filter_set.network_filters.len(), 4);
+        assert!(filter_set.popup_filters.is_empty());
+    }
+
+    #[test]
+    fn preprocessed_loading() {
+        let list = "!#if env_chromium\n||chromium.com^\n!#else\n||firefox.com^\n!#endif\n!#include quick-fixes.txt\n||always.com^\n";
+
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_filter_list(list, FilterFormat::Standard);
+        assert_eq!(filter_set.network_filters.len(), 3);
+
+        let mut filter_set = FilterSet::new(false);
+        filter_set.set_preprocessor_options(Some(PreprocessorOptions::new(&["env_chromium"])));
+        filter_set.add_filter_list(list, FilterFormat::Standard);
+        assert_eq!(filter_set.network_filters.len(), 2);
+
+        let mut options = PreprocessorOptions::new(&["env_firefox"]);
+        options.unknown_directives = UnknownDirectivePolicy::Report;
+        let mut filter_set = FilterSet::new(true);
+        filter_set.set_preprocessor_options(Some(options));
+        let report = filter_set.add_filter_lists_soft_fail(vec![("ubo", Ok::<_, String>(list))], FilterFormat::Standard);
+        assert_eq!(filter_set.network_filters.len(), 2);
+        assert!(filter_set.network_filters.iter().any(|filter| filter.hostname.as_deref() == Some("firefox.com")));
+        assert_eq!(report.skipped_rules.len(), 1);
+        assert_eq!(report.skipped_rules[0].line, 6);
+        assert!(matches!(report.skipped_rules[0].error, FilterParseError::UnknownDirective));
+
+        // Rules added individually or imported from a user dump are preprocessed too
+        let rules = list.lines().map(str::to_string).collect::<Vec<_>>();
+        let mut filter_set = FilterSet::new(false);
+        filter_set.set_preprocessor_options(Some(PreprocessorOptions::new(&["env_chromium"])));
+        filter_set.add_filters(&rules, FilterFormat::Standard);
+        assert_eq!(filter_set.network_filters.len(), 2);
+        assert!(!filter_set.network_filters.iter().any(|filter| filter.hostname.as_deref() == Some("firefox.com")));
+
+        let mut options = PreprocessorOptions::new(&["env_chromium"]);
+        options.unknown_directives = UnknownDirectivePolicy::Report;
+        let mut filter_set = FilterSet::new(false);
+        filter_set.set_preprocessor_options(Some(options));
+        let report = filter_set.import_user_rules("user", list);
+        let kinds: Vec<_> = report.lines.iter().map(|line| format!("{:?}", line.kind)).collect();
+        assert_eq!(kinds, vec!["Comment", "Rule", "Comment", "Excluded", "Comment", "Skipped(UnknownDirective)", "Rule"]);
+        assert_eq!(filter_set.network_filters.len(), 2);
+    }
+
+    #[test]
+    fn imports_mixed_user_rules() {
+        let dump = "! My filters\n\n# hosts-style comment\n0.0.0.0 ads.com # inline\ntracker.net\n||cdn.com/ad.js^$script\n##.banner\n## Section\nexample.com##+js(unknown)\n127.0.0.1 localhost\n[Adblock Plus 2.0]\n";
+        let mut filter_set = FilterSet::new(true);
+        let report = filter_set.import_user_rules("user", dump);
+
+        let kinds: Vec<_> = report.lines.iter().map(|line| (line.line, format!("{:?}", line.kind), line.normalized.as_deref())).collect();
+        assert_eq!(kinds, vec![
+            (1, "Comment".to_string(), None),
+            (2, "Empty".to_string(), None),
+            (3, "Comment".to_string(), None),
+            (4, "HostsEntry".to_string(), Some("||ads.com^")),
+            (5, "Domain".to_string(), Some("||tracker.net^")),
+            (6, "Rule".to_string(), Some("||cdn.com/ad.js^$script")),
+            (7, "Rule".to_string(), Some("##.banner")),
+            (8, "Comment".to_string(), None),
+            (9, "Rule".to_string(), Some("example.com##+js(unknown)")),
+            (10, "Skipped(Unsupported)".to_string(), None),
+            (11, "Comment".to_string(), None),
+        ]);
+        assert_eq!(report.imported(), 5);
+        assert_eq!(report.skipped().count(), 1);
+        assert_eq!(filter_set.network_filters.len(), 3);
+        assert_eq!(filter_set.cosmetic_filters.len(), 2);
+        assert_eq!(filter_set.sources["tracker.net"][0].line, 5);
+    }
+
+    #[test]
+    fn list_metadata() {
+        let list = "[Adblock Plus 2.0]\n\
+            ! Title: EasyList\n\
+            ! Homepage: https://easylist.to/\n\
+            ! Version: 202610161200\n\
+            ! Expires: 4 days (update frequency)\n\
+            !\n\
+            ||ads.com^\n\
+            ! Expires: 1 hour\n";
+        assert_eq!(ListMetadata::from_list(list), ListMetadata {
+            title: Some("EasyList".to_string()),
+            homepage: Some("https://easylist.to/".to_string()),
+            expires: Some(ExpiresInterval::Days(4)),
+            version: Some("202610161200".to_string()),
+        });
+
+        assert_eq!(ListMetadata::from_list("! Expires: 12 hours").expires, Some(ExpiresInterval::Hours(12)));
+        assert_eq!(ListMetadata::from_list("! Expires: 0 hours").expires, None);
+        assert_eq!(ListMetadata::from_list("! Expires: 300 days").expires, None);
+        assert_eq!(ListMetadata::from_list("! Expires: soon").expires, None);
+        assert_eq!(ListMetadata::from_list("||ads.com^"), ListMetadata::default());
+    }
 }
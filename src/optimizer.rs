@@ -1,6 +1,7 @@
 use crate::filters::network::{NetworkFilter, NetworkFilterMask, FilterPart};
 use itertools::*;
-use std::collections::{HashMap, HashSet};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::iter::FromIterator;
 
 trait Optimization {
@@ -9,26 +10,80 @@ trait Optimization {
     fn select(&self, filter: &NetworkFilter) -> bool;
 }
 
+/// Describes what optimization did to a set of filters, to judge whether it is worth its cost
+/// when building engines.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct OptimizationReport {
+    pub filters_before: usize,
+    pub filters_after: usize,
+    /// Number of filters that were merged with others
+    pub filters_fused: usize,
+    /// Number of patterns left out of fused filters, because they were duplicates or another
+    /// member of the group matches everything
+    pub redundant_patterns: usize,
+    /// Size of the largest bucket of a `NetworkFilterList` before and after optimization. Every
+    /// filter in the buckets of a request's tokens is evaluated, so this bounds the worst case.
+    pub largest_bucket_before: usize,
+    pub largest_bucket_after: usize,
+    /// Number of filters across all buckets of a `NetworkFilterList` before and after
+    /// optimization. Filters can be in several buckets, so this may exceed the number of filters.
+    pub bucket_entries_before: usize,
+    pub bucket_entries_after: usize,
+}
+
+impl OptimizationReport {
+    /// Adds the counts of `other`, e.g. to describe several filter lists at once.
+    pub fn merge(&mut self, other: &OptimizationReport) {
+        self.filters_before += other.filters_before;
+        self.filters_after += other.filters_after;
+        self.filters_fused += other.filters_fused;
+        self.redundant_patterns += other.redundant_patterns;
+        self.largest_bucket_before = self.largest_bucket_before.max(other.largest_bucket_before);
+        self.largest_bucket_after = self.largest_bucket_after.max(other.largest_bucket_after);
+        self.bucket_entries_before += other.bucket_entries_before;
+        self.bucket_entries_after += other.bucket_entries_after;
+    }
+}
+
 /// Fuse `NetworkFilter`s together by applying optimizations sequentially.
 pub fn optimize(filters: Vec<NetworkFilter>) -> Vec<NetworkFilter> {
+    optimize_with_report(filters).0
+}
+
+/// Like `optimize`, but also reports how many filters were fused. Bucket sizes are left at 0.
+pub fn optimize_with_report(filters: Vec<NetworkFilter>) -> (Vec<NetworkFilter>, OptimizationReport) {
+    let mut report = OptimizationReport {
+        filters_before: filters.len(),
+        ..Default::default()
+    };
     let mut optimized: Vec<NetworkFilter> = Vec::new();
 
     // let union_domain_group = UnionDomainGroup {};
-    // let (mut fused, unfused) = apply_optimisation(&union_domain_group, filters);
+    // let (mut fused, unfused) = apply_optimisation(&union_domain_group, filters, &mut report);
     // optimized.append(&mut fused);
 
     let simple_pattern_group = SimplePatternGroup {};
-    let (mut fused, mut unfused) = apply_optimisation(&simple_pattern_group, filters);
+    let (mut fused, mut unfused) = apply_optimisation(&simple_pattern_group, filters, &mut report);
     optimized.append(&mut fused);
 
     // Append whatever is still left unfused
     optimized.append(&mut unfused);
-    optimized
+    report.filters_after = optimized.len();
+    (optimized, report)
+}
+
+fn pattern_count(filter: &NetworkFilter) -> usize {
+    match &filter.filter {
+        FilterPart::Empty => 0,
+        FilterPart::Simple(_) => 1,
+        FilterPart::AnyOf(patterns) => patterns.len(),
+    }
 }
 
 fn apply_optimisation<T: Optimization>(
     optimization: &T,
     filters: Vec<NetworkFilter>,
+    report: &mut OptimizationReport,
 ) -> (Vec<NetworkFilter>, Vec<NetworkFilter>) {
     let (positive, mut negative): (Vec<NetworkFilter>, Vec<NetworkFilter>) =
         filters.into_iter().partition_map(|f| {
@@ -48,7 +103,10 @@ fn apply_optimisation<T: Optimization>(
     for (_, group) in to_fuse {
         if group.len() > 1 {
             // println!("Fusing {} filters together", group.len());
-            fused.push(optimization.fusion(group.as_slice()));
+            let filter = optimization.fusion(group.as_slice());
+            report.filters_fused += group.len();
+            report.redundant_patterns += group.iter().map(pattern_count).sum::<usize>().saturating_sub(pattern_count(&filter));
+            fused.push(filter);
         } else {
             group.into_iter().for_each(|f| negative.push(f));
         }
@@ -74,6 +132,7 @@ impl Optimization for SimplePatternGroup {
     fn fusion(&self, filters: &[NetworkFilter]) -> NetworkFilter {
         let base_filter = &filters[0]; // FIXME: can technically panic, if filters list is empty
         let mut filter = base_filter.clone();
+        filter.reset_cached_state();
 
         // if any filter is empty (meaning matches anything), the entire combiation matches anything
         if filters.iter().any(|f| matches!(f.filter, FilterPart::Empty)) {
@@ -87,6 +146,9 @@ impl Optimization for SimplePatternGroup {
                     FilterPart::AnyOf(s) => flat_patterns.extend_from_slice(s)
                 }
             }
+            // Duplicated patterns would only make the regex longer
+            let mut seen = HashSet::with_capacity(flat_patterns.len());
+            flat_patterns.retain(|pattern| seen.insert(pattern.clone()));
 
             if flat_patterns.is_empty() {
                 filter.filter = FilterPart::Empty;
@@ -103,6 +165,9 @@ impl Optimization for SimplePatternGroup {
         let is_complete_regex = filters.iter().any(|f| f.is_complete_regex());
         filter.mask.set(NetworkFilterMask::IS_COMPLETE_REGEX, is_complete_regex);
 
+        // A fused filter takes the place of its earliest member
+        filter.position = filters.iter().map(|f| f.position).min().unwrap_or(filter.position);
+
         if base_filter.raw_line.is_some() {
             filter.raw_line = Some(
                 filters
@@ -124,7 +189,13 @@ impl Optimization for SimplePatternGroup {
             && !filter.is_hostname_anchor()
             && !filter.is_redirect()
             && !filter.is_csp()
+            && !filter.is_removeparam()
+            && !filter.is_removeheader()
+            && !filter.has_denyallow()
             && !filter.has_bug()
+            && !filter.has_client_tags()
+            && !filter.has_dns_types()
+            && !filter.is_dns_rewrite()
     }
 }
 
@@ -135,6 +206,7 @@ impl Optimization for UnionDomainGroup {
     fn fusion(&self, filters: &[NetworkFilter]) -> NetworkFilter {
         let base_filter = &filters[0]; // FIXME: can technically panic, if filters list is empty
         let mut filter = base_filter.clone();
+        filter.reset_cached_state();
         let mut domains = HashSet::new();
         let mut not_domains = HashSet::new();
 
@@ -165,7 +237,13 @@ impl Optimization for UnionDomainGroup {
             filter.opt_not_domains = Some(domains);
             filter.opt_not_domains_union = opt_not_domains_union;
         }
+        filter.opt_domain_names = filters.iter()
+            .map(|f| f.opt_domain_names.as_ref())
+            .collect::<Option<Vec<_>>>()
+            .map(|names| names.into_iter().flatten().cloned().collect::<BTreeSet<_>>().into_iter().collect());
 
+        // A fused filter takes the place of its earliest member
+        filter.position = filters.iter().map(|f| f.position).min().unwrap_or(filter.position);
 
         if base_filter.raw_line.is_some() {
             filter.raw_line = Some(
@@ -185,7 +263,12 @@ impl Optimization for UnionDomainGroup {
 
     fn select(&self, filter: &NetworkFilter) -> bool {
         !filter.is_csp()
+            && !filter.is_removeparam()
+            && !filter.is_removeheader()
+            && !filter.has_denyallow()
             && !filter.has_bug()
+            && !filter.has_dns_types()
+            && !filter.is_dns_rewrite()
             && (filter.opt_domains.is_some() || filter.opt_not_domains.is_some())
     }
 }
@@ -278,6 +361,25 @@ mod optimization_tests_pattern_group {
         check_regex_match(&fused_regex, "/static/adv/foobar/asd?q=1", true);
     }
 
+    #[test]
+    fn reports_fused_filters() {
+        let rules = vec![
+            String::from("/static/ad-"),
+            String::from("/static/ad-"),
+            String::from("/static/adv-"),
+            String::from("||example.com/ad"),
+        ];
+        let (filters, _) = lists::parse_filters(&rules, true, FilterFormat::Standard);
+
+        let (optimized, report) = optimize_with_report(filters);
+        assert_eq!(optimized.len(), 2);
+        assert_eq!(report.filters_before, 4);
+        assert_eq!(report.filters_after, 2);
+        assert_eq!(report.filters_fused, 3);
+        assert_eq!(report.redundant_patterns, 1);
+        assert!(optimized.iter().any(|filter| matches!(&filter.filter, FilterPart::AnyOf(patterns) if patterns == &["/static/ad-", "/static/adv-"])));
+    }
+
     #[test]
     fn separates_pattern_by_grouping() {
         let rules = vec![
@@ -292,7 +394,7 @@ mod optimization_tests_pattern_group {
 
         let optimization = SimplePatternGroup {};
 
-        let (fused, skipped) = apply_optimisation(&optimization, filters);
+        let (fused, skipped) = apply_optimisation(&optimization, filters, &mut OptimizationReport::default());
 
         assert_eq!(fused.len(), 1);
         let filter = fused.get(0).unwrap();
@@ -333,7 +435,7 @@ mod optimization_tests_union_domain {
 
         let (filters, _) = lists::parse_filters(&rules, true, FilterFormat::Standard);
         let optimization = UnionDomainGroup {};
-        let (fused, _) = apply_optimisation(&optimization, filters);
+        let (fused, _) = apply_optimisation(&optimization, filters, &mut OptimizationReport::default());
 
         assert_eq!(fused.len(), 1);
         let filter = fused.get(0).unwrap();
@@ -363,7 +465,7 @@ mod optimization_tests_union_domain {
 
         let (filters, _) = lists::parse_filters(&rules, true, FilterFormat::Standard);
         let optimization = UnionDomainGroup {};
-        let (_, skipped) = apply_optimisation(&optimization, filters);
+        let (_, skipped) = apply_optimisation(&optimization, filters, &mut OptimizationReport::default());
 
         assert_eq!(skipped.len(), 1);
         let filter = skipped.get(0).unwrap();
@@ -386,7 +488,7 @@ mod optimization_tests_union_domain {
 
         let optimization = UnionDomainGroup {};
 
-        let (fused, skipped) = apply_optimisation(&optimization, filters);
+        let (fused, skipped) = apply_optimisation(&optimization, filters, &mut OptimizationReport::default());
 
         assert_eq!(fused.len(), 1);
         let filter = fused.get(0).unwrap();
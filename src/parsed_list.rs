@@ -0,0 +1,134 @@
+//! Filter lists that were parsed, but not yet indexed into an `Engine`.
+//!
+//! Parsing is the expensive part of loading a list. A `ParsedList` can be produced once, e.g. on a
+//! server, and shipped to clients in its serialized form. Clients then add any number of parsed
+//! lists to a `FilterSet` with `FilterSet::add_parsed_list`, and only have to build the engine's
+//! indexes.
+
+use serde::{Deserialize, Serialize};
+use rmp_serde as rmps;
+
+use crate::data_format::SerializationError;
+use crate::filters::cosmetic::CosmeticFilter;
+use crate::filters::network::NetworkFilter;
+use crate::lists::{FilterFormat, FilterSet, FilterSource};
+
+/// Version of the serialized form of `ParsedList`, written before the list itself. It changes
+/// whenever lists serialized by older versions of this crate can no longer be read correctly.
+/// Version 2 changed how the ids of filters with `$domain` options are computed.
+pub const PARSED_LIST_FORMAT_VERSION: u32 = 2;
+
+/// The rules of a single filter list, parsed as by `FilterSet::add_filter_list_with_id`.
+///
+/// Any new fields should be added to the _end_ of the struct with `#[serde(default)]`, so that
+/// lists serialized without them can still be deserialized.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ParsedList {
+    pub list_id: String,
+    /// The `! Version:` header of the list, if any
+    pub version: Option<String>,
+    /// Whether the list was parsed in debug mode, keeping the text and sources of its rules
+    pub debug: bool,
+    pub network_filters: Vec<NetworkFilter>,
+    pub cosmetic_filters: Vec<CosmeticFilter>,
+    pub popup_filters: Vec<NetworkFilter>,
+    /// Sources of the rules, keyed by their trimmed text. Empty unless `debug` is set.
+    pub sources: Vec<(String, Vec<FilterSource>)>,
+}
+
+#[derive(Debug)]
+pub enum ParsedListError {
+    RmpSerdeError(rmps::decode::Error),
+    /// The list was serialized with a different `PARSED_LIST_FORMAT_VERSION`, given here.
+    UnsupportedVersion(u32),
+}
+
+impl From<rmps::decode::Error> for ParsedListError {
+    fn from(e: rmps::decode::Error) -> Self { Self::RmpSerdeError(e) }
+}
+
+impl ParsedList {
+    /// Parses the rules of `filter_list`. Rules that cannot be parsed are left out, as with
+    /// `FilterSet::add_filter_list_with_id`.
+    pub fn parse(list_id: &str, filter_list: &str, format: FilterFormat, debug: bool) -> Self {
+        let mut filter_set = FilterSet::new(debug);
+        filter_set.add_filter_list_with_id(list_id, filter_list, format);
+
+        let mut sources: Vec<_> = filter_set.sources.into_iter().collect();
+        sources.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Self {
+            list_id: list_id.to_string(),
+            version: filter_set.list_versions.remove(list_id),
+            debug,
+            network_filters: filter_set.network_filters,
+            cosmetic_filters: filter_set.cosmetic_filters,
+            popup_filters: filter_set.popup_filters,
+            sources,
+        }
+    }
+
+    /// Serializes the list, preceded by `PARSED_LIST_FORMAT_VERSION`.
+    pub fn serialize(&self) -> Result<Vec<u8>, SerializationError> {
+        let mut serialized = Vec::new();
+        rmps::encode::write(&mut serialized, &PARSED_LIST_FORMAT_VERSION)?;
+        rmps::encode::write(&mut serialized, self)?;
+        Ok(serialized)
+    }
+
+    /// Deserializes a list serialized with `serialize`, failing with
+    /// `ParsedListError::UnsupportedVersion` if it was serialized with another version of the
+    /// format.
+    pub fn deserialize(mut serialized: &[u8]) -> Result<Self, ParsedListError> {
+        let version: u32 = rmps::decode::from_read(&mut serialized)?;
+        if version != PARSED_LIST_FORMAT_VERSION {
+            return Err(ParsedListError::UnsupportedVersion(version));
+        }
+        Ok(rmps::decode::from_read(&mut serialized)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+
+    const LIST: &str = "! Version: 202110150\n||ads.com^\n@@||ads.com/ok.js\nexample.com##.ad\n||popup.com^$popup\n";
+
+    #[test]
+    fn builds_engine_from_deserialized_lists() {
+        let parsed = ParsedList::parse("ads", LIST, FilterFormat::Standard, true);
+        assert_eq!(parsed.version.as_deref(), Some("202110150"));
+        assert_eq!((parsed.network_filters.len(), parsed.cosmetic_filters.len(), parsed.popup_filters.len()), (2, 1, 1));
+
+        let other = ParsedList::parse("other", "||tracker.net^", FilterFormat::Standard, true);
+
+        let mut filter_set = FilterSet::new(true);
+        for list in [parsed, other].iter() {
+            let list = ParsedList::deserialize(&list.serialize().unwrap()).unwrap();
+            filter_set.add_parsed_list(list);
+        }
+        let engine = Engine::from_filter_set(filter_set, true);
+
+        assert!(engine.check_network_urls("https://ads.com/pixel.png", "https://example.com", "image").matched);
+        assert!(!engine.check_network_urls("https://ads.com/ok.js", "https://example.com", "script").matched);
+        assert!(engine.check_network_urls("https://tracker.net/t.js", "https://example.com", "script").matched);
+        assert!(engine.url_cosmetic_resources("https://example.com").hide_selectors.contains(".ad"));
+        assert_eq!(engine.filter_sources("||tracker.net^"), vec![&FilterSource {
+            list_id: "other".to_string(),
+            line: 1,
+            text: "||tracker.net^".to_string(),
+        }]);
+    }
+
+    #[test]
+    fn rejects_other_format_versions() {
+        let parsed = ParsedList::parse("ads", LIST, FilterFormat::Standard, false);
+        let mut serialized = parsed.serialize().unwrap();
+        // A positive fixint holding the version
+        serialized[0] = PARSED_LIST_FORMAT_VERSION as u8 + 1;
+        match ParsedList::deserialize(&serialized) {
+            Err(ParsedListError::UnsupportedVersion(version)) => assert_eq!(version, PARSED_LIST_FORMAT_VERSION + 1),
+            _ => panic!("expected an unsupported version"),
+        }
+    }
+}
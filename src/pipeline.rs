@@ -0,0 +1,51 @@
+//! Hooks into the stages an `Engine` goes through to reach its decisions.
+//!
+//! Checks run through a fixed sequence of stages: the request is normalized, network filters
+//! decide whether it is blocked or redirected, header rules decide what happens to its headers,
+//! and cosmetic filters decide what to hide on the page it loads. A `DecisionHook` added with
+//! `Engine::add_hook` can inspect or override the outcome of each stage, e.g. to enforce an
+//! enterprise policy or to log decisions, without wrapping every method of `Engine`.
+//!
+//! Hooks run in the order they were added. Each stage method has a default implementation that
+//! does nothing, so hooks only implement the stages they are interested in.
+//!
+//! Every `Engine` method that decides something about a request normalizes it first, and the
+//! later stages are run by the methods making the corresponding decisions:
+//!
+//! - network filters: the `check_network_*` methods, `check_hostname` and `check_dns_query`;
+//! - header rules: `referrer_decision`;
+//! - cosmetic filters: `url_cosmetic_resources`.
+//!
+//! `cosmetic_policy`, `scriptlets_and_flags`, `frame_state` and `check_popup` only go through
+//! normalization. `filter_url_params`, which rewrites the URL it is given, and maintenance methods
+//! like `prewarm` or `record_hits` don't run any hooks.
+
+use crate::blocker::BlockerResult;
+use crate::cosmetic_filter_cache::UrlSpecificResources;
+use crate::referrer::ReferrerDecision;
+use crate::request::Request;
+
+/// Custom logic run at each stage of an `Engine`'s decisions, see the module documentation.
+pub trait DecisionHook: Send + Sync {
+    /// Normalization stage: adjusts a request before any filter is consulted, e.g. to unwrap URLs
+    /// rewritten by a corporate proxy. This runs once per decision, for every stage.
+    fn normalize(&self, _request: &mut Request) {}
+
+    /// Network filter stage, before the filters are consulted. Returning a result skips the
+    /// filters entirely, as well as the `before_network` method of any later hooks.
+    fn before_network(&self, _request: &Request) -> Option<BlockerResult> {
+        None
+    }
+
+    /// Network filter stage, after the filters are consulted. Cached decisions also go through
+    /// this stage.
+    fn after_network(&self, _request: &Request, _result: &mut BlockerResult) {}
+
+    /// Header rule stage, after `Engine::referrer_decision` combined `$removeheader` rules with
+    /// the embedder's `ReferrerPolicy`.
+    fn after_headers(&self, _request: &Request, _decision: &mut ReferrerDecision) {}
+
+    /// Cosmetic stage, after the cosmetic resources of a page were computed. `request` is built
+    /// from the URL of the page alone, without a source URL or request type.
+    fn after_cosmetic(&self, _request: &Request, _resources: &mut UrlSpecificResources) {}
+}
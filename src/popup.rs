@@ -0,0 +1,166 @@
+//! Decisions on whether pages may open new tabs or windows.
+//!
+//! `$popup` filters only describe which popups are unwanted. Browsers usually combine them with
+//! heuristics, like only allowing popups opened in response to a click. `Engine::check_popup`
+//! applies both, so that all embedders get the same policy.
+
+use crate::url_parser::parse_url;
+use crate::utils::Hash;
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Heuristics applied to popups that are not blocked by a filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PopupHeuristics {
+    /// Block popups not opened in response to a user gesture, e.g. a click or a key press
+    pub require_user_gesture: bool,
+    /// Maximum number of tabs or windows a single opener may have open at once. `None` for no
+    /// limit.
+    pub max_tabs_per_opener: Option<usize>,
+    /// Maximum number of popups a single opener may open within `rate_window`, whether or not
+    /// they are still open. `None` for no limit. Only enforced by
+    /// `Engine::check_popup_with_tracker`, which keeps count in a `PopupTracker`.
+    pub max_popups_per_window: Option<usize>,
+    pub rate_window: Duration,
+}
+
+impl Default for PopupHeuristics {
+    fn default() -> Self {
+        Self {
+            require_user_gesture: true,
+            max_tabs_per_opener: Some(3),
+            max_popups_per_window: Some(5),
+            rate_window: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The outcome of checking a popup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PopupDecision {
+    Allow,
+    /// A `$popup` filter matched the target URL. `filter` is the text of the rule in
+    /// debug mode, and its canonical text otherwise.
+    BlockedByFilter { filter: Option<String>, filter_id: Hash },
+    /// `PopupHeuristics::require_user_gesture` is set, and there was no user gesture
+    NoUserGesture,
+    /// The opener already has `PopupHeuristics::max_tabs_per_opener` tabs open
+    TooManyTabs,
+    /// The opener already opened `PopupHeuristics::max_popups_per_window` popups within
+    /// `PopupHeuristics::rate_window`
+    RateLimited,
+}
+
+impl PopupDecision {
+    pub fn is_allowed(&self) -> bool {
+        *self == PopupDecision::Allow
+    }
+}
+
+/// Context of a popup, as passed to `Engine::check_popup`.
+#[derive(Debug, Clone, Copy)]
+pub struct PopupRequest<'a> {
+    /// URL of the page opening the popup
+    pub opener_url: &'a str,
+    /// URL the popup is about to load
+    pub target_url: &'a str,
+    pub user_gesture: bool,
+    /// Number of tabs or windows previously opened by the same opener that are still open
+    pub opener_tab_count: usize,
+}
+
+/// Popups recently allowed for each opener, used by `Engine::check_popup_with_tracker` to enforce
+/// `PopupHeuristics::max_popups_per_window`. Openers are identified by hostname, so that
+/// reloading a page or navigating within a site does not reset its count.
+#[derive(Debug, Clone, Default)]
+pub struct PopupTracker {
+    opened: HashMap<String, VecDeque<Instant>>,
+}
+
+impl PopupTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a popup from `opener_url` at `now`, unless it already opened `max_popups` within
+    /// `window`, in which case `false` is returned. Openers without any popup within `window` are
+    /// forgotten.
+    pub(crate) fn try_open(&mut self, opener_url: &str, max_popups: usize, window: Duration, now: Instant) -> bool {
+        self.opened.retain(|_, opened| {
+            while opened.front().map_or(false, |&at| now.saturating_duration_since(at) >= window) {
+                opened.pop_front();
+            }
+            !opened.is_empty()
+        });
+
+        let opener = parse_url(opener_url).map_or_else(|| opener_url.to_string(), |url| url.hostname().to_string());
+        let opened = self.opened.entry(opener).or_insert_with(VecDeque::new);
+        if opened.len() >= max_popups {
+            return false;
+        }
+        opened.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::lists::FilterFormat;
+
+    #[test]
+    fn combines_filters_and_heuristics() {
+        let engine = Engine::from_rules_debug(&[
+            "||ads.com^$popup".to_string(),
+            "@@||ads.com/landing^$popup,domain=partner.com".to_string(),
+            "||ads.com^$script".to_string(),
+        ], FilterFormat::Standard);
+        let heuristics = PopupHeuristics::default();
+        let popup = |opener_url, target_url, user_gesture, opener_tab_count| PopupRequest { opener_url, target_url, user_gesture, opener_tab_count };
+
+        assert!(matches!(
+            engine.check_popup(&popup("https://news.com", "https://ads.com/offer", true, 0), &heuristics),
+            PopupDecision::BlockedByFilter { filter: Some(f), .. } if f == "||ads.com^$popup"
+        ));
+        assert_eq!(engine.check_popup(&popup("https://partner.com", "https://ads.com/landing/1", false, 5), &heuristics), PopupDecision::Allow);
+
+        assert_eq!(engine.check_popup(&popup("https://news.com", "https://example.com", true, 0), &heuristics), PopupDecision::Allow);
+        assert_eq!(engine.check_popup(&popup("https://news.com", "about:blank", false, 0), &heuristics), PopupDecision::NoUserGesture);
+        assert_eq!(engine.check_popup(&popup("https://news.com", "https://example.com", true, 3), &heuristics), PopupDecision::TooManyTabs);
+
+        let lenient = PopupHeuristics { require_user_gesture: false, max_tabs_per_opener: None, ..PopupHeuristics::default() };
+        assert!(engine.check_popup(&popup("https://news.com", "https://example.com", false, 10), &lenient).is_allowed());
+
+        // `$popup` filters never apply to requests
+        assert!(!engine.check_network_urls("https://ads.com/offer", "https://news.com", "document").matched);
+        assert!(engine.check_network_urls("https://ads.com/a.js", "https://news.com", "script").matched);
+    }
+
+    #[test]
+    fn rate_limits_each_opener() {
+        let engine = Engine::from_rules(&["@@||partner.com^$popup".to_string()], FilterFormat::Standard);
+        let heuristics = PopupHeuristics { max_popups_per_window: Some(2), rate_window: Duration::from_secs(10), ..PopupHeuristics::default() };
+        let popup = |opener_url, target_url| PopupRequest { opener_url, target_url, user_gesture: true, opener_tab_count: 0 };
+        let mut tracker = PopupTracker::new();
+        let start = Instant::now();
+        let mut check = |opener_url, target_url, seconds| {
+            engine.check_popup_with_tracker(&popup(opener_url, target_url), &heuristics, &mut tracker, start + Duration::from_secs(seconds))
+        };
+
+        assert_eq!(check("https://news.com/a", "https://example.com", 0), PopupDecision::Allow);
+        assert_eq!(check("https://news.com/b", "https://example.com", 1), PopupDecision::Allow);
+        assert_eq!(check("https://news.com/c", "https://example.com", 2), PopupDecision::RateLimited);
+        // Other openers have their own count, and exceptions are not limited
+        assert_eq!(check("https://blog.com", "https://example.com", 2), PopupDecision::Allow);
+        assert_eq!(check("https://news.com", "https://partner.com", 2), PopupDecision::Allow);
+        // Popups older than the window no longer count
+        assert_eq!(check("https://news.com", "https://example.com", 10), PopupDecision::Allow);
+        assert_eq!(check("https://news.com", "https://example.com", 11), PopupDecision::Allow);
+        assert_eq!(check("https://news.com", "https://example.com", 11), PopupDecision::RateLimited);
+
+        // Without a tracker, only the other heuristics apply
+        assert!(engine.check_popup(&popup("https://news.com", "https://example.com"), &heuristics).is_allowed());
+    }
+}
@@ -0,0 +1,9 @@
+//! Re-exports the types needed by most integrations, so that they can be imported with
+//! `use adblock::prelude::*;`. Less common types are available from their own modules.
+
+pub use crate::blocker::BlockerResult;
+pub use crate::cosmetic::UrlSpecificResources;
+pub use crate::engine::Engine;
+pub use crate::lists::{FilterFormat, FilterSet, LoadReport};
+pub use crate::request::{Request, RequestType};
+pub use crate::resources::{MimeType, Resource, ResourceType};
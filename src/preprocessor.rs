@@ -0,0 +1,308 @@
+//! Evaluation of `!#if` preprocessor directives in filter lists, as used by uBlock Origin's lists
+//! to ship rules that only apply to some platforms:
+//!
+//! ```text
+//! !#if env_mobile && !env_safari
+//! example.com##.mobile-ad
+//! !#else
+//! example.com##.desktop-ad
+//! !#endif
+//! ```
+//!
+//! Blocks may be nested. Conditions combine tokens with `!`, `&&`, `||` and parentheses. Tokens
+//! listed in `PreprocessorOptions::env` are true, as is `true`; all others are false. Conditions
+//! that fail to parse are false, so that their rules are left out, as are conditions longer than
+//! `MAX_CONDITION_LENGTH` bytes or nested deeper than `MAX_CONDITION_DEPTH`.
+
+use std::collections::HashSet;
+
+/// Conditions longer than this many bytes are treated as malformed. Real conditions are a few
+/// tokens long, and directives are not subject to `FilterSet::set_max_filter_length`.
+pub const MAX_CONDITION_LENGTH: usize = 1024;
+
+/// Conditions with more nested `!` and parentheses than this are treated as malformed, so that
+/// evaluating untrusted lists can't overflow the stack.
+pub const MAX_CONDITION_DEPTH: usize = 32;
+
+/// What to do with directives other than `!#if`, `!#else` and `!#endif`, like uBO's
+/// `!#include`, which are not supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownDirectivePolicy {
+    /// Treat them as comments.
+    Ignore,
+    /// Report them as skipped rules, in loaders that return a `LoadReport`.
+    Report,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreprocessorOptions {
+    /// Tokens that are true in `!#if` conditions, e.g. `env_chromium` or `cap_html_filtering`.
+    pub env: HashSet<String>,
+    pub unknown_directives: UnknownDirectivePolicy,
+}
+
+impl PreprocessorOptions {
+    pub fn new(env: &[&str]) -> Self {
+        Self {
+            env: env.iter().map(|token| token.to_string()).collect(),
+            unknown_directives: UnknownDirectivePolicy::Ignore,
+        }
+    }
+}
+
+/// How `Preprocessor::process` classified a line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LineOutcome {
+    /// The line is not a directive, and is outside of any excluded block
+    Include,
+    /// The line is not a directive, but is in a block whose condition is false
+    Exclude,
+    Directive,
+    UnknownDirective,
+}
+
+#[derive(Debug)]
+struct Block {
+    condition: bool,
+    in_else: bool,
+}
+
+impl Block {
+    fn is_active(&self) -> bool {
+        self.condition != self.in_else
+    }
+}
+
+/// Tracks the `!#if` blocks enclosing each line of a single list.
+pub(crate) struct Preprocessor {
+    options: PreprocessorOptions,
+    blocks: Vec<Block>,
+}
+
+impl Preprocessor {
+    pub fn new(options: PreprocessorOptions) -> Self {
+        Self {
+            options,
+            blocks: vec![],
+        }
+    }
+
+    pub fn unknown_directives(&self) -> UnknownDirectivePolicy {
+        self.options.unknown_directives
+    }
+
+    /// Classifies the next line of the list. Stray `!#else` and `!#endif` directives are ignored,
+    /// and unknown directives in excluded blocks are excluded like any other line.
+    pub fn process(&mut self, line: &str) -> LineOutcome {
+        let line = line.trim();
+        let directive = match line.strip_prefix("!#") {
+            Some(directive) if directive.starts_with(|c: char| c.is_ascii_alphabetic()) => directive,
+            _ => return if self.is_active() { LineOutcome::Include } else { LineOutcome::Exclude },
+        };
+
+        let (name, argument) = match directive.find(char::is_whitespace) {
+            Some(end) => (&directive[..end], directive[end..].trim()),
+            None => (directive, ""),
+        };
+        match name {
+            "if" => {
+                let condition = evaluate(argument, &self.options.env).unwrap_or(false);
+                self.blocks.push(Block { condition, in_else: false });
+            }
+            "else" => if let Some(block) = self.blocks.last_mut() {
+                block.in_else = true;
+            },
+            "endif" => {
+                self.blocks.pop();
+            }
+            _ if self.is_active() => return LineOutcome::UnknownDirective,
+            _ => return LineOutcome::Exclude,
+        }
+        LineOutcome::Directive
+    }
+
+    fn is_active(&self) -> bool {
+        self.blocks.iter().all(Block::is_active)
+    }
+}
+
+/// Evaluates a condition, or returns `None` if it is malformed.
+fn evaluate(condition: &str, env: &HashSet<String>) -> Option<bool> {
+    if condition.len() > MAX_CONDITION_LENGTH {
+        return None;
+    }
+    let mut tokens = vec![];
+    let mut rest = condition.trim_start();
+    while !rest.is_empty() {
+        let len = if rest.starts_with("&&") || rest.starts_with("||") {
+            2
+        } else if rest.starts_with(|c: char| c == '!' || c == '(' || c == ')') {
+            1
+        } else {
+            rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len())
+        };
+        if len == 0 {
+            return None;
+        }
+        tokens.push(&rest[..len]);
+        rest = rest[len..].trim_start();
+    }
+
+    let mut parser = Parser { tokens: &tokens, position: 0, depth: 0, env };
+    let value = parser.or()?;
+    if parser.position == tokens.len() { Some(value) } else { None }
+}
+
+/// Recursive descent parser over the tokens of a condition, where `||` binds weaker than `&&`,
+/// which binds weaker than `!`.
+struct Parser<'a> {
+    tokens: &'a [&'a str],
+    position: usize,
+    // Number of `not` calls in progress, bounded by `MAX_CONDITION_DEPTH`
+    depth: usize,
+    env: &'a HashSet<String>,
+}
+
+impl<'a> Parser<'a> {
+    fn next_if(&mut self, token: &str) -> bool {
+        if self.tokens.get(self.position) == Some(&token) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Option<bool> {
+        let mut value = self.and()?;
+        while self.next_if("||") {
+            value |= self.and()?;
+        }
+        Some(value)
+    }
+
+    fn and(&mut self) -> Option<bool> {
+        let mut value = self.not()?;
+        while self.next_if("&&") {
+            value &= self.not()?;
+        }
+        Some(value)
+    }
+
+    fn not(&mut self) -> Option<bool> {
+        if self.depth == MAX_CONDITION_DEPTH {
+            return None;
+        }
+        self.depth += 1;
+        let value = self.operand();
+        self.depth -= 1;
+        value
+    }
+
+    fn operand(&mut self) -> Option<bool> {
+        if self.next_if("!") {
+            return self.not().map(|value| !value);
+        }
+        if self.next_if("(") {
+            let value = self.or()?;
+            return if self.next_if(")") { Some(value) } else { None };
+        }
+        let token = self.tokens.get(self.position)?;
+        if !token.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_') {
+            return None;
+        }
+        self.position += 1;
+        Some(*token == "true" || self.env.contains(*token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluates_conditions() {
+        let env: HashSet<String> = ["env_chromium", "cap_html_filtering"].iter().map(|s| s.to_string()).collect();
+        let eval = |condition| evaluate(condition, &env);
+
+        assert_eq!(eval("env_chromium"), Some(true));
+        assert_eq!(eval("env_firefox"), Some(false));
+        assert_eq!(eval("!env_firefox"), Some(true));
+        assert_eq!(eval("env_firefox || env_chromium"), Some(true));
+        assert_eq!(eval("env_chromium && !cap_html_filtering"), Some(false));
+        assert_eq!(eval("!(env_firefox || env_safari) && cap_html_filtering"), Some(true));
+        assert_eq!(eval("env_firefox && env_safari || env_chromium"), Some(true));
+        assert_eq!(eval("false"), Some(false));
+        assert_eq!(eval("true"), Some(true));
+        assert_eq!(eval(""), None);
+        assert_eq!(eval("(env_chromium"), None);
+        assert_eq!(eval("env_chromium env_firefox"), None);
+        assert_eq!(eval("env_chromium &&"), None);
+    }
+
+    #[test]
+    fn bounds_conditions() {
+        let env: HashSet<String> = ["env_chromium"].iter().map(|s| s.to_string()).collect();
+        let eval = |condition: &str| evaluate(condition, &env);
+
+        let nested = |depth: usize| format!("{}env_chromium{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(eval(&nested(MAX_CONDITION_DEPTH - 1)), Some(true));
+        assert_eq!(eval(&nested(MAX_CONDITION_DEPTH)), None);
+        assert_eq!(eval(&format!("{}env_chromium", "!".repeat(MAX_CONDITION_DEPTH))), None);
+        assert_eq!(eval(&["env_chromium"; 200].join(" || ")), None);
+
+        // Deeply nested conditions used to overflow the stack
+        let mut preprocessor = Preprocessor::new(PreprocessorOptions::new(&["env_chromium"]));
+        assert_eq!(preprocessor.process(&format!("!#if {}", "(".repeat(100_000))), LineOutcome::Directive);
+        assert_eq!(preprocessor.process(&format!("!#if {}env_chromium", "!".repeat(100_000))), LineOutcome::Directive);
+        assert_eq!(preprocessor.process("example.com##.ad"), LineOutcome::Exclude);
+    }
+
+    #[test]
+    fn nested_blocks() {
+        // Adapted from uBlock Origin's `filters.txt` and `quick-fixes.txt`
+        let list = "\
+!#if env_mobile
+mobile.com##.ad
+!#if !env_safari
+mobile.com##+js(nobab)
+!#endif
+!#else
+desktop.com##.ad
+!#endif
+!#if cap_html_filtering
+example.com##^script:has-text(ads)
+!#else
+example.com##+js(rmnt, script, ads)
+!#include quick-fixes-legacy.txt
+!#endif
+!#include quick-fixes.txt
+!#----- not a directive
+always.com##.ad
+!#endif
+after-stray-endif.com##.ad";
+
+        let mut preprocessor = Preprocessor::new(PreprocessorOptions::new(&["env_mobile", "env_chromium"]));
+        let included: Vec<_> = list.lines()
+            .filter(|line| preprocessor.process(line) == LineOutcome::Include)
+            .collect();
+        assert_eq!(included, vec![
+            "mobile.com##.ad",
+            "mobile.com##+js(nobab)",
+            "example.com##+js(rmnt, script, ads)",
+            "!#----- not a directive",
+            "always.com##.ad",
+            "after-stray-endif.com##.ad",
+        ]);
+
+        let mut preprocessor = Preprocessor::new(PreprocessorOptions::new(&["env_safari", "cap_html_filtering"]));
+        let outcomes: Vec<_> = list.lines().map(|line| preprocessor.process(line)).collect();
+        assert_eq!(outcomes[1], LineOutcome::Exclude);
+        assert_eq!(outcomes[3], LineOutcome::Exclude);
+        assert_eq!(outcomes[6], LineOutcome::Include);
+        assert_eq!(outcomes[9], LineOutcome::Include);
+        assert_eq!(outcomes[11], LineOutcome::Exclude);
+        assert_eq!(outcomes[12], LineOutcome::Exclude);
+        assert_eq!(outcomes[14], LineOutcome::UnknownDirective);
+    }
+}
@@ -0,0 +1,81 @@
+//! Progress reporting and cooperative cancellation for long-running operations, such as compiling
+//! an engine from large list bundles.
+//!
+//! Operations accepting a `CancellationToken` check it at regular checkpoints, on the thread they
+//! run on, and return early once it is cancelled. Work done before the last checkpoint is
+//! discarded, unless documented otherwise.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Number of lines parsed, or rules compiled, between two progress reports and cancellation
+/// checks.
+pub const PROGRESS_INTERVAL: usize = 1024;
+
+/// Stage of an engine build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BuildPhase {
+    ParsingLists,
+    CompilingNetworkFilters,
+    CompilingCosmeticFilters,
+    Done,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BuildProgress {
+    pub phase: BuildPhase,
+    /// Lines read from the lists so far, including comments and rules that failed to parse
+    pub lines_parsed: usize,
+    /// Rules compiled into the engine so far
+    pub filters_compiled: usize,
+    /// Rules to be compiled in total, known once all lists have been parsed
+    pub filters_total: usize,
+}
+
+impl Default for BuildProgress {
+    fn default() -> Self {
+        Self {
+            phase: BuildPhase::ParsingLists,
+            lines_parsed: 0,
+            filters_compiled: 0,
+            filters_total: 0,
+        }
+    }
+}
+
+/// A flag shared with a running operation, which stops at its next checkpoint once the flag is
+/// set. Clones refer to the same flag, so one can be kept by e.g. a UI thread while the other is
+/// passed to the build.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Returns `Err(Cancelled)` once cancelled, for use as a checkpoint with `?`.
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Error of operations stopped through their `CancellationToken`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
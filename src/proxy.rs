@@ -0,0 +1,341 @@
+//! Helpers for using the engine from an intercepting (MITM) HTTP proxy.
+//!
+//! Unlike browsers, proxies only see raw HTTP traffic, so the request type and the initiating
+//! page have to be inferred from the request headers. `check_intercepted` performs that mapping,
+//! runs the network rules, and condenses the result into a single `ProxyAction`.
+//!
+//! Cookies are only handled through header rules: `$removeheader=set-cookie` and
+//! `$removeheader=request:cookie` strip them entirely. AdGuard's `$cookie` option, which removes
+//! individual cookies, is not supported, and rules using it fail to parse.
+
+use crate::engine::Engine;
+use crate::request::Request;
+
+/// An HTTP request as seen by an intercepting proxy.
+pub struct InterceptedRequest<'a> {
+    pub method: &'a str,
+    pub url: &'a str,
+    /// Raw request headers. Header names are compared case-insensitively.
+    pub headers: &'a [(String, String)],
+    /// URL of the page that initiated the request, if known. When `None`, the `Origin` and
+    /// `Referer` headers are used instead.
+    pub initiator: Option<&'a str>,
+}
+
+/// The response to an intercepted request, once it has been received from upstream.
+pub struct InterceptedResponse<'a> {
+    pub status: u16,
+    /// Raw response headers. Header names are compared case-insensitively.
+    pub headers: &'a [(String, String)],
+}
+
+/// Replacement content to be served instead of the upstream response.
+#[derive(Debug, PartialEq)]
+pub struct RedirectBody {
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+/// What the proxy should do with an intercepted request.
+#[derive(Debug, Default, PartialEq)]
+pub struct ProxyAction {
+    /// The request should not be forwarded upstream.
+    pub block: bool,
+    /// If set, the request should be answered locally with this content rather than being
+    /// blocked outright.
+    pub redirect: Option<RedirectBody>,
+    /// Value of a `Content-Security-Policy` header to add to the response. Only ever set for
+    /// document responses.
+    pub csp: Option<String>,
+    /// Headers to remove, as given by `$removeheader` filters: lowercase names of response
+    /// headers, or of request headers prefixed with `request:`, e.g. `set-cookie` or
+    /// `request:cookie`. Also set for blocked requests, in case the proxy forwards them anyway.
+    pub strip_headers: Vec<String>,
+    /// The blocking filter that matched, if any.
+    pub filter: Option<String>,
+    /// The exception filter that matched, if any.
+    pub exception: Option<String>,
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Infers the request type of an intercepted request, in the format accepted by
+/// `Request::from_urls`. `Sec-Fetch-Dest` is used when available, falling back to the `Accept`
+/// header otherwise.
+pub fn infer_request_type(request: &InterceptedRequest) -> &'static str {
+    if let Some(destination) = header(request.headers, "sec-fetch-dest") {
+        return match destination.trim().to_ascii_lowercase().as_str() {
+            "document" => "document",
+            "iframe" | "frame" | "embed" => "subdocument",
+            "script" | "worker" | "sharedworker" | "serviceworker" | "audioworklet" | "paintworklet" => "script",
+            "style" | "xslt" => "stylesheet",
+            "image" => "image",
+            "font" => "font",
+            "audio" | "video" | "track" => "media",
+            "object" => "object",
+            "report" => "ping",
+            "empty" => {
+                if request.method.eq_ignore_ascii_case("post")
+                    && header(request.headers, "content-type").map(|ct| ct.starts_with("text/ping")).unwrap_or(false)
+                {
+                    "ping"
+                } else {
+                    "xmlhttprequest"
+                }
+            }
+            _ => "other",
+        };
+    }
+
+    match header(request.headers, "accept") {
+        Some(accept) if accept.starts_with("text/html") => "document",
+        Some(accept) if accept.starts_with("text/css") => "stylesheet",
+        Some(accept) if accept.starts_with("image/") => "image",
+        Some(accept) if accept.starts_with("font/") => "font",
+        Some(accept) if accept.starts_with("audio/") || accept.starts_with("video/") => "media",
+        Some(accept) if accept.starts_with("application/json") => "xmlhttprequest",
+        _ => "other",
+    }
+}
+
+/// Decodes a `data:<mime>;base64,<data>` URL as produced by the blocker for redirects.
+fn decode_data_url(data_url: &str) -> Option<RedirectBody> {
+    if !data_url.starts_with("data:") {
+        return None;
+    }
+    let data_url = &data_url["data:".len()..];
+    let separator = data_url.find(";base64,")?;
+    let body = base64::decode(&data_url[separator + ";base64,".len()..]).ok()?;
+
+    Some(RedirectBody {
+        content_type: data_url[..separator].to_owned(),
+        body,
+    })
+}
+
+/// Checks an intercepted request against the engine's network rules.
+///
+/// If the upstream `response` is already available, it will also be used to compute the
+/// Content Security Policy to inject into HTML documents. Requests that cannot be parsed are
+/// allowed.
+pub fn check_intercepted(
+    engine: &Engine,
+    request: &InterceptedRequest,
+    response: Option<&InterceptedResponse>,
+) -> ProxyAction {
+    let request_type = infer_request_type(request);
+    let initiator = request
+        .initiator
+        .or_else(|| header(request.headers, "origin"))
+        .or_else(|| header(request.headers, "referer"))
+        // top-level navigations are initiated by the page they load
+        .unwrap_or(if request_type == "document" { request.url } else { "" });
+
+    let parsed = match Request::from_urls(request.url, initiator, request_type) {
+        Ok(parsed) => parsed,
+        Err(_) => return ProxyAction::default(),
+    };
+
+    // Normalized once, so that every decision below is about the same request
+    let parsed = engine.normalize(&parsed);
+    let result = engine.check_normalized_request(&parsed, false, false);
+    let mut strip_headers: Vec<String> = vec![];
+    for header in engine.blocker.check_removeheaders(&parsed).into_iter().filter_map(|filter| filter.removeheader.as_ref()) {
+        if !strip_headers.contains(header) {
+            strip_headers.push(header.clone());
+        }
+    }
+    let redirect = result.redirect.as_ref().and_then(|data_url| decode_data_url(data_url));
+
+    let csp = response.and_then(|response| {
+        let is_html = header(response.headers, "content-type")
+            .map(|content_type| content_type.trim_start().to_ascii_lowercase().starts_with("text/html"))
+            .unwrap_or(false);
+        if !result.matched && is_html && !(300..400).contains(&response.status) {
+            engine.blocker.get_csp_directives(&parsed)
+        } else {
+            None
+        }
+    });
+
+    ProxyAction {
+        block: result.matched && redirect.is_none(),
+        redirect,
+        csp,
+        strip_headers,
+        filter: result.filter,
+        exception: result.exception,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lists::FilterFormat;
+    use crate::resources::{MimeType, Resource, ResourceType};
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn infers_request_types() {
+        let cases = [
+            (vec![("Sec-Fetch-Dest", "script")], "script"),
+            (vec![("sec-fetch-dest", "iframe")], "subdocument"),
+            (vec![("Sec-Fetch-Dest", "empty")], "xmlhttprequest"),
+            (vec![("Accept", "text/html,application/xhtml+xml")], "document"),
+            (vec![("Accept", "image/webp,*/*")], "image"),
+            (vec![], "other"),
+        ];
+
+        for (pairs, expected) in cases.iter() {
+            let headers = headers(pairs);
+            let request = InterceptedRequest {
+                method: "GET",
+                url: "https://example.com/",
+                headers: &headers,
+                initiator: None,
+            };
+            assert_eq!(infer_request_type(&request), *expected, "{:?}", pairs);
+        }
+    }
+
+    #[test]
+    fn decodes_data_urls() {
+        assert_eq!(
+            decode_data_url("data:text/plain;base64,aGVsbG8="),
+            Some(RedirectBody { content_type: "text/plain".to_string(), body: b"hello".to_vec() }),
+        );
+        assert_eq!(decode_data_url("text/plain;base64,aGVsbG8="), None);
+        assert_eq!(decode_data_url("data:text/plain,hello"), None);
+    }
+
+    #[test]
+    fn blocks_and_redirects() {
+        let mut engine = Engine::from_rules(&[
+            "||tracker.com^$third-party".to_string(),
+            "||cdn.com/ad.js$redirect=noop.js".to_string(),
+        ], FilterFormat::Standard);
+        engine.add_resource(Resource {
+            name: "noop.js".to_string(),
+            aliases: vec![],
+            kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+            content: base64::encode("(function() {})()"),
+            privileged: false,
+        }).unwrap();
+
+        let script = headers(&[("Sec-Fetch-Dest", "script"), ("Referer", "https://example.com/page")]);
+
+        let action = check_intercepted(&engine, &InterceptedRequest {
+            method: "GET",
+            url: "https://tracker.com/t.js",
+            headers: &script,
+            initiator: None,
+        }, None);
+        assert!(action.block);
+        assert_eq!(action.redirect, None);
+
+        let action = check_intercepted(&engine, &InterceptedRequest {
+            method: "GET",
+            url: "https://cdn.com/ad.js",
+            headers: &script,
+            initiator: None,
+        }, None);
+        assert!(!action.block);
+        assert_eq!(action.redirect, Some(RedirectBody {
+            content_type: "application/javascript".to_string(),
+            body: b"(function() {})()".to_vec(),
+        }));
+
+        // same-origin request is not affected by the `$third-party` rule
+        let action = check_intercepted(&engine, &InterceptedRequest {
+            method: "GET",
+            url: "https://tracker.com/t.js",
+            headers: &script,
+            initiator: Some("https://tracker.com/"),
+        }, None);
+        assert_eq!(action, ProxyAction::default());
+    }
+
+    #[test]
+    fn strips_headers() {
+        let engine = Engine::from_rules(&[
+            "||tracker.com^$removeheader=set-cookie".to_string(),
+            "||tracker.com^$removeheader=request:cookie".to_string(),
+            "||tracker.com^$removeheader=request:referer".to_string(),
+            "@@||tracker.com/allowed/$removeheader=request:referer".to_string(),
+        ], FilterFormat::Standard);
+        let script = headers(&[("Sec-Fetch-Dest", "script"), ("Referer", "https://example.com/page")]);
+
+        let action = check_intercepted(&engine, &InterceptedRequest {
+            method: "GET",
+            url: "https://tracker.com/t.js",
+            headers: &script,
+            initiator: None,
+        }, None);
+        assert!(!action.block);
+        assert_eq!(action.strip_headers, vec!["set-cookie", "request:cookie", "request:referer"]);
+
+        let action = check_intercepted(&engine, &InterceptedRequest {
+            method: "GET",
+            url: "https://tracker.com/allowed/t.js",
+            headers: &script,
+            initiator: None,
+        }, None);
+        assert_eq!(action.strip_headers, vec!["set-cookie", "request:cookie"]);
+    }
+
+    #[test]
+    fn injects_csp_into_documents() {
+        let engine = Engine::from_rules(&[
+            "||example.com^$csp=script-src 'none'".to_string(),
+        ], FilterFormat::Standard);
+
+        let document = headers(&[("Sec-Fetch-Dest", "document")]);
+        let html = headers(&[("Content-Type", "text/html; charset=utf-8")]);
+        let json = headers(&[("Content-Type", "application/json")]);
+
+        let request = InterceptedRequest {
+            method: "GET",
+            url: "https://example.com/",
+            headers: &document,
+            initiator: None,
+        };
+
+        let action = check_intercepted(&engine, &request, Some(&InterceptedResponse { status: 200, headers: &html }));
+        assert_eq!(action.csp, Some("script-src 'none'".to_string()));
+
+        let action = check_intercepted(&engine, &request, Some(&InterceptedResponse { status: 200, headers: &json }));
+        assert_eq!(action.csp, None);
+
+        let action = check_intercepted(&engine, &request, None);
+        assert_eq!(action.csp, None);
+    }
+
+    #[test]
+    fn injects_tagged_csp() {
+        let mut engine = Engine::from_rules(&[
+            "||example.com^$csp=script-src 'none',tag=strict".to_string(),
+        ], FilterFormat::Standard);
+
+        let document = headers(&[("Sec-Fetch-Dest", "document")]);
+        let html = headers(&[("Content-Type", "text/html")]);
+        let request = InterceptedRequest {
+            method: "GET",
+            url: "https://example.com/",
+            headers: &document,
+            initiator: None,
+        };
+        let response = InterceptedResponse { status: 200, headers: &html };
+
+        assert_eq!(check_intercepted(&engine, &request, Some(&response)).csp, None);
+        engine.enable_tags(&["strict"]);
+        assert_eq!(check_intercepted(&engine, &request, Some(&response)).csp, Some("script-src 'none'".to_string()));
+    }
+}
@@ -0,0 +1,91 @@
+//! Decisions about the `Referer` header of network requests.
+//!
+//! Whether a request should keep its `Referer` depends both on the loaded lists, through
+//! `$removeheader=referer` rules, and on the embedder's own privacy settings.
+//! `Engine::referrer_decision` combines the two, so that embedders don't have to consult each of
+//! them separately.
+
+use serde::Serialize;
+
+use crate::utils::Hash;
+
+/// What to do with the `Referer` header of a request, from least to most private.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum ReferrerAction {
+    Keep,
+    /// Only send the origin of the referrer, e.g. `https://example.com/`
+    TrimToOrigin,
+    Remove,
+}
+
+impl Default for ReferrerAction {
+    fn default() -> Self {
+        Self::Keep
+    }
+}
+
+/// The embedder's policy for `Referer` headers, applied in addition to `$removeheader` rules, see
+/// `Engine::set_referrer_policy`. The default keeps every header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReferrerPolicy {
+    pub first_party: ReferrerAction,
+    pub third_party: ReferrerAction,
+}
+
+/// The outcome of `Engine::referrer_decision`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ReferrerDecision {
+    /// The most private of the actions recommended by the rules and the embedder's policy
+    pub action: ReferrerAction,
+    /// The `$removeheader` rule that asked for the header to be removed, if any. Like
+    /// `BlockerResult::filter`, it is only the text of the rule in debug mode, and its canonical
+    /// text otherwise.
+    pub filter: Option<String>,
+    /// Stable identifier of `filter`, see `NetworkFilter::id`.
+    pub filter_id: Option<Hash>,
+}
+
+impl ReferrerDecision {
+    /// Returns the value that the `Referer` header should have instead of `referrer`, or `None` if
+    /// it should be removed. Referrers that are not valid URLs can't be trimmed, and are removed.
+    pub fn apply(&self, referrer: &str) -> Option<String> {
+        match self.action {
+            ReferrerAction::Keep => Some(referrer.to_string()),
+            ReferrerAction::TrimToOrigin => url::Url::parse(referrer)
+                .ok()
+                .map(|url| url.origin())
+                .filter(|origin| origin.is_tuple())
+                .map(|origin| format!("{}/", origin.ascii_serialization())),
+            ReferrerAction::Remove => None,
+        }
+    }
+}
+
+/// Whether a `$removeheader` value refers to the `Referer` request header. The `request:` prefix
+/// of AdGuard's syntax is optional, since `Referer` is only ever sent with requests.
+pub(crate) fn is_referrer_header(removeheader: &str) -> bool {
+    removeheader.strip_prefix("request:").unwrap_or(removeheader) == "referer"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_decisions() {
+        let decision = |action| ReferrerDecision { action, ..Default::default() };
+        let referrer = "https://news.example.com:8443/article?id=1#comments";
+        assert_eq!(decision(ReferrerAction::Keep).apply(referrer).as_deref(), Some(referrer));
+        assert_eq!(decision(ReferrerAction::TrimToOrigin).apply(referrer).as_deref(), Some("https://news.example.com:8443/"));
+        assert_eq!(decision(ReferrerAction::TrimToOrigin).apply("data:text/plain,x"), None);
+        assert_eq!(decision(ReferrerAction::Remove).apply(referrer), None);
+    }
+
+    #[test]
+    fn recognizes_referrer_headers() {
+        assert!(is_referrer_header("referer"));
+        assert!(is_referrer_header("request:referer"));
+        assert!(!is_referrer_header("referrer-policy"));
+        assert!(!is_referrer_header("location"));
+    }
+}
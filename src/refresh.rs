@@ -0,0 +1,214 @@
+//! Scheduling for periodic filter list updates.
+//!
+//! This crate does not download lists itself. Instead, `RefreshScheduler` keeps track of when each
+//! list was last fetched and how long it remains valid according to its `! Expires:` header, and
+//! reports which lists are due for a refresh. Embedders are expected to poll `due` with the current
+//! time, fetch the reported lists, and then call `record_refresh`.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use crate::lists::ListMetadata;
+use crate::utils::fast_hash;
+
+/// Settings for a `RefreshScheduler`.
+#[derive(Debug, Clone)]
+pub struct RefreshOptions {
+    /// Interval used for lists without an `Expires` header.
+    pub default_interval: Duration,
+    /// Lists are never refreshed more often than this, regardless of their `Expires` header.
+    pub min_interval: Duration,
+    /// Upper bound on a random-looking delay added to each list's interval, to avoid refreshing
+    /// many lists (or many clients) at the same time. The delay is derived from the list id and
+    /// the time of the last refresh, so it is stable between calls.
+    pub max_jitter: Duration,
+}
+
+impl Default for RefreshOptions {
+    fn default() -> Self {
+        Self {
+            default_interval: Duration::from_secs(4 * 24 * 60 * 60),
+            min_interval: Duration::from_secs(60 * 60),
+            max_jitter: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+struct ListState {
+    interval: Duration,
+    last_refresh: Option<SystemTime>,
+}
+
+/// Tracks the refresh schedule of a set of filter lists, identified by arbitrary string ids.
+pub struct RefreshScheduler {
+    options: RefreshOptions,
+    lists: HashMap<String, ListState>,
+}
+
+impl RefreshScheduler {
+    pub fn new(options: RefreshOptions) -> Self {
+        Self {
+            options,
+            lists: HashMap::new(),
+        }
+    }
+
+    /// Starts tracking a list which has never been fetched, so it will be due immediately. If the
+    /// list is already tracked, this has no effect.
+    pub fn add_list(&mut self, id: &str) {
+        let interval = self.options.default_interval.max(self.options.min_interval);
+        self.lists.entry(id.to_string()).or_insert(ListState {
+            interval,
+            last_refresh: None,
+        });
+    }
+
+    /// Stops tracking a list.
+    pub fn remove_list(&mut self, id: &str) {
+        self.lists.remove(id);
+    }
+
+    /// Records that a list was successfully fetched at `time`. If the new contents of the list are
+    /// available, their `metadata` will be used to determine the next refresh; otherwise, the
+    /// previous interval is kept.
+    ///
+    /// Lists that have not been added yet will be added.
+    pub fn record_refresh(&mut self, id: &str, time: SystemTime, metadata: Option<&ListMetadata>) {
+        let interval = metadata.map(|metadata| self.interval_for(metadata));
+        let default_interval = self.options.default_interval.max(self.options.min_interval);
+        let state = self.lists.entry(id.to_string()).or_insert(ListState {
+            interval: default_interval,
+            last_refresh: None,
+        });
+        if let Some(interval) = interval {
+            state.interval = interval;
+        }
+        state.last_refresh = Some(time);
+    }
+
+    /// Returns the time at which the list with the given id should next be refreshed, if it is
+    /// tracked. Lists which have never been fetched are due at `UNIX_EPOCH`.
+    pub fn next_refresh(&self, id: &str) -> Option<SystemTime> {
+        self.lists.get(id).map(|state| self.next_refresh_for(id, state))
+    }
+
+    /// Returns the ids of all lists that should be refreshed at `now`, in order of how overdue
+    /// they are.
+    pub fn due(&self, now: SystemTime) -> Vec<&str> {
+        let mut due = self.lists
+            .iter()
+            .map(|(id, state)| (self.next_refresh_for(id, state), id.as_str()))
+            .filter(|(next_refresh, _)| *next_refresh <= now)
+            .collect::<Vec<_>>();
+        due.sort_unstable();
+        due.into_iter().map(|(_, id)| id).collect()
+    }
+
+    fn interval_for(&self, metadata: &ListMetadata) -> Duration {
+        metadata.expires
+            .map(|expires| expires.as_duration())
+            .unwrap_or(self.options.default_interval)
+            .max(self.options.min_interval)
+    }
+
+    fn next_refresh_for(&self, id: &str, state: &ListState) -> SystemTime {
+        match state.last_refresh {
+            None => SystemTime::UNIX_EPOCH,
+            Some(last_refresh) => last_refresh + state.interval + self.jitter(id, last_refresh),
+        }
+    }
+
+    fn jitter(&self, id: &str, last_refresh: SystemTime) -> Duration {
+        let max_jitter = self.options.max_jitter.as_secs();
+        if max_jitter == 0 {
+            return Duration::from_secs(0);
+        }
+        let seed = last_refresh
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or(0);
+        Duration::from_secs((fast_hash(id) ^ seed) % (max_jitter + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lists::ExpiresInterval;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    fn no_jitter() -> RefreshOptions {
+        RefreshOptions {
+            max_jitter: Duration::from_secs(0),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn new_lists_are_due_immediately() {
+        let mut scheduler = RefreshScheduler::new(no_jitter());
+        scheduler.add_list("easylist");
+        assert_eq!(scheduler.due(SystemTime::UNIX_EPOCH), vec!["easylist"]);
+    }
+
+    #[test]
+    fn uses_expires_header() {
+        let mut scheduler = RefreshScheduler::new(no_jitter());
+        let start = SystemTime::UNIX_EPOCH + 1000 * HOUR;
+
+        let metadata = ListMetadata {
+            expires: Some(ExpiresInterval::Hours(12)),
+            ..Default::default()
+        };
+        scheduler.record_refresh("easylist", start, Some(&metadata));
+        scheduler.record_refresh("easyprivacy", start, None);
+
+        assert_eq!(scheduler.next_refresh("easylist"), Some(start + 12 * HOUR));
+        assert_eq!(scheduler.next_refresh("easyprivacy"), Some(start + 4 * 24 * HOUR));
+        assert_eq!(scheduler.next_refresh("unknown"), None);
+
+        assert!(scheduler.due(start + 11 * HOUR).is_empty());
+        assert_eq!(scheduler.due(start + 12 * HOUR), vec!["easylist"]);
+        assert_eq!(scheduler.due(start + 5 * 24 * HOUR), vec!["easylist", "easyprivacy"]);
+
+        // keeps the previous interval if the new metadata is unknown
+        scheduler.record_refresh("easylist", start + 12 * HOUR, None);
+        assert_eq!(scheduler.next_refresh("easylist"), Some(start + 24 * HOUR));
+
+        scheduler.remove_list("easylist");
+        assert_eq!(scheduler.due(start + 5 * 24 * HOUR), vec!["easyprivacy"]);
+    }
+
+    #[test]
+    fn respects_min_interval() {
+        let mut scheduler = RefreshScheduler::new(RefreshOptions {
+            min_interval: 6 * HOUR,
+            ..no_jitter()
+        });
+        let metadata = ListMetadata {
+            expires: Some(ExpiresInterval::Hours(1)),
+            ..Default::default()
+        };
+        scheduler.record_refresh("list", SystemTime::UNIX_EPOCH, Some(&metadata));
+        assert_eq!(scheduler.next_refresh("list"), Some(SystemTime::UNIX_EPOCH + 6 * HOUR));
+    }
+
+    #[test]
+    fn jitter_is_bounded_and_stable() {
+        let scheduler_options = RefreshOptions {
+            max_jitter: HOUR,
+            ..Default::default()
+        };
+        let mut scheduler = RefreshScheduler::new(scheduler_options);
+        let start = SystemTime::UNIX_EPOCH + 1000 * HOUR;
+
+        for id in ["a", "b", "c", "d"].iter() {
+            scheduler.record_refresh(id, start, None);
+            let next_refresh = scheduler.next_refresh(id).unwrap();
+            assert!(next_refresh >= start + 4 * 24 * HOUR);
+            assert!(next_refresh <= start + 4 * 24 * HOUR + HOUR);
+            assert_eq!(scheduler.next_refresh(id), Some(next_refresh));
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Recommendations of regional filter lists for the languages of a user.
+//!
+//! Lists like EasyList only cover English-language sites well, so most embedders also offer lists
+//! maintained for other languages. A `RegionalCatalog` maps each such list to the locales it is
+//! meant for, and selects the lists to enable by default from an `Accept-Language` header. The
+//! catalog is plain data, usually deserialized from a file such as `data/regional-lists.json`, so
+//! that it can be curated without changes to this crate.
+
+use serde::{Deserialize, Serialize};
+
+use std::cmp::Ordering;
+
+/// A filter list recommended for users of some locales.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegionalList {
+    /// Identifier of the list, e.g. for `FilterSet::add_filter_list_with_id`
+    pub id: String,
+    pub title: String,
+    /// Location the list can be downloaded from
+    pub url: String,
+    /// Language tags the list is meant for, e.g. `de` or `pt-BR`. A language without a region
+    /// covers all of its regions.
+    pub locales: Vec<String>,
+}
+
+impl RegionalList {
+    /// Whether this list is meant for `locale`, given as a language tag like `de-AT`.
+    pub fn supports(&self, locale: &str) -> bool {
+        let locale = normalize_locale(locale);
+        self.locales.iter().any(|supported| locale_matches(&normalize_locale(supported), &locale))
+    }
+}
+
+/// A curated set of regional lists.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegionalCatalog {
+    pub lists: Vec<RegionalList>,
+}
+
+impl RegionalCatalog {
+    pub fn get(&self, id: &str) -> Option<&RegionalList> {
+        self.lists.iter().find(|list| list.id == id)
+    }
+
+    /// Returns the lists meant for `locale`, in catalog order.
+    pub fn lists_for_locale(&self, locale: &str) -> Vec<&RegionalList> {
+        self.lists.iter().filter(|list| list.supports(locale)).collect()
+    }
+
+    /// Returns the lists meant for any of the languages of an `Accept-Language` header, ordered by
+    /// the preference given to their languages. Languages with a quality of 0 and the `*` wildcard
+    /// are ignored, and each list is only returned once.
+    pub fn select_bundle(&self, accept_language: &str) -> Vec<&RegionalList> {
+        let mut bundle: Vec<&RegionalList> = vec![];
+        for locale in parse_accept_language(accept_language) {
+            for list in self.lists_for_locale(&locale) {
+                if !bundle.iter().any(|selected| selected.id == list.id) {
+                    bundle.push(list);
+                }
+            }
+        }
+        bundle
+    }
+}
+
+/// Lowercases a language tag, accepting `_` as well as `-` as the subtag separator, e.g. for POSIX
+/// locales like `pt_BR`.
+fn normalize_locale(locale: &str) -> String {
+    locale.trim().replace('_', "-").to_ascii_lowercase()
+}
+
+/// Whether a list for `supported` should be recommended for `requested`. Either one may be more
+/// specific than the other: a list for `de` is meant for `de-AT`, and a list for `pt-br` is the
+/// best available choice for `pt`.
+fn locale_matches(supported: &str, requested: &str) -> bool {
+    let is_prefix = |prefix: &str, tag: &str| tag.starts_with(prefix) && tag[prefix.len()..].starts_with('-');
+    supported == requested || is_prefix(supported, requested) || is_prefix(requested, supported)
+}
+
+/// Returns the normalized language tags of an `Accept-Language` header, most preferred first.
+fn parse_accept_language(header: &str) -> Vec<String> {
+    let mut ranges: Vec<(String, f32)> = header.split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let locale = normalize_locale(parts.next()?);
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q=").or_else(|| param.strip_prefix("Q=")))
+                .map(|quality| quality.trim().parse::<f32>().unwrap_or(0.))
+                .unwrap_or(1.);
+            if locale.is_empty() || locale == "*" || quality <= 0. {
+                None
+            } else {
+                Some((locale, quality))
+            }
+        })
+        .collect();
+    // Stable, so that ranges with the same quality keep the order of the header
+    ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+    ranges.into_iter().map(|(locale, _)| locale).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalog() -> RegionalCatalog {
+        let data = std::fs::read_to_string("./data/regional-lists.json").unwrap();
+        serde_json::from_str(&data).unwrap()
+    }
+
+    fn ids(lists: Vec<&RegionalList>) -> Vec<&str> {
+        lists.into_iter().map(|list| list.id.as_str()).collect()
+    }
+
+    #[test]
+    fn selects_lists_by_accept_language() {
+        let catalog = catalog();
+        assert_eq!(catalog.get("easylist-germany").unwrap().title, "EasyList Germany");
+
+        assert_eq!(ids(catalog.select_bundle("de-AT,de;q=0.9,en;q=0.8")), vec!["easylist-germany"]);
+        assert_eq!(ids(catalog.select_bundle("fr;q=0.5, uk, *;q=0.1")), vec!["ru-adlist", "liste-fr"]);
+        assert_eq!(ids(catalog.select_bundle("pt_BR, ru, be")), vec!["easylist-portuguese", "ru-adlist"]);
+        assert_eq!(ids(catalog.select_bundle("it;q=0, en-US")), Vec::<&str>::new());
+        assert_eq!(ids(catalog.select_bundle("")), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn matches_locales_of_different_specificity() {
+        let list = RegionalList {
+            id: "brazil".to_string(),
+            title: "Brazil".to_string(),
+            url: "https://example.com/brazil.txt".to_string(),
+            locales: vec!["pt-BR".to_string()],
+        };
+        assert!(list.supports("pt-br"));
+        assert!(list.supports("pt"));
+        assert!(list.supports("pt_BR"));
+        assert!(!list.supports("pt-PT"));
+        assert!(!list.supports("ptx"));
+    }
+}
@@ -0,0 +1,141 @@
+//! Hosting of many engines in a single process, e.g. one per browser profile, or one per customer
+//! of a filtering service.
+//!
+//! Each tenant usually has its own rules, but the resources used by `$redirect` rules and
+//! scriptlet injections are the same for all of them and make up a large part of an engine's
+//! memory. An `EngineRegistry` keeps a single copy of those resources, shared by all its engines.
+//!
+//! Public suffix list data needs no such handling: engines never hold a copy of it, and resolve
+//! domains through a single instance for the whole process, either the list embedded with the
+//! `embedded-domain-resolver` feature or the resolver passed to `url_parser::set_domain_resolver`.
+
+use crate::engine::Engine;
+use crate::resources::{add_all, RedirectResourceStorage, RejectedResource, Resource, ScriptletResourceStorage};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Immutable resources that can be used by several engines without being copied.
+#[derive(Clone, Default)]
+pub struct SharedResources {
+    pub(crate) redirects: Arc<RedirectResourceStorage>,
+    pub(crate) scriptlets: Arc<ScriptletResourceStorage>,
+}
+
+impl SharedResources {
+    /// Builds shared resources from the given resources, also returning the ones that could not be
+    /// added, like `Engine::use_resources`.
+    pub fn new(resources: &[Resource]) -> (Self, Vec<RejectedResource>) {
+        let (redirects, mut rejected) = RedirectResourceStorage::from_resources(resources);
+        let mut scriptlets = ScriptletResourceStorage::default();
+        for scriptlet in add_all(resources, |resource| scriptlets.add_resource(resource)) {
+            if !rejected.iter().any(|r| r.name == scriptlet.name) {
+                rejected.push(scriptlet);
+            }
+        }
+
+        let shared = Self {
+            redirects: Arc::new(redirects),
+            scriptlets: Arc::new(scriptlets),
+        };
+        (shared, rejected)
+    }
+}
+
+/// A set of engines identified by tenant id, using the same resources.
+#[derive(Clone, Default)]
+pub struct EngineRegistry {
+    resources: SharedResources,
+    engines: HashMap<String, Engine>,
+}
+
+impl EngineRegistry {
+    pub fn new(resources: SharedResources) -> Self {
+        Self {
+            resources,
+            engines: HashMap::new(),
+        }
+    }
+
+    /// Replaces the resources used by all engines, including those added later.
+    pub fn use_resources(&mut self, resources: SharedResources) {
+        self.resources = resources;
+        for engine in self.engines.values_mut() {
+            engine.use_shared_resources(&self.resources);
+        }
+    }
+
+    /// Adds the engine of a tenant, replacing any previous one, which is returned. Its resources
+    /// are replaced by the shared ones.
+    pub fn insert(&mut self, tenant: &str, mut engine: Engine) -> Option<Engine> {
+        engine.use_shared_resources(&self.resources);
+        self.engines.insert(tenant.to_string(), engine)
+    }
+
+    pub fn remove(&mut self, tenant: &str) -> Option<Engine> {
+        self.engines.remove(tenant)
+    }
+
+    pub fn get(&self, tenant: &str) -> Option<&Engine> {
+        self.engines.get(tenant)
+    }
+
+    /// Returns the engine of a tenant for modification. Adding resources to it, or deserializing
+    /// into it, makes it stop sharing resources with the other engines.
+    pub fn get_mut(&mut self, tenant: &str) -> Option<&mut Engine> {
+        self.engines.get_mut(tenant)
+    }
+
+    pub fn tenants(&self) -> impl Iterator<Item = &str> {
+        self.engines.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.engines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.engines.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lists::FilterFormat;
+    use crate::resources::{MimeType, ResourceType};
+
+    #[test]
+    fn engines_share_resources() {
+        let resources = vec![Resource {
+            name: "noop.js".to_string(),
+            aliases: vec![],
+            kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+            content: base64::encode("(function() {})()"),
+            privileged: false,
+        }];
+        let (shared, rejected) = SharedResources::new(&resources);
+        assert_eq!(rejected, vec![]);
+        let mut registry = EngineRegistry::new(shared);
+
+        let rules = |rules: &[&str]| rules.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        registry.insert("alice", Engine::from_rules(&rules(&["||ads.com^$redirect=noop.js"]), FilterFormat::Standard));
+        registry.insert("bob", Engine::from_rules(&rules(&["||tracker.com^$redirect=noop.js"]), FilterFormat::Standard));
+        assert_eq!(registry.len(), 2);
+
+        let alice = registry.get("alice").unwrap();
+        let bob = registry.get("bob").unwrap();
+        assert!(Arc::ptr_eq(&alice.blocker.resources, &bob.blocker.resources));
+        assert!(alice.check_network_urls("https://ads.com/a.js", "https://example.com", "script").redirect.is_some());
+        assert!(!alice.check_network_urls("https://tracker.com/a.js", "https://example.com", "script").matched);
+        assert!(bob.check_network_urls("https://tracker.com/a.js", "https://example.com", "script").redirect.is_some());
+
+        // Modifying one engine's resources leaves the others untouched
+        assert_eq!(registry.get_mut("bob").unwrap().use_resources(&[]), vec![]);
+        assert!(registry.get("alice").unwrap().check_network_urls("https://ads.com/a.js", "https://example.com", "script").redirect.is_some());
+        assert!(registry.get("bob").unwrap().check_network_urls("https://tracker.com/a.js", "https://example.com", "script").redirect.is_none());
+
+        assert!(registry.remove("bob").is_some());
+        assert_eq!(registry.tenants().collect::<Vec<_>>(), vec!["alice"]);
+    }
+}
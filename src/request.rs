@@ -1,9 +1,14 @@
 //! Contains structures needed to describe network requests.
 
+use std::borrow::Cow;
+
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+
 use crate::url_parser;
 use crate::utils;
 
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub enum RequestType {
     Beacon,
     Csp,
@@ -16,12 +21,17 @@ pub enum RequestType {
     Object,
     Other,
     Ping,
+    Prefetch,
     Script,
+    SignedExchange,
+    Speculative,
     Stylesheet,
     Subdocument,
     Websocket,
     Xlst,
     Xmlhttprequest,
+    /// A page opened in a new tab or window, only matched by `$popup` filters
+    Popup,
 }
 
 #[derive(Debug, PartialEq)]
@@ -43,37 +53,92 @@ impl From<url::ParseError> for RequestError {
     }
 }
 
-fn cpt_match_type(cpt: &str) -> RequestType {
-    match cpt {
-        "beacon" => RequestType::Ping,
-        "csp_report" => RequestType::Csp,
-        "document" | "main_frame" => RequestType::Document,
-        "font" => RequestType::Font,
-        "image" | "imageset" => RequestType::Image,
-        "media" => RequestType::Media,
-        "object" | "object_subrequest" => RequestType::Object,
-        "ping" => RequestType::Ping,
-        "script" => RequestType::Script,
-        "stylesheet" => RequestType::Stylesheet,
-        "sub_frame" | "subdocument" => RequestType::Subdocument,
-        "websocket" => RequestType::Websocket,
-        "xhr" | "xmlhttprequest" => RequestType::Xmlhttprequest,
-        "other" => RequestType::Other,
-        "speculative" => RequestType::Other,
-        "web_manifest" => RequestType::Other,
-        "xbl" => RequestType::Other,
-        "xml_dtd" => RequestType::Other,
-        "xslt" => RequestType::Other,
-        _ => RequestType::Other
+impl RequestType {
+    /// Maps a resource type string, as reported by browser APIs, to a `RequestType`. Unknown
+    /// values are treated as `Other`.
+    ///
+    /// | `RequestType`    | Chromium (`webRequest`)                 | Firefox (`webRequest`)              | Chromium (DevTools protocol) |
+    /// |------------------|-----------------------------------------|-------------------------------------|------------------------------|
+    /// | `Csp`            | `csp_report`                            | `csp_report`                        | `cspviolationreport`         |
+    /// | `Document`       | `main_frame`                            | `main_frame`                        | `document`                   |
+    /// | `Dtd`            |                                         | `xml_dtd`                           |                              |
+    /// | `Fetch`          |                                         |                                     | `fetch`                      |
+    /// | `Font`           | `font`                                  | `font`                              | `font`                       |
+    /// | `Image`          | `image`                                 | `image`, `imageset`                 | `image`                      |
+    /// | `Media`          | `media`                                 | `media`                             | `media`                      |
+    /// | `Object`         | `object`                                | `object`, `object_subrequest`       |                              |
+    /// | `Other`          | `other`                                 | `other`, `web_manifest`, `xbl`      | `other`, `manifest`          |
+    /// | `Ping`           | `ping`                                  | `ping`, `beacon`                    | `ping`                       |
+    /// | `Prefetch`       |                                         |                                     | `prefetch`                   |
+    /// | `Script`         | `script`                                | `script`                            | `script`                     |
+    /// | `SignedExchange` |                                         |                                     | `signedexchange`             |
+    /// | `Speculative`    |                                         | `speculative`                       |                              |
+    /// | `Stylesheet`     | `stylesheet`                            | `stylesheet`                        | `stylesheet`                 |
+    /// | `Subdocument`    | `sub_frame`                             | `sub_frame`                         |                              |
+    /// | `Websocket`      | `websocket`                             | `websocket`                         | `websocket`                  |
+    /// | `Xlst`           |                                         | `xslt`                              |                              |
+    /// | `Xmlhttprequest` | `xmlhttprequest`                        | `xmlhttprequest`                    | `xhr`                        |
+    ///
+    /// The filter option names `subdocument` and `cspreport` are also accepted. Values are
+    /// expected to be lowercase.
+    pub fn from_resource_type(resource_type: &str) -> Self {
+        match resource_type {
+            "beacon" => RequestType::Ping,
+            "csp_report" | "cspreport" | "cspviolationreport" => RequestType::Csp,
+            "document" | "main_frame" => RequestType::Document,
+            "fetch" => RequestType::Fetch,
+            "font" => RequestType::Font,
+            "image" | "imageset" => RequestType::Image,
+            "media" => RequestType::Media,
+            "object" | "object_subrequest" => RequestType::Object,
+            "ping" => RequestType::Ping,
+            "prefetch" => RequestType::Prefetch,
+            "script" => RequestType::Script,
+            "signedexchange" | "signed_exchange" => RequestType::SignedExchange,
+            "speculative" => RequestType::Speculative,
+            "stylesheet" => RequestType::Stylesheet,
+            "sub_frame" | "subdocument" => RequestType::Subdocument,
+            "websocket" => RequestType::Websocket,
+            "xhr" | "xmlhttprequest" => RequestType::Xmlhttprequest,
+            "xml_dtd" => RequestType::Dtd,
+            "xslt" => RequestType::Xlst,
+            "popup" => RequestType::Popup,
+            "other" | "manifest" | "web_manifest" | "xbl" => RequestType::Other,
+            _ => RequestType::Other,
+        }
     }
 }
 
+/// Components of a request that an embedder has already parsed, for `Request::from_parts`. Unlike
+/// `Request::from_urls`, nothing is parsed again, so the components must be well-formed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestParts<'a> {
+    /// Scheme of the URL, in lowercase and without the trailing `:`, e.g. `https`.
+    pub scheme: &'a str,
+    /// Hostname of the URL, in lowercase and punycode.
+    pub hostname: &'a str,
+    /// Port of the URL, if it was given explicitly.
+    pub port: Option<u16>,
+    /// Path of the URL, starting with `/`.
+    pub path: &'a str,
+    /// Query of the URL, without the leading `?`.
+    pub query: Option<&'a str>,
+    /// Hostname of the page that initiated the request, or an empty string if there is none.
+    pub source_hostname: &'a str,
+    /// Whether the request is third-party. If `None`, it is derived from the registrable domains
+    /// of `hostname` and `source_hostname`, as in `Request::from_urls`.
+    pub third_party: Option<bool>,
+    pub request_type: &'a str,
+}
+
 #[derive(Clone, Debug)]
 pub struct Request {
     pub request_type: RequestType,
 
     pub is_http: bool,
     pub is_https: bool,
+    /// Whether the scheme is `http`, `https`, `ws`, `wss` or `ftp`. Requests with other schemes
+    /// are only matched if allowed by the `UnknownSchemePolicy` of the blocker.
     pub is_supported: bool,
     pub is_first_party: Option<bool>,
     pub is_third_party: Option<bool>,
@@ -83,13 +148,30 @@ pub struct Request {
 
     // mutable fields, set later
     pub bug: Option<u32>,
-    hostname_end: usize
+    /// Hashes of the tags of the client making this request, for `$ctag` filters. Sorted.
+    pub client_tags: Option<Vec<utils::Hash>>,
+    /// Record type of the DNS query this request stands for, in uppercase, for `$dnstype` filters.
+    pub dns_type: Option<String>,
+    hostname_end: usize,
+    // Whether the URL was shortened by `truncated`, in which case its end is not the real end
+    truncated: bool,
 }
 
 impl<'a> Request {
     pub fn get_tokens(&self, mut token_buffer: &mut Vec<utils::Hash>) {
         token_buffer.clear();
         utils::tokenize_pooled(&self.url, &mut token_buffer);
+        utils::tokenize_query_params(self.url_after_hostname(), &mut token_buffer);
+        // Filters are usually written against decoded text, so also add the tokens of a decoded
+        // path and query, e.g. `ads` and `banner` for `/r?u=https%3a%2f%2fads.com%2fbanner`.
+        // Only a single level of encoding is removed, and only if the result is valid UTF-8.
+        let after_hostname = self.url_after_hostname();
+        if after_hostname.contains('%') {
+            // `Cow::Borrowed` means that nothing could be decoded
+            if let Ok(Cow::Owned(decoded)) = percent_decode_str(after_hostname).decode_utf8() {
+                utils::tokenize_pooled(&decoded.to_ascii_lowercase(), &mut token_buffer);
+            }
+        }
         // Add zero token as a fallback to wildcard rule bucket
         token_buffer.push(0);
     }
@@ -98,6 +180,94 @@ impl<'a> Request {
         &self.url[self.hostname_end..]
     }
 
+    /// Returns the port given explicitly in the URL, unless it is the default port of the scheme.
+    ///
+    /// Ports are kept in the URL for matching: `||example.com^` applies to any port of
+    /// `example.com`, while `||example.com:8080^` only applies to port 8080.
+    pub fn port(&self) -> Option<u16> {
+        let after_hostname = self.url_after_hostname();
+        if !after_hostname.starts_with(':') {
+            return None;
+        }
+        let digits = after_hostname[1..].find(|c: char| !c.is_ascii_digit()).unwrap_or(after_hostname.len() - 1);
+        let port = after_hostname[1..1 + digits].parse::<u16>().ok()?;
+
+        let default_port = match self.url.split(':').next() {
+            Some("http") | Some("ws") => 80,
+            Some("https") | Some("wss") => 443,
+            Some("ftp") => 21,
+            _ => 0,
+        };
+        if port == default_port {
+            None
+        } else {
+            Some(port)
+        }
+    }
+
+    /// Returns a copy of this request with the URL shortened to at most `max_length` bytes. The
+    /// scheme and hostname are always kept in full. Right-anchored filters (`|` at the end) never
+    /// match a shortened URL, since its end is not the end of the real URL.
+    pub fn truncated(&self, max_length: usize) -> Request {
+        let mut end = max_length.max(self.hostname_end).min(self.url.len());
+        while !self.url.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut request = self.clone();
+        if end < request.url.len() {
+            request.url.truncate(end);
+            request.truncated = true;
+        }
+        request
+    }
+
+    /// Whether the URL was shortened with `truncated`.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Returns a copy of this request for a different URL without a hostname, such as the `data:`
+    /// URL of a redirect resource. The type and initiator of the request are kept.
+    pub(crate) fn with_hostless_url(&self, url: &str) -> Request {
+        let mut request = self.clone();
+        request.url = url.to_ascii_lowercase();
+        request.hostname = String::new();
+        request.hostname_end = 0;
+        request.is_http = false;
+        request.is_https = false;
+        request.is_supported = false;
+        request.is_first_party = None;
+        request.is_third_party = None;
+        request.truncated = false;
+        request
+    }
+
+    /// Sets the tags of the client making this request, like `device_phone` or `user_child`, which
+    /// are matched against the `$ctag` option of filters. Filters listing tags, like
+    /// `$ctag=device_phone`, only match clients with one of them, so they never match requests
+    /// without client tags. Negated tags, like `$ctag=~user_child`, exclude clients with that tag
+    /// and still match every other client, including those without tags.
+    pub fn set_client_tags(&mut self, tags: &[&str]) {
+        let mut hashes: Vec<utils::Hash> = tags.iter().map(|tag| utils::fast_hash(tag)).collect();
+        hashes.sort_unstable();
+        hashes.dedup();
+        self.client_tags = Some(hashes);
+    }
+
+    /// Marks this request as a DNS query for records of the given type, like `A` or `AAAA`, which is
+    /// matched against the `$dnstype` option of filters. Filters with `$dnstype` never match other
+    /// requests.
+    pub fn set_dns_type(&mut self, record_type: &str) {
+        self.dns_type = Some(record_type.to_ascii_uppercase());
+    }
+
+    /// Whether the URL consists of nothing but a scheme and hostname, like `https://example.com/`.
+    /// Such requests are common for DNS-level and connection-level blocking, and can skip
+    /// evaluating most filters that target specific paths.
+    pub fn is_hostname_only(&self) -> bool {
+        matches!(self.url_after_hostname(), "" | "/")
+    }
+
     pub fn new(
         raw_type: &str,
         url: &str,
@@ -121,6 +291,7 @@ impl<'a> Request {
             schema,
             hostname,
             source_hostname,
+            source_domain,
             third_party,
             hostname_end
         )
@@ -133,6 +304,7 @@ impl<'a> Request {
         schema: &str,
         hostname: &str,
         source_hostname: &str,
+        source_domain: &str,
         third_party: Option<bool>,
         hostname_end: usize
     ) -> Request {
@@ -148,29 +320,32 @@ impl<'a> Request {
             is_https = true;
             is_http = false;
             is_supported = true;
-            request_type = cpt_match_type(raw_type);
+            request_type = RequestType::from_resource_type(raw_type);
         } else {
             is_http = schema == "http";
             is_https = !is_http && schema == "https";
 
             let is_websocket = !is_http && !is_https && (schema == "ws" || schema == "wss");
-            is_supported = is_http || is_https || is_websocket;
+            is_supported = is_http || is_https || is_websocket || schema == "ftp";
             if is_websocket {
                 request_type = RequestType::Websocket;
             } else {
-                request_type = cpt_match_type(raw_type);
+                request_type = RequestType::from_resource_type(raw_type);
             }
         }
 
         let source_hostname_hashes = if !source_hostname.is_empty() {
             let mut hashes = Vec::with_capacity(4);
             hashes.push(utils::fast_hash(&source_hostname));
-            for (i, c) in
-                source_hostname.char_indices()
-            {
-                if c == '.' && i + 1 < source_hostname.len() {
-                    hashes.push(utils::fast_hash(&source_hostname[i + 1..]));
+            if !utils::is_ip_literal(source_hostname) {
+                for (i, c) in
+                    source_hostname.char_indices()
+                {
+                    if c == '.' && i + 1 < source_hostname.len() {
+                        hashes.push(utils::fast_hash(&source_hostname[i + 1..]));
+                    }
                 }
+                push_entity_hashes(&mut hashes, source_hostname, source_domain);
             }
             Some(hashes)
         } else {
@@ -188,7 +363,10 @@ impl<'a> Request {
             is_https,
             is_supported,
             bug: None,
-            hostname_end
+            client_tags: None,
+            dns_type: None,
+            hostname_end,
+            truncated: false,
         }
     }
 
@@ -213,6 +391,7 @@ impl<'a> Request {
                     parsed_url.schema(),
                     parsed_url.hostname(),
                     parsed_source.hostname(),
+                    source_domain,
                     third_party,
                     parsed_url.hostname_pos.1
                 ))
@@ -223,6 +402,7 @@ impl<'a> Request {
                     parsed_url.schema(),
                     parsed_url.hostname(),
                     "",
+                    "",
                     None,
                     parsed_url.hostname_pos.1
                 ))
@@ -265,21 +445,235 @@ impl<'a> Request {
             &schema,
             &hostname,
             &source_hostname,
+            &source_domain,
             third_party,
             splitter + 2 + hostname.len()
         )
     }
 
+    /// Creates a request from components that were already parsed by the caller, which avoids the
+    /// cost of parsing its URLs. See `RequestParts`.
+    pub fn from_parts(parts: &RequestParts) -> Request {
+        let mut url = format!("{}://{}", parts.scheme, parts.hostname);
+        if let Some(port) = parts.port {
+            url.push(':');
+            url.push_str(&port.to_string());
+        }
+        let hostname_end = parts.scheme.len() + 3 + parts.hostname.len();
+        url.push_str(parts.path);
+        if let Some(query) = parts.query {
+            url.push('?');
+            url.push_str(query);
+        }
+        url.make_ascii_lowercase();
+
+        let third_party = parts.third_party.or_else(|| {
+            if parts.source_hostname.is_empty() {
+                return None;
+            }
+            let (source_domain_start, source_domain_end) = url_parser::get_host_domain(parts.source_hostname);
+            let (domain_start, domain_end) = url_parser::get_host_domain(parts.hostname);
+            Some(parts.source_hostname[source_domain_start..source_domain_end] != parts.hostname[domain_start..domain_end])
+        });
+
+        Request::from_detailed_parameters(
+            parts.request_type,
+            &url,
+            parts.scheme,
+            parts.hostname,
+            parts.source_hostname,
+            "",
+            third_party,
+            hostname_end
+        )
+    }
+
+    /// Creates a request for nothing but a hostname, as for DNS-level blocking, equivalent to
+    /// `https://{hostname}/` with the `other` type. Plain ASCII hostnames don't go through URL
+    /// parsing at all; others, like IDNs or IP literals, fall back to `from_urls`. Either way the
+    /// hostname is lowercased, since DNS names may arrive in any case.
+    pub fn from_hostname(hostname: &str) -> Result<Request, RequestError> {
+        let is_plain = !hostname.is_empty()
+            && !hostname.starts_with('.')
+            && !hostname.contains("..")
+            && hostname.bytes().any(|b| b.is_ascii_alphabetic())
+            && hostname.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_');
+        if !is_plain {
+            return Self::from_urls(&format!("https://{}/", hostname), "", "other");
+        }
+
+        let hostname = hostname.to_ascii_lowercase();
+        Ok(Request::from_detailed_parameters(
+            "other",
+            &format!("https://{}/", hostname),
+            "https",
+            &hostname,
+            "",
+            "",
+            None,
+            "https://".len() + hostname.len()
+        ))
+    }
+
     pub fn from_url(url: &str) -> Result<Request, RequestError> {
         // Used in testing - assume empty source_url and default request type
         Self::from_urls(url, "", "")
     }
 }
 
+/// Adds the hashes of the entities `source_hostname` belongs to, so that they can be matched by
+/// `$domain=example.*` options. Entities are the hostname and its parent domains up to, but
+/// excluding, the public suffix of its registrable domain, followed by `.*`; e.g. `example.*` and
+/// `www.example.*` for `www.example.co.uk`. `source_domain` is the registrable domain of the
+/// hostname, and is looked up in the public suffix list if empty.
+fn push_entity_hashes(hashes: &mut Vec<utils::Hash>, source_hostname: &str, source_domain: &str) {
+    let source_domain = if source_domain.is_empty() {
+        let (start, end) = url_parser::get_host_domain(source_hostname);
+        &source_hostname[start..end]
+    } else {
+        source_domain
+    };
+    let public_suffix_len = match source_domain.find('.') {
+        Some(dot) if source_hostname.ends_with(source_domain) => source_domain.len() - dot,
+        _ => return,
+    };
+    let without_suffix = &source_hostname[..source_hostname.len() - public_suffix_len];
+
+    let mut entity = String::with_capacity(without_suffix.len() + 2);
+    let starts = std::iter::once(0).chain(without_suffix.match_indices('.').map(|(i, _)| i + 1));
+    for start in starts {
+        entity.clear();
+        entity.push_str(&without_suffix[start..]);
+        entity.push_str(".*");
+        hashes.push(utils::fast_hash(&entity));
+    }
+}
+
+#[cfg(feature = "url-interop")]
+impl Request {
+    /// Construct a new `Request` from already parsed `url::Url`s, skipping the crate's own URL
+    /// parsing step.
+    pub fn from_parsed_urls(url: &url::Url, source_url: Option<&url::Url>, request_type: &str) -> Request {
+        let hostname = url.host_str().unwrap_or_default();
+        let source_hostname = source_url.and_then(|u| u.host_str()).unwrap_or_default();
+
+        let third_party = if source_hostname.is_empty() {
+            None
+        } else {
+            let (domain_start, domain_end) = url_parser::get_host_domain(&hostname);
+            let (source_domain_start, source_domain_end) = url_parser::get_host_domain(&source_hostname);
+            Some(source_hostname[source_domain_start..source_domain_end] != hostname[domain_start..domain_end])
+        };
+
+        Request::from_detailed_parameters(
+            request_type,
+            &url.as_str().to_ascii_lowercase(),
+            url.scheme(),
+            &hostname.to_ascii_lowercase(),
+            &source_hostname.to_ascii_lowercase(),
+            "",
+            third_party,
+            url[..url::Position::AfterHost].len()
+        )
+    }
+}
+
+#[cfg(feature = "url-interop")]
+impl From<&url::Url> for Request {
+    /// Assumes no source URL and the default request type, like `Request::from_url`.
+    fn from(url: &url::Url) -> Request {
+        Request::from_parsed_urls(url, None, "")
+    }
+}
+
+#[cfg(feature = "http-interop")]
+impl Request {
+    /// Construct a new `Request` from the parts of an `http::Request`.
+    ///
+    /// Requests in origin-form (e.g. as received by a server) are resolved against the `Host`
+    /// header, assuming `https`. The source URL is taken from the `Origin` header, falling back
+    /// to `Referer`.
+    pub fn from_http_parts(parts: &http::request::Parts, request_type: &str) -> Result<Request, RequestError> {
+        let header = |name: http::header::HeaderName| parts.headers.get(name).and_then(|value| value.to_str().ok());
+
+        let url = if parts.uri.scheme().is_some() && parts.uri.authority().is_some() {
+            parts.uri.to_string()
+        } else {
+            let host = parts.uri.authority().map(|a| a.as_str())
+                .or_else(|| header(http::header::HOST))
+                .ok_or(RequestError::HostnameParseError)?;
+            let path = parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+            format!("https://{}{}", host, path)
+        };
+
+        let source_url = header(http::header::ORIGIN)
+            .or_else(|| header(http::header::REFERER))
+            .unwrap_or("");
+
+        Request::from_urls(&url, source_url, request_type)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn from_parts_matches_from_urls() {
+        let parts = RequestParts {
+            scheme: "https",
+            hostname: "cdn.ads.com",
+            port: Some(8080),
+            path: "/banner.js",
+            query: Some("id=1"),
+            source_hostname: "www.example.com",
+            third_party: None,
+            request_type: "script",
+        };
+        let request = Request::from_parts(&parts);
+        let parsed = Request::from_urls("https://cdn.ads.com:8080/banner.js?id=1", "https://www.example.com/", "script").unwrap();
+        assert_eq!(request.url, parsed.url);
+        assert_eq!(request.hostname, parsed.hostname);
+        assert_eq!(request.url_after_hostname(), parsed.url_after_hostname());
+        assert_eq!(request.port(), Some(8080));
+        assert_eq!(request.is_third_party, Some(true));
+        assert_eq!(request.source_hostname_hashes, parsed.source_hostname_hashes);
+        assert_eq!(request.request_type, RequestType::Script);
+
+        let first_party = Request::from_parts(&RequestParts { source_hostname: "static.ads.com", ..parts });
+        assert_eq!(first_party.is_third_party, Some(false));
+        let unknown_party = Request::from_parts(&RequestParts { source_hostname: "", ..parts });
+        assert_eq!(unknown_party.is_third_party, None);
+        let given_party = Request::from_parts(&RequestParts { third_party: Some(false), ..parts });
+        assert_eq!(given_party.is_first_party, Some(true));
+    }
+
+    #[test]
+    fn from_hostname_matches_from_urls() {
+        for hostname in ["ads.example.com", "Tracker.Example.COM", "a-b_c.example.org", "localhost", "127.0.0.1", "[::1]", "bücher.de"].iter() {
+            let request = Request::from_hostname(hostname).unwrap();
+            let parsed = Request::from_urls(&format!("https://{}/", hostname), "", "other").unwrap();
+            assert_eq!(request.url, parsed.url, "{}", hostname);
+            assert_eq!(request.hostname, parsed.hostname, "{}", hostname);
+            assert_eq!(request.url_after_hostname(), parsed.url_after_hostname(), "{}", hostname);
+            assert_eq!(request.request_type, RequestType::Other);
+            assert!(request.is_https);
+            assert!(request.is_hostname_only());
+        }
+        assert!(Request::from_hostname("").is_err());
+    }
+
+    #[test]
+    fn from_hostname_lowercases_the_url() {
+        // DNS names are case-insensitive and resolvers may randomize their case
+        let request = Request::from_hostname("Tracker.Example.COM").unwrap();
+        let parsed = Request::from_urls("https://tracker.example.com/", "", "other").unwrap();
+        assert_eq!(request.url, "https://tracker.example.com/");
+        assert_eq!(request.url, parsed.url);
+        assert_eq!(request.hostname, parsed.hostname);
+        assert_eq!(request.url_after_hostname(), parsed.url_after_hostname());
+    }
+
     #[test]
     fn new_works() {
         let simple_example = Request::new(
@@ -298,7 +692,7 @@ mod tests {
         assert_eq!(simple_example.request_type, RequestType::Document);
         assert_eq!(
             simple_example.source_hostname_hashes,
-            Some(vec![utils::fast_hash("example.com"), utils::fast_hash("com")]),
+            Some(vec![utils::fast_hash("example.com"), utils::fast_hash("com"), utils::fast_hash("example.*")]),
         );
 
         let unsupported_example = Request::new(
@@ -378,6 +772,33 @@ mod tests {
         tokens
     }
 
+    #[test]
+    fn entity_hashes_use_public_suffixes() {
+        let request = Request::from_urls("https://ads.com/ad.js", "https://www.example.co.uk/", "script").unwrap();
+        let hashes = request.source_hostname_hashes.unwrap();
+        assert!(hashes.contains(&utils::fast_hash("example.*")));
+        assert!(hashes.contains(&utils::fast_hash("www.example.*")));
+        assert!(!hashes.contains(&utils::fast_hash("example.co.*")));
+
+        let request = Request::from_parts(&RequestParts {
+            scheme: "https",
+            hostname: "ads.com",
+            port: None,
+            path: "/ad.js",
+            query: None,
+            source_hostname: "a.b.example.com",
+            third_party: None,
+            request_type: "script",
+        });
+        let hashes = request.source_hostname_hashes.unwrap();
+        assert!(hashes.contains(&utils::fast_hash("a.b.example.*")));
+        assert!(hashes.contains(&utils::fast_hash("b.example.*")));
+        assert!(hashes.contains(&utils::fast_hash("example.*")));
+
+        let request = Request::from_urls("https://ads.com/ad.js", "http://127.0.0.1/", "script").unwrap();
+        assert_eq!(request.source_hostname_hashes, Some(vec![utils::fast_hash("127.0.0.1")]));
+    }
+
     #[test]
     fn tokens_works() {
         let simple_example = Request::new(
@@ -395,6 +816,8 @@ mod tests {
                 "subdomain.example.com",
                 "example.com",
                 "com",
+                "subdomain.example.*",
+                "example.*",
             ], &[])
             .as_slice()
         );
@@ -413,6 +836,23 @@ mod tests {
         )
     }
 
+    #[test]
+    fn tokens_include_decoded_path() {
+        let request = Request::from_urls("https://example.com/r?u=https%3A%2F%2Fads.com%2FBanner", "", "document").unwrap();
+        let mut tokens = Vec::new();
+        request.get_tokens(&mut tokens);
+        assert!(tokens.contains(&utils::fast_hash("https%3a%2f%2fads")));
+        assert!(tokens.contains(&utils::fast_hash("ads")));
+        assert!(tokens.contains(&utils::fast_hash("banner")));
+        assert_eq!(tokens.last(), Some(&0));
+
+        // invalid UTF-8 is not decoded
+        let request = Request::from_urls("https://example.com/%ff%fe/ads", "", "document").unwrap();
+        let mut tokens = Vec::new();
+        request.get_tokens(&mut tokens);
+        assert_eq!(tokens, tokenize(&["https", "example", "com", "%ff%fe", "ads"], &[0]));
+    }
+
     #[test]
     fn parses_urls() {
         let parsed = Request::from_urls(
@@ -433,7 +873,7 @@ mod tests {
         // assert_eq!(parsed.source_domain, "example.com");
         assert_eq!(
             parsed.source_hostname_hashes,
-            Some(vec![utils::fast_hash("example.com"), utils::fast_hash("com")]),
+            Some(vec![utils::fast_hash("example.com"), utils::fast_hash("com"), utils::fast_hash("example.*")]),
         );
         // assert_eq!(parsed.source_hostname, "example.com");
 
@@ -443,6 +883,11 @@ mod tests {
             "document",
         );
         assert_eq!(bad_url.err(), Some(RequestError::HostnameParseError));
+
+        let mixed_case = Request::from_urls("https://Sub.Example.COM/Ad", "https://EXAMPLE.com/", "script").unwrap();
+        assert_eq!(mixed_case.url, "https://sub.example.com/ad");
+        assert_eq!(mixed_case.hostname, "sub.example.com");
+        assert_eq!(mixed_case.source_hostname_hashes, parsed.source_hostname_hashes);
     }
 
     #[test]
@@ -486,4 +931,91 @@ mod tests {
             assert!(parsed.is_err());
         }
     }
+
+    #[test]
+    fn truncates_urls() {
+        let request = Request::from_url("https://example.com/path/to/resource").unwrap();
+        assert_eq!(request.truncated(24).url, "https://example.com/path");
+        assert_eq!(request.truncated(24).url_after_hostname(), "/path");
+        // the hostname is always kept
+        assert_eq!(request.truncated(5).url, "https://example.com");
+        assert_eq!(request.truncated(1000).url, request.url);
+        assert!(request.truncated(24).is_truncated());
+        assert!(!request.truncated(1000).is_truncated());
+    }
+
+    #[test]
+    fn explicit_ports() {
+        let port = |url: &str| Request::from_url(url).unwrap().port();
+        assert_eq!(port("https://example.com:8443/path"), Some(8443));
+        assert_eq!(port("http://example.com:8080"), Some(8080));
+        assert_eq!(port("http://[::1]:8080/"), Some(8080));
+        assert_eq!(port("https://example.com/path:8080"), None);
+        assert_eq!(port("https://example.com:443/"), None);
+        assert_eq!(port("https://example.com/"), None);
+    }
+
+    #[test]
+    #[cfg(feature = "url-interop")]
+    fn from_parsed_urls_matches_from_urls() {
+        let url = url::Url::parse("https://Sub.Example.com/Ad.js?x=1").unwrap();
+        let source_url = url::Url::parse("https://other.org/page").unwrap();
+
+        let parsed = Request::from_parsed_urls(&url, Some(&source_url), "script");
+        let expected = Request::from_urls("https://Sub.Example.com/Ad.js?x=1", "https://other.org/page", "script").unwrap();
+
+        assert_eq!(parsed.url, expected.url);
+        assert_eq!(parsed.hostname, expected.hostname);
+        assert_eq!(parsed.url_after_hostname(), expected.url_after_hostname());
+        assert_eq!(parsed.source_hostname_hashes, expected.source_hostname_hashes);
+        assert_eq!(parsed.is_third_party, Some(true));
+        assert_eq!(parsed.request_type, RequestType::Script);
+
+        let parsed = Request::from(&url);
+        assert_eq!(parsed.is_third_party, None);
+        assert_eq!(parsed.source_hostname_hashes, None);
+    }
+
+    #[test]
+    #[cfg(feature = "http-interop")]
+    fn from_http_parts_works() {
+        let (parts, _) = http::Request::get("/ads/banner.png")
+            .header(http::header::HOST, "example.com")
+            .header(http::header::REFERER, "https://news.com/article")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        let parsed = Request::from_http_parts(&parts, "image").unwrap();
+        assert_eq!(parsed.url, "https://example.com/ads/banner.png");
+        assert_eq!(parsed.hostname, "example.com");
+        assert_eq!(parsed.is_third_party, Some(true));
+
+        let (parts, _) = http::Request::get("http://example.com/x").body(()).unwrap().into_parts();
+        let parsed = Request::from_http_parts(&parts, "").unwrap();
+        assert_eq!(parsed.url, "http://example.com/x");
+        assert_eq!(parsed.is_third_party, None);
+
+        let (parts, _) = http::Request::get("/x").body(()).unwrap().into_parts();
+        assert_eq!(Request::from_http_parts(&parts, "").err(), Some(RequestError::HostnameParseError));
+    }
+
+    #[test]
+    fn maps_resource_types() {
+        assert_eq!(RequestType::from_resource_type("main_frame"), RequestType::Document);
+        assert_eq!(RequestType::from_resource_type("sub_frame"), RequestType::Subdocument);
+        assert_eq!(RequestType::from_resource_type("xhr"), RequestType::Xmlhttprequest);
+        assert_eq!(RequestType::from_resource_type("beacon"), RequestType::Ping);
+        assert_eq!(RequestType::from_resource_type("imageset"), RequestType::Image);
+        assert_eq!(RequestType::from_resource_type("csp_report"), RequestType::Csp);
+        assert_eq!(RequestType::from_resource_type("cspreport"), RequestType::Csp);
+        assert_eq!(RequestType::from_resource_type("speculative"), RequestType::Speculative);
+        assert_eq!(RequestType::from_resource_type("prefetch"), RequestType::Prefetch);
+        assert_eq!(RequestType::from_resource_type("signedexchange"), RequestType::SignedExchange);
+        assert_eq!(RequestType::from_resource_type("web_manifest"), RequestType::Other);
+        assert_eq!(RequestType::from_resource_type("unknown"), RequestType::Other);
+
+        let request = Request::from_urls("https://example.com/next", "https://example.com", "prefetch").unwrap();
+        assert_eq!(request.request_type, RequestType::Prefetch);
+    }
 }
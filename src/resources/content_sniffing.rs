@@ -0,0 +1,183 @@
+//! Checks that the contents of redirect resources match their declared MIME type, so that a
+//! corrupted resource pack cannot replace requests with broken surrogates.
+
+use crate::resources::MimeType;
+
+/// Keywords after which a `/` starts a regular expression literal rather than a division.
+const REGEX_PRECEDING_KEYWORDS: &[&[u8]] = &[
+    b"return", b"typeof", b"case", b"do", b"else", b"in", b"of", b"new", b"delete", b"void",
+    b"throw", b"instanceof", b"yield", b"await",
+];
+
+impl MimeType {
+    /// Whether `content` plausibly holds data of this type. Binary formats are recognized by their
+    /// magic bytes. Text must be valid UTF-8, and scripts must additionally have balanced brackets
+    /// and terminated literals and comments, which catches truncated files. `Unknown` accepts
+    /// anything.
+    pub fn matches_content(&self, content: &[u8]) -> bool {
+        match self {
+            MimeType::ImageGif => content.starts_with(b"GIF87a") || content.starts_with(b"GIF89a"),
+            MimeType::ImagePng => content.starts_with(b"\x89PNG\r\n\x1a\n"),
+            // Either an ID3 tag or the sync word of the first frame
+            MimeType::AudioMp3 => content.starts_with(b"ID3") || matches!(content, [0xff, second, ..] if second & 0xe0 == 0xe0),
+            MimeType::VideoMp4 => content.get(4..8) == Some(&b"ftyp"[..]),
+            MimeType::ApplicationJavascript => std::str::from_utf8(content).map_or(false, is_balanced_javascript),
+            MimeType::TextHtml | MimeType::TextPlain => std::str::from_utf8(content).is_ok(),
+            MimeType::Unknown => true,
+        }
+    }
+}
+
+fn is_identifier_byte(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'$' || c >= 0x80
+}
+
+/// A sanity check rather than a parser: skips over comments and string, template and regex
+/// literals, and checks that all brackets are closed in order.
+fn is_balanced_javascript(source: &str) -> bool {
+    let bytes = source.as_bytes();
+    // Expected closing brackets, innermost last. `` ` `` stands for an open template literal, and
+    // `$` for a `${` substitution within one.
+    let mut closers: Vec<u8> = vec![];
+    let mut in_template = false;
+    // Last significant byte and identifier, to tell regex literals apart from divisions
+    let mut previous: Option<u8> = None;
+    let mut word: &[u8] = &[];
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if in_template {
+            if c == b'\\' {
+                i += 1;
+            } else if c == b'`' {
+                closers.pop();
+                in_template = false;
+                previous = Some(c);
+            } else if c == b'$' && bytes.get(i + 1) == Some(&b'{') {
+                closers.push(b'$');
+                in_template = false;
+                previous = Some(b'{');
+                i += 1;
+            }
+            i += 1;
+            continue;
+        }
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        if is_identifier_byte(c) {
+            let start = i;
+            while i < bytes.len() && is_identifier_byte(bytes[i]) {
+                i += 1;
+            }
+            word = &bytes[start..i];
+            previous = Some(b'a');
+            continue;
+        }
+
+        let regex_allowed = previous.map_or(true, |p| b"(,=:[!&|?{};+-*%<>~^".contains(&p))
+            || REGEX_PRECEDING_KEYWORDS.iter().any(|keyword| *keyword == word);
+        match c {
+            b'"' | b'\'' => match literal_end(bytes, i + 1, c) {
+                Some(end) => i = end,
+                None => return false,
+            },
+            b'`' => {
+                closers.push(c);
+                in_template = true;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                i = bytes[i..].iter().position(|&b| b == b'\n').map_or(bytes.len(), |end| i + end);
+                continue;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => match source[i + 2..].find("*/") {
+                Some(end) => {
+                    i += 2 + end + 2;
+                    continue;
+                }
+                None => return false,
+            },
+            b'/' if regex_allowed => match literal_end(bytes, i + 1, c) {
+                Some(end) => i = end,
+                None => return false,
+            },
+            b'(' => closers.push(b')'),
+            b'[' => closers.push(b']'),
+            b'{' => closers.push(b'}'),
+            b')' | b']' | b'}' => match closers.pop() {
+                Some(b'$') if c == b'}' => in_template = true,
+                Some(closer) if closer == c => (),
+                _ => return false,
+            },
+            _ => (),
+        }
+        word = &[];
+        previous = Some(c);
+        i += 1;
+    }
+    closers.is_empty() && !in_template
+}
+
+/// Returns the index of the unescaped `quote` that ends the string or regex literal starting at
+/// `start`, or `None` if the line ends first.
+fn literal_end(bytes: &[u8], start: usize, quote: u8) -> Option<usize> {
+    let mut in_class = false;
+    let mut i = start;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\n' => return None,
+            b'\\' => i += 1,
+            b'[' if quote == b'/' => in_class = true,
+            b']' => in_class = false,
+            c if c == quote && !in_class => return Some(i),
+            _ => (),
+        }
+        i += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_binary_formats() {
+        let resources = std::path::Path::new("data/test/fake-uBO-files/web_accessible_resources");
+        for (file, mime) in [
+            ("1x1.gif", MimeType::ImageGif),
+            ("2x2.png", MimeType::ImagePng),
+            ("noop-0.1s.mp3", MimeType::AudioMp3),
+            ("noop-1s.mp4", MimeType::VideoMp4),
+        ].iter() {
+            let content = std::fs::read(resources.join(file)).unwrap();
+            assert!(mime.matches_content(&content), "{}", file);
+            assert!(!MimeType::ImagePng.matches_content(&content[1..]), "{}", file);
+        }
+        assert!(!MimeType::ImageGif.matches_content(b"GIF"));
+        assert!(!MimeType::VideoMp4.matches_content(b""));
+        assert!(MimeType::Unknown.matches_content(b"\x00\x01"));
+        assert!(!MimeType::TextPlain.matches_content(b"\xff\xfe"));
+    }
+
+    #[test]
+    fn sniffs_javascript() {
+        let js = |source: &str| MimeType::ApplicationJavascript.matches_content(source.as_bytes());
+
+        assert!(js(""));
+        assert!(js("(function() {})();"));
+        assert!(js("const re = /[)}]/g; const x = a / b / c;"));
+        assert!(js("if (x) { return /}/.test(s); }"));
+        assert!(js("const s = `${ {a: 1}.a }) ${'`'}`; // ) unbalanced in a comment"));
+        assert!(js("/* { */ const s = '\\' ) ';"));
+        assert!(js(&std::fs::read_to_string("data/test/fake-uBO-files/web_accessible_resources/googletagservices_gpt.js").unwrap()));
+
+        assert!(!js("(function() {"));
+        assert!(!js("(function() {)}"));
+        assert!(!js("const s = 'unterminated;\n"));
+        assert!(!js("const s = `${a`;"));
+        assert!(!js("/* unterminated"));
+        assert!(!MimeType::ApplicationJavascript.matches_content(b"\x89PNG\r\n\x1a\n"));
+    }
+}
@@ -6,8 +6,9 @@
 #[cfg(feature = "resource-assembler")]
 pub mod resource_assembler;
 
+mod content_sniffing;
 mod scriptlet_resource_storage;
-pub(crate) use scriptlet_resource_storage::ScriptletResourceStorage;
+pub(crate) use scriptlet_resource_storage::{ScriptletResourceStorage, parse_scriptlet_args, TRUSTED_SCRIPTLET_PREFIX};
 
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
@@ -21,12 +22,19 @@ use std::collections::HashMap;
 /// - `kind`: How to interpret the resource data within `content`
 ///
 /// - `content`: The resource data, encoded using standard base64 configuration
+///
+/// - `privileged`: Whether the resource may only be injected as a scriptlet by rules from trusted
+/// sources, such as the embedder's own rules. Scriptlets named with the `trusted-` prefix are
+/// always privileged. Has no effect on `$redirect` resources, since network filters don't track
+/// where they came from.
 #[derive(Serialize, Deserialize)]
 pub struct Resource {
     pub name: String,
     pub aliases: Vec<String>,
     pub kind: ResourceType,
     pub content: String,
+    #[serde(default)]
+    pub privileged: bool,
 }
 
 /// Different ways that the data within the `content` field of a `Resource` can be interpreted.
@@ -60,6 +68,30 @@ pub enum MimeType {
 pub enum AddResourceError {
     InvalidBase64Content,
     InvalidUtf8Content,
+    /// The content does not look like data of the declared MIME type; see
+    /// `MimeType::matches_content`.
+    ContentMismatch,
+}
+
+/// A resource that was left out when using a whole set of resources, e.g. with
+/// `Engine::use_resources`, and the reason it could not be added.
+#[derive(Debug, PartialEq)]
+pub struct RejectedResource {
+    pub name: String,
+    pub error: AddResourceError,
+}
+
+/// Adds each of `resources` with `add`, returning the ones that could not be added.
+pub(crate) fn add_all<F>(resources: &[Resource], mut add: F) -> Vec<RejectedResource>
+where
+    F: FnMut(&Resource) -> Result<(), AddResourceError>,
+{
+    resources.iter()
+        .filter_map(|resource| add(resource).err().map(|error| RejectedResource {
+            name: resource.name.to_owned(),
+            error,
+        }))
+        .collect()
 }
 
 impl From<base64::DecodeError> for AddResourceError {
@@ -80,7 +112,7 @@ pub struct RedirectResource {
     pub data: String
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default, Clone)]
 pub struct RedirectResourceStorage {
     pub resources: HashMap<String, RedirectResource>,
 }
@@ -111,36 +143,20 @@ impl MimeType {
 }
 
 impl RedirectResourceStorage {
-    pub fn from_resources(resources: &[Resource]) -> Self {
-        let mut redirectable_resources: HashMap<String, RedirectResource> = HashMap::new();
-
-        resources.iter().filter_map(|descriptor| {
-            if let ResourceType::Mime(ref content_type) = descriptor.kind {
-                let resource = RedirectResource {
-                    content_type: content_type.clone().into(),
-                    data: descriptor.content.to_owned(),
-                };
-                Some((descriptor.name.to_owned(), descriptor.aliases.to_owned(), resource))
-            } else {
-                None
-            }
-        }).for_each(|(name, res_aliases, resource)| {
-            res_aliases.iter().for_each(|alias| {
-                redirectable_resources.insert(alias.to_owned(), resource.clone());
-            });
-            redirectable_resources.insert(name, resource);
-        });
-
-        Self {
-            resources: redirectable_resources,
-        }
+    /// Builds storage for all of `resources` that can be added, also returning the ones that were
+    /// rejected, as `add_resource` would.
+    pub fn from_resources(resources: &[Resource]) -> (Self, Vec<RejectedResource>) {
+        let mut storage = Self::default();
+        let rejected = add_all(resources, |resource| storage.add_resource(resource));
+        (storage, rejected)
     }
 
     pub fn get_resource(&self, name: &str) -> Option<&RedirectResource> {
         self.resources.get(name)
     }
 
-    /// Adds a resource. Only has an effect for mimetyped scriptlets.
+    /// Adds a resource. Only has an effect for mimetyped scriptlets. Resources whose content does
+    /// not match their MIME type are rejected.
     pub fn add_resource(&mut self, resource: &Resource) -> Result<(), AddResourceError> {
         if let ResourceType::Mime(ref content_type) = resource.kind {
             // Ensure the resource contents are valid base64
@@ -148,10 +164,13 @@ impl RedirectResourceStorage {
             match content_type {
                 // Ensure any text contents are also valid utf8
                 MimeType::ApplicationJavascript | MimeType::TextPlain | MimeType::TextHtml => {
-                    let _ = String::from_utf8(decoded)?;
+                    std::str::from_utf8(&decoded).map_err(|_| AddResourceError::InvalidUtf8Content)?;
                 }
                 _ => (),
             }
+            if !content_type.matches_content(&decoded) {
+                return Err(AddResourceError::ContentMismatch);
+            }
 
             let name = resource.name.to_owned();
             let redirect_resource = RedirectResource {
@@ -209,6 +228,7 @@ mod tests {
             aliases: vec![],
             kind: ResourceType::Mime(MimeType::ApplicationJavascript),
             content: base64::encode("resource data"),
+            privileged: false,
         }).unwrap();
 
         assert_eq!(storage.get_resource("name.js"), Some(&RedirectResource {
@@ -225,6 +245,7 @@ mod tests {
             aliases: vec!["alias.js".to_owned()],
             kind: ResourceType::Mime(MimeType::ApplicationJavascript),
             content: base64::encode("resource data"),
+            privileged: false,
         }).unwrap();
 
         assert_eq!(storage.get_resource("alias.js"), Some(&RedirectResource {
@@ -232,5 +253,27 @@ mod tests {
             data: base64::encode("resource data"),
         }));
     }
-}
 
+    #[test]
+    fn rejects_mismatched_content() {
+        let mut storage = RedirectResourceStorage::default();
+        let result = storage.add_resource(&Resource {
+            name: "1x1.gif".to_owned(),
+            aliases: vec![],
+            kind: ResourceType::Mime(MimeType::ImageGif),
+            content: base64::encode("<html>"),
+            privileged: false,
+        });
+        assert_eq!(result, Err(AddResourceError::ContentMismatch));
+        assert_eq!(storage.get_resource("1x1.gif"), None);
+
+        let result = storage.add_resource(&Resource {
+            name: "noop.js".to_owned(),
+            aliases: vec![],
+            kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+            content: base64::encode("(function() {"),
+            privileged: false,
+        });
+        assert_eq!(result, Err(AddResourceError::ContentMismatch));
+    }
+}
@@ -145,6 +145,7 @@ fn read_template_resources(scriptlets_data: &str) -> Vec<Resource> {
             aliases: details.get("alias").iter().map(|alias| alias.to_string()).collect(),
             kind,
             content: base64::encode(&script),
+            privileged: false,
         });
 
         name = None;
@@ -176,6 +177,7 @@ fn build_resource_from_file_contents(resource_contents: &[u8], resource_info: &R
         aliases,
         kind: ResourceType::Mime(mimetype),
         content,
+        privileged: false,
     }
 }
 
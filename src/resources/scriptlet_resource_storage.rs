@@ -29,11 +29,22 @@ fn template_argument_regex(i: usize) -> Regex {
 pub enum ScriptletResourceError {
     NoMatchingScriptlet,
     MissingScriptletName,
+    /// The scriptlet is privileged, and the rule injecting it doesn't come from a trusted source.
+    UntrustedScriptlet,
 }
 
+/// Prefix of scriptlet names that can only be injected by rules from trusted sources, following
+/// uBlock Origin's convention. These scriptlets can e.g. set arbitrary cookies or replace page
+/// content, so lists that are not under the embedder's control must not be able to use them.
+pub const TRUSTED_SCRIPTLET_PREFIX: &str = "trusted-";
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct ScriptletResource {
     scriptlet: String,
+    /// Set for privileged scriptlets, based on `Resource::privileged` and the resource's name
+    /// rather than any of its aliases.
+    #[serde(default)]
+    requires_trust: bool,
 }
 
 impl ScriptletResource {
@@ -47,7 +58,7 @@ impl ScriptletResource {
     }
 }
 
-#[derive(Default, Deserialize, Serialize)]
+#[derive(Clone, Default, Deserialize, Serialize)]
 pub struct ScriptletResourceStorage {
     resources: HashMap<String, ScriptletResource>,
 }
@@ -73,6 +84,7 @@ impl ScriptletResourceStorage {
             ResourceType::Mime(MimeType::ApplicationJavascript) | ResourceType::Template => {
                 let scriptlet = ScriptletResource {
                     scriptlet: String::from_utf8(base64::decode(&resource.content)?)?,
+                    requires_trust: resource.privileged || resource.name.starts_with(TRUSTED_SCRIPTLET_PREFIX),
                 };
                 Some((resource.name.to_owned(), resource.aliases.to_owned(), scriptlet))
             }
@@ -89,7 +101,19 @@ impl ScriptletResourceStorage {
         Ok(())
     }
 
+    /// Fills in the scriptlet template for the contents of a `+js(...)` rule. Privileged
+    /// scriptlets (see `Resource::privileged`) are refused, including when referenced through
+    /// an alias; use `get_trusted_scriptlet` for rules from trusted sources.
     pub fn get_scriptlet(&self, scriptlet_args: &str) -> Result<String, ScriptletResourceError> {
+        self.get_scriptlet_with_trust(scriptlet_args, false)
+    }
+
+    /// Like `get_scriptlet`, but also allows privileged scriptlets.
+    pub fn get_trusted_scriptlet(&self, scriptlet_args: &str) -> Result<String, ScriptletResourceError> {
+        self.get_scriptlet_with_trust(scriptlet_args, true)
+    }
+
+    fn get_scriptlet_with_trust(&self, scriptlet_args: &str, trusted: bool) -> Result<String, ScriptletResourceError> {
         let scriptlet_args = parse_scriptlet_args(scriptlet_args);
         if scriptlet_args.is_empty() {
             return Err(ScriptletResourceError::MissingScriptletName);
@@ -100,6 +124,10 @@ impl ScriptletResourceStorage {
             .get(scriptlet_name)
             .ok_or_else(|| ScriptletResourceError::NoMatchingScriptlet)?;
 
+        if template.requires_trust && !trusted {
+            return Err(ScriptletResourceError::UntrustedScriptlet);
+        }
+
         Ok(template.patch(args))
     }
 }
@@ -174,10 +202,10 @@ mod tests {
     #[test]
     fn get_patched_scriptlets() {
         let mut resources = HashMap::new();
-        resources.insert("greet".to_owned(), ScriptletResource { scriptlet: "console.log('Hello {{1}}, my name is {{2}}')".to_owned() });
-        resources.insert("alert".to_owned(), ScriptletResource { scriptlet: "alert('{{1}}')".to_owned() });
-        resources.insert("blocktimer".to_owned(), ScriptletResource { scriptlet: "setTimeout(blockAds, {{1}})".to_owned() });
-        resources.insert("null".to_owned(), ScriptletResource { scriptlet: "(()=>{})()".to_owned() });
+        resources.insert("greet".to_owned(), ScriptletResource { scriptlet: "console.log('Hello {{1}}, my name is {{2}}')".to_owned(), requires_trust: false });
+        resources.insert("alert".to_owned(), ScriptletResource { scriptlet: "alert('{{1}}')".to_owned(), requires_trust: false });
+        resources.insert("blocktimer".to_owned(), ScriptletResource { scriptlet: "setTimeout(blockAds, {{1}})".to_owned(), requires_trust: false });
+        resources.insert("null".to_owned(), ScriptletResource { scriptlet: "(()=>{})()".to_owned(), requires_trust: false });
         let scriptlets = ScriptletResourceStorage {
             resources,
         };
@@ -194,6 +222,46 @@ mod tests {
         assert_eq!(scriptlets.get_scriptlet(""), Err(ScriptletResourceError::MissingScriptletName));
     }
 
+    #[test]
+    fn trusted_scriptlets_require_trust() {
+        let scriptlets = ScriptletResourceStorage::from_resources(&[
+            Resource {
+                name: "trusted-set-cookie.js".into(),
+                aliases: vec!["tsc".into()],
+                kind: ResourceType::Template,
+                content: base64::encode("setCookie('{{1}}', '{{2}}');"),
+                privileged: false,
+            },
+            Resource {
+                name: "set-cookie.js".into(),
+                aliases: vec!["trusted-looking".into()],
+                kind: ResourceType::Template,
+                content: base64::encode("setCookie('{{1}}', 'ok');"),
+                privileged: false,
+            },
+            Resource {
+                name: "set-local-storage-item.js".into(),
+                aliases: vec!["slsi".into()],
+                kind: ResourceType::Template,
+                content: base64::encode("setItem('{{1}}');"),
+                privileged: true,
+            },
+        ]);
+
+        assert_eq!(scriptlets.get_scriptlet("trusted-set-cookie, a, b"), Err(ScriptletResourceError::UntrustedScriptlet));
+        assert_eq!(scriptlets.get_scriptlet("tsc.js, a, b"), Err(ScriptletResourceError::UntrustedScriptlet));
+        assert_eq!(scriptlets.get_trusted_scriptlet("tsc, a, b"), Ok("setCookie('a', 'b');".to_owned()));
+
+        // trust depends on the resource's name, not on the alias used to refer to it
+        assert_eq!(scriptlets.get_scriptlet("trusted-looking, a"), Ok("setCookie('a', 'ok');".to_owned()));
+        assert_eq!(scriptlets.get_trusted_scriptlet("set-cookie, a"), Ok("setCookie('a', 'ok');".to_owned()));
+
+        // resources can also be marked as privileged regardless of their name
+        assert_eq!(scriptlets.get_scriptlet("set-local-storage-item, a"), Err(ScriptletResourceError::UntrustedScriptlet));
+        assert_eq!(scriptlets.get_scriptlet("slsi, a"), Err(ScriptletResourceError::UntrustedScriptlet));
+        assert_eq!(scriptlets.get_trusted_scriptlet("slsi, a"), Ok("setItem('a');".to_owned()));
+    }
+
     #[test]
     fn parse_template_file_format() {
         let scriptlets = ScriptletResourceStorage::from_resources(&[
@@ -202,12 +270,14 @@ mod tests {
                 aliases: vec!["acis.js".into()],
                 kind: ResourceType::Mime(MimeType::ApplicationJavascript),
                 content: base64::encode("(function() {alert(\"hi\");})();"),
+                privileged: false,
             },
             Resource {
                 name: "abort-on-property-read.js".into(),
                 aliases: vec!["aopr".into()],
                 kind: ResourceType::Template,
                 content: base64::encode("(function() {confirm(\"Do you want to {{1}}?\");})();"),
+                privileged: false,
             },
         ]);
 
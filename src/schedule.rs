@@ -0,0 +1,121 @@
+//! Weekly schedules restricting when groups of network filters apply.
+//!
+//! Embedders can associate a `$tag` with a `Schedule` through `Engine::set_tag_schedule`, e.g. to
+//! block social media only during school hours. The engine doesn't know about time zones, so it
+//! asks a caller-provided `Clock` for the current local time whenever a scheduled tag could affect
+//! a request.
+
+use serde::{Deserialize, Serialize};
+
+/// Number of minutes in a day, i.e. the end of the last minute of a day.
+pub const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// A point in the week, in the local time of the user the schedule applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    /// Day of the week, from 0 for Monday to 6 for Sunday
+    pub weekday: u8,
+    /// Minutes elapsed since midnight, from 0 to `MINUTES_PER_DAY - 1`
+    pub minute_of_day: u16,
+}
+
+impl LocalTime {
+    pub fn new(weekday: u8, hour: u8, minute: u8) -> Self {
+        Self {
+            weekday: weekday % 7,
+            minute_of_day: (hour as u16 * 60 + minute as u16) % MINUTES_PER_DAY,
+        }
+    }
+}
+
+/// Source of the current local time, provided by the embedder.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> LocalTime;
+}
+
+/// A recurring period of time during which a schedule is active.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduleWindow {
+    /// Days on which the window starts, as a bit mask with bit 0 for Monday
+    pub weekdays: u8,
+    /// First minute of the window
+    pub start: u16,
+    /// Minute at which the window ends. If it isn't after `start`, the window spans midnight and
+    /// ends on the following day.
+    pub end: u16,
+}
+
+impl ScheduleWindow {
+    pub const WEEKDAYS: u8 = 0b0001_1111;
+    pub const WEEKEND: u8 = 0b0110_0000;
+    pub const EVERY_DAY: u8 = 0b0111_1111;
+
+    fn contains(&self, time: LocalTime) -> bool {
+        let starts_on = |weekday: u8| self.weekdays & (1 << (weekday % 7)) != 0;
+        if self.start < self.end {
+            starts_on(time.weekday) && time.minute_of_day >= self.start && time.minute_of_day < self.end
+        } else {
+            (starts_on(time.weekday) && time.minute_of_day >= self.start)
+                || (starts_on(time.weekday + 6) && time.minute_of_day < self.end)
+        }
+    }
+}
+
+/// A set of weekly windows. A schedule without any window is never active.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    windows: Vec<ScheduleWindow>,
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a window starting at `start` and ending at `end`, given as `(hour, minute)`, on each
+    /// of the days in the `weekdays` mask.
+    pub fn with_window(mut self, weekdays: u8, start: (u8, u8), end: (u8, u8)) -> Self {
+        self.windows.push(ScheduleWindow {
+            weekdays,
+            start: LocalTime::new(0, start.0, start.1).minute_of_day,
+            end: LocalTime::new(0, end.0, end.1).minute_of_day,
+        });
+        self
+    }
+
+    pub fn windows(&self) -> &[ScheduleWindow] {
+        &self.windows
+    }
+
+    /// Returns `true` if `time` falls within any of the windows of this schedule.
+    pub fn is_active(&self, time: LocalTime) -> bool {
+        self.windows.iter().any(|window| window.contains(time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows() {
+        let schedule = Schedule::new()
+            .with_window(ScheduleWindow::WEEKDAYS, (8, 0), (15, 30))
+            .with_window(ScheduleWindow::WEEKEND, (22, 0), (7, 0));
+
+        assert!(schedule.is_active(LocalTime::new(0, 8, 0)));
+        assert!(schedule.is_active(LocalTime::new(4, 15, 29)));
+        assert!(!schedule.is_active(LocalTime::new(4, 15, 30)));
+        assert!(!schedule.is_active(LocalTime::new(2, 7, 59)));
+        assert!(!schedule.is_active(LocalTime::new(5, 12, 0)));
+
+        // Windows spanning midnight belong to the day they start on
+        assert!(schedule.is_active(LocalTime::new(5, 23, 0)));
+        assert!(schedule.is_active(LocalTime::new(6, 6, 59)));
+        assert!(schedule.is_active(LocalTime::new(0, 6, 59)));
+        assert!(!schedule.is_active(LocalTime::new(5, 6, 59)));
+        assert!(!schedule.is_active(LocalTime::new(0, 7, 0)));
+
+        assert!(!Schedule::new().is_active(LocalTime::new(0, 12, 0)));
+    }
+}
@@ -0,0 +1,104 @@
+//! Fixed-size signatures for finding near-duplicate rules and URLs at scale.
+//!
+//! A `FuzzySignature` summarizes the set of tokens of a pattern (as produced by
+//! `utils::tokenize`) with a min-hash for each of `SIGNATURE_WIDTH` hash functions. The fraction of
+//! min-hashes two signatures have in common estimates the Jaccard similarity of their token sets,
+//! so signatures can be compared, bucketed or stored without keeping the tokens themselves.
+
+use serde::{Deserialize, Serialize};
+
+use crate::filters::network::NetworkFilter;
+use crate::utils::{create_fuzzy_signature, Hash};
+
+/// Number of min-hashes in a signature. The standard error of `similarity` is at most
+/// `0.5 / sqrt(SIGNATURE_WIDTH)`, i.e. under 0.09.
+pub const SIGNATURE_WIDTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FuzzySignature {
+    min_hashes: [Hash; SIGNATURE_WIDTH],
+    empty: bool,
+}
+
+impl FuzzySignature {
+    /// Signature of a set of tokens. Duplicates and order do not matter.
+    pub fn from_tokens(tokens: &[Hash]) -> Self {
+        let mut min_hashes = [Hash::MAX; SIGNATURE_WIDTH];
+        for &token in tokens {
+            for (i, min_hash) in min_hashes.iter_mut().enumerate() {
+                *min_hash = (*min_hash).min(permute(token, i));
+            }
+        }
+        Self {
+            min_hashes,
+            empty: tokens.is_empty(),
+        }
+    }
+
+    /// Signature of the tokens of a pattern or URL, as found by `utils::create_fuzzy_signature`.
+    pub fn from_pattern(pattern: &str) -> Self {
+        Self::from_tokens(&create_fuzzy_signature(pattern))
+    }
+
+    /// Signature of the tokens the engine uses to look up a network rule.
+    pub fn from_filter(filter: &NetworkFilter) -> Self {
+        let tokens: Vec<Hash> = filter.get_tokens().into_iter().flatten().collect();
+        Self::from_tokens(&tokens)
+    }
+
+    /// Whether the signature was created from no tokens at all, e.g. for `*`.
+    pub fn is_empty(&self) -> bool {
+        self.empty
+    }
+
+    /// The min-hashes of the signature, e.g. to split into bands for locality-sensitive hashing.
+    pub fn min_hashes(&self) -> &[Hash] {
+        &self.min_hashes
+    }
+}
+
+/// Estimated Jaccard similarity of the token sets behind two signatures, between `0.0` and `1.0`.
+/// Signatures without tokens are not similar to anything, including each other.
+pub fn similarity(a: &FuzzySignature, b: &FuzzySignature) -> f64 {
+    if a.empty || b.empty {
+        return 0.0;
+    }
+    let equal = a.min_hashes.iter().zip(b.min_hashes.iter()).filter(|(a, b)| a == b).count();
+    equal as f64 / SIGNATURE_WIDTH as f64
+}
+
+/// The `i`th hash function: the SplitMix64 finalizer, applied to the token offset by a
+/// per-function constant.
+fn permute(token: Hash, i: usize) -> Hash {
+    let mut x = token ^ (i as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn similarity_of_patterns() {
+        let sig = FuzzySignature::from_pattern;
+
+        let banner = sig("||ads.example.com/banner/300x250.gif");
+        assert_eq!(similarity(&banner, &sig("ads.example.com gif banner 300x250")), 1.0);
+
+        let other_size = similarity(&banner, &sig("||ads.example.com/banner/728x90.gif"));
+        assert!(other_size > 0.3 && other_size < 1.0, "{}", other_size);
+
+        assert_eq!(similarity(&banner, &sig("||tracker.net/pixel")), 0.0);
+        assert!(sig("*").is_empty());
+        assert_eq!(similarity(&sig("*"), &sig("*")), 0.0);
+    }
+
+    #[test]
+    fn similarity_of_filters() {
+        let sig = |rule: &str| FuzzySignature::from_filter(&NetworkFilter::parse(rule, false).unwrap());
+        assert_eq!(similarity(&sig("||ads.com/banner^"), &sig("||ads.com/banner^$script")), 1.0);
+        assert!(similarity(&sig("||ads.com/banner^"), &sig("||ads.com/popup^")) < 1.0);
+    }
+}
@@ -0,0 +1,71 @@
+//! Removal of well-known tracking parameters from URLs, independently of any `$removeparam` rules.
+//!
+//! Lists only strip the parameters they have rules for. `strip_tracking_params` removes a fixed
+//! set of parameters that are used for tracking by almost every site, like `utm_source` or
+//! `fbclid`. The same set is used by `Engine::filter_url_params` once enabled with
+//! `Engine::set_tracking_param_stripping`.
+
+use crate::utils;
+
+/// Parameters removed by `strip_tracking_params`, compared case-insensitively.
+pub const TRACKING_PARAMS: &[&str] = &[
+    "fbclid",
+    "gclid",
+    "gclsrc",
+    "dclid",
+    "gbraid",
+    "wbraid",
+    "msclkid",
+    "yclid",
+    "twclid",
+    "ttclid",
+    "igshid",
+    "li_fat_id",
+    "mc_cid",
+    "mc_eid",
+    "_hsenc",
+    "_hsmi",
+    "mkt_tok",
+    "oly_anon_id",
+    "oly_enc_id",
+    "vero_id",
+];
+
+/// Prefixes of parameters removed by `strip_tracking_params`, compared case-insensitively.
+pub const TRACKING_PARAM_PREFIXES: &[&str] = &[
+    "utm_",
+];
+
+/// Whether the query parameter called `name` is a well-known tracking parameter.
+pub fn is_tracking_param(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    TRACKING_PARAMS.contains(&name.as_str()) || TRACKING_PARAM_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+}
+
+/// Returns `url` without any of its well-known tracking parameters. Other parameters keep their
+/// order, and `url` is returned as is if it has no tracking parameters.
+pub fn strip_tracking_params(url: &str) -> String {
+    strip(url).unwrap_or_else(|| url.to_string())
+}
+
+/// Like `strip_tracking_params`, but returns `None` if `url` has no tracking parameters.
+pub(crate) fn strip(url: &str) -> Option<String> {
+    utils::remove_query_params(url, |param| is_tracking_param(param.split('=').next().unwrap_or_default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_tracking_params() {
+        assert_eq!(
+            strip_tracking_params("https://example.com/page?utm_source=news&id=5&UTM_Medium=email&fbclid=Ab&gclid#top"),
+            "https://example.com/page?id=5#top",
+        );
+        assert_eq!(strip_tracking_params("https://example.com/?utm_campaign=x"), "https://example.com/");
+        assert_eq!(strip_tracking_params("https://example.com/?id=5&utm=1&fbclid_x=2"), "https://example.com/?id=5&utm=1&fbclid_x=2");
+        assert_eq!(strip_tracking_params("https://example.com/#?utm_source=x"), "https://example.com/#?utm_source=x");
+        assert_eq!(strip(""), None);
+    }
+}
@@ -103,6 +103,11 @@ pub(crate) fn get_host_domain(host: &str) -> (usize, usize) {
     #[cfg(feature = "embedded-domain-resolver")]
     let domain_resolver = DefaultResolver;
 
+    // IP addresses don't have a registrable domain; the whole address identifies the party
+    if crate::utils::is_ip_literal(host) {
+        return (0, host.len());
+    }
+
     domain_resolver.get_host_domain(host)
 }
 
@@ -518,7 +518,10 @@ impl Parser {
         }
 
         if host_str.is_ascii() {
+            // Hostnames are case-insensitive, and `domain_to_ascii` lowercases the other ones
+            let host_start = self.serialization.len();
             write!(&mut self.serialization, "{}", host_str).unwrap();
+            self.serialization[host_start..].make_ascii_lowercase();
         } else {
             let encoded = idna::domain_to_ascii(&host_str)?;
             write!(&mut self.serialization, "{}", encoded).unwrap();
@@ -1,5 +1,9 @@
 //! Common utilities used by the library. Some tests and benchmarks rely on this module having
 //! public visibility.
+//!
+//! `tokenize`, `tokenize_filter` and `token_histogram` are stable, and re-exported from the crate
+//! root, so that other systems, like log classifiers, can bucket URLs and rules with exactly the
+//! same tokens as the engine.
 
 #[cfg(not(target_arch = "wasm32"))]
 use std::io::{BufRead, BufReader};
@@ -10,6 +14,10 @@ use seahash::reference::hash;
 #[cfg(target_pointer_width = "64")]
 use seahash::hash;
 
+use std::collections::HashMap;
+
+use crate::filters::network::NetworkFilter;
+
 pub type Hash = u64;
 
 #[inline]
@@ -22,27 +30,135 @@ fn is_allowed_filter(ch: char) -> bool {
     ch.is_alphanumeric() || ch == '%'
 }
 
+const LOW_BITS: u64 = 0x0101_0101_0101_0101;
+const HIGH_BITS: u64 = 0x8080_8080_8080_8080;
+
+/// Sets the high bit of each byte of `word` that is strictly between `low` and `high`, and clears
+/// all other bits. Only valid for words of ASCII bytes, with `low < 128` and `high <= 128`: no
+/// byte then carries into its neighbour.
+#[inline(always)]
+fn bytes_between(word: u64, low: u8, high: u8) -> u64 {
+    (LOW_BITS * (127 + high as u64)).wrapping_sub(word)
+        & !word
+        & word.wrapping_add(LOW_BITS * (127 - low as u64))
+        & HIGH_BITS
+}
+
+/// `is_allowed_filter` for the eight ASCII bytes of `word` at once, as a mask of their high bits.
+#[inline(always)]
+fn allowed_filter_bytes(word: u64) -> u64 {
+    bytes_between(word, b'0' - 1, b'9' + 1)
+        | bytes_between(word, b'A' - 1, b'Z' + 1)
+        | bytes_between(word, b'a' - 1, b'z' + 1)
+        | bytes_between(word, b'%' - 1, b'%' + 1)
+}
+
+/// Calls `f` with the start and end of each maximal run of `is_allowed_filter` bytes in the ASCII
+/// `bytes`, until it returns `false`. Bytes are classified eight at a time, and only the
+/// boundaries of runs are visited individually, by counting trailing zeros.
+#[inline(always)]
+fn for_each_ascii_run(bytes: &[u8], mut f: impl FnMut(usize, usize) -> bool) {
+    let chunks = bytes.chunks_exact(8);
+    // The remaining bytes are padded with zeros, which are never allowed. That last word is always
+    // present, so that it ends any run still open at the end of `bytes`.
+    let mut last = [0u8; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    let words = chunks
+        .map(|chunk| {
+            let mut word = [0u8; 8];
+            word.copy_from_slice(chunk);
+            word
+        })
+        .chain(std::iter::once(last));
+
+    let mut start = 0;
+    let mut inside = false;
+    let mut previous: u64 = 0;
+    for (word_index, word) in words.enumerate() {
+        let allowed = allowed_filter_bytes(u64::from_le_bytes(word));
+        // Bytes whose class differs from that of the byte before them, which alternate between
+        // starting and ending a run
+        let mut boundaries = allowed ^ ((allowed << 8) | (previous >> 56));
+        previous = allowed;
+        while boundaries != 0 {
+            let index = word_index * 8 + (boundaries.trailing_zeros() / 8) as usize;
+            boundaries &= boundaries - 1;
+            inside = !inside;
+            if inside {
+                start = index;
+            } else if !f(start, index) {
+                return;
+            }
+        }
+    }
+}
+
 pub(crate) const TOKENS_BUFFER_SIZE: usize = 128;
 pub(crate) const TOKENS_BUFFER_RESERVED: usize = 1;
 const TOKENS_MAX: usize = TOKENS_BUFFER_SIZE - TOKENS_BUFFER_RESERVED;
 
 fn fast_tokenizer_no_regex(
     pattern: &str,
-    is_allowed_code: &dyn Fn(char) -> bool,
     skip_first_token: bool,
     skip_last_token: bool,
     tokens_buffer: &mut Vec<Hash>
 ) {
-    // let mut tokens_buffer_index = 0;
+    // Nearly all URLs and patterns are ASCII, which `is_ascii` checks a word at a time. Those are
+    // split into runs a word at a time as well, rather than decoding each character.
+    if pattern.is_ascii() {
+        tokenize_ascii(pattern, skip_first_token, skip_last_token, tokens_buffer);
+    } else {
+        let classes = pattern.char_indices().map(|(i, c)| (i, is_allowed_filter(c), c == '*'));
+        tokenize_classified(pattern, classes, skip_first_token, skip_last_token, tokens_buffer);
+    }
+}
+
+/// ASCII path of `fast_tokenizer_no_regex`, with the same results as `tokenize_classified`.
+fn tokenize_ascii(
+    pattern: &str,
+    skip_first_token: bool,
+    skip_last_token: bool,
+    tokens_buffer: &mut Vec<Hash>
+) {
+    let bytes = pattern.as_bytes();
+    for_each_ascii_run(bytes, |start, end| {
+        if tokens_buffer.len() >= TOKENS_MAX {
+            return false;
+        }
+        // Should not be preceded or followed by '*'. A token that reaches the end of the pattern
+        // is only subject to `skip_last_token`.
+        let kept = if end == bytes.len() {
+            !skip_last_token
+        } else {
+            (start != 0 || !skip_first_token) && bytes[end] != b'*'
+        };
+        if kept && end - start > 1 && (start == 0 || bytes[start - 1] != b'*') {
+            tokens_buffer.push(fast_hash(&pattern[start..end]));
+        }
+        true
+    });
+}
+
+/// Tokenizer loop for the Unicode path of `fast_tokenizer_no_regex`. `classes` yields the byte
+/// index of each character in `pattern`, whether it can be part of a token, and whether it is a
+/// `*`.
+#[inline(always)]
+fn tokenize_classified(
+    pattern: &str,
+    classes: impl Iterator<Item = (usize, bool, bool)>,
+    skip_first_token: bool,
+    skip_last_token: bool,
+    tokens_buffer: &mut Vec<Hash>
+) {
     let mut inside: bool = false;
     let mut start = 0;
-    let mut preceding_ch: Option<char> = None; // Used to check if a '*' is not just before a token
+    let mut preceded_by_star = false; // Used to check if a '*' is not just before a token
 
-    for (i, c) in pattern.char_indices() {
+    for (i, allowed, is_star) in classes {
         if tokens_buffer.len() >= TOKENS_MAX {
             return;
         }
-        if is_allowed_code(c) {
+        if allowed {
             if !inside {
                 inside = true;
                 start = i;
@@ -52,22 +168,22 @@ fn fast_tokenizer_no_regex(
             // Should not be followed by '*'
             if (start != 0 || !skip_first_token)
                 && i - start > 1
-                && c != '*'
-                && preceding_ch != Some('*')
+                && !is_star
+                && !preceded_by_star
             {
                 let hash = fast_hash(&pattern[start..i]);
                 tokens_buffer.push(hash);
             }
-            preceding_ch = Some(c);
+            preceded_by_star = is_star;
         } else {
-            preceding_ch = Some(c);
+            preceded_by_star = is_star;
         }
     }
 
     if !skip_last_token
         && inside
         && pattern.len() - start > 1
-        && (preceding_ch != Some('*'))
+        && !preceded_by_star
     {
         let hash = fast_hash(&pattern[start..]);
         tokens_buffer.push(hash);
@@ -110,22 +226,135 @@ fn fast_tokenizer(
 }
 
 pub(crate) fn tokenize_pooled(pattern: &str, tokens_buffer: &mut Vec<Hash>) {
-    fast_tokenizer_no_regex(pattern, &is_allowed_filter, false, false, tokens_buffer);
+    fast_tokenizer_no_regex(pattern, false, false, tokens_buffer);
 }
 
+/// Splits `pattern` into the tokens the engine uses to look up rules: maximal runs of
+/// alphanumeric characters and `%`, hashed with `fast_hash`. Single characters and runs adjacent
+/// to a `*` are not tokens, and at most 127 tokens are produced.
+///
+/// Request URLs are tokenized this way, after being lowercased; the engine additionally adds
+/// tokens for their query parameters.
 pub fn tokenize(pattern: &str) -> Vec<Hash> {
     let mut tokens_buffer: Vec<Hash> = Vec::with_capacity(TOKENS_BUFFER_SIZE);
-    fast_tokenizer_no_regex(pattern, &is_allowed_filter, false, false, &mut tokens_buffer);
+    fast_tokenizer_no_regex(pattern, false, false, &mut tokens_buffer);
     tokens_buffer
 }
 
 
-pub(crate) fn tokenize_filter(pattern: &str, skip_first_token: bool, skip_last_token: bool) -> Vec<Hash> {
+/// Like `tokenize`, but optionally leaves out the first and last runs of `pattern`, which may
+/// only be part of a token in matching URLs. A rule like `/ads/banner` can match
+/// `/ads/banners.js`, so its last token must be skipped, unless the rule is anchored on the right.
+/// `NetworkFilter::get_tokens` returns the complete set of tokens for a rule.
+pub fn tokenize_filter(pattern: &str, skip_first_token: bool, skip_last_token: bool) -> Vec<Hash> {
     let mut tokens_buffer: Vec<Hash> = Vec::with_capacity(TOKENS_BUFFER_SIZE);
-    fast_tokenizer_no_regex(pattern, &is_allowed_filter, skip_first_token, skip_last_token, &mut tokens_buffer);
+    fast_tokenizer_no_regex(pattern, skip_first_token, skip_last_token, &mut tokens_buffer);
     tokens_buffer
 }
 
+/// Tokens that occur in so many URLs that rules are only placed in their bucket if they have no
+/// other token.
+const BAD_TOKENS: [&str; 4] = ["http", "https", "www", "com"];
+
+/// Counts how often each token occurs among the tokens of `filters`, as used by the engine to
+/// place each rule in the bucket of its least frequent token. Returns the total number of tokens
+/// along with the count of each one. Tokens in `BAD_TOKENS` are given the total count, which
+/// makes them the least preferred.
+pub fn token_histogram<'a>(filters: impl IntoIterator<Item = &'a NetworkFilter>) -> (u32, HashMap<Hash, u32>) {
+    let filter_tokens: Vec<_> = filters.into_iter().map(NetworkFilter::get_tokens).collect();
+    count_tokens(filter_tokens.iter().flatten())
+}
+
+pub(crate) fn count_tokens<'a>(token_groups: impl Iterator<Item = &'a Vec<Hash>>) -> (u32, HashMap<Hash, u32>) {
+    let mut tokens_histogram: HashMap<Hash, u32> = HashMap::new();
+    let mut number_of_tokens = 0;
+    for tokens in token_groups {
+        for token in tokens {
+            *tokens_histogram.entry(*token).or_insert(0) += 1;
+            number_of_tokens += 1;
+        }
+    }
+
+    for bad_token in BAD_TOKENS.iter() {
+        tokens_histogram.insert(fast_hash(bad_token), number_of_tokens);
+    }
+
+    (number_of_tokens, tokens_histogram)
+}
+
+fn is_allowed_query_key(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '%' || ch == '_' || ch == '-' || ch == '.'
+}
+
+/// Adds tokens for the query parameters of a request URL, given everything after its hostname.
+/// Any segment following a `?` or `&` is treated as a parameter. Parameter keys that would be
+/// split up by the regular tokenizer (like `utm_source`) are added as a whole, and `key=value`
+/// pairs are added as well.
+pub(crate) fn tokenize_query_params(url_after_hostname: &str, tokens_buffer: &mut Vec<Hash>) {
+    for param in query_params(url_after_hostname) {
+        if tokens_buffer.len() + 2 > TOKENS_MAX {
+            return;
+        }
+        let key = param.split('=').next().unwrap_or_default();
+        if key.is_empty() || !key.chars().all(is_allowed_query_key) {
+            continue;
+        }
+        if !key.chars().all(is_allowed_filter) {
+            tokens_buffer.push(fast_hash(key));
+        }
+        if param.len() > key.len() {
+            tokens_buffer.push(fast_hash(param));
+        }
+    }
+}
+
+/// Counterpart of `tokenize_query_params` for filter patterns. Tokens are only produced for
+/// parameters that are guaranteed to appear in the same form in any matching request: the key
+/// must directly follow a literal `?` or `&` and end with `=`, and a `key=value` pair must also
+/// be followed by `?`, `&`, or the end of a right-anchored pattern.
+pub(crate) fn tokenize_filter_query_params(pattern: &str, right_anchored: bool, tokens_buffer: &mut Vec<Hash>) {
+    let mut params = query_params(pattern).peekable();
+    while let Some(param) = params.next() {
+        let is_last = params.peek().is_none();
+        let separator = match param.find('=') {
+            Some(separator) => separator,
+            None => continue,
+        };
+        let (key, value) = (&param[..separator], &param[separator + 1..]);
+        if key.is_empty() || !key.chars().all(is_allowed_query_key) {
+            continue;
+        }
+        if !key.chars().all(is_allowed_filter) {
+            tokens_buffer.push(fast_hash(key));
+        }
+        let value_is_literal = !value.contains(|c| c == '*' || c == '^' || c == '|');
+        if value_is_literal && (!is_last || right_anchored) {
+            tokens_buffer.push(fast_hash(param));
+        }
+    }
+}
+
+/// Returns each segment of `s` following a `?` or `&`, i.e. the same as
+/// `s.split(|c| c == '?' || c == '&').skip(1)`. Separators are located with `memchr2` instead of
+/// decoding each character; this matters for long URLs with few or no query parameters.
+fn query_params(s: &str) -> impl Iterator<Item = &str> {
+    let mut separators = memchr::memchr2_iter(b'?', b'&', s.as_bytes()).peekable();
+    std::iter::from_fn(move || {
+        let start = separators.next()? + 1;
+        let end = separators.peek().copied().unwrap_or(s.len());
+        Some(&s[start..end])
+    })
+}
+
+/// Returns the distinct tokens of `pattern`, sorted. Patterns with the same signature contain the
+/// same words, possibly in a different order; see `similarity` for a fixed-size alternative that
+/// also scores partial overlap.
+pub fn create_fuzzy_signature(pattern: &str) -> Vec<Hash> {
+    let mut tokens = tokenize(pattern);
+    compact_tokens(&mut tokens);
+    tokens
+}
+
 fn compact_tokens<T: std::cmp::Ord>(tokens: &mut Vec<T>) {
     tokens.sort_unstable();
     tokens.dedup();
@@ -135,6 +364,26 @@ pub(crate) fn bin_lookup<T: Ord>(arr: &[T], elt: T) -> bool {
     arr.binary_search(&elt).is_ok()
 }
 
+/// Checks whether a host is an IPv4 address, or an IPv6 address between brackets, as found in
+/// URLs. Such hosts have no parent domains.
+pub fn is_ip_literal(host: &str) -> bool {
+    if host.starts_with('[') && host.ends_with(']') {
+        host[1..host.len() - 1].parse::<std::net::Ipv6Addr>().is_ok()
+    } else {
+        host.parse::<std::net::Ipv4Addr>().is_ok()
+    }
+}
+
+/// Hashes a domain given in the `$domain` option of a filter. IPv6 addresses can be given with or
+/// without brackets.
+pub(crate) fn domain_option_hash(domain: &str) -> Hash {
+    if domain.contains(':') && domain.parse::<std::net::Ipv6Addr>().is_ok() {
+        fast_hash(&format!("[{}]", domain))
+    } else {
+        fast_hash(domain)
+    }
+}
+
 const EXPECTED_RULES: usize = 75000;
 #[cfg(not(target_arch = "wasm32"))]
 pub fn read_file_lines(filename: &str) -> Vec<String> {
@@ -159,15 +408,34 @@ pub fn rules_from_lists(lists: &[String]) -> Vec<String> {
     rules
 }
 
+/// Returns `url` without the query parameters for which `remove` returns `true`, or `None` if no
+/// parameter is removed. Parameters are passed as `name=value`, or `name` if they have no value.
+/// The fragment is kept as is.
+pub(crate) fn remove_query_params(url: &str, remove: impl Fn(&str) -> bool) -> Option<String> {
+    let query_end = url.find('#').unwrap_or(url.len());
+    let query_start = url[..query_end].find('?')?;
+
+    let params: Vec<&str> = url[query_start + 1..query_end].split('&').filter(|param| !param.is_empty()).collect();
+    let kept: Vec<&str> = params.iter().copied().filter(|param| !remove(param)).collect();
+    if kept.len() == params.len() {
+        return None;
+    }
+
+    let mut filtered = url[..query_start].to_string();
+    if !kept.is_empty() {
+        filtered.push('?');
+        filtered.push_str(&kept.join("&"));
+    }
+    filtered.push_str(&url[query_end..]);
+    Some(filtered)
+}
+
+#[cfg(feature = "compression")]
 pub(crate) fn is_eof_error(e: &rmp_serde::decode::Error) -> bool {
+    // Newer versions of the standard library report a short read without a custom inner error,
+    // so only the kind of the error can be relied on
     if let rmp_serde::decode::Error::InvalidMarkerRead(e) = e {
-        if e.kind() == std::io::ErrorKind::UnexpectedEof {
-            if let Some(e) = e.get_ref() {
-                if format!("{}", e) == "failed to fill whole buffer" {
-                    return true;
-                }
-            }
-        }
+        return e.kind() == std::io::ErrorKind::UnexpectedEof;
     }
     false
 }
@@ -192,6 +460,26 @@ mod tests {
         tokens.into_iter().map(|t| fast_hash(&t)).collect()
     }
 
+    #[test]
+    fn tokenize_query_params_works() {
+        let mut tokens = vec![];
+        tokenize_query_params("/path_x/a?utm_source=news&gclid=abc&flag&x_y=", &mut tokens);
+        assert_eq!(tokens, t(&["utm_source", "utm_source=news", "gclid=abc", "x_y", "x_y="]));
+
+        let mut tokens = vec![];
+        tokenize_filter_query_params("/a?utm_source=news&gclid=abc", false, &mut tokens);
+        assert_eq!(tokens, t(&["utm_source", "utm_source=news"]));
+
+        let mut tokens = vec![];
+        tokenize_filter_query_params("/a?utm_source=news&gclid=abc", true, &mut tokens);
+        assert_eq!(tokens, t(&["utm_source", "utm_source=news", "gclid=abc"]));
+
+        // keys must be complete and directly follow a `?` or `&`
+        let mut tokens = vec![];
+        tokenize_filter_query_params("utm_source=a&utm_*=b&utm_medium&x_id=*&", false, &mut tokens);
+        assert_eq!(tokens, t(&["x_id"]));
+    }
+
     #[test]
     fn tokenize_filter_works() {
         assert_eq!(
@@ -237,6 +525,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn create_fuzzy_signature_works() {
+        assert_eq!(create_fuzzy_signature("foo/bar/foo"), create_fuzzy_signature("bar-foo"));
+        assert_eq!(create_fuzzy_signature("foo/bar").len(), 2);
+        assert!(create_fuzzy_signature("*").is_empty());
+    }
+
+    #[test]
+    fn token_histogram_works() {
+        let filters: Vec<_> = ["||ads.com/banner^", "/banner/*", "||tracker.net^"].iter()
+            .map(|rule| NetworkFilter::parse(rule, false).unwrap())
+            .collect();
+        let (total, histogram) = token_histogram(&filters);
+        assert_eq!(total, 6);
+        assert_eq!(histogram.get(&fast_hash("banner")), Some(&2));
+        assert_eq!(histogram.get(&fast_hash("ads")), Some(&1));
+        assert_eq!(histogram.get(&fast_hash("tracker")), Some(&1));
+        assert_eq!(histogram.get(&fast_hash("com")), Some(&6));
+        assert_eq!(histogram.get(&fast_hash("www")), Some(&6));
+    }
+
     #[test]
     fn tokenize_works() {
         assert_eq!(
@@ -279,6 +588,56 @@ mod tests {
         );
     }
 
+    #[test]
+    fn ascii_tokenization_matches_unicode() {
+        // Appending a non-ASCII token forces the Unicode path for the same leading content
+        for pattern in &["foo", "*foo.bar", "foo.bar*", "a/bb/c*dd/ee*", "%AB%20cd", "data:image/png;base64,iVBORw0KGgo*", "x"] {
+            let mut ascii = tokenize(pattern);
+            ascii.push(fast_hash("Ƭƫ"));
+            assert_eq!(tokenize(&format!("{}/Ƭƫ", pattern)), ascii, "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn ascii_runs_match_classified() {
+        let long = "https://cdn.example.com/a/bb/ccc*/dddd?x=1&yy=22*%2Fz".repeat(20);
+        let patterns = ["", "a", "ab", "*ab", "ab*", "abcdefgh", "abcdefg*", "*bcdefgh/ij", "a.b.c.d.e.f.g.h.i", "%%/**/__", long.as_str()];
+        for pattern in patterns.iter() {
+            for &(skip_first, skip_last) in &[(false, false), (true, false), (false, true), (true, true)] {
+                let mut ascii = vec![];
+                tokenize_ascii(pattern, skip_first, skip_last, &mut ascii);
+                let mut classified = vec![];
+                let classes = pattern.char_indices().map(|(i, c)| (i, is_allowed_filter(c), c == '*'));
+                tokenize_classified(pattern, classes, skip_first, skip_last, &mut classified);
+                assert_eq!(ascii, classified, "{} {} {}", pattern, skip_first, skip_last);
+            }
+        }
+    }
+
+    #[test]
+    fn query_params_works() {
+        for s in &["", "/path", "/path?", "/p?a=1&b=2", "?&&x", "/p&a?b=ƫ&c"] {
+            assert_eq!(
+                query_params(s).collect::<Vec<_>>(),
+                s.split(|c| c == '?' || c == '&').skip(1).collect::<Vec<_>>(),
+                "{}", s,
+            );
+        }
+    }
+
+    #[test]
+    fn ip_literals() {
+        assert!(is_ip_literal("192.168.1.1"));
+        assert!(is_ip_literal("[::1]"));
+        assert!(is_ip_literal("[2001:db8::ff00:42:8329]"));
+        assert!(!is_ip_literal("::1"));
+        assert!(!is_ip_literal("192.168.1"));
+        assert!(!is_ip_literal("1.2.3.4.example.com"));
+        assert!(!is_ip_literal("example.com"));
+        assert_eq!(domain_option_hash("::1"), fast_hash("[::1]"));
+        assert_eq!(domain_option_hash("[::1]"), fast_hash("[::1]"));
+    }
+
     #[test]
     fn bin_lookup_works() {
         assert_eq!(bin_lookup(&vec![], 42), false);
@@ -0,0 +1,85 @@
+//! Checks `NetworkFilter` matching against the hand-written vectors in
+//! `data/filter-matching-vectors/network-filters.tsv`, which give the expected result of pairs of
+//! filters and requests according to the documented filter syntax. See the header of that file for
+//! its format.
+
+use adblock::filters::network::{NetworkFilter, NetworkMatchable};
+use adblock::request::Request;
+
+use serde::Deserialize;
+
+use std::fs::File;
+use std::io::BufReader;
+
+const VECTORS: &str = "data/filter-matching-vectors/network-filters.tsv";
+
+#[derive(Debug, Deserialize)]
+struct MatchingVector {
+    filter: String,
+    url: String,
+    source_url: String,
+    r#type: String,
+    expected: u8,
+    /// Reason why this crate is known to disagree with the documented behavior on this vector
+    #[serde(default)]
+    divergence: Option<String>,
+}
+
+fn load_vectors() -> Vec<MatchingVector> {
+    let reader = BufReader::new(File::open(VECTORS).expect("file not found"));
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .comment(Some(b'#'))
+        .quoting(false)
+        .flexible(true)
+        .from_reader(reader);
+
+    rdr.deserialize()
+        .map(|result| result.expect("malformed matching vector"))
+        .collect()
+}
+
+/// Whether the filter matches the request, or a description of why it could not be evaluated.
+fn evaluate(vector: &MatchingVector) -> Result<bool, String> {
+    let filter = NetworkFilter::parse(&vector.filter, true)
+        .map_err(|e| format!("filter could not be parsed: {:?}", e))?;
+    let source_url = if vector.source_url == "-" { "" } else { &vector.source_url };
+    let request = Request::from_urls(&vector.url, source_url, &vector.r#type)
+        .map_err(|e| format!("request could not be parsed: {:?}", e))?;
+    Ok(filter.matches(&request))
+}
+
+#[test]
+fn matches_documented_behavior() {
+    let vectors = load_vectors();
+    assert!(!vectors.is_empty());
+
+    let mut failures = vec![];
+    for vector in vectors.iter() {
+        let expected = vector.expected == 1;
+        // Known divergences are checked too, so that they are removed from the vectors once fixed
+        let expected = if vector.divergence.is_some() { !expected } else { expected };
+        match evaluate(vector) {
+            Ok(matched) if matched == expected => (),
+            Ok(matched) => failures.push(format!(
+                "{}\t{}\t{}\t{}: expected {}, got {}{}",
+                vector.filter,
+                vector.url,
+                vector.source_url,
+                vector.r#type,
+                expected,
+                matched,
+                vector.divergence.as_ref().map(|reason| format!(" (known divergence: {})", reason)).unwrap_or_default(),
+            )),
+            Err(error) => failures.push(format!("{}\t{}: {}", vector.filter, vector.url, error)),
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{} of {} matching vectors failed:\n{}",
+        failures.len(),
+        vectors.len(),
+        failures.join("\n"),
+    );
+}
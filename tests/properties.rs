@@ -0,0 +1,190 @@
+//! Property-based tests, checking invariants of the engine against randomly generated requests
+//! and filter lists.
+//!
+//! Hostnames, paths and options are drawn from small vocabularies, so that generated filters
+//! frequently match generated requests. Failing cases are shrunk by `proptest` and reported with
+//! the minimal filters and request reproducing them.
+
+use adblock::blocker::BlockerResult;
+use adblock::engine::Engine;
+use adblock::filters::network::NetworkFilter;
+use adblock::lists::FilterFormat;
+
+use proptest::prelude::*;
+use proptest::sample::{select, subsequence};
+
+const LABELS: &[&str] = &["ads", "cdn", "tracker", "example", "static"];
+const TLDS: &[&str] = &["com", "net", "org"];
+const SEGMENTS: &[&str] = &["ad", "ads", "banner", "js", "img", "track", "pixel"];
+const EXTENSIONS: &[&str] = &["", ".js", ".png", ".css", ".gif"];
+const REQUEST_TYPES: &[&str] = &["script", "image", "stylesheet", "xmlhttprequest", "sub_frame", "document", "other"];
+const TYPE_OPTIONS: &[&str] = &["script", "image", "stylesheet", "xmlhttprequest", "subdocument", "document", "other"];
+
+fn hostname() -> impl Strategy<Value = String> {
+    (prop::collection::vec(select(LABELS), 1..3), select(TLDS))
+        .prop_map(|(labels, tld)| format!("{}.{}", labels.join("."), tld))
+}
+
+fn path() -> impl Strategy<Value = String> {
+    (prop::collection::vec(select(SEGMENTS), 0..3), select(EXTENSIONS), any::<bool>())
+        .prop_map(|(segments, extension, query)| {
+            let mut path = format!("/{}", segments.join("/"));
+            if !segments.is_empty() {
+                path.push_str(extension);
+            }
+            if query {
+                path.push_str("?id=1");
+            }
+            path
+        })
+}
+
+fn url() -> impl Strategy<Value = String> {
+    (select(&["http", "https"][..]), hostname(), path())
+        .prop_map(|(scheme, hostname, path)| format!("{}://{}{}", scheme, hostname, path))
+}
+
+/// A request, as the arguments of `Engine::check_network_urls`.
+fn request() -> impl Strategy<Value = (String, String, &'static str)> {
+    (url(), hostname(), select(REQUEST_TYPES))
+        .prop_map(|(url, source_hostname, request_type)| (url, format!("https://{}/", source_hostname), request_type))
+}
+
+/// The pattern of a network rule, without options.
+fn pattern() -> impl Strategy<Value = String> {
+    prop_oneof![
+        hostname().prop_map(|hostname| format!("||{}^", hostname)),
+        (hostname(), path()).prop_map(|(hostname, path)| format!("||{}{}", hostname, path)),
+        path(),
+        select(SEGMENTS).prop_map(|segment| format!("/{}/", segment)),
+        (select(SEGMENTS), select(EXTENSIONS)).prop_map(|(segment, extension)| format!("{}*{}", segment, extension)),
+        hostname().prop_map(|hostname| format!("|https://{}/", hostname)),
+    ]
+}
+
+/// Options of a network rule, with each kind of option given at most once, in a canonical order.
+fn options() -> impl Strategy<Value = Vec<String>> {
+    let party = select(&["", "third-party", "~third-party"][..]);
+    let types = subsequence(TYPE_OPTIONS, 0..3);
+    let domains = prop::collection::vec((hostname(), any::<bool>()), 0..3).prop_map(|domains| {
+        let values: Vec<String> = domains.into_iter()
+            .map(|(domain, negated)| if negated { format!("~{}", domain) } else { domain })
+            .collect();
+        values.join("|")
+    });
+    (party, types, domains).prop_map(|(party, types, domains)| {
+        let mut options: Vec<String> = types.into_iter().map(String::from).collect();
+        if !party.is_empty() {
+            options.push(party.to_string());
+        }
+        if !domains.is_empty() {
+            options.push(format!("domain={}", domains));
+        }
+        options
+    })
+}
+
+fn with_options(pattern: &str, options: &[String]) -> String {
+    if options.is_empty() {
+        pattern.to_string()
+    } else {
+        format!("{}${}", pattern, options.join(","))
+    }
+}
+
+/// A blocking rule, possibly `$important`.
+fn blocking_rule() -> impl Strategy<Value = String> {
+    (pattern(), options(), prop::bool::weighted(0.1)).prop_map(|(pattern, mut options, important)| {
+        if important {
+            options.push("important".to_string());
+        }
+        with_options(&pattern, &options)
+    })
+}
+
+fn exception_rule() -> impl Strategy<Value = String> {
+    (pattern(), options()).prop_map(|(pattern, options)| format!("@@{}", with_options(&pattern, &options)))
+}
+
+fn check(engine: &Engine, request: &(String, String, &str)) -> BlockerResult {
+    engine.check_network_urls(&request.0, &request.1, request.2)
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(128))]
+
+    /// Exceptions can only ever unblock requests.
+    #[test]
+    fn exceptions_never_increase_blocking(
+        rules in prop::collection::vec(blocking_rule(), 1..12),
+        exceptions in prop::collection::vec(exception_rule(), 1..6),
+        requests in prop::collection::vec(request(), 1..16),
+    ) {
+        let engine = Engine::from_rules(&rules, FilterFormat::Standard);
+        let with_exceptions = Engine::from_rules(&[rules.clone(), exceptions].concat(), FilterFormat::Standard);
+        for request in requests.iter() {
+            if check(&with_exceptions, request).matched {
+                prop_assert!(check(&engine, request).matched, "{:?}", request);
+            }
+        }
+    }
+
+    /// `$important` rules are not affected by exceptions.
+    #[test]
+    fn important_rules_ignore_exceptions(
+        rule in (pattern(), options()).prop_map(|(pattern, mut options)| {
+            options.push("important".to_string());
+            with_options(&pattern, &options)
+        }),
+        exceptions in prop::collection::vec(exception_rule(), 1..6),
+        requests in prop::collection::vec(request(), 1..16),
+    ) {
+        let engine = Engine::from_rules(&[rule.clone()], FilterFormat::Standard);
+        let with_exceptions = Engine::from_rules(&[vec![rule], exceptions].concat(), FilterFormat::Standard);
+        for request in requests.iter() {
+            prop_assert_eq!(check(&engine, request).matched, check(&with_exceptions, request).matched, "{:?}", request);
+        }
+    }
+
+    /// A serialized and deserialized engine makes the same decisions, reporting the same filters.
+    #[test]
+    fn serialization_preserves_decisions(
+        rules in prop::collection::vec(prop_oneof![3 => blocking_rule(), 1 => exception_rule()], 1..16),
+        requests in prop::collection::vec(request(), 1..16),
+    ) {
+        let engine = Engine::from_rules_debug(&rules, FilterFormat::Standard);
+        let mut deserialized = Engine::new(true);
+        deserialized.deserialize(&engine.serialize().unwrap()).unwrap();
+        for request in requests.iter() {
+            let expected = check(&engine, request);
+            let actual = check(&deserialized, request);
+            prop_assert_eq!(expected.matched, actual.matched, "{:?}", request);
+            prop_assert_eq!(expected.important, actual.important, "{:?}", request);
+            prop_assert_eq!(expected.filter_id, actual.filter_id, "{:?}", request);
+            prop_assert_eq!(expected.exception_id, actual.exception_id, "{:?}", request);
+        }
+    }
+
+    /// The stable id of a rule does not depend on the order of its options.
+    #[test]
+    fn option_order_does_not_affect_id(
+        (rule_pattern, rule_options, shuffled) in (pattern(), options())
+            .prop_flat_map(|(pattern, options)| (Just(pattern), Just(options.clone()), Just(options).prop_shuffle())),
+    ) {
+        let filter = NetworkFilter::parse(&with_options(&rule_pattern, &rule_options), false).unwrap();
+        let reordered = NetworkFilter::parse(&with_options(&rule_pattern, &shuffled), false).unwrap();
+        prop_assert_eq!(filter.id, reordered.id);
+    }
+
+    /// Matching a request never depends on the letter case of its hostname.
+    #[test]
+    fn hostname_case_does_not_affect_decisions(
+        rules in prop::collection::vec(blocking_rule(), 1..12),
+        (scheme, host, url_path, request_type) in (select(&["http", "https"][..]), hostname(), path(), select(REQUEST_TYPES)),
+    ) {
+        let engine = Engine::from_rules(&rules, FilterFormat::Standard);
+        let lowercase = (format!("{}://{}{}", scheme, host, url_path), String::new(), request_type);
+        let uppercase = (format!("{}://{}{}", scheme, host.to_ascii_uppercase(), url_path), String::new(), request_type);
+        prop_assert_eq!(check(&engine, &lowercase).matched, check(&engine, &uppercase).matched);
+    }
+}